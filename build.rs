@@ -0,0 +1,94 @@
+// With the `codegen` feature, parse the cl100k ranks and recover their merges
+// here, and generate static tables of them for `GPT4Tokenizer::new`.
+
+#[cfg(feature = "codegen")]
+#[path = "src/recover.rs"]
+mod recover;
+
+fn main() {
+    println!("cargo:rerun-if-changed=build.rs");
+    #[cfg(feature = "codegen")]
+    codegen::generate();
+}
+
+#[cfg(feature = "codegen")]
+mod codegen {
+    use base64::{Engine as _, engine::general_purpose};
+    use miniz_oxide::inflate::decompress_to_vec_zlib;
+    use std::collections::HashMap;
+    use std::fmt::Write as _;
+    use std::path::PathBuf;
+    use std::{env, fs};
+
+    use crate::recover::recover_merges;
+
+    const RANKS: &str = "assets/cl100k_base.tiktoken.zlib";
+
+    pub(crate) fn generate() {
+        println!("cargo:rerun-if-changed={}", RANKS);
+        println!("cargo:rerun-if-changed=src/recover.rs");
+        let compressed = fs::read(RANKS).expect("can't read the cl100k ranks");
+        let text = decompress_to_vec_zlib(&compressed).expect("corrupted cl100k ranks");
+        let ranks: Vec<(Vec<u8>, i32)> = text
+            .split(|&b| b == b'\n')
+            .filter(|line| !line.is_empty())
+            .map(|line| {
+                let line = std::str::from_utf8(line).unwrap();
+                let (token, rank) = line.split_once(' ').unwrap();
+                let token = general_purpose::STANDARD.decode(token).unwrap();
+                (token, rank.parse().unwrap())
+            })
+            .collect();
+        let rank_of: HashMap<&[u8], i32> = ranks
+            .iter()
+            .map(|(token, rank)| (&token[..], *rank))
+            .collect();
+        let merges = recover_merges(
+            ranks.iter().map(|(token, rank)| (&token[..], *rank)),
+            |bytes| rank_of.get(bytes).copied(),
+            || {},
+        )
+        .expect("incoherent cl100k ranks");
+
+        let mut out = String::new();
+        writeln!(out, "// Generated by build.rs from {}.", RANKS).unwrap();
+        let byte_shuffle: Vec<String> =
+            (0..=255u8).map(|b| rank_of[&[b][..]].to_string()).collect();
+        writeln!(
+            out,
+            "pub(crate) static CL100K_BYTE_SHUFFLE: [u8; 256] = [{}];",
+            byte_shuffle.join(", ")
+        )
+        .unwrap();
+        writeln!(
+            out,
+            "pub(crate) static CL100K_MERGES: [((Token, Token), Token); {}] = [",
+            merges.len()
+        )
+        .unwrap();
+        for ((left, right), rank) in &merges {
+            writeln!(out, "    (({}, {}), {}),", left, right, rank).unwrap();
+        }
+        out.push_str("];\n");
+        writeln!(
+            out,
+            "pub(crate) static CL100K_RANKS: [&[u8]; {}] = [",
+            ranks.len()
+        )
+        .unwrap();
+        let mut by_rank: Vec<&(Vec<u8>, i32)> = ranks.iter().collect();
+        by_rank.sort_by_key(|&&(_, rank)| rank);
+        for (i, (token, rank)) in by_rank.into_iter().enumerate() {
+            assert_eq!(i as i32, *rank, "the cl100k ranks must be dense");
+            out.push_str("    b\"");
+            for b in token {
+                write!(out, "\\x{:02x}", b).unwrap();
+            }
+            out.push_str("\",\n");
+        }
+        out.push_str("];\n");
+
+        let path = PathBuf::from(env::var_os("OUT_DIR").unwrap()).join("cl100k_tables.rs");
+        fs::write(path, out).unwrap();
+    }
+}
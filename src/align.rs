@@ -0,0 +1,91 @@
+/*
+Maps between token indices, character indices and byte offsets of an encoded
+text, for UI code that works in character positions (cursors, selections)
+and needs to know which tokens cover them.
+*/
+
+use std::ops::Range;
+
+use crate::base::Token;
+
+/// How the tokens of an encoding line up with the characters and bytes of the
+/// text it came from. Built from the output of `encode_with_offsets`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Alignment {
+    /// Byte range of every token, consecutive and in order.
+    tokens: Vec<Range<usize>>,
+    /// Byte offset where every character starts, plus the length of the text.
+    chars: Vec<usize>,
+}
+
+impl Alignment {
+    pub fn new(text: &str, offsets: &[(Token, Range<usize>)]) -> Self {
+        let mut chars: Vec<usize> = text.char_indices().map(|(at, _)| at).collect();
+        chars.push(text.len());
+        Alignment {
+            tokens: offsets.iter().map(|(_, range)| range.clone()).collect(),
+            chars,
+        }
+    }
+
+    pub fn num_tokens(&self) -> usize {
+        self.tokens.len()
+    }
+
+    pub fn num_chars(&self) -> usize {
+        self.chars.len() - 1
+    }
+
+    pub fn token_to_bytes(&self, token: usize) -> Option<Range<usize>> {
+        self.tokens.get(token).cloned()
+    }
+
+    /// The characters a token overlaps, including any it only holds some of
+    /// the bytes of.
+    pub fn token_to_chars(&self, token: usize) -> Option<Range<usize>> {
+        let bytes = self.tokens.get(token)?;
+        let start = self.byte_to_char(bytes.start)?;
+        // the character holding the token's last byte is the last one covered
+        let end = self.byte_to_char(bytes.end - 1)? + 1;
+        Some(start..end)
+    }
+
+    /// The token whose bytes include `byte`.
+    pub fn byte_to_token(&self, byte: usize) -> Option<usize> {
+        let token = self.tokens.partition_point(|range| range.end <= byte);
+        self.tokens
+            .get(token)
+            .filter(|range| range.contains(&byte))
+            .map(|_| token)
+    }
+
+    /// The character whose bytes include `byte`.
+    pub fn byte_to_char(&self, byte: usize) -> Option<usize> {
+        if byte >= *self.chars.last().unwrap() {
+            return None;
+        }
+        Some(self.chars.partition_point(|&at| at <= byte) - 1)
+    }
+
+    /// The byte offset where character `char` starts. The number of
+    /// characters maps to the length of the text, i.e. the end position.
+    pub fn char_to_byte(&self, char: usize) -> Option<usize> {
+        self.chars.get(char).copied()
+    }
+
+    /// The tokens covering character `char`. Usually one, but a character
+    /// split over several tokens (e.g. an emoji) is covered by all of them.
+    pub fn char_to_tokens(&self, char: usize) -> Option<Range<usize>> {
+        let start = *self.chars.get(char)?;
+        let end = *self.chars.get(char + 1)?;
+        let first = self.byte_to_token(start)?;
+        let last = self.byte_to_token(end - 1)?;
+        Some(first..last + 1)
+    }
+
+    /// The token a cursor placed before character `char` is in, i.e. the
+    /// first token covering that character.
+    pub fn char_to_token(&self, char: usize) -> Option<usize> {
+        self.char_to_tokens(char).map(|tokens| tokens.start)
+    }
+}
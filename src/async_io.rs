@@ -0,0 +1,66 @@
+/*
+Encoding from async code. Tokenizing is CPU-bound, so running it on the
+worker threads of an async runtime stalls every other task scheduled there;
+these helpers move the work to tokio's blocking thread pool instead.
+*/
+
+use std::fs::File;
+use std::io;
+use std::panic;
+use std::path::PathBuf;
+use std::sync::Arc;
+
+use tokio::sync::mpsc;
+use tokio::task;
+
+use crate::base::Token;
+use crate::gpt4::GPT4Tokenizer;
+use crate::stream::SafeChunks;
+
+/// Number of encoded blocks buffered ahead of a slow receiver.
+const CHANNEL_CAPACITY: usize = 4;
+
+/// Encode `text` like `GPT4Tokenizer::encode`, on tokio's blocking pool.
+pub async fn encode_async(tokenizer: Arc<GPT4Tokenizer>, text: String) -> Vec<Token> {
+    let encoded = task::spawn_blocking(move || {
+        let mut ids = Vec::new();
+        tokenizer.encode_into(&text, &mut ids);
+        ids
+    });
+    encoded
+        .await
+        .unwrap_or_else(|e| panic::resume_unwind(e.into_panic()))
+}
+
+/// Encode the file at `path` like `GPT4Tokenizer::encode_reader`, on tokio's
+/// blocking pool, streaming the tokens of every block (about 1 MiB of text)
+/// as soon as it is encoded. Reading stops early when the receiver is dropped.
+/// Must be called from within a tokio runtime.
+pub fn encode_file_async(
+    tokenizer: Arc<GPT4Tokenizer>,
+    path: impl Into<PathBuf>,
+) -> mpsc::Receiver<io::Result<Vec<Token>>> {
+    let path = path.into();
+    let (sender, receiver) = mpsc::channel(CHANNEL_CAPACITY);
+    task::spawn_blocking(move || {
+        let file = match File::open(&path) {
+            Ok(file) => file,
+            Err(e) => {
+                let _ = sender.blocking_send(Err(e));
+                return;
+            }
+        };
+        for block in SafeChunks::new(file) {
+            let encoded = block.map(|text| {
+                let mut ids = Vec::new();
+                tokenizer.encode_into(&text, &mut ids);
+                ids
+            });
+            let failed = encoded.is_err();
+            if sender.blocking_send(encoded).is_err() || failed {
+                return;
+            }
+        }
+    });
+    receiver
+}
@@ -0,0 +1,190 @@
+/*
+Saving files atomically: the contents go to a temporary file next to the
+destination, which is renamed over it once complete. A crash or error
+mid-save leaves the previous file (or none) in place, never a half-written
+one that fails to load later. All `save*` methods of the tokenizers write
+this way; `AtomicFile` does the same for their `*_to_writer` variants, and
+can also flush the file to disk before the rename for durability across
+power loss.
+*/
+
+use std::fs::{self, File};
+use std::io::{self, BufWriter, Write};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+/// Numbers the temporary files of a process, so that saves of the same file
+/// from several threads don't write to the same one.
+static NEXT_TMP: AtomicUsize = AtomicUsize::new(0);
+
+/// A file that replaces `path` when committed. Dropping it without
+/// committing removes what was written.
+pub struct AtomicFile {
+    writer: Option<BufWriter<File>>,
+    path: PathBuf,
+    tmp: PathBuf,
+    sync: bool,
+    /// Whether the temporary file was renamed, so there's nothing to remove.
+    renamed: bool,
+}
+
+impl AtomicFile {
+    /// Start writing the file that will replace `path`.
+    pub fn create(path: impl AsRef<Path>) -> io::Result<Self> {
+        let path = path.as_ref().to_path_buf();
+        let mut name = path
+            .file_name()
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "not a file path"))?
+            .to_owned();
+        // unique among processes and threads saving the same file
+        name.push(format!(
+            ".tmp{}.{}",
+            std::process::id(),
+            NEXT_TMP.fetch_add(1, Ordering::Relaxed)
+        ));
+        let tmp = path.with_file_name(name);
+        Ok(AtomicFile {
+            writer: Some(BufWriter::new(File::create(&tmp)?)),
+            path,
+            tmp,
+            sync: false,
+            renamed: false,
+        })
+    }
+
+    /// Flush the file and the rename to disk on `commit` (with `fsync`), so
+    /// the new contents survive a power loss right after. Off by default, as
+    /// it's slow and an interrupted process can't tear the file without it.
+    pub fn sync(mut self, sync: bool) -> Self {
+        self.sync = sync;
+        self
+    }
+
+    /// Replace the file at the destination with what was written. On error
+    /// the destination is left as it was and the temporary file is removed.
+    pub fn commit(mut self) -> io::Result<()> {
+        let file = self
+            .writer
+            .take()
+            .unwrap()
+            .into_inner()
+            .map_err(|e| e.into_error())?;
+        if self.sync {
+            file.sync_all()?;
+        }
+        drop(file);
+        fs::rename(&self.tmp, &self.path)?;
+        self.renamed = true;
+        if self.sync {
+            sync_dir(&self.path)?;
+        }
+        Ok(())
+    }
+}
+
+/// Flush the directory entry of `path`, which holds the rename.
+#[cfg(unix)]
+fn sync_dir(path: &Path) -> io::Result<()> {
+    match path.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => File::open(dir)?.sync_all(),
+        _ => File::open(".")?.sync_all(),
+    }
+}
+
+/// Windows flushes renames with the file.
+#[cfg(not(unix))]
+fn sync_dir(_path: &Path) -> io::Result<()> {
+    Ok(())
+}
+
+impl Write for AtomicFile {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.writer.as_mut().unwrap().write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.writer.as_mut().unwrap().flush()
+    }
+}
+
+impl Drop for AtomicFile {
+    fn drop(&mut self) {
+        // close the file before removing it
+        self.writer.take();
+        if !self.renamed {
+            let _ = fs::remove_file(&self.tmp);
+        }
+    }
+}
+
+/// Save the file at `path` with `write`, atomically.
+pub(crate) fn save_atomically(
+    path: &Path,
+    write: impl FnOnce(&mut AtomicFile) -> io::Result<()>,
+) -> io::Result<()> {
+    let mut file = AtomicFile::create(path)?;
+    write(&mut file)?;
+    file.commit()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// An empty directory of its own for the test `name`.
+    fn test_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("atomic-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir(&dir).unwrap();
+        dir
+    }
+
+    fn entries(dir: &Path) -> usize {
+        fs::read_dir(dir).unwrap().count()
+    }
+
+    #[test]
+    fn commit_replaces_the_file() {
+        let dir = test_dir("commit");
+        let path = dir.join("model");
+        fs::write(&path, "old").unwrap();
+        let mut file = AtomicFile::create(&path).unwrap().sync(true);
+        file.write_all(b"new").unwrap();
+        assert_eq!(fs::read_to_string(&path).unwrap(), "old");
+        file.commit().unwrap();
+        assert_eq!(fs::read_to_string(&path).unwrap(), "new");
+        assert_eq!(entries(&dir), 1);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn dropping_keeps_the_old_file() {
+        let dir = test_dir("drop");
+        let path = dir.join("model");
+        fs::write(&path, "old").unwrap();
+        let mut first = AtomicFile::create(&path).unwrap();
+        let mut second = AtomicFile::create(&path).unwrap();
+        assert_ne!(first.tmp, second.tmp);
+        first.write_all(b"first").unwrap();
+        second.write_all(b"second").unwrap();
+        drop(first);
+        drop(second);
+        assert_eq!(fs::read_to_string(&path).unwrap(), "old");
+        assert_eq!(entries(&dir), 1);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn failed_commit_removes_the_temporary_file() {
+        let dir = test_dir("fail");
+        // a file can't be renamed over a directory that isn't empty
+        let path = dir.join("model");
+        fs::create_dir(&path).unwrap();
+        fs::write(path.join("inside"), "").unwrap();
+        let mut file = AtomicFile::create(&path).unwrap();
+        file.write_all(b"new").unwrap();
+        assert!(file.commit().is_err());
+        assert_eq!(entries(&dir), 1);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -1,6 +1,449 @@
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+use std::ops::Range;
+
+use crate::hash::FastHashMap;
+use crate::verify::{RoundTripReport, compare};
+
 pub type Token = i32;
+/// A learned merge: the pair of ids and the id of the token it produces.
+pub type Merge = ((Token, Token), Token);
 
 pub trait Tokenizer {
     fn encode(&mut self, text: &str) -> Vec<Token>;
     fn decode(&self, ids: &[Token]) -> String;
+
+    /// Encode `documents` one after the other with `separator` (e.g. the id
+    /// of `<|endoftext|>`) between them, the usual way documents are packed
+    /// into a training corpus.
+    fn encode_documents<S: AsRef<str>>(&mut self, documents: &[S], separator: Token) -> Vec<Token>
+    where
+        Self: Sized,
+    {
+        let mut ids = Vec::new();
+        for (i, document) in documents.iter().enumerate() {
+            if i > 0 {
+                ids.push(separator);
+            }
+            ids.extend(self.encode(document.as_ref()));
+        }
+        ids
+    }
+
+    /// Encode `text`, decode it again and report which parts didn't come
+    /// back, e.g. to check a loaded or converted model on your own corpus.
+    fn verify_roundtrip(&mut self, text: &str) -> RoundTripReport {
+        let ids = self.encode(text);
+        let decoded = self.decode(&ids);
+        RoundTripReport {
+            tokens: ids.len(),
+            mismatches: compare(text.as_bytes(), decoded.as_bytes()),
+        }
+    }
+}
+
+/// A selection of special tokens, for `encode_with_special`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum SpecialSet {
+    /// Every registered special token.
+    All,
+    None,
+    /// Just these literals.
+    Only(HashSet<String>),
+}
+
+impl SpecialSet {
+    pub fn only<I>(specials: I) -> Self
+    where
+        I: IntoIterator,
+        I::Item: Into<String>,
+    {
+        SpecialSet::Only(specials.into_iter().map(Into::into).collect())
+    }
+
+    pub fn contains(&self, special: &str) -> bool {
+        match self {
+            SpecialSet::All => true,
+            SpecialSet::None => false,
+            SpecialSet::Only(specials) => specials.contains(special),
+        }
+    }
+}
+
+/// Why a text could not be encoded.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum EncodeError {
+    /// The text contains the literal of a special token that was disallowed,
+    /// starting at byte `offset`. This usually means user input is trying to
+    /// inject control tokens.
+    DisallowedSpecial { token: String, offset: usize },
+}
+
+impl fmt::Display for EncodeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            EncodeError::DisallowedSpecial { token, offset } => {
+                write!(f, "disallowed special token {:?} at byte {}", token, offset)
+            }
+        }
+    }
+}
+
+impl std::error::Error for EncodeError {}
+
+/// Why a sequence of ids could not be decoded.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum DecodeError {
+    /// The id at index `position` is neither in the vocab nor a special token.
+    UnknownId { position: usize, id: Token },
+}
+
+impl fmt::Display for DecodeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DecodeError::UnknownId { position, id } => {
+                write!(f, "unknown token id {} at position {}", id, position)
+            }
+        }
+    }
+}
+
+impl std::error::Error for DecodeError {}
+
+/// A piece of text to encode: either ordinary text or a special token.
+#[derive(Debug, PartialEq, Eq)]
+pub(crate) enum Piece<'a> {
+    Text(&'a str),
+    Special(Token),
+}
+
+/// Cut `text` into ordinary text and the `allowed` special tokens among
+/// `special_tokens`, like tiktoken's `encode`. Literals of the `disallowed`
+/// special tokens that aren't also allowed are an error; the remaining ones
+/// are just text.
+pub(crate) fn special_pieces<'a>(
+    text: &'a str,
+    special_tokens: &HashMap<String, Token>,
+    allowed: &SpecialSet,
+    disallowed: &SpecialSet,
+) -> Result<Vec<Piece<'a>>, EncodeError> {
+    let forbidden: Vec<&str> = special_tokens
+        .keys()
+        .map(String::as_str)
+        .filter(|s| disallowed.contains(s) && !allowed.contains(s))
+        .collect();
+    if let Some(&(offset, token)) = find_specials(text, &forbidden).first() {
+        return Err(EncodeError::DisallowedSpecial {
+            token: token.to_string(),
+            offset,
+        });
+    }
+    let allowed: Vec<&str> = special_tokens
+        .keys()
+        .map(String::as_str)
+        .filter(|s| allowed.contains(s))
+        .collect();
+    let mut pieces = Vec::new();
+    let mut start = 0;
+    for (at, special) in find_specials(text, &allowed) {
+        pieces.push(Piece::Text(&text[start..at]));
+        pieces.push(Piece::Special(special_tokens[special]));
+        start = at + special.len();
+    }
+    pieces.push(Piece::Text(&text[start..]));
+    Ok(pieces)
+}
+
+/// Count how often each consecutive pair of ids occurs in `ids`, adding the
+/// counts to `counts` so they can be accumulated over many chunks.
+pub fn update_stats(ids: &[Token], counts: &mut FastHashMap<(Token, Token), usize>) {
+    for pair in ids.windows(2) {
+        // `windows(2)` creates pairs efficiently
+        let pair = (pair[0], pair[1]);
+        *counts.entry(pair).or_insert(0) += 1;
+    }
+}
+
+pub fn get_stats(ids: &[Token]) -> FastHashMap<(Token, Token), usize> {
+    let mut counts = FastHashMap::default();
+    update_stats(ids, &mut counts);
+    counts
+}
+
+pub fn merge(ids: &[Token], pair: (Token, Token), new_token: Token) -> Vec<Token> {
+    // in the slice of ints (ids), replace all consecutive occurences of pair with the new token
+    let mut new_ids = Vec::with_capacity(ids.len());
+    let mut i = 0;
+    while i < ids.len() {
+        // if we are not at the very last position and the pair matches, replace it
+        if i < ids.len() - 1 && ids[i] == pair.0 && ids[i + 1] == pair.1 {
+            new_ids.push(new_token);
+            i += 2;
+        } else {
+            new_ids.push(ids[i]);
+            i += 1;
+        }
+    }
+    new_ids
+}
+
+/// Rebuild the byte vocabulary from the 256 raw bytes plus `merges`. Merges are
+/// applied in id order so that both parents always exist before their child.
+pub fn build_vocab(merges: &FastHashMap<(Token, Token), Token>) -> FastHashMap<Token, Vec<u8>> {
+    let mut vocab: FastHashMap<Token, Vec<u8>> =
+        (0..256).map(|idx| (idx, vec![idx as u8])).collect();
+    let mut ordered: Vec<_> = merges.iter().collect();
+    ordered.sort_by_key(|&(_, &idx)| idx);
+    for (&(p0, p1), &idx) in ordered {
+        if let (Some(v0), Some(v1)) = (vocab.get(&p0), vocab.get(&p1)) {
+            let mut merged = v0.clone();
+            merged.extend(v1);
+            vocab.insert(idx, merged);
+        }
+    }
+    vocab
+}
+
+/// Encode `text_bytes` by repeatedly applying the merge with the lowest id,
+/// which is the order the merges were learned in.
+pub fn encode_bytes(text_bytes: &[u8], merges: &FastHashMap<(Token, Token), Token>) -> Vec<Token> {
+    // Convert all bytes to integers in range 0..255
+    let ids: Vec<Token> = text_bytes.iter().map(|&b| b as Token).collect();
+    apply_merges(ids, merges)
+}
+
+/// Apply `merges` to `ids` in the order they were learned until none matches.
+pub fn apply_merges(ids: Vec<Token>, merges: &FastHashMap<(Token, Token), Token>) -> Vec<Token> {
+    apply_merges_with(ids, merges, |_| {})
+}
+
+/// Like `apply_merges`, calling `on_merge` with every merge as it is applied.
+pub(crate) fn apply_merges_with<F>(
+    mut ids: Vec<Token>,
+    merges: &FastHashMap<(Token, Token), Token>,
+    mut on_merge: F,
+) -> Vec<Token>
+where
+    F: FnMut(Merge),
+{
+    while ids.len() >= 2 {
+        let stats = get_stats(&ids);
+        // Find the pair with the lowest merge index
+        let pair = stats
+            .keys()
+            .filter(|pair| merges.contains_key(pair))
+            .min_by_key(|pair| merges[pair]);
+        // If no valid merge is found, stop
+        let Some(&pair) = pair else {
+            break;
+        };
+        on_merge((pair, merges[&pair]));
+        ids = merge(&ids, pair, merges[&pair]);
+    }
+    ids
+}
+
+/// Concatenate the bytes of `ids`, looking them up in `vocab` and then among
+/// the special tokens, and silently skipping ids missing from both.
+pub fn decode_bytes(
+    ids: &[Token],
+    vocab: &FastHashMap<Token, Vec<u8>>,
+    inverse_special_tokens: &HashMap<Token, String>,
+) -> Vec<u8> {
+    let mut text_bytes = Vec::new();
+    for &id in ids {
+        decode_token(id, vocab, inverse_special_tokens, &mut text_bytes);
+    }
+    text_bytes
+}
+
+/// Append the bytes of `id` to `out`, looking it up among the special tokens
+/// and then in `vocab`, as `GPT4Tokenizer` does. Returns whether it was found
+/// in either.
+pub(crate) fn decode_token(
+    id: Token,
+    vocab: &FastHashMap<Token, Vec<u8>>,
+    inverse_special_tokens: &HashMap<Token, String>,
+    out: &mut Vec<u8>,
+) -> bool {
+    if let Some(special) = inverse_special_tokens.get(&id) {
+        out.extend(special.as_bytes());
+    } else if let Some(bytes) = vocab.get(&id) {
+        out.extend(bytes);
+    } else {
+        return false;
+    }
+    true
+}
+
+/// Check that no special token has the id of a byte or merged token in
+/// `vocab`, which would make decoding that id ambiguous.
+pub(crate) fn check_special_ids(
+    vocab: &FastHashMap<Token, Vec<u8>>,
+    special_tokens: &HashMap<String, Token>,
+) -> Result<(), String> {
+    let mut specials: Vec<_> = special_tokens.iter().collect();
+    specials.sort_by_key(|&(special, &idx)| (idx, special));
+    match specials
+        .into_iter()
+        .find(|(_, idx)| vocab.contains_key(idx))
+    {
+        Some((special, idx)) => Err(format!(
+            "special token {:?} has the id {} of a regular token",
+            special, idx
+        )),
+        None => Ok(()),
+    }
+}
+
+/// Render the bytes of a token for display, like minbpe's `render_token`:
+/// control characters are escaped as `\u000a`, and bytes that aren't valid
+/// UTF-8 (e.g. half of a multi-byte character) as `\xe2`.
+pub fn render_token(bytes: &[u8]) -> String {
+    let mut out = String::new();
+    for chunk in bytes.utf8_chunks() {
+        for c in chunk.valid().chars() {
+            if c.is_control() {
+                out.push_str(&format!("\\u{:04x}", c as u32));
+            } else {
+                out.push(c);
+            }
+        }
+        for b in chunk.invalid() {
+            out.push_str(&format!("\\x{:02x}", b));
+        }
+    }
+    out
+}
+
+/// Pair every id with the byte range it covers, given that `ids` decode to
+/// consecutive bytes starting at 0. A range may start or end inside a
+/// multi-byte character when a token holds only part of it.
+pub(crate) fn token_offsets(
+    ids: Vec<Token>,
+    vocab: &FastHashMap<Token, Vec<u8>>,
+    inverse_special_tokens: &HashMap<Token, String>,
+) -> Vec<(Token, Range<usize>)> {
+    let mut start = 0;
+    ids.into_iter()
+        .map(|id| {
+            let len = match vocab.get(&id) {
+                Some(bytes) => bytes.len(),
+                None => inverse_special_tokens.get(&id).map_or(0, String::len),
+            };
+            start += len;
+            (id, start - len..start)
+        })
+        .collect()
+}
+
+/// Find the non-overlapping occurrences of `specials` in `text`, scanning
+/// left to right and preferring the longest literal when several start at
+/// the same position. Returns the byte offset and the matched literal.
+pub(crate) fn find_specials<'a>(text: &str, specials: &[&'a str]) -> Vec<(usize, &'a str)> {
+    let specials: Vec<&str> = specials.iter().copied().filter(|s| !s.is_empty()).collect();
+    // next occurrence of every literal at or after `pos`, updated lazily
+    let mut next: Vec<Option<usize>> = specials.iter().map(|s| text.find(s)).collect();
+    let mut found = Vec::new();
+    let mut pos = 0;
+    loop {
+        for (i, special) in specials.iter().enumerate() {
+            if let Some(at) = next[i]
+                && at < pos
+            {
+                next[i] = text[pos..].find(special).map(|at| at + pos);
+            }
+        }
+        let best = (0..specials.len())
+            .filter_map(|i| next[i].map(|at| (at, specials[i])))
+            .min_by_key(|&(at, special)| (at, std::cmp::Reverse(special.len())));
+        let Some((at, special)) = best else {
+            break;
+        };
+        found.push((at, special));
+        pos = at + special.len();
+    }
+    found
+}
+
+/// The parts of `text` between occurrences of `specials`.
+pub(crate) fn split_specials<'a>(text: &'a str, specials: &[&str]) -> Vec<&'a str> {
+    let mut pieces = Vec::new();
+    let mut start = 0;
+    for (at, special) in find_specials(text, specials) {
+        pieces.push(&text[start..at]);
+        start = at + special.len();
+    }
+    pieces.push(&text[start..]);
+    pieces
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::basic::BasicTokenizer;
+    use crate::regex::RegexTokenizer;
+    use crate::train::Trainable;
+
+    const TEXT: &str = "hi<|endoftext|>there<|fim|>";
+
+    fn specials() -> HashMap<String, Token> {
+        HashMap::from([
+            ("<|endoftext|>".to_string(), 300),
+            ("<|fim|>".to_string(), 301),
+            ("<|endof".to_string(), 302),
+        ])
+    }
+
+    #[test]
+    fn pieces_of_allowed_specials() {
+        let allowed = SpecialSet::only(["<|endoftext|>", "<|endof"]);
+        let pieces = special_pieces(TEXT, &specials(), &allowed, &SpecialSet::None).unwrap();
+        // the longest literal wins
+        assert_eq!(
+            pieces,
+            [
+                Piece::Text("hi"),
+                Piece::Special(300),
+                Piece::Text("there<|fim|>")
+            ]
+        );
+        let error = special_pieces(TEXT, &specials(), &allowed, &SpecialSet::All).unwrap_err();
+        assert_eq!(
+            error,
+            EncodeError::DisallowedSpecial {
+                token: "<|fim|>".to_string(),
+                offset: 20
+            }
+        );
+    }
+
+    #[test]
+    fn encode_with_special_sets() {
+        let mut regex = RegexTokenizer::new();
+        regex.register_special_tokens(specials()).unwrap();
+        let mut basic = BasicTokenizer::new();
+        basic.set_special_tokens(specials());
+        for ids in [
+            regex.encode_with_special(TEXT, &SpecialSet::All, &SpecialSet::All),
+            basic.encode_with_special(TEXT, &SpecialSet::All, &SpecialSet::All),
+        ] {
+            let ids = ids.unwrap();
+            assert_eq!(ids.iter().filter(|&&id| id >= 300).count(), 2);
+            assert_eq!(basic.decode(&ids), TEXT);
+        }
+        for result in [
+            regex.encode_with_special(TEXT, &SpecialSet::None, &SpecialSet::All),
+            basic.encode_with_special(TEXT, &SpecialSet::None, &SpecialSet::All),
+        ] {
+            assert!(matches!(
+                result,
+                Err(EncodeError::DisallowedSpecial { offset: 2, .. })
+            ));
+        }
+        let plain = basic
+            .encode_with_special(TEXT, &SpecialSet::None, &SpecialSet::None)
+            .unwrap();
+        assert_eq!(plain, basic.encode(TEXT));
+    }
 }
@@ -0,0 +1,700 @@
+/*
+Minimal (byte-level) Byte Pair Encoding tokenizer.
+
+Algorithmically follows along the GPT tokenizer, but does not handle the
+regular expression splitting pattern. Special tokens reserved during training
+are decoded, but never produced by `encode`.
+*/
+
+use std::collections::HashMap;
+use std::fs;
+use std::io::{self, Read, Write};
+use std::ops::Range;
+use std::path::Path;
+
+use crate::atomic::{AtomicFile, save_atomically};
+use crate::base::{
+    DecodeError, EncodeError, Merge, Piece, SpecialSet, Token, Tokenizer, build_vocab,
+    check_special_ids, decode_token, encode_bytes, render_token, special_pieces, token_offsets,
+};
+#[cfg(feature = "parallel")]
+use crate::batch::Padding;
+use crate::decode::{
+    LossyPolicy, SpecialRendering, UnknownIdPolicy, decode_delta, decode_into, decode_lossy,
+    decode_to_writer, decode_with_spans, decode_with_specials,
+};
+use crate::gguf::{GgufValue, pre_tokenizer_name, read_gguf_vocab, tokenizer_metadata, write_gguf};
+use crate::hash::FastHashMap;
+use crate::heal::{TokenHealing, heal_tokens};
+use crate::hf::{write_gpt2_files, write_hf_tokenizer};
+#[cfg(feature = "serde")]
+use crate::model::check_merges;
+use crate::model::{ModelFile, read_model, write_merge_graph, write_model, write_vocab};
+#[cfg(feature = "binary")]
+use crate::model::{read_binary, write_binary};
+#[cfg(feature = "json")]
+use crate::model::{read_json, write_json};
+use crate::normalize::Normalizer;
+use crate::prefix::PrefixIndex;
+#[cfg(feature = "parallel")]
+use crate::progress::Progress;
+use crate::split::{Boundary, TextChunk, split_by_tokens};
+use crate::stream::StreamDecoder;
+use crate::table::{TableFormat, write_vocab_table};
+use crate::trace::{ChunkTrace, trace_chunk};
+use crate::train::{TrainConfig, Trainable};
+use crate::truncate::{Truncation, truncate};
+use crate::validate::{ValidationReport, validate};
+use crate::visualize::visualize;
+
+pub struct BasicTokenizer {
+    merges: FastHashMap<(Token, Token), Token>,
+    vocab: FastHashMap<Token, Vec<u8>>,
+    /// The vocab sorted by bytes, for prefix queries.
+    prefix_index: PrefixIndex,
+    special_tokens: HashMap<String, Token>,
+    inverse_special_tokens: HashMap<Token, String>,
+    /// What decoding does with unknown ids, see `set_unknown_id_policy`.
+    unknown_id_policy: UnknownIdPolicy,
+}
+
+impl BasicTokenizer {
+    pub fn new() -> Self {
+        let merges = FastHashMap::default();
+        let vocab = build_vocab(&merges);
+        BasicTokenizer {
+            merges,
+            prefix_index: PrefixIndex::new(&vocab),
+            vocab,
+            special_tokens: HashMap::new(),
+            inverse_special_tokens: HashMap::new(),
+            unknown_id_policy: UnknownIdPolicy::default(),
+        }
+    }
+
+    /// Save the tokenizer as a minbpe `.model` file: its special tokens and
+    /// merges, loadable by the Python minbpe as well.
+    ///
+    /// Like every `save*` method, this replaces the file atomically; wrap
+    /// the writer of `save_to_writer` in an `AtomicFile` to also `fsync` it.
+    pub fn save(&self, path: impl AsRef<Path>) -> io::Result<()> {
+        save_atomically(path.as_ref(), |file| self.save_to_writer(file))
+    }
+
+    /// Like `save`, writing the `.model` file to `writer` instead.
+    pub fn save_to_writer(&self, writer: impl Write) -> io::Result<()> {
+        write_model(writer, &self.model_file())
+    }
+
+    /// Save the tokenizer as a HuggingFace `tokenizer.json`: a byte-level
+    /// BPE with the same vocabulary and merges, without splitting text, and the special
+    /// tokens as added tokens, which `transformers` loads with
+    /// `PreTrainedTokenizerFast(tokenizer_file=...)`.
+    pub fn save_hf_tokenizer(&self, path: impl AsRef<Path>) -> io::Result<()> {
+        save_atomically(path.as_ref(), |file| self.save_hf_tokenizer_to_writer(file))
+    }
+
+    /// Like `save_hf_tokenizer`, writing the `tokenizer.json` to `writer` instead.
+    pub fn save_hf_tokenizer_to_writer(&self, writer: impl Write) -> io::Result<()> {
+        write_hf_tokenizer(
+            writer,
+            &Normalizer::default(),
+            None,
+            &self.vocab,
+            &self.merges,
+            &self.special_tokens,
+        )
+    }
+
+    /// Save the tokenizer in GPT-2's format, as `vocab.json` and `merges.txt`
+    /// in the existing directory `dir`, for toolchains that predate
+    /// `tokenizer.json`.
+    pub fn save_gpt2(&self, dir: impl AsRef<Path>) -> io::Result<()> {
+        let dir = dir.as_ref();
+        let mut vocab = AtomicFile::create(dir.join("vocab.json"))?;
+        let mut merges = AtomicFile::create(dir.join("merges.txt"))?;
+        self.save_gpt2_to_writers(&mut vocab, &mut merges)?;
+        vocab.commit()?;
+        merges.commit()
+    }
+
+    /// Like `save_gpt2`, writing `vocab.json` to `vocab_writer` and
+    /// `merges.txt` to `merges_writer` instead.
+    pub fn save_gpt2_to_writers(
+        &self,
+        vocab_writer: impl Write,
+        merges_writer: impl Write,
+    ) -> io::Result<()> {
+        write_gpt2_files(
+            vocab_writer,
+            merges_writer,
+            &self.vocab,
+            &self.merges,
+            &self.special_tokens,
+        )
+    }
+
+    /// The `tokenizer.ggml.*` metadata llama.cpp reads from a GGUF file, to
+    /// embed the tokenizer in a model with its GGUF writer. llama.cpp always splits
+    /// text with a pattern first, so it tokenizes some text differently.
+    pub fn gguf_metadata(&self) -> io::Result<Vec<(String, GgufValue)>> {
+        tokenizer_metadata(
+            &self.vocab,
+            &self.merges,
+            &self.special_tokens,
+            pre_tokenizer_name(None),
+        )
+    }
+
+    /// Save `gguf_metadata` as a vocab-only GGUF file.
+    pub fn save_gguf_vocab(&self, path: impl AsRef<Path>) -> io::Result<()> {
+        save_atomically(path.as_ref(), |file| self.save_gguf_vocab_to_writer(file))
+    }
+
+    /// Like `save_gguf_vocab`, writing the GGUF file to `writer` instead.
+    pub fn save_gguf_vocab_to_writer(&self, writer: impl Write) -> io::Result<()> {
+        write_gguf(writer, &self.gguf_metadata()?)
+    }
+
+    /// The tokenizer in the metadata of the GGUF file at `path`, such as one
+    /// `save_gguf_vocab` wrote. The vocab must not split text with a pattern
+    /// (`tokenizer.ggml.pre` "default") and must give every byte its own id.
+    pub fn from_gguf(path: impl AsRef<Path>) -> io::Result<Self> {
+        Self::from_gguf_reader(fs::File::open(path)?)
+    }
+
+    /// Like `from_gguf`, reading the GGUF file from `reader` instead.
+    pub fn from_gguf_reader(reader: impl Read) -> io::Result<Self> {
+        let vocab = read_gguf_vocab(reader)?;
+        vocab.check_unshuffled()?;
+        if vocab.pre != pre_tokenizer_name(None) {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!(
+                    "GGUF vocab splits text with {:?}, load it with RegexTokenizer",
+                    vocab.pre
+                ),
+            ));
+        }
+        let mut tokenizer = Self::new();
+        tokenizer.set_merges(vocab.merges);
+        check_special_ids(&tokenizer.vocab, &vocab.special_tokens)
+            .map_err(|msg| io::Error::new(io::ErrorKind::InvalidData, msg))?;
+        tokenizer.set_special_tokens(vocab.special_tokens);
+        Ok(tokenizer)
+    }
+
+    /// Save the minbpe `.vocab` listing of the tokenizer, for eyeballing what
+    /// it learned: every token's rendered bytes (see `render_token`) and the
+    /// merge that produced it. It can't be loaded back, use `save` for that.
+    pub fn save_vocab(&self, path: impl AsRef<Path>) -> io::Result<()> {
+        save_atomically(path.as_ref(), |file| self.save_vocab_to_writer(file))
+    }
+
+    /// Like `save_vocab`, writing the listing to `writer` instead.
+    pub fn save_vocab_to_writer(&self, writer: impl Write) -> io::Result<()> {
+        write_vocab(
+            writer,
+            &self.vocab,
+            &self.merges,
+            &self.inverse_special_tokens,
+        )
+    }
+
+    /// Export the merges as a Graphviz DOT graph, in which every merged
+    /// token points at the two tokens it was merged from, to visualize how
+    /// long tokens were built up (e.g. `dot -Tsvg merges.dot`).
+    pub fn export_merge_graph(&self, path: impl AsRef<Path>) -> io::Result<()> {
+        save_atomically(path.as_ref(), |file| {
+            self.export_merge_graph_to_writer(file)
+        })
+    }
+
+    /// Like `export_merge_graph`, writing the graph to `writer` instead.
+    pub fn export_merge_graph_to_writer(&self, writer: impl Write) -> io::Result<()> {
+        write_merge_graph(writer, &self.vocab, &self.merges)
+    }
+
+    /// Export the vocabulary as a CSV or TSV table with a row per token: its
+    /// id, merge rank, length in bytes, escaped text and the ids it was
+    /// merged from, to analyze it with pandas, duckdb or a spreadsheet.
+    pub fn export_vocab_table(
+        &self,
+        path: impl AsRef<Path>,
+        format: TableFormat,
+    ) -> io::Result<()> {
+        save_atomically(path.as_ref(), |file| {
+            self.export_vocab_table_to_writer(file, format)
+        })
+    }
+
+    /// Like `export_vocab_table`, writing the table to `writer` instead.
+    pub fn export_vocab_table_to_writer(
+        &self,
+        writer: impl Write,
+        format: TableFormat,
+    ) -> io::Result<()> {
+        write_vocab_table(
+            writer,
+            format,
+            &self.vocab,
+            &self.merges,
+            &self.special_tokens,
+        )
+    }
+
+    /// Load a tokenizer from a minbpe `.model` file without a split pattern,
+    /// e.g. one saved by the Python minbpe's `BasicTokenizer`.
+    pub fn load(path: impl AsRef<Path>) -> io::Result<Self> {
+        Self::load_from_reader(fs::File::open(path)?)
+    }
+
+    /// Like `load`, reading the `.model` file from `reader`, e.g. embedded
+    /// bytes or a network stream.
+    pub fn load_from_reader(reader: impl Read) -> io::Result<Self> {
+        Self::from_model_file(read_model(reader)?)
+            .map_err(|msg| io::Error::new(io::ErrorKind::InvalidData, msg))
+    }
+
+    /// Save the tokenizer in a compact binary format with a version header,
+    /// quicker to load and smaller than a `.model` file.
+    #[cfg(feature = "binary")]
+    pub fn save_binary(&self, path: impl AsRef<Path>) -> io::Result<()> {
+        save_atomically(path.as_ref(), |file| self.save_binary_to_writer(file))
+    }
+
+    /// Like `save_binary`, writing the model to `writer` instead.
+    #[cfg(feature = "binary")]
+    pub fn save_binary_to_writer(&self, writer: impl Write) -> io::Result<()> {
+        write_binary(writer, &self.model_file())
+    }
+
+    /// Load a tokenizer saved by `save_binary`. Its checksum is verified, so
+    /// a corrupted file is an `InvalidData` error.
+    #[cfg(feature = "binary")]
+    pub fn load_binary(path: impl AsRef<Path>) -> io::Result<Self> {
+        Self::load_binary_from_reader(fs::File::open(path)?)
+    }
+
+    /// Like `load_binary`, reading the model from `reader`.
+    #[cfg(feature = "binary")]
+    pub fn load_binary_from_reader(reader: impl Read) -> io::Result<Self> {
+        Self::from_model_file(read_binary(reader)?)
+            .map_err(|msg| io::Error::new(io::ErrorKind::InvalidData, msg))
+    }
+
+    /// Save the tokenizer as pretty JSON, to diff and inspect it with
+    /// standard tools: its pattern, special tokens, merges in order and the
+    /// bytes of every token by id.
+    #[cfg(feature = "json")]
+    pub fn save_json(&self, path: impl AsRef<Path>) -> io::Result<()> {
+        save_atomically(path.as_ref(), |file| self.save_json_to_writer(file))
+    }
+
+    /// Like `save_json`, writing the JSON to `writer` instead.
+    #[cfg(feature = "json")]
+    pub fn save_json_to_writer(&self, writer: impl Write) -> io::Result<()> {
+        write_json(writer, &self.model_file(), &self.vocab)
+    }
+
+    /// Load a tokenizer saved by `save_json`. Its checksum is verified, so
+    /// a corrupted or edited file is an `InvalidData` error.
+    #[cfg(feature = "json")]
+    pub fn load_json(path: impl AsRef<Path>) -> io::Result<Self> {
+        Self::load_json_from_reader(fs::File::open(path)?)
+    }
+
+    /// Like `load_json`, reading the JSON from `reader`.
+    #[cfg(feature = "json")]
+    pub fn load_json_from_reader(reader: impl Read) -> io::Result<Self> {
+        Self::from_model_file(read_json(reader)?)
+            .map_err(|msg| io::Error::new(io::ErrorKind::InvalidData, msg))
+    }
+
+    fn model_file(&self) -> ModelFile {
+        let mut merges: Vec<Merge> = self
+            .merges
+            .iter()
+            .map(|(&pair, &idx)| (pair, idx))
+            .collect();
+        merges.sort_by_key(|&(_, idx)| idx);
+        ModelFile {
+            pattern: String::new(),
+            special_tokens: self.special_tokens.clone(),
+            merges,
+            normalization: Vec::new(),
+        }
+    }
+
+    fn from_model_file(model: ModelFile) -> Result<Self, String> {
+        if !model.pattern.is_empty() {
+            return Err("model has a split pattern, load it with RegexTokenizer".to_string());
+        }
+        if !model.normalization.is_empty() {
+            return Err("model normalizes text, load it with RegexTokenizer".to_string());
+        }
+        let mut tokenizer = Self::new();
+        tokenizer.set_merges(model.merges);
+        check_special_ids(&tokenizer.vocab, &model.special_tokens)?;
+        tokenizer.set_special_tokens(model.special_tokens);
+        Ok(tokenizer)
+    }
+
+    /// Set what decoding does with ids that are neither in the vocab nor a
+    /// special token, by default `UnknownIdPolicy::Error`.
+    pub fn set_unknown_id_policy(&mut self, policy: UnknownIdPolicy) {
+        self.unknown_id_policy = policy;
+    }
+
+    /// Check that the merges, vocab and special tokens agree with each
+    /// other, e.g. after loading a model from an untrusted file, and report
+    /// every problem found.
+    pub fn validate(&self) -> ValidationReport {
+        validate(&self.merges, &self.vocab, &self.special_tokens)
+    }
+
+    /// The tokenizer if `validate` finds no problems, so a model can be
+    /// checked as it's loaded, e.g. `Self::load(path)?.validated()?`.
+    pub fn validated(self) -> Result<Self, ValidationReport> {
+        let report = self.validate();
+        if report.is_valid() {
+            Ok(self)
+        } else {
+            Err(report)
+        }
+    }
+
+    /// Encode `texts` like `encode`, spread over rayon's thread pool, with
+    /// the results in the order of `texts` and, with `padding`, all of the
+    /// same length.
+    #[cfg(feature = "parallel")]
+    pub fn encode_batch_parallel<S: AsRef<str> + Sync>(
+        &self,
+        texts: &[S],
+        padding: Option<&Padding>,
+    ) -> Vec<Vec<Token>> {
+        use rayon::prelude::*;
+
+        let progress = Progress::new(texts.len(), "encoding");
+        let mut batch: Vec<Vec<Token>> = texts
+            .par_iter()
+            .map(|text| {
+                let ids = encode_bytes(text.as_ref().as_bytes(), &self.merges);
+                progress.inc(1);
+                ids
+            })
+            .collect();
+        progress.finish();
+        if let Some(padding) = padding {
+            padding.apply(&mut batch);
+        }
+        batch
+    }
+
+    /// Decode `batch` like `decode`, spread over rayon's thread pool, with
+    /// the texts in the order of `batch`.
+    #[cfg(feature = "parallel")]
+    pub fn decode_batch_parallel<S: AsRef<[Token]> + Sync>(&self, batch: &[S]) -> Vec<String> {
+        use rayon::prelude::*;
+
+        let progress = Progress::new(batch.len(), "decoding");
+        let texts = batch
+            .par_iter()
+            .map(|ids| {
+                let text = self.decode(ids.as_ref());
+                progress.inc(1);
+                text
+            })
+            .collect();
+        progress.finish();
+        texts
+    }
+
+    /// Encode `bytes` that may not be valid UTF-8, e.g. scraped web data.
+    /// The basic tokenizer works on bytes anyway, so invalid ones simply
+    /// take part in the merges and decode back unchanged.
+    pub fn encode_raw(&self, bytes: &[u8]) -> Vec<Token> {
+        encode_bytes(bytes, &self.merges)
+    }
+
+    /// Encode `text` like `encode`, appending the tokens to `out`.
+    pub fn encode_into(&self, text: &str, out: &mut Vec<Token>) {
+        out.extend(encode_bytes(text.as_bytes(), &self.merges));
+    }
+
+    /// Encode `text`, turning the literals of the `allowed` special tokens into
+    /// their ids; `encode` treats them as plain text, like the Python
+    /// minbpe. The literals of `disallowed` special tokens are an error (pass
+    /// `SpecialSet::None` to encode them as plain text instead), as in
+    /// tiktoken, where `SpecialSet::All` is the default for `disallowed`.
+    pub fn encode_with_special(
+        &self,
+        text: &str,
+        allowed: &SpecialSet,
+        disallowed: &SpecialSet,
+    ) -> Result<Vec<Token>, EncodeError> {
+        let pieces = special_pieces(text, &self.special_tokens, allowed, disallowed)?;
+        let mut ids = Vec::new();
+        for piece in pieces {
+            match piece {
+                Piece::Text(text) => self.encode_into(text, &mut ids),
+                Piece::Special(idx) => ids.push(idx),
+            }
+        }
+        Ok(ids)
+    }
+
+    /// The tokens whose bytes start with `prefix`, e.g. to find the tokens
+    /// allowed next during constrained decoding. Special tokens are not
+    /// included.
+    pub fn tokens_with_byte_prefix(&self, prefix: &[u8]) -> impl Iterator<Item = Token> + use<'_> {
+        self.prefix_index.with_prefix(prefix)
+    }
+
+    /// Prepare encoded prompt `ids` for token healing: remove up to
+    /// `backtrack` trailing tokens and return their bytes along with every
+    /// token extending them.
+    pub fn heal_tokens(&self, ids: &[Token], backtrack: usize) -> TokenHealing {
+        heal_tokens(ids, backtrack, &self.vocab, &self.prefix_index)
+    }
+
+    /// Encode `text` like `encode`, returning it as a single chunk with its
+    /// tokens and the merges that produced them, to investigate why a string
+    /// tokenizes unexpectedly.
+    pub fn encode_debug(&self, text: &str) -> Vec<ChunkTrace> {
+        let ids = text.bytes().map(|b| b as Token).collect();
+        vec![trace_chunk(text, ids, &self.merges)]
+    }
+
+    /// Decode `ids` to the raw bytes of their tokens, without the UTF-8
+    /// conversion of `decode`, so binary data round-tripped through tokens
+    /// comes back intact. Unknown ids are handled by the `UnknownIdPolicy`,
+    /// like in `decode`.
+    pub fn decode_to_bytes(&self, ids: &[Token]) -> Vec<u8> {
+        let mut text_bytes = Vec::new();
+        for &id in ids {
+            self.decode_id(id, &mut text_bytes);
+        }
+        text_bytes
+    }
+
+    /// Decode ids as an iterator yields them, e.g. from a channel or a
+    /// generation loop, like `decode` but without collecting them first.
+    pub fn decode_iter<I: IntoIterator<Item = Token>>(&self, ids: I) -> String {
+        let mut text_bytes = Vec::new();
+        for id in ids {
+            self.decode_id(id, &mut text_bytes);
+        }
+        String::from_utf8_lossy(&text_bytes).to_string()
+    }
+
+    /// Decode `ids` like `decode`, but fail on the first id that is neither
+    /// in the vocab nor a special token instead of replacing it, if the
+    /// `UnknownIdPolicy` is `Error`.
+    pub fn try_decode(&self, ids: &[Token]) -> Result<String, DecodeError> {
+        let mut text_bytes = Vec::new();
+        for (position, &id) in ids.iter().enumerate() {
+            if !self.try_decode_id(id, &mut text_bytes) {
+                return Err(DecodeError::UnknownId { position, id });
+            }
+        }
+        Ok(String::from_utf8_lossy(&text_bytes).into_owned())
+    }
+
+    /// Decode `ids` like `decode`, handling ids that are neither in the vocab
+    /// nor a special token by `policy`.
+    pub fn decode_lossy(&self, ids: &[Token], policy: &LossyPolicy) -> String {
+        decode_lossy(ids, policy, |id, out| {
+            decode_token(id, &self.vocab, &self.inverse_special_tokens, out)
+        })
+    }
+
+    /// Decode `ids` like `decode`, rendering special tokens by `rendering`,
+    /// e.g. to drop `<|endoftext|>` from model output.
+    pub fn decode_with_specials(&self, ids: &[Token], rendering: &SpecialRendering) -> String {
+        decode_with_specials(
+            ids,
+            rendering,
+            |id| self.inverse_special_tokens.contains_key(&id),
+            |id, out| {
+                self.decode_id(id, out);
+                true
+            },
+        )
+    }
+
+    /// Render token `id` for display with `render_token`, e.g. for vocab dumps.
+    /// Returns `None` for an unknown id.
+    pub fn render_token(&self, id: Token) -> Option<String> {
+        let mut bytes = Vec::new();
+        decode_token(id, &self.vocab, &self.inverse_special_tokens, &mut bytes)
+            .then(|| render_token(&bytes))
+    }
+
+    /// Decode `ids` like `decode`, also returning the byte range of the text
+    /// every id produced, e.g. to map attention or logprobs back onto the
+    /// generated text. Invalid UTF-8 is replaced like `String::from_utf8_lossy`
+    /// does; a range may start or end inside a character split over tokens.
+    pub fn decode_with_spans(&self, ids: &[Token]) -> (String, Vec<Range<usize>>) {
+        decode_with_spans(ids, |id, out| self.decode_id(id, out))
+    }
+
+    /// Render the tokens of `text` for a terminal, each on its own ANSI
+    /// background color, and with `show_ids` their ids below, e.g. to see
+    /// at a glance how a string is split.
+    pub fn visualize(&self, text: &str, show_ids: bool) -> String {
+        let ids = encode_bytes(text.as_bytes(), &self.merges);
+        let (decoded, spans) = self.decode_with_spans(&ids);
+        visualize(&decoded, &ids, &spans, show_ids)
+    }
+
+    /// Decode only the text `ids` adds to the previously decoded `prev`, for
+    /// streaming output that is appended as tokens arrive. A character split
+    /// over tokens is held back until it is complete, so nothing returned
+    /// earlier ever changes. Panics if `ids` doesn't start with `prev`.
+    pub fn decode_delta(&self, prev: &[Token], ids: &[Token]) -> String {
+        decode_delta(prev, ids, |id, out| self.decode_id(id, out))
+    }
+
+    /// Decode `ids` like `decode`, appending the text to `out` instead of
+    /// building a new `String`.
+    pub fn decode_into(&self, ids: &[Token], out: &mut String) {
+        decode_into(ids, out, |id, out| self.decode_id(id, out))
+    }
+
+    /// Decode `ids` like `decode`, writing the text to `writer` as it is
+    /// decoded instead of building a `String`.
+    pub fn decode_to_writer<W: Write>(&self, ids: &[Token], writer: W) -> io::Result<()> {
+        decode_to_writer(ids, writer, |id, out| self.decode_id(id, out))
+    }
+
+    /// Decode tokens arriving one at a time, see `StreamDecoder`.
+    pub fn stream_decoder(&self) -> StreamDecoder<'_> {
+        StreamDecoder::new(|idx, out| self.decode_id(idx, out))
+    }
+
+    /// Append the bytes of `id` to `out`, handling an unknown id by the
+    /// `UnknownIdPolicy`. Returns false if the policy makes it an error.
+    fn try_decode_id(&self, id: Token, out: &mut Vec<u8>) -> bool {
+        decode_token(id, &self.vocab, &self.inverse_special_tokens, out)
+            || self.unknown_id_policy.apply(out)
+    }
+
+    /// Like `try_decode_id`, but an error becomes U+FFFD.
+    fn decode_id(&self, id: Token, out: &mut Vec<u8>) {
+        if !decode_token(id, &self.vocab, &self.inverse_special_tokens, out) {
+            self.unknown_id_policy.apply_or_replace(out);
+        }
+    }
+
+    /// Encode `text` like `encode`, pairing every token with the byte range
+    /// of `text` it came from, e.g. to highlight tokens or align spans. A
+    /// range may start or end inside a character that is split over tokens.
+    pub fn encode_with_offsets(&self, text: &str) -> Vec<(Token, Range<usize>)> {
+        let ids = encode_bytes(text.as_bytes(), &self.merges);
+        token_offsets(ids, &self.vocab, &self.inverse_special_tokens)
+    }
+
+    /// Cut `text` to at most `max_tokens` tokens at token boundaries, giving
+    /// up the part chosen by `truncation`. Returns the kept text and its exact
+    /// token count.
+    pub fn truncate_to_tokens(
+        &self,
+        text: &str,
+        max_tokens: usize,
+        truncation: &Truncation,
+    ) -> (String, usize) {
+        truncate(text, max_tokens, truncation, |text| {
+            self.encode_with_offsets(text)
+        })
+    }
+
+    /// Split `text` into consecutive pieces of at most `max_tokens` tokens,
+    /// each overlapping the previous one by `overlap_tokens` tokens, e.g. for
+    /// retrieval ingestion. Every piece comes with its span and exact count.
+    pub fn split_by_tokens<'a>(
+        &self,
+        text: &'a str,
+        max_tokens: usize,
+        overlap_tokens: usize,
+    ) -> Vec<TextChunk<'a>> {
+        self.split_by_tokens_at(text, max_tokens, overlap_tokens, Boundary::Token)
+    }
+
+    /// Like `split_by_tokens`, but prefer ending pieces at sentence or
+    /// paragraph boundaries, cutting a sentence only when it doesn't fit on
+    /// its own.
+    pub fn split_by_tokens_at<'a>(
+        &self,
+        text: &'a str,
+        max_tokens: usize,
+        overlap_tokens: usize,
+        boundary: Boundary,
+    ) -> Vec<TextChunk<'a>> {
+        split_by_tokens(text, max_tokens, overlap_tokens, boundary, |text| {
+            self.encode_with_offsets(text)
+        })
+    }
+}
+
+impl Default for BasicTokenizer {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Serialized as its merges and special tokens.
+#[cfg(feature = "serde")]
+impl serde::Serialize for BasicTokenizer {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serde::Serialize::serialize(&self.model_file(), serializer)
+    }
+}
+
+#[cfg(feature = "serde")]
+impl<'de> serde::Deserialize<'de> for BasicTokenizer {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let model = ModelFile::deserialize(deserializer)?;
+        check_merges(&model.merges).map_err(serde::de::Error::custom)?;
+        Self::from_model_file(model).map_err(serde::de::Error::custom)
+    }
+}
+
+impl Trainable for BasicTokenizer {
+    /// The raw bytes of `text` form a single chunk; `config.split` has no effect.
+    fn training_chunks(&self, text: &str, _config: &TrainConfig) -> Vec<Vec<Token>> {
+        vec![text.bytes().map(|b| b as Token).collect()]
+    }
+
+    fn merges(&self) -> &FastHashMap<(Token, Token), Token> {
+        &self.merges
+    }
+
+    fn special_tokens(&self) -> &HashMap<String, Token> {
+        &self.special_tokens
+    }
+
+    fn set_special_tokens(&mut self, special_tokens: HashMap<String, Token>) {
+        self.inverse_special_tokens = special_tokens
+            .iter()
+            .map(|(special, &idx)| (idx, special.clone()))
+            .collect();
+        self.special_tokens = special_tokens;
+    }
+
+    fn set_merges(&mut self, merges: Vec<Merge>) {
+        self.merges = merges.into_iter().collect();
+        self.vocab = build_vocab(&self.merges);
+        self.prefix_index = PrefixIndex::new(&self.vocab);
+    }
+}
+
+impl Tokenizer for BasicTokenizer {
+    fn encode(&mut self, text: &str) -> Vec<Token> {
+        encode_bytes(text.as_bytes(), &self.merges)
+    }
+
+    fn decode(&self, ids: &[Token]) -> String {
+        self.decode_iter(ids.iter().copied())
+    }
+}
@@ -0,0 +1,72 @@
+/*
+Shaping batches of encoded documents for models that take fixed-shape input.
+*/
+
+use crate::base::Token;
+
+/// Which end of a sequence gets the padding.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum PadSide {
+    #[default]
+    Right,
+    Left,
+}
+
+/// How long padded sequences are.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PadLength {
+    /// As long as the longest sequence of the batch.
+    Longest,
+    /// Exactly this long. Longer sequences are cut on the padding side.
+    Fixed(usize),
+}
+
+/// Padding for the batch encode APIs, so every sequence of a batch comes out
+/// with the same length.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Padding {
+    pub pad_id: Token,
+    pub side: PadSide,
+    pub length: PadLength,
+}
+
+impl Padding {
+    /// Pad on the right to the longest sequence of the batch.
+    pub fn new(pad_id: Token) -> Self {
+        Padding {
+            pad_id,
+            side: PadSide::Right,
+            length: PadLength::Longest,
+        }
+    }
+
+    pub fn side(mut self, side: PadSide) -> Self {
+        self.side = side;
+        self
+    }
+
+    pub fn length(mut self, length: PadLength) -> Self {
+        self.length = length;
+        self
+    }
+
+    /// Bring every sequence of `batch` to the same length.
+    pub fn apply(&self, batch: &mut [Vec<Token>]) {
+        let length = match self.length {
+            PadLength::Longest => batch.iter().map(Vec::len).max().unwrap_or(0),
+            PadLength::Fixed(length) => length,
+        };
+        for ids in batch {
+            match self.side {
+                PadSide::Right => ids.resize(length, self.pad_id),
+                PadSide::Left => {
+                    if ids.len() > length {
+                        ids.drain(..ids.len() - length);
+                    } else {
+                        ids.splice(0..0, std::iter::repeat_n(self.pad_id, length - ids.len()));
+                    }
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,258 @@
+/*
+Several named tokenizers in a single file, for applications shipping more
+than one vocabulary (say "base", "code" and "multilingual"). The file starts
+with a manifest listing every tokenizer's name, kind and size, followed by
+their `.model` files one after the other:
+
+    minbpe-rs bundle v1
+    base regex 1843
+    code regex 2710
+    bytes basic 954
+
+    <the .model of "base"><the .model of "code"><the .model of "bytes">
+
+Opening a bundle only reads the manifest and the raw models; a tokenizer is
+built when it's asked for with `get`, so unused ones cost no loading time.
+*/
+
+use std::fs;
+use std::io::{self, Read, Write};
+use std::path::Path;
+
+use indexmap::IndexMap;
+
+use crate::atomic::save_atomically;
+use crate::base::{Token, Tokenizer};
+use crate::basic::BasicTokenizer;
+use crate::regex::RegexTokenizer;
+
+/// First line of a bundle, bumped whenever its format changes.
+const BUNDLE_HEADER: &str = "minbpe-rs bundle v1";
+
+/// The tokenizer types a bundle can hold.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BundleKind {
+    Basic,
+    Regex,
+}
+
+impl BundleKind {
+    fn name(self) -> &'static str {
+        match self {
+            BundleKind::Basic => "basic",
+            BundleKind::Regex => "regex",
+        }
+    }
+
+    fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "basic" => Some(BundleKind::Basic),
+            "regex" => Some(BundleKind::Regex),
+            _ => None,
+        }
+    }
+}
+
+/// A tokenizer taken from a bundle.
+pub enum BundledTokenizer {
+    Basic(Box<BasicTokenizer>),
+    Regex(Box<RegexTokenizer>),
+}
+
+impl Tokenizer for BundledTokenizer {
+    fn encode(&mut self, text: &str) -> Vec<Token> {
+        match self {
+            BundledTokenizer::Basic(tokenizer) => tokenizer.encode(text),
+            BundledTokenizer::Regex(tokenizer) => tokenizer.encode(text),
+        }
+    }
+
+    fn decode(&self, ids: &[Token]) -> String {
+        match self {
+            BundledTokenizer::Basic(tokenizer) => tokenizer.decode(ids),
+            BundledTokenizer::Regex(tokenizer) => tokenizer.decode(ids),
+        }
+    }
+}
+
+struct Entry {
+    kind: BundleKind,
+    /// The tokenizer's `.model` file.
+    model: Vec<u8>,
+}
+
+/// Named tokenizers to save as, or opened from, a single file.
+#[derive(Default)]
+pub struct Bundle {
+    entries: IndexMap<String, Entry>,
+}
+
+fn invalid_data(msg: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+impl Bundle {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Open the bundle saved at `path`.
+    pub fn open(path: impl AsRef<Path>) -> io::Result<Self> {
+        Self::from_reader(fs::File::open(path)?)
+    }
+
+    /// Like `open`, reading the bundle from `reader` instead.
+    pub fn from_reader(mut reader: impl Read) -> io::Result<Self> {
+        let mut data = Vec::new();
+        reader.read_to_end(&mut data)?;
+        let mut lines = data.split(|&b| b == b'\n');
+        let mut header_len = 0;
+        let mut next_line = || {
+            let line = lines.next()?;
+            header_len += line.len() + 1;
+            Some(String::from_utf8_lossy(line).into_owned())
+        };
+        match next_line() {
+            Some(header) if header == BUNDLE_HEADER => {}
+            Some(header) if header.starts_with("minbpe-rs bundle ") => {
+                return Err(invalid_data(format!(
+                    "unsupported bundle format {:?}, written by a newer version of minbpe-rs",
+                    header
+                )));
+            }
+            _ => return Err(invalid_data("not a minbpe-rs bundle".to_string())),
+        }
+        let mut manifest = Vec::new();
+        loop {
+            let line = next_line().ok_or_else(|| invalid_data("truncated manifest".to_string()))?;
+            if line.is_empty() {
+                break;
+            }
+            let parts: Vec<&str> = line.split(' ').collect();
+            let &[name, kind, len] = &parts[..] else {
+                return Err(invalid_data(format!("invalid manifest line {:?}", line)));
+            };
+            let kind = BundleKind::from_name(kind)
+                .ok_or_else(|| invalid_data(format!("unknown tokenizer kind {:?}", kind)))?;
+            let len: usize = len
+                .parse()
+                .map_err(|_| invalid_data(format!("invalid size in manifest line {:?}", line)))?;
+            manifest.push((name.to_string(), kind, len));
+        }
+
+        let mut rest = &data[header_len..];
+        let mut entries = IndexMap::new();
+        for (name, kind, len) in manifest {
+            if len > rest.len() {
+                return Err(invalid_data(format!("tokenizer {:?} is truncated", name)));
+            }
+            let (model, tail) = rest.split_at(len);
+            rest = tail;
+            let model = model.to_vec();
+            if entries
+                .insert(name.clone(), Entry { kind, model })
+                .is_some()
+            {
+                return Err(invalid_data(format!("tokenizer {:?} appears twice", name)));
+            }
+        }
+        if !rest.is_empty() {
+            return Err(invalid_data(
+                "data after the last tokenizer of the bundle".to_string(),
+            ));
+        }
+        Ok(Bundle { entries })
+    }
+
+    /// Save the bundle to `path`.
+    pub fn save(&self, path: impl AsRef<Path>) -> io::Result<()> {
+        save_atomically(path.as_ref(), |file| self.save_to_writer(file))
+    }
+
+    /// Like `save`, writing the bundle to `writer` instead.
+    pub fn save_to_writer(&self, mut writer: impl Write) -> io::Result<()> {
+        writeln!(writer, "{}", BUNDLE_HEADER)?;
+        for (name, entry) in &self.entries {
+            writeln!(
+                writer,
+                "{} {} {}",
+                name,
+                entry.kind.name(),
+                entry.model.len()
+            )?;
+        }
+        writeln!(writer)?;
+        for entry in self.entries.values() {
+            writer.write_all(&entry.model)?;
+        }
+        writer.flush()
+    }
+
+    /// Add `tokenizer` as `name`, replacing any tokenizer of that name.
+    /// Names can't be empty or contain whitespace.
+    pub fn insert_basic(&mut self, name: &str, tokenizer: &BasicTokenizer) -> io::Result<()> {
+        let mut model = Vec::new();
+        tokenizer.save_to_writer(&mut model)?;
+        self.insert(name, BundleKind::Basic, model)
+    }
+
+    /// Add `tokenizer` as `name`, like `insert_basic`.
+    pub fn insert_regex(&mut self, name: &str, tokenizer: &RegexTokenizer) -> io::Result<()> {
+        let mut model = Vec::new();
+        tokenizer.save_to_writer(&mut model)?;
+        self.insert(name, BundleKind::Regex, model)
+    }
+
+    fn insert(&mut self, name: &str, kind: BundleKind, model: Vec<u8>) -> io::Result<()> {
+        if name.is_empty() || name.contains(char::is_whitespace) {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("invalid tokenizer name {:?}", name),
+            ));
+        }
+        self.entries.insert(name.to_string(), Entry { kind, model });
+        Ok(())
+    }
+
+    /// Remove the tokenizer `name`, returning whether there was one.
+    pub fn remove(&mut self, name: &str) -> bool {
+        self.entries.shift_remove(name).is_some()
+    }
+
+    /// Load the tokenizer `name`.
+    pub fn get(&self, name: &str) -> io::Result<BundledTokenizer> {
+        let entry = self.entries.get(name).ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::NotFound,
+                format!("no tokenizer {:?} in the bundle", name),
+            )
+        })?;
+        let model = &entry.model[..];
+        Ok(match entry.kind {
+            BundleKind::Basic => {
+                BundledTokenizer::Basic(Box::new(BasicTokenizer::load_from_reader(model)?))
+            }
+            BundleKind::Regex => {
+                BundledTokenizer::Regex(Box::new(RegexTokenizer::load_from_reader(model)?))
+            }
+        })
+    }
+
+    /// The kind of the tokenizer `name`, if the bundle has it.
+    pub fn kind(&self, name: &str) -> Option<BundleKind> {
+        self.entries.get(name).map(|entry| entry.kind)
+    }
+
+    /// The names of the tokenizers, in the order they were added.
+    pub fn names(&self) -> impl Iterator<Item = &str> {
+        self.entries.keys().map(String::as_str)
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+}
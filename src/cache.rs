@@ -0,0 +1,176 @@
+/*
+A bounded cache of the tokens of recently encoded chunks. Chunks repeat a lot
+in natural text (common words, whitespace runs), so looking them up saves
+running the merges again, but a diverse corpus has an endless tail of rare
+chunks, so the least recently used ones are evicted past a capacity.
+`SharedCache` splits such a cache into independently locked shards, so
+threads encoding with one tokenizer share their hits without contending on a
+single lock.
+*/
+
+use std::collections::BTreeMap;
+use std::collections::hash_map::RandomState;
+use std::hash::BuildHasher;
+use std::sync::Mutex;
+
+use crate::base::Token;
+use crate::hash::FastHashMap;
+
+/// How much the chunk cache of `RegexTokenizer` may hold.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CacheCapacity {
+    /// Don't cache at all.
+    Disabled,
+    /// At most this many chunks.
+    Entries(usize),
+    /// At most about this many bytes of chunk text and tokens.
+    Bytes(usize),
+}
+
+impl Default for CacheCapacity {
+    fn default() -> Self {
+        CacheCapacity::Entries(1 << 16)
+    }
+}
+
+/// A least recently used cache from chunks to their tokens.
+#[derive(Debug, Clone, Default)]
+pub(crate) struct ChunkCache {
+    capacity: CacheCapacity,
+    /// The tokens of every chunk and when it was last used.
+    entries: FastHashMap<String, (Vec<Token>, u64)>,
+    /// The chunks by when they were last used, oldest first.
+    order: BTreeMap<u64, String>,
+    clock: u64,
+    /// Approximate memory held by the entries.
+    bytes: usize,
+}
+
+impl ChunkCache {
+    pub(crate) fn new(capacity: CacheCapacity) -> Self {
+        ChunkCache {
+            capacity,
+            ..Default::default()
+        }
+    }
+
+    pub(crate) fn set_capacity(&mut self, capacity: CacheCapacity) {
+        self.capacity = capacity;
+        self.evict();
+    }
+
+    /// The tokens of `chunk`, marking it as recently used.
+    pub(crate) fn get(&mut self, chunk: &str) -> Option<&[Token]> {
+        let (ids, used) = self.entries.get_mut(chunk)?;
+        self.clock += 1;
+        let key = self.order.remove(used).unwrap();
+        *used = self.clock;
+        self.order.insert(self.clock, key);
+        Some(ids)
+    }
+
+    /// The tokens of `chunk`, leaving the order of use as it is.
+    pub(crate) fn peek(&self, chunk: &str) -> Option<&[Token]> {
+        self.entries.get(chunk).map(|(ids, _)| ids.as_slice())
+    }
+
+    pub(crate) fn insert(&mut self, chunk: &str, ids: Vec<Token>) {
+        if self.capacity == CacheCapacity::Disabled || self.entries.contains_key(chunk) {
+            return;
+        }
+        self.clock += 1;
+        self.bytes += entry_size(chunk, &ids);
+        self.order.insert(self.clock, chunk.to_string());
+        self.entries.insert(chunk.to_string(), (ids, self.clock));
+        self.evict();
+    }
+
+    pub(crate) fn clear(&mut self) {
+        self.entries.clear();
+        self.order.clear();
+        self.bytes = 0;
+    }
+
+    /// Drop the least recently used entries until the capacity holds.
+    fn evict(&mut self) {
+        loop {
+            let full = match self.capacity {
+                CacheCapacity::Disabled => !self.entries.is_empty(),
+                CacheCapacity::Entries(max) => self.entries.len() > max,
+                CacheCapacity::Bytes(max) => self.bytes > max,
+            };
+            if !full {
+                break;
+            }
+            let (_, chunk) = self.order.pop_first().unwrap();
+            let (ids, _) = self.entries.remove(&chunk).unwrap();
+            self.bytes -= entry_size(&chunk, &ids);
+        }
+    }
+}
+
+/// A thread-safe chunk cache, for tokenizers encoding from several threads
+/// at once, see `RegexTokenizer::set_shared_cache`.
+#[derive(Debug)]
+pub struct SharedCache {
+    shards: Vec<Mutex<ChunkCache>>,
+    hasher: RandomState,
+}
+
+impl SharedCache {
+    const SHARDS: usize = 16;
+
+    /// A cache holding up to `capacity` in total, split evenly over the
+    /// shards.
+    pub fn new(capacity: CacheCapacity) -> Self {
+        let shard_capacity = match capacity {
+            CacheCapacity::Disabled => CacheCapacity::Disabled,
+            CacheCapacity::Entries(max) => CacheCapacity::Entries(max.div_ceil(Self::SHARDS)),
+            CacheCapacity::Bytes(max) => CacheCapacity::Bytes(max.div_ceil(Self::SHARDS)),
+        };
+        SharedCache {
+            shards: (0..Self::SHARDS)
+                .map(|_| Mutex::new(ChunkCache::new(shard_capacity)))
+                .collect(),
+            hasher: RandomState::new(),
+        }
+    }
+
+    fn shard(&self, chunk: &str) -> &Mutex<ChunkCache> {
+        let hash = self.hasher.hash_one(chunk) as usize;
+        &self.shards[hash % Self::SHARDS]
+    }
+
+    /// Append the tokens of `chunk` to `out` if it is cached.
+    pub(crate) fn get_into(&self, chunk: &str, out: &mut Vec<Token>) -> bool {
+        let mut shard = self.shard(chunk).lock().unwrap();
+        match shard.get(chunk) {
+            Some(cached) => {
+                out.extend_from_slice(cached);
+                true
+            }
+            None => false,
+        }
+    }
+
+    pub(crate) fn insert(&self, chunk: &str, ids: Vec<Token>) {
+        self.shard(chunk).lock().unwrap().insert(chunk, ids);
+    }
+
+    pub(crate) fn clear(&self) {
+        for shard in &self.shards {
+            shard.lock().unwrap().clear();
+        }
+    }
+}
+
+impl Default for SharedCache {
+    fn default() -> Self {
+        Self::new(CacheCapacity::default())
+    }
+}
+
+fn entry_size(chunk: &str, ids: &[Token]) -> usize {
+    // the chunk is held twice, as key of `entries` and value of `order`
+    2 * chunk.len() + size_of_val(ids)
+}
@@ -0,0 +1,61 @@
+/*
+Where the crate keeps files it derives and can always derive again, such as
+the merges GPT4Tokenizer recovers from its ranks. Deployments with a
+read-only home or a shared cache volume point it elsewhere with
+`MINBPE_CACHE_DIR`; otherwise it follows the platform's conventions, the XDG
+base directory specification on Linux and other Unixes.
+*/
+
+use std::env;
+use std::path::PathBuf;
+
+/// The environment variable overriding `cache_dir`.
+pub const CACHE_DIR_VAR: &str = "MINBPE_CACHE_DIR";
+
+/// Name of the crate's directory within the platform's cache directory.
+const APP_DIR: &str = "minbpe-rs";
+
+/// The directory to cache files in, which may not exist yet:
+/// - `$MINBPE_CACHE_DIR` if it is set and not empty, as it is,
+/// - `$XDG_CACHE_HOME/minbpe-rs` if it is an absolute path (on Unix other
+///   than macOS),
+/// - the platform's cache directory: `~/.cache/minbpe-rs` on Unix,
+///   `~/Library/Caches/minbpe-rs` on macOS, `%LOCALAPPDATA%\minbpe-rs` on
+///   Windows,
+/// - and without a home directory, `minbpe-rs` in the temporary directory.
+pub fn cache_dir() -> PathBuf {
+    if let Some(dir) = env::var_os(CACHE_DIR_VAR).filter(|dir| !dir.is_empty()) {
+        return PathBuf::from(dir);
+    }
+    platform_cache_dir()
+        .unwrap_or_else(env::temp_dir)
+        .join(APP_DIR)
+}
+
+/// An absolute path from the environment variable `var`.
+fn absolute_var(var: &str) -> Option<PathBuf> {
+    env::var_os(var)
+        .map(PathBuf::from)
+        .filter(|path| path.is_absolute())
+}
+
+#[cfg(target_os = "macos")]
+fn platform_cache_dir() -> Option<PathBuf> {
+    Some(absolute_var("HOME")?.join("Library").join("Caches"))
+}
+
+#[cfg(windows)]
+fn platform_cache_dir() -> Option<PathBuf> {
+    absolute_var("LOCALAPPDATA")
+}
+
+#[cfg(all(unix, not(target_os = "macos")))]
+fn platform_cache_dir() -> Option<PathBuf> {
+    // relative paths in XDG variables are invalid and must be ignored
+    absolute_var("XDG_CACHE_HOME").or_else(|| Some(absolute_var("HOME")?.join(".cache")))
+}
+
+#[cfg(not(any(unix, windows)))]
+fn platform_cache_dir() -> Option<PathBuf> {
+    None
+}
@@ -0,0 +1,101 @@
+/*
+Counting the tokens a list of chat messages takes up in the prompt of an
+OpenAI chat model. Messages are rendered in the ChatML format, which wraps
+every message in a few special tokens and primes the reply with a few more,
+so the count is more than the tokens of the message contents alone. The
+overheads follow the OpenAI cookbook ("How to count tokens with tiktoken").
+*/
+
+use lazy_static::lazy_static;
+
+use crate::gpt4::GPT4Tokenizer;
+
+lazy_static! {
+    static ref TOKENIZER: GPT4Tokenizer = GPT4Tokenizer::new();
+}
+
+/// A message of a chat prompt.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ChatMessage {
+    /// `"system"`, `"user"`, `"assistant"`, ...
+    pub role: String,
+    pub content: String,
+    /// Optional name of the participant sending the message.
+    pub name: Option<String>,
+}
+
+impl ChatMessage {
+    pub fn new(role: impl Into<String>, content: impl Into<String>) -> Self {
+        ChatMessage {
+            role: role.into(),
+            content: content.into(),
+            name: None,
+        }
+    }
+
+    pub fn name(mut self, name: impl Into<String>) -> Self {
+        self.name = Some(name.into());
+        self
+    }
+}
+
+/// The chat models using `cl100k_base`, grouped by how they render messages.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum ChatModel {
+    /// `gpt-4` and `gpt-3.5-turbo` from `0613` on.
+    #[default]
+    Gpt4,
+    /// `gpt-3.5-turbo-0301`, which spends one more token per message and
+    /// puts the name in place of the role.
+    Gpt35Turbo0301,
+}
+
+impl ChatModel {
+    /// The model family of an OpenAI model name, e.g. `"gpt-4-0613"`.
+    /// Returns `None` for models not using `cl100k_base` (e.g. `gpt-4o`).
+    pub fn from_name(name: &str) -> Option<Self> {
+        if name == "gpt-3.5-turbo-0301" {
+            Some(ChatModel::Gpt35Turbo0301)
+        } else if name.starts_with("gpt-4o") {
+            None
+        } else if name.starts_with("gpt-4") || name.starts_with("gpt-3.5-turbo") {
+            Some(ChatModel::Gpt4)
+        } else {
+            None
+        }
+    }
+
+    /// Tokens every message takes on top of its fields.
+    fn tokens_per_message(self) -> isize {
+        match self {
+            ChatModel::Gpt4 => 3,
+            ChatModel::Gpt35Turbo0301 => 4,
+        }
+    }
+
+    /// Tokens a name takes on top of its text.
+    fn tokens_per_name(self) -> isize {
+        match self {
+            ChatModel::Gpt4 => 1,
+            ChatModel::Gpt35Turbo0301 => -1,
+        }
+    }
+}
+
+/// Number of prompt tokens `messages` take up when sent to `model`,
+/// including the tokens priming the assistant's reply.
+pub fn num_tokens_from_messages(messages: &[ChatMessage], model: ChatModel) -> usize {
+    let count = |text: &str| TOKENIZER.encode_ordinary(text).len() as isize;
+    let mut num_tokens = 0;
+    for message in messages {
+        num_tokens += model.tokens_per_message();
+        num_tokens += count(&message.role);
+        num_tokens += count(&message.content);
+        if let Some(name) = &message.name {
+            num_tokens += count(name) + model.tokens_per_name();
+        }
+    }
+    // every reply is primed with <|start|>assistant<|message|>
+    num_tokens += 3;
+    num_tokens as usize
+}
@@ -0,0 +1,85 @@
+/*
+A training corpus made of many files, e.g. every `*.txt` under a dataset folder.
+*/
+
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Corpus {
+    files: Vec<PathBuf>,
+}
+
+impl Corpus {
+    pub fn from_files<I>(files: I) -> Self
+    where
+        I: IntoIterator,
+        I::Item: Into<PathBuf>,
+    {
+        Corpus {
+            files: files.into_iter().map(Into::into).collect(),
+        }
+    }
+
+    /// Every file below `dir` (recursively) whose name matches `pattern`, in
+    /// sorted order. In the pattern `*` matches any run of characters and `?`
+    /// matches exactly one, so `*.txt` selects all text files.
+    pub fn from_dir(dir: impl AsRef<Path>, pattern: &str) -> io::Result<Self> {
+        let mut files = Vec::new();
+        let mut pending = vec![dir.as_ref().to_path_buf()];
+        while let Some(dir) = pending.pop() {
+            for entry in fs::read_dir(&dir)? {
+                let path = entry?.path();
+                if path.is_dir() {
+                    pending.push(path);
+                } else if path
+                    .file_name()
+                    .and_then(|name| name.to_str())
+                    .is_some_and(|name| wildcard_match(pattern, name))
+                {
+                    files.push(path);
+                }
+            }
+        }
+        files.sort();
+        Ok(Corpus { files })
+    }
+
+    pub fn files(&self) -> &[PathBuf] {
+        &self.files
+    }
+}
+
+/// How much a single file contributed to a training run.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FileStats {
+    pub path: PathBuf,
+    pub bytes: usize,
+    /// Number of chunks the file was split into before counting pairs.
+    pub chunks: usize,
+}
+
+fn wildcard_match(pattern: &str, name: &str) -> bool {
+    let pattern: Vec<char> = pattern.chars().collect();
+    let name: Vec<char> = name.chars().collect();
+    // classic greedy matching, backtracking to the last `*` on a mismatch
+    let (mut p, mut n) = (0, 0);
+    let mut star = None;
+    while n < name.len() {
+        if p < pattern.len() && (pattern[p] == '?' || pattern[p] == name[n]) {
+            p += 1;
+            n += 1;
+        } else if p < pattern.len() && pattern[p] == '*' {
+            star = Some((p, n));
+            p += 1;
+        } else if let Some((star_p, star_n)) = star {
+            p = star_p + 1;
+            n = star_n + 1;
+            star = Some((star_p, star_n + 1));
+        } else {
+            return false;
+        }
+    }
+    pattern[p..].iter().all(|&c| c == '*')
+}
@@ -0,0 +1,337 @@
+/*
+Decoding options beyond plain `decode`: ids that may not all belong to the
+vocabulary (e.g. model output produced with a different vocabulary or
+corrupted in transit), and how special tokens show up in the text.
+*/
+
+use std::collections::HashMap;
+use std::io::{self, Write};
+use std::ops::Range;
+
+use crate::base::Token;
+
+/// What decoding does with an id that is neither in the vocab nor a special
+/// token, set on a tokenizer with `set_unknown_id_policy`. `decode_lossy`
+/// takes its own `LossyPolicy` instead.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub enum UnknownIdPolicy {
+    /// Fail `try_decode`, like minbpe raising an error for an invalid id.
+    /// Decoding that can't fail, like `decode`, puts the replacement
+    /// character U+FFFD in its place instead.
+    #[default]
+    Error,
+    /// Leave it out.
+    Skip,
+    /// Put the given bytes in its place, e.g. `b"<unk>".to_vec()`.
+    Replace(Vec<u8>),
+}
+
+impl UnknownIdPolicy {
+    /// Handle an unknown id, appending any replacement to `out`. Returns
+    /// false if it is an error.
+    pub(crate) fn apply(&self, out: &mut Vec<u8>) -> bool {
+        match self {
+            UnknownIdPolicy::Error => return false,
+            UnknownIdPolicy::Skip => {}
+            UnknownIdPolicy::Replace(bytes) => out.extend(bytes),
+        }
+        true
+    }
+
+    /// Like `apply`, for the decoding that can't fail: an error becomes the
+    /// replacement character U+FFFD.
+    pub(crate) fn apply_or_replace(&self, out: &mut Vec<u8>) {
+        if !self.apply(out) {
+            out.extend("\u{FFFD}".as_bytes());
+        }
+    }
+}
+
+/// What `decode_lossy` puts in place of an id that is neither in the vocab
+/// nor a special token.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub enum LossyPolicy {
+    /// The replacement character U+FFFD.
+    #[default]
+    Replacement,
+    /// The given text, e.g. `"<unk>"`.
+    Placeholder(String),
+    /// Nothing.
+    Skip,
+}
+
+/// Decode `ids` with `decode_token` (which appends the bytes of a token and
+/// returns whether it is known), handling unknown ids by `policy`.
+pub(crate) fn decode_lossy<F>(ids: &[Token], policy: &LossyPolicy, mut decode_token: F) -> String
+where
+    F: FnMut(Token, &mut Vec<u8>) -> bool,
+{
+    let mut text_bytes = Vec::new();
+    for &id in ids {
+        if decode_token(id, &mut text_bytes) {
+            continue;
+        }
+        match policy {
+            LossyPolicy::Replacement => text_bytes.extend("\u{FFFD}".as_bytes()),
+            LossyPolicy::Placeholder(text) => text_bytes.extend(text.as_bytes()),
+            LossyPolicy::Skip => {}
+        }
+    }
+    String::from_utf8_lossy(&text_bytes).into_owned()
+}
+
+/// How `decode_with_specials` renders special tokens.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub enum SpecialRendering {
+    /// Their literal text, e.g. `<|endoftext|>`, like `decode`.
+    #[default]
+    Literal,
+    /// Not at all.
+    Skip,
+    /// The text given for their id, e.g. `"\n\n"` for `<|endoftext|>`, or the
+    /// literal for ids not given.
+    Map(HashMap<Token, String>),
+}
+
+/// Decode `ids` with `decode_token` (which appends the bytes of a token and
+/// returns whether it is known), rendering the ids for which `is_special`
+/// holds by `rendering`.
+pub(crate) fn decode_with_specials<F, S>(
+    ids: &[Token],
+    rendering: &SpecialRendering,
+    is_special: S,
+    mut decode_token: F,
+) -> String
+where
+    F: FnMut(Token, &mut Vec<u8>) -> bool,
+    S: Fn(Token) -> bool,
+{
+    let mut text_bytes = Vec::new();
+    for &id in ids {
+        if is_special(id) {
+            match rendering {
+                SpecialRendering::Literal => {}
+                SpecialRendering::Skip => continue,
+                SpecialRendering::Map(texts) => {
+                    if let Some(text) = texts.get(&id) {
+                        text_bytes.extend(text.as_bytes());
+                        continue;
+                    }
+                }
+            }
+        }
+        decode_token(id, &mut text_bytes);
+    }
+    String::from_utf8_lossy(&text_bytes).into_owned()
+}
+
+/// Decode `ids` with `decode_token` (which appends the bytes of a token),
+/// returning the text with invalid UTF-8 replaced like `from_utf8_lossy`, and
+/// the byte range of the text every id produced. A replacement character
+/// belongs to the token holding the first byte it replaces.
+pub(crate) fn decode_with_spans<F>(
+    ids: &[Token],
+    mut decode_token: F,
+) -> (String, Vec<Range<usize>>)
+where
+    F: FnMut(Token, &mut Vec<u8>),
+{
+    let mut text_bytes = Vec::new();
+    let mut raw_spans = Vec::with_capacity(ids.len());
+    for &id in ids {
+        let start = text_bytes.len();
+        decode_token(id, &mut text_bytes);
+        raw_spans.push(start..text_bytes.len());
+    }
+    // where every decoded byte (and the end) lands in the text
+    let mut offsets = Vec::with_capacity(text_bytes.len() + 1);
+    let mut text = String::with_capacity(text_bytes.len());
+    for chunk in text_bytes.utf8_chunks() {
+        offsets.extend(text.len()..text.len() + chunk.valid().len());
+        text.push_str(chunk.valid());
+        if !chunk.invalid().is_empty() {
+            offsets.push(text.len());
+            text.push(char::REPLACEMENT_CHARACTER);
+            offsets.extend(std::iter::repeat_n(text.len(), chunk.invalid().len() - 1));
+        }
+    }
+    offsets.push(text.len());
+    let spans = raw_spans
+        .into_iter()
+        .map(|span| offsets[span.start]..offsets[span.end])
+        .collect();
+    (text, spans)
+}
+
+/// Move the text of `pending` into `out`, replacing invalid UTF-8 like
+/// `from_utf8_lossy` but keeping back a trailing incomplete character that
+/// more bytes may still complete.
+pub(crate) fn drain_complete_utf8(pending: &mut Vec<u8>, out: &mut String) {
+    loop {
+        let error = match std::str::from_utf8(pending) {
+            Ok(text) => {
+                out.push_str(text);
+                pending.clear();
+                return;
+            }
+            Err(error) => error,
+        };
+        let valid = error.valid_up_to();
+        // the bytes up to `valid` were just validated
+        out.push_str(std::str::from_utf8(&pending[..valid]).unwrap());
+        match error.error_len() {
+            // bytes that can't start a character, as `decode` replaces them
+            Some(len) => {
+                out.push(char::REPLACEMENT_CHARACTER);
+                pending.drain(..valid + len);
+            }
+            // an incomplete character that later bytes may complete
+            None => {
+                pending.drain(..valid);
+                return;
+            }
+        }
+    }
+}
+
+/// Decode `ids` with `decode_token` (which appends the bytes of a token),
+/// appending the text to `out`. Only a character split over tokens is
+/// buffered on the side.
+pub(crate) fn decode_into<F>(ids: &[Token], out: &mut String, mut decode_token: F)
+where
+    F: FnMut(Token, &mut Vec<u8>),
+{
+    let mut pending = Vec::new();
+    for &id in ids {
+        decode_token(id, &mut pending);
+        drain_complete_utf8(&mut pending, out);
+    }
+    out.push_str(&String::from_utf8_lossy(&pending));
+}
+
+/// Tokens before the end of the previous ids that `decode_delta` decodes
+/// again. Every token has at least one byte, so a character split over
+/// tokens (at most four bytes) starts within them.
+const DELTA_CONTEXT: usize = 4;
+
+/// Decode the text `ids` adds to `prev`, of which it must be an extension.
+/// A trailing incomplete character is held back in both, so a later call
+/// returns it whole once the tokens completing it arrive.
+pub(crate) fn decode_delta<F>(prev: &[Token], ids: &[Token], mut decode_token: F) -> String
+where
+    F: FnMut(Token, &mut Vec<u8>),
+{
+    assert!(ids.starts_with(prev), "ids must extend the previous ids");
+    // the context may start inside a character, which only changes the
+    // shared start of both texts
+    let mut bytes = Vec::new();
+    for &id in &prev[prev.len().saturating_sub(DELTA_CONTEXT)..] {
+        decode_token(id, &mut bytes);
+    }
+    let mut before = String::new();
+    drain_complete_utf8(&mut bytes.clone(), &mut before);
+    for &id in &ids[prev.len()..] {
+        decode_token(id, &mut bytes);
+    }
+    let mut after = String::new();
+    drain_complete_utf8(&mut bytes, &mut after);
+    after.split_off(before.len())
+}
+
+/// Size of the text buffered by `decode_to_writer` between writes.
+const WRITE_BUFFER: usize = 1 << 13;
+
+/// Like `decode_into`, but write the text to `writer` in pieces of about
+/// `WRITE_BUFFER` bytes.
+pub(crate) fn decode_to_writer<W, F>(
+    ids: &[Token],
+    mut writer: W,
+    mut decode_token: F,
+) -> io::Result<()>
+where
+    W: Write,
+    F: FnMut(Token, &mut Vec<u8>),
+{
+    let mut pending = Vec::new();
+    let mut text = String::new();
+    for &id in ids {
+        decode_token(id, &mut pending);
+        drain_complete_utf8(&mut pending, &mut text);
+        if text.len() >= WRITE_BUFFER {
+            writer.write_all(text.as_bytes())?;
+            text.clear();
+        }
+    }
+    text.push_str(&String::from_utf8_lossy(&pending));
+    writer.write_all(text.as_bytes())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Ids are bytes, plus 256 for a whole "€" and 257 for a lone
+    /// continuation byte followed by "a".
+    fn decode_token(id: Token, out: &mut Vec<u8>) {
+        match id {
+            256 => out.extend("€".as_bytes()),
+            257 => out.extend(b"\x82a"),
+            _ => out.push(id as u8),
+        }
+    }
+
+    fn lossy(ids: &[Token]) -> String {
+        let mut bytes = Vec::new();
+        for &id in ids {
+            decode_token(id, &mut bytes);
+        }
+        String::from_utf8_lossy(&bytes).into_owned()
+    }
+
+    /// Valid, split, truncated and invalid UTF-8.
+    fn cases() -> Vec<Vec<Token>> {
+        vec![
+            vec![],
+            vec![104, 105],
+            vec![256, 104, 256],
+            vec![0xe2, 0x82, 0xac],
+            vec![0xe2, 0x82, 104],
+            vec![104, 0xe2, 0x82],
+            vec![0xff, 0xfe, 104],
+            vec![0xe2, 257, 0xf0, 0x9f],
+            vec![0xf0, 0x9f, 0x98, 0x80, 0x80, 256],
+        ]
+    }
+
+    #[test]
+    fn spans_text_matches_lossy() {
+        for ids in cases() {
+            let (text, spans) = decode_with_spans(&ids, decode_token);
+            assert_eq!(text, lossy(&ids));
+            assert_eq!(spans.len(), ids.len());
+            let mut end = 0;
+            for span in spans {
+                assert_eq!(span.start, end);
+                assert!(span.end <= text.len());
+                end = span.end;
+            }
+            assert_eq!(end, text.len());
+        }
+    }
+
+    #[test]
+    fn into_and_writer_match_lossy() {
+        let mut long = Vec::new();
+        for i in 0..WRITE_BUFFER {
+            long.extend([0xe2, 0x82, if i % 3 == 0 { 0xac } else { 104 }]);
+        }
+        for ids in cases().into_iter().chain([long]) {
+            let mut text = "prefix ".to_string();
+            decode_into(&ids, &mut text, decode_token);
+            assert_eq!(text, format!("prefix {}", lossy(&ids)));
+            let mut written = Vec::new();
+            decode_to_writer(&ids, &mut written, decode_token).unwrap();
+            assert_eq!(String::from_utf8(written).unwrap(), lossy(&ids));
+        }
+    }
+}
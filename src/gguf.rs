@@ -0,0 +1,536 @@
+/*
+The tokenizer metadata of GGUF, the model format of llama.cpp. A model embeds
+its tokenizer as `tokenizer.ggml.*` key-value pairs: the token strings (in the
+byte-level encoding of GPT-2, see `hf`) indexed by id, their types, the merges
+in order, and the ids of special tokens such as the end of text.
+
+`gguf_metadata` returns these pairs for a model's own GGUF writer, and
+`save_gguf_vocab` writes them as a vocab-only GGUF file (a file without
+tensors, like the `ggml-vocab-*.gguf` files llama.cpp tests with).
+
+`from_gguf` goes the other way, building a tokenizer from the metadata of a
+GGUF file, vocab-only or a full model (whose tensors aren't read). Only
+byte-level BPE vocabularies (`tokenizer.ggml.model` "gpt2") can be imported,
+and their merges must produce tokens in the order of their ids, the order
+minbpe applies merges in.
+*/
+
+use std::collections::HashMap;
+use std::io::{self, Read, Write};
+
+use crate::base::{Merge, Token};
+use crate::hash::FastHashMap;
+use crate::hf::{ByteLevel, byte_level, char_bytes};
+use crate::regex::{GPT2_SPLIT_PATTERN, GPT4_SPLIT_PATTERN};
+
+/// A metadata value, of the GGUF types the tokenizer keys use.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum GgufValue {
+    U32(u32),
+    String(String),
+    I32Array(Vec<i32>),
+    StringArray(Vec<String>),
+}
+
+/// Token types of `tokenizer.ggml.token_type`, as in llama.cpp.
+const TOKEN_TYPE_NORMAL: i32 = 1;
+const TOKEN_TYPE_CONTROL: i32 = 3;
+const TOKEN_TYPE_USER_DEFINED: i32 = 4;
+const TOKEN_TYPE_UNUSED: i32 = 5;
+
+/// The `tokenizer.ggml.pre` of a split pattern. llama.cpp doesn't read the
+/// pattern from the file but picks one of its own by this name, so patterns
+/// it doesn't know fall back to its default.
+pub(crate) fn pre_tokenizer_name(pattern: Option<&str>) -> &'static str {
+    match pattern {
+        // LLaMA 3 adopted cl100k's pattern
+        Some(GPT4_SPLIT_PATTERN) => "llama-bpe",
+        Some(GPT2_SPLIT_PATTERN) => "gpt-2",
+        _ => "default",
+    }
+}
+
+/// The split pattern of a `tokenizer.ggml.pre`, if it's one of ours.
+pub(crate) fn pre_tokenizer_pattern(pre: &str) -> Option<&'static str> {
+    match pre {
+        "llama-bpe" => Some(GPT4_SPLIT_PATTERN),
+        "gpt-2" => Some(GPT2_SPLIT_PATTERN),
+        _ => None,
+    }
+}
+
+/// The `tokenizer.ggml.*` metadata of a byte-level BPE with `vocab` (the
+/// actual bytes of the tokens) and `merges`. Ids missing from the vocab are
+/// filled with unused `[PAD<id>]` tokens, as llama.cpp's converter does.
+pub(crate) fn tokenizer_metadata(
+    vocab: &FastHashMap<Token, Vec<u8>>,
+    merges: &FastHashMap<(Token, Token), Token>,
+    special_tokens: &HashMap<String, Token>,
+    pre: &str,
+) -> io::Result<Vec<(String, GgufValue)>> {
+    let ByteLevel {
+        tokens: vocab_tokens,
+        merges,
+    } = byte_level(vocab, merges)?;
+    if let Some((special, idx)) = special_tokens.iter().find(|&(_, &idx)| idx < 0) {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!(
+                "special token {:?} has the negative id {}, which GGUF can't store",
+                special, idx
+            ),
+        ));
+    }
+    let size = vocab_tokens
+        .iter()
+        .map(|&(_, idx)| idx)
+        .chain(special_tokens.values().copied())
+        .max()
+        .map_or(0, |idx| idx as usize + 1);
+    let mut tokens: Vec<String> = (0..size).map(|idx| format!("[PAD{}]", idx)).collect();
+    let mut token_types = vec![TOKEN_TYPE_UNUSED; size];
+    for (string, idx) in vocab_tokens {
+        tokens[idx as usize] = string;
+        token_types[idx as usize] = TOKEN_TYPE_NORMAL;
+    }
+    for (special, &idx) in special_tokens {
+        tokens[idx as usize] = special.clone();
+        token_types[idx as usize] = TOKEN_TYPE_CONTROL;
+    }
+    let mut metadata = vec![
+        (
+            "tokenizer.ggml.model".to_string(),
+            GgufValue::String("gpt2".to_string()),
+        ),
+        (
+            "tokenizer.ggml.pre".to_string(),
+            GgufValue::String(pre.to_string()),
+        ),
+        (
+            "tokenizer.ggml.tokens".to_string(),
+            GgufValue::StringArray(tokens),
+        ),
+        (
+            "tokenizer.ggml.token_type".to_string(),
+            GgufValue::I32Array(token_types),
+        ),
+        (
+            "tokenizer.ggml.merges".to_string(),
+            GgufValue::StringArray(
+                merges
+                    .into_iter()
+                    .map(|(left, right)| format!("{} {}", left, right))
+                    .collect(),
+            ),
+        ),
+    ];
+    if let Some(&idx) = special_tokens.get("<|endoftext|>") {
+        metadata.push((
+            "tokenizer.ggml.eos_token_id".to_string(),
+            GgufValue::U32(idx as u32),
+        ));
+    }
+    Ok(metadata)
+}
+
+const GGUF_MAGIC: &[u8; 4] = b"GGUF";
+const GGUF_VERSION: u32 = 3;
+const GGUF_ALIGNMENT: usize = 32;
+
+// value types of the GGUF format
+const GGUF_TYPE_UINT8: u32 = 0;
+const GGUF_TYPE_INT8: u32 = 1;
+const GGUF_TYPE_UINT16: u32 = 2;
+const GGUF_TYPE_INT16: u32 = 3;
+const GGUF_TYPE_UINT32: u32 = 4;
+const GGUF_TYPE_INT32: u32 = 5;
+const GGUF_TYPE_FLOAT32: u32 = 6;
+const GGUF_TYPE_BOOL: u32 = 7;
+const GGUF_TYPE_STRING: u32 = 8;
+const GGUF_TYPE_ARRAY: u32 = 9;
+const GGUF_TYPE_UINT64: u32 = 10;
+const GGUF_TYPE_INT64: u32 = 11;
+const GGUF_TYPE_FLOAT64: u32 = 12;
+
+fn push_string(out: &mut Vec<u8>, s: &str) {
+    out.extend((s.len() as u64).to_le_bytes());
+    out.extend(s.as_bytes());
+}
+
+/// Save `metadata` as a GGUF file without tensors.
+pub(crate) fn write_gguf(
+    mut writer: impl Write,
+    metadata: &[(String, GgufValue)],
+) -> io::Result<()> {
+    let mut out = GGUF_MAGIC.to_vec();
+    out.extend(GGUF_VERSION.to_le_bytes());
+    // no tensors
+    out.extend(0u64.to_le_bytes());
+    out.extend((metadata.len() as u64).to_le_bytes());
+    for (key, value) in metadata {
+        push_string(&mut out, key);
+        match value {
+            GgufValue::U32(n) => {
+                out.extend(GGUF_TYPE_UINT32.to_le_bytes());
+                out.extend(n.to_le_bytes());
+            }
+            GgufValue::String(s) => {
+                out.extend(GGUF_TYPE_STRING.to_le_bytes());
+                push_string(&mut out, s);
+            }
+            GgufValue::I32Array(values) => {
+                out.extend(GGUF_TYPE_ARRAY.to_le_bytes());
+                out.extend(GGUF_TYPE_INT32.to_le_bytes());
+                out.extend((values.len() as u64).to_le_bytes());
+                for n in values {
+                    out.extend(n.to_le_bytes());
+                }
+            }
+            GgufValue::StringArray(values) => {
+                out.extend(GGUF_TYPE_ARRAY.to_le_bytes());
+                out.extend(GGUF_TYPE_STRING.to_le_bytes());
+                out.extend((values.len() as u64).to_le_bytes());
+                for s in values {
+                    push_string(&mut out, s);
+                }
+            }
+        }
+    }
+    // the (empty) tensor data starts aligned
+    out.resize(out.len().next_multiple_of(GGUF_ALIGNMENT), 0);
+    writer.write_all(&out)
+}
+
+fn invalid_data(msg: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+fn read_u32(reader: &mut impl Read) -> io::Result<u32> {
+    let mut buf = [0; 4];
+    reader.read_exact(&mut buf)?;
+    Ok(u32::from_le_bytes(buf))
+}
+
+fn read_u64(reader: &mut impl Read) -> io::Result<u64> {
+    let mut buf = [0; 8];
+    reader.read_exact(&mut buf)?;
+    Ok(u64::from_le_bytes(buf))
+}
+
+fn read_string(reader: &mut impl Read) -> io::Result<String> {
+    let len = read_u64(reader)?;
+    // read through `take` so a corrupt length can't allocate it all upfront
+    let mut bytes = Vec::new();
+    reader.take(len).read_to_end(&mut bytes)?;
+    if bytes.len() as u64 != len {
+        return Err(io::ErrorKind::UnexpectedEof.into());
+    }
+    String::from_utf8(bytes).map_err(|_| invalid_data("GGUF string isn't UTF-8".to_string()))
+}
+
+/// The size of a value of the fixed-size type `value_type`.
+fn scalar_size(value_type: u32) -> io::Result<u64> {
+    match value_type {
+        GGUF_TYPE_UINT8 | GGUF_TYPE_INT8 | GGUF_TYPE_BOOL => Ok(1),
+        GGUF_TYPE_UINT16 | GGUF_TYPE_INT16 => Ok(2),
+        GGUF_TYPE_UINT32 | GGUF_TYPE_INT32 | GGUF_TYPE_FLOAT32 => Ok(4),
+        GGUF_TYPE_UINT64 | GGUF_TYPE_INT64 | GGUF_TYPE_FLOAT64 => Ok(8),
+        _ => Err(invalid_data(format!(
+            "unknown GGUF value type {}",
+            value_type
+        ))),
+    }
+}
+
+fn skip(reader: &mut impl Read, len: u64) -> io::Result<()> {
+    if io::copy(&mut reader.take(len), &mut io::sink())? != len {
+        return Err(io::ErrorKind::UnexpectedEof.into());
+    }
+    Ok(())
+}
+
+/// Read a value of `value_type`, or skip it if it's of a type the tokenizer
+/// keys don't use.
+fn read_value(reader: &mut impl Read, value_type: u32) -> io::Result<Option<GgufValue>> {
+    match value_type {
+        GGUF_TYPE_UINT32 => Ok(Some(GgufValue::U32(read_u32(reader)?))),
+        GGUF_TYPE_STRING => Ok(Some(GgufValue::String(read_string(reader)?))),
+        GGUF_TYPE_ARRAY => {
+            let item_type = read_u32(reader)?;
+            let len = read_u64(reader)?;
+            match item_type {
+                GGUF_TYPE_INT32 => {
+                    let values = (0..len)
+                        .map(|_| Ok(read_u32(reader)? as i32))
+                        .collect::<io::Result<_>>()?;
+                    Ok(Some(GgufValue::I32Array(values)))
+                }
+                GGUF_TYPE_STRING => {
+                    let values = (0..len)
+                        .map(|_| read_string(reader))
+                        .collect::<io::Result<_>>()?;
+                    Ok(Some(GgufValue::StringArray(values)))
+                }
+                _ => {
+                    for _ in 0..len {
+                        read_value(reader, item_type)?;
+                    }
+                    Ok(None)
+                }
+            }
+        }
+        _ => {
+            skip(reader, scalar_size(value_type)?)?;
+            Ok(None)
+        }
+    }
+}
+
+/// The metadata of the GGUF file in `reader`, of the types in `GgufValue`.
+/// Reading stops before the tensors.
+pub(crate) fn read_gguf(reader: impl Read) -> io::Result<HashMap<String, GgufValue>> {
+    let mut reader = io::BufReader::new(reader);
+    let mut magic = [0; 4];
+    reader.read_exact(&mut magic)?;
+    if &magic != GGUF_MAGIC {
+        return Err(invalid_data("not a GGUF file".to_string()));
+    }
+    // version 1 had 32-bit counts and lengths, and is long obsolete
+    let version = read_u32(&mut reader)?;
+    if !(2..=GGUF_VERSION).contains(&version) {
+        return Err(invalid_data(format!(
+            "unsupported GGUF version {}",
+            version
+        )));
+    }
+    let _tensors = read_u64(&mut reader)?;
+    let pairs = read_u64(&mut reader)?;
+    let mut metadata = HashMap::new();
+    for _ in 0..pairs {
+        let key = read_string(&mut reader)?;
+        let value_type = read_u32(&mut reader)?;
+        if let Some(value) = read_value(&mut reader, value_type)? {
+            metadata.insert(key, value);
+        }
+    }
+    Ok(metadata)
+}
+
+/// A byte-level BPE read from GGUF metadata.
+pub(crate) struct GgufVocab {
+    /// The id of every single byte.
+    pub(crate) byte_ids: [Token; 256],
+    /// The merges, in order.
+    pub(crate) merges: Vec<Merge>,
+    pub(crate) special_tokens: HashMap<String, Token>,
+    /// `tokenizer.ggml.pre`, see `pre_tokenizer_name`.
+    pub(crate) pre: String,
+}
+
+impl GgufVocab {
+    /// Check that every byte is its own id, as `BasicTokenizer` and
+    /// `RegexTokenizer` require.
+    pub(crate) fn check_unshuffled(&self) -> io::Result<()> {
+        if (0..256).eq(self.byte_ids) {
+            Ok(())
+        } else {
+            Err(invalid_data(
+                "GGUF vocab doesn't give bytes their own ids, load it with GPT4Tokenizer"
+                    .to_string(),
+            ))
+        }
+    }
+}
+
+/// The vocabulary in the tokenizer metadata of a GGUF file.
+pub(crate) fn read_gguf_vocab(reader: impl Read) -> io::Result<GgufVocab> {
+    let mut metadata = read_gguf(reader)?;
+    let mut take = |key: &str| {
+        metadata
+            .remove(key)
+            .ok_or_else(|| invalid_data(format!("GGUF file has no valid {}", key)))
+    };
+    match take("tokenizer.ggml.model")? {
+        GgufValue::String(model) if model == "gpt2" => {}
+        GgufValue::String(model) => {
+            return Err(invalid_data(format!(
+                "GGUF tokenizer {:?} isn't a byte-level BPE",
+                model
+            )));
+        }
+        _ => return Err(invalid_data("invalid tokenizer.ggml.model".to_string())),
+    }
+    let GgufValue::StringArray(tokens) = take("tokenizer.ggml.tokens")? else {
+        return Err(invalid_data("invalid tokenizer.ggml.tokens".to_string()));
+    };
+    let GgufValue::StringArray(merge_strings) = take("tokenizer.ggml.merges")? else {
+        return Err(invalid_data("invalid tokenizer.ggml.merges".to_string()));
+    };
+    let token_types = match take("tokenizer.ggml.token_type") {
+        Ok(GgufValue::I32Array(types)) if types.len() == tokens.len() => types,
+        Ok(_) => {
+            return Err(invalid_data(
+                "invalid tokenizer.ggml.token_type".to_string(),
+            ));
+        }
+        // without types every token is a normal one
+        Err(_) => vec![TOKEN_TYPE_NORMAL; tokens.len()],
+    };
+    let pre = match take("tokenizer.ggml.pre") {
+        Ok(GgufValue::String(pre)) => pre,
+        _ => "default".to_string(),
+    };
+
+    let char_bytes = char_bytes();
+    let mut ids: HashMap<&str, Token> = HashMap::new();
+    let mut bytes: FastHashMap<Token, Vec<u8>> = FastHashMap::default();
+    let mut special_tokens = HashMap::new();
+    for ((idx, token), &token_type) in (0..).zip(&tokens).zip(&token_types) {
+        match token_type {
+            TOKEN_TYPE_NORMAL => {
+                let token_bytes = token
+                    .chars()
+                    .map(|c| char_bytes.get(&c).copied())
+                    .collect::<Option<Vec<u8>>>()
+                    .ok_or_else(|| {
+                        invalid_data(format!("token {} {:?} isn't byte-level", idx, token))
+                    })?;
+                ids.insert(token, idx);
+                bytes.insert(idx, token_bytes);
+            }
+            TOKEN_TYPE_CONTROL | TOKEN_TYPE_USER_DEFINED => {
+                special_tokens.insert(token.clone(), idx);
+            }
+            // unused, unknown and byte (SentencePiece) tokens
+            _ => {}
+        }
+    }
+
+    let mut byte_ids = [None; 256];
+    for (&idx, token) in &bytes {
+        if let &[b] = &token[..] {
+            byte_ids[b as usize] = Some(idx);
+        }
+    }
+    let mut missing = (0..256).filter(|&b| byte_ids[b].is_none());
+    if let Some(b) = missing.next() {
+        return Err(invalid_data(format!(
+            "GGUF vocab has no token for byte {}",
+            b
+        )));
+    }
+    let byte_ids = byte_ids.map(Option::unwrap);
+
+    let mut merges = Vec::with_capacity(merge_strings.len());
+    let mut last = None;
+    for merge in &merge_strings {
+        let id = |token: &str| {
+            ids.get(token)
+                .copied()
+                .ok_or_else(|| invalid_data(format!("merge {:?} has an unknown token", merge)))
+        };
+        let (left, right) = merge
+            .split_once(' ')
+            .ok_or_else(|| invalid_data(format!("invalid merge {:?}", merge)))?;
+        let (p0, p1) = (id(left)?, id(right)?);
+        let idx = id(&[left, right].concat())?;
+        if [&bytes[&p0][..], &bytes[&p1][..]].concat() != bytes[&idx] {
+            return Err(invalid_data(format!("invalid merge {:?}", merge)));
+        }
+        if last.is_some_and(|last| idx <= last) {
+            return Err(invalid_data(format!(
+                "merge {:?} comes after one producing a higher id, which minbpe can't apply in order",
+                merge
+            )));
+        }
+        last = Some(idx);
+        merges.push(((p0, p1), idx));
+    }
+    let merged = merges.len() + 256;
+    if merged < bytes.len() {
+        return Err(invalid_data(format!(
+            "{} tokens of the GGUF vocab are neither bytes nor merged",
+            bytes.len() - merged
+        )));
+    }
+
+    Ok(GgufVocab {
+        byte_ids,
+        merges,
+        special_tokens,
+        pre,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::base::build_vocab;
+    use crate::basic::BasicTokenizer;
+    use crate::regex::RegexTokenizer;
+    use crate::train::{TrainConfig, Trainable};
+
+    fn merges() -> FastHashMap<(Token, Token), Token> {
+        [((104, 101), 256), ((32, 256), 257)].into_iter().collect()
+    }
+
+    fn metadata(special_tokens: &HashMap<String, Token>) -> io::Result<HashMap<String, GgufValue>> {
+        let merges = merges();
+        let metadata = tokenizer_metadata(&build_vocab(&merges), &merges, special_tokens, "gpt-2")?;
+        Ok(metadata.into_iter().collect())
+    }
+
+    #[test]
+    fn tokens_are_indexed_by_id() {
+        let specials = HashMap::from([("<|endoftext|>".to_string(), 259)]);
+        let metadata = metadata(&specials).unwrap();
+        let GgufValue::StringArray(tokens) = &metadata["tokenizer.ggml.tokens"] else {
+            panic!("tokens aren't strings");
+        };
+        assert_eq!(tokens.len(), 260);
+        assert_eq!(tokens[b' ' as usize], "Ġ");
+        assert_eq!(tokens[257..], ["Ġhe", "[PAD258]", "<|endoftext|>"]);
+        let GgufValue::I32Array(types) = &metadata["tokenizer.ggml.token_type"] else {
+            panic!("token types aren't integers");
+        };
+        assert_eq!(
+            types[257..],
+            [TOKEN_TYPE_NORMAL, TOKEN_TYPE_UNUSED, TOKEN_TYPE_CONTROL]
+        );
+        assert_eq!(
+            metadata["tokenizer.ggml.merges"],
+            GgufValue::StringArray(vec!["h e".to_string(), "Ġ he".to_string()])
+        );
+        assert_eq!(metadata["tokenizer.ggml.eos_token_id"], GgufValue::U32(259));
+    }
+
+    #[test]
+    fn negative_special_ids_are_an_error() {
+        let specials = HashMap::from([("<|endoftext|>".to_string(), -1)]);
+        let error = metadata(&specials).unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::InvalidInput);
+    }
+
+    #[test]
+    fn vocab_round_trip() {
+        let mut tokenizer = RegexTokenizer::new();
+        tokenizer.train(include_str!("../taylorswift.txt"), TrainConfig::new(300));
+        tokenizer
+            .register_special_tokens(HashMap::from([("<|endoftext|>".to_string(), 300)]))
+            .unwrap();
+        let mut file = Vec::new();
+        tokenizer.save_gguf_vocab_to_writer(&mut file).unwrap();
+        let loaded = RegexTokenizer::from_gguf_reader(&file[..]).unwrap();
+        assert_eq!(loaded.merges(), tokenizer.merges());
+        assert_eq!(loaded.special_tokens(), tokenizer.special_tokens());
+        // without the split pattern, only BasicTokenizer can load it
+        let error = BasicTokenizer::from_gguf_reader(&file[..]).err().unwrap();
+        assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn other_files_are_an_error() {
+        let error = read_gguf(&b"GGML\x03\0\0\0"[..]).err().unwrap();
+        assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+    }
+}
@@ -1,166 +1,631 @@
 /*
 Implements the GPT-4 Tokenizer as a wrapper around the RegexTokenizer.
 Note that this is a pretrained tokenizer, which loads the pretrained tokenizer
-from the `cl100k_base` of tiktoken.
+from the `cl100k_base` of tiktoken, along with its special tokens.
+`GPT4Tokenizer::untrained` keeps the split
+pattern and byte shuffle of cl100k but no merges, so a custom vocabulary can
+be trained in the same scheme and exported with `save_tiktoken`.
 */
 
-use base64::{engine::general_purpose, Engine as _};
+use base64::{Engine as _, engine::general_purpose};
 use fancy_regex::Regex;
-use indexmap::IndexMap;
 use lazy_static::lazy_static;
+#[cfg(all(feature = "embed", not(feature = "codegen")))]
+use miniz_oxide::inflate::decompress_to_vec_zlib;
+use std::borrow::Borrow;
+#[cfg(all(feature = "embed", not(feature = "codegen")))]
+use std::borrow::Cow;
+use std::collections::HashMap;
+use std::fs;
+use std::io::{self, Read, Write};
+use std::ops::Range;
+use std::path::Path;
 
-use crate::base::{Token, Tokenizer};
+use crate::atomic::{AtomicFile, save_atomically};
+use crate::base::{
+    DecodeError, EncodeError, Merge, Piece, SpecialSet, Token, Tokenizer, build_vocab,
+    check_special_ids, get_stats, merge, render_token, special_pieces, token_offsets,
+};
+#[cfg(feature = "parallel")]
+use crate::batch::Padding;
+use crate::decode::{
+    LossyPolicy, SpecialRendering, UnknownIdPolicy, decode_delta, decode_into, decode_lossy,
+    decode_to_writer, decode_with_spans, decode_with_specials,
+};
+use crate::gguf::{
+    GgufValue, pre_tokenizer_name, pre_tokenizer_pattern, read_gguf_vocab, tokenizer_metadata,
+    write_gguf,
+};
+use crate::hash::{FastHashMap, FastIndexMap};
+use crate::heal::{TokenHealing, heal_tokens};
+use crate::hf::{write_gpt2_files, write_hf_tokenizer};
+use crate::merges_cache::{self, RecoveredMerges};
+use crate::model::{write_merge_graph, write_tiktoken, write_vocab};
+use crate::normalize::Normalizer;
+use crate::prefix::PrefixIndex;
+use crate::pretokenize::{compile, split_chunks};
+use crate::progress::Progress;
+use crate::recover;
+use crate::regex::GPT4_SPLIT_PATTERN;
+use crate::split::{Boundary, TextChunk, split_by_tokens};
+#[cfg(feature = "mmap")]
+use crate::stream::encode_mmap;
+#[cfg(feature = "parallel")]
+use crate::stream::{PARALLEL_PIECE_SIZE, split_safe};
+use crate::stream::{StreamDecoder, StreamEncoder, count_blocks, encode_blocks};
+use crate::table::{TableFormat, write_vocab_table};
+use crate::trace::{ChunkTrace, trace_chunk, trace_special};
+use crate::train::{TrainConfig, Trainable};
+use crate::truncate::{Truncation, encode_limited, truncate};
+use crate::validate::{ValidationReport, validate};
+use crate::visualize::visualize;
 
-const GPT4_SPLIT_PATTERN: &str = r"'(?i:[sdmt]|ll|ve|re)|[^\r\n\p{L}\p{N}]?+\p{L}+|\p{N}{1,3}| ?[^\s\p{L}\p{N}]++[\r\n]*|\s*[\r\n]|\s+(?!\S)|\s+";
+/// The special tokens of `cl100k_base` and their ids.
+pub const GPT4_SPECIAL_TOKENS: [(&str, Token); 5] = [
+    ("<|endoftext|>", 100257),
+    ("<|fim_prefix|>", 100258),
+    ("<|fim_middle|>", 100259),
+    ("<|fim_suffix|>", 100260),
+    ("<|endofprompt|>", 100276),
+];
 
 lazy_static! {
     static ref GPT4_SPLIT_COMPILED_PATTERN: Regex = Regex::new(GPT4_SPLIT_PATTERN).unwrap();
 }
 
+/// The environment variable naming the cl100k ranks file when they aren't
+/// embedded (without the `embed` feature).
+pub const RANKS_PATH_VAR: &str = "MINBPE_CL100K_PATH";
+
+#[cfg(all(feature = "embed", not(feature = "codegen")))]
 lazy_static! {
-    static ref GPT4_MERGEABLE_RANKS: IndexMap<Vec<u8>, Token> = {
-        // https://github.com/zurawiki/tiktoken-rs/blob/main/tiktoken-rs/assets/cl100k_base.tiktoken
-        let cl100k_base: &str = include_str!("../assets/cl100k_base.tiktoken");
-
-        let mut encoder = IndexMap::default();
-        for line in cl100k_base.lines() {
-            let mut parts = line.split(' ');
-            let raw = parts.next().unwrap();
-            let token = &general_purpose::STANDARD.decode(raw).unwrap();
-            let rank: Token = parts.next().unwrap().parse().unwrap();
-            if rank < 0 {
-                panic!("Rank {} for token {:?} is negative", rank, token);
-            }
-            encoder.insert(token.clone(), rank);
-        }
-        encoder
-    };
+    static ref GPT4_RANKS_SOURCE: Cow<'static, [u8]> = ranks_source();
+    static ref GPT4_MERGEABLE_RANKS: FastIndexMap<Vec<u8>, Token> = load_ranks(&GPT4_RANKS_SOURCE);
 }
 
-fn bpe(
-    mergeable_ranks: &IndexMap<Vec<u8>, Token>,
-    token: &[u8],
-    max_rank: Option<Token>,
-) -> Vec<Vec<u8>> {
-    // helper function used to reconstruct the merge forest
-    let mut parts: Vec<Vec<u8>> = Vec::with_capacity(token.len());
-    for &b in token {
-        parts.push(vec![b]);
-    }
-
-    loop {
-        let mut min_idx = None;
-        let mut min_rank = None;
-        for (i, pair) in parts.windows(2).enumerate() {
-            let rank = mergeable_ranks.get(&[pair[0].clone(), pair[1].clone()].concat());
-            if let Some(rank) = rank {
-                if min_rank.is_none() || rank < min_rank.unwrap() {
-                    min_idx = Some(i);
-                    min_rank = Some(rank);
-                }
-            }
+/// The cl100k byte shuffle, merges and ranks generated by the build script.
+#[cfg(feature = "codegen")]
+mod tables {
+    use crate::base::Token;
+
+    include!(concat!(env!("OUT_DIR"), "/cl100k_tables.rs"));
+}
+
+// https://github.com/zurawiki/tiktoken-rs/blob/main/tiktoken-rs/assets/cl100k_base.tiktoken,
+// embedded zlib-compressed (Python's `zlib.compress(data, 9)`) as that
+// halves what it adds to the binary
+#[cfg(all(feature = "embed", not(feature = "codegen")))]
+fn ranks_source() -> Cow<'static, [u8]> {
+    Cow::Borrowed(include_bytes!("../assets/cl100k_base.tiktoken.zlib"))
+}
+
+#[cfg(all(feature = "embed", not(feature = "codegen")))]
+fn load_ranks(compressed: &[u8]) -> FastIndexMap<Vec<u8>, Token> {
+    let cl100k_base = decompress_to_vec_zlib(compressed).unwrap();
+    parse_ranks(&cl100k_base).unwrap()
+}
+
+/// The byte shuffle of `cl100k_base`, whose single bytes are ranked in the
+/// order of GPT-2's `bytes_to_unicode`: printable bytes first.
+fn cl100k_byte_shuffle() -> [u8; 256] {
+    let printable = |b: &u8| matches!(b, b'!'..=b'~' | 0xA1..=0xAC | 0xAE..=0xFF);
+    let order = (0..=255u8)
+        .filter(printable)
+        .chain((0..=255u8).filter(|b| !printable(b)));
+    let mut byte_shuffle = [0; 256];
+    for (rank, b) in (0..=255u8).zip(order) {
+        byte_shuffle[b as usize] = rank;
+    }
+    byte_shuffle
+}
+
+/// Parse a tiktoken ranks file: one line per token with the base64 of its
+/// bytes and its rank. The lines are parsed in place, so `data` can be a
+/// memory map of the file.
+fn parse_ranks(data: &[u8]) -> io::Result<FastIndexMap<Vec<u8>, Token>> {
+    let invalid = |msg: String| io::Error::new(io::ErrorKind::InvalidData, msg);
+    let lines = data
+        .split(|&b| b == b'\n')
+        .map(|line| line.strip_suffix(b"\r").unwrap_or(line))
+        .filter(|line| !line.is_empty());
+    let capacity = data.iter().filter(|&&b| b == b'\n').count();
+    let mut encoder = FastIndexMap::with_capacity_and_hasher(capacity, Default::default());
+    for line in lines {
+        let mut parts = line.split(|&b| b == b' ');
+        let raw = parts.next().unwrap();
+        let token = general_purpose::STANDARD.decode(raw).map_err(|_| {
+            invalid(format!(
+                "Malformed token {:?}",
+                String::from_utf8_lossy(raw)
+            ))
+        })?;
+        let rank: Token = parts
+            .next()
+            .and_then(|rank| std::str::from_utf8(rank).ok()?.parse().ok())
+            .ok_or_else(|| invalid(format!("Missing rank for token {:?}", token)))?;
+        if rank < 0 {
+            return Err(invalid(format!(
+                "Rank {} for token {:?} is negative",
+                rank, token
+            )));
         }
-        if min_rank.is_none() || (max_rank.is_some() && *min_rank.unwrap() >= max_rank.unwrap()) {
-            break;
+        encoder.insert(token, rank);
+    }
+    Ok(encoder)
+}
+
+/// The rank of each single byte, which GPT-4 permutes bytes by before
+/// merging them. Every byte must have its own rank of at most 255.
+fn byte_shuffle(mergeable_ranks: &FastIndexMap<Vec<u8>, Token>) -> io::Result<[u8; 256]> {
+    let mut byte_shuffle = [0; 256];
+    for (i, slot) in (0..=255u8).zip(byte_shuffle.iter_mut()) {
+        let value = *mergeable_ranks.get(&vec![i]).ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("Missing rank for byte {}", i),
+            )
+        })?;
+        *slot = u8::try_from(value).map_err(|_| {
+            io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!(
+                    "Value {} for key {} in mergeable_ranks does not fit in u8",
+                    value, i
+                ),
+            )
+        })?;
+    }
+    let mut seen = [false; 256];
+    for &value in &byte_shuffle {
+        if std::mem::replace(&mut seen[value as usize], true) {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("Several bytes have rank {}", value),
+            ));
         }
-        let min_idx = min_idx.unwrap();
-        parts[min_idx] = [parts[min_idx].clone(), parts[min_idx + 1].clone()].concat();
-        parts.remove(min_idx + 1);
     }
-    parts
+    Ok(byte_shuffle)
 }
 
-fn recover_merges(mergeable_ranks: &IndexMap<Vec<u8>, Token>) -> IndexMap<(Token, Token), Token> {
+fn recover_merges(
+    mergeable_ranks: &FastIndexMap<Vec<u8>, Token>,
+) -> io::Result<FastHashMap<(Token, Token), Token>> {
     // the `merges` are already the byte sequences in their merged state
-    // so we have to recover the original pairings. We can do this by doing
-    // a small BPE training run on all the tokens, in their order
-    let mut merges = IndexMap::new();
-    for (token, &rank) in mergeable_ranks {
-        if token.len() == 1 {
-            continue;
-        }
-        let pair = bpe(mergeable_ranks, token, Some(rank));
-        assert_eq!(pair.len(), 2);
-        // recover the integer ranks of the pair
-        let ix0 = mergeable_ranks[&pair[0]];
-        let ix1 = mergeable_ranks[&pair[1]];
-        merges.insert((ix0, ix1), rank);
-    }
-    merges
+    // so we have to recover the original pairings
+    let progress = Progress::new(mergeable_ranks.len(), "recovering merges");
+    let merges = recover::recover_merges(
+        mergeable_ranks
+            .iter()
+            .map(|(token, &rank)| (&token[..], rank)),
+        |bytes| mergeable_ranks.get(bytes).copied(),
+        || progress.inc(1),
+    )
+    .map_err(|msg| io::Error::new(io::ErrorKind::InvalidData, msg))?;
+    progress.finish();
+    Ok(merges.into_iter().collect())
 }
 
 pub struct GPT4Tokenizer {
     // Lightweight wrapper on RegexTokenizer that matches GPT-4's tokenizer
-    merges: IndexMap<(Token, Token), Token>,
-    vocab: IndexMap<Token, Vec<u8>>,
+    merges: FastHashMap<(Token, Token), Token>,
+    vocab: FastHashMap<Token, Vec<u8>>,
+    /// The vocab sorted by (shuffled) bytes, for prefix queries.
+    prefix_index: PrefixIndex,
+    special_tokens: HashMap<String, Token>,
+    inverse_special_tokens: HashMap<Token, String>,
+    /// Special tokens that `encode` turns into their ids.
+    allowed_special: SpecialSet,
+    /// Special tokens whose literals `try_encode` rejects.
+    disallowed_special: SpecialSet,
+
+    /// Applied to text before splitting, see `set_normalizer`.
+    normalizer: Normalizer,
+    /// GPT-4's split pattern, see `set_backtrack_limit`.
+    split_regex: Regex,
 
-    byte_shuffle: IndexMap<u8, u8>,
-    inverse_byte_shuffle: IndexMap<u8, u8>,
+    /// The byte each byte is permuted to, indexed by byte.
+    byte_shuffle: [u8; 256],
+    /// The inverse of `byte_shuffle`, for decoding.
+    inverse_byte_shuffle: [u8; 256],
+    /// What decoding does with unknown ids, see `set_unknown_id_policy`.
+    unknown_id_policy: UnknownIdPolicy,
 }
 
 impl GPT4Tokenizer {
+    /// GPT-4's tokenizer with the `cl100k_base` ranks, see `try_new`. Only
+    /// available when they are built in, which makes loading them infallible.
+    #[cfg(any(feature = "embed", feature = "codegen"))]
     pub fn new() -> Self {
-        let mergeable_ranks = &GPT4_MERGEABLE_RANKS;
-        // the merges are those of gpt4, but we have to recover them
-        let merges = recover_merges(mergeable_ranks);
-        // reconstruct the vocab from the merges
-        let mut vocab: IndexMap<Token, Vec<u8>> =
-            (0..=255).map(|i| (i as Token, vec![i])).collect();
-        for (&(p0, p1), &idx) in &merges {
-            let mut token = vocab[&p0].clone();
-            token.extend_from_slice(&vocab[&p1]);
-            vocab.insert(idx, token);
-        }
-
-        let byte_shuffle: IndexMap<u8, u8> = (0..=255)
-            .map(|i| {
-                let value = mergeable_ranks[&vec![i]];
-                if value < 0 || value > u8::MAX as Token {
-                    panic!(
-                        "Value {} for key {} in mergeable_ranks does not fit in u8",
-                        value, i
-                    );
-                }
-                (i, value as u8)
+        Self::try_new().unwrap()
+    }
+
+    /// GPT-4's tokenizer with the `cl100k_base` ranks, which are embedded
+    /// with the `embed` feature. The merges recovered from the ranks are
+    /// cached on disk (see `cache_dir`), so only the first run pays for
+    /// recovering them.
+    #[cfg(all(feature = "embed", not(feature = "codegen")))]
+    pub fn try_new() -> io::Result<Self> {
+        Self::from_ranks(&GPT4_RANKS_SOURCE, || Ok(&*GPT4_MERGEABLE_RANKS))
+    }
+
+    /// GPT-4's tokenizer with the `cl100k_base` ranks, which without the
+    /// `embed` feature are read from the file named by `RANKS_PATH_VAR`
+    /// (with `from_ranks_file`).
+    #[cfg(not(any(feature = "embed", feature = "codegen")))]
+    pub fn try_new() -> io::Result<Self> {
+        let path = std::env::var_os(RANKS_PATH_VAR).ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::NotFound,
+                format!(
+                    "{} must name the cl100k_base.tiktoken file when it isn't embedded",
+                    RANKS_PATH_VAR
+                ),
+            )
+        })?;
+        Self::from_ranks_file(path)
+    }
+
+    /// GPT-4's tokenizer with the `cl100k_base` merges and ranks the build
+    /// script generated, so nothing is parsed or recovered at runtime.
+    #[cfg(feature = "codegen")]
+    pub fn try_new() -> io::Result<Self> {
+        let merges = tables::CL100K_MERGES.iter().copied().collect();
+        // the tokens in the shuffled bytes `build_vocab` would produce
+        let vocab = (0..)
+            .zip(tables::CL100K_RANKS)
+            .map(|(idx, bytes)| {
+                let shuffled = bytes
+                    .iter()
+                    .map(|&b| tables::CL100K_BYTE_SHUFFLE[b as usize]);
+                (idx, shuffled.collect())
             })
             .collect();
+        Ok(Self::pretrained(
+            &tables::CL100K_BYTE_SHUFFLE,
+            merges,
+            vocab,
+        ))
+    }
 
-        let inverse_byte_shuffle: IndexMap<u8, u8> =
-            byte_shuffle.iter().map(|(&k, &v)| (v, k)).collect();
+    /// GPT-4's tokenizer with the `cl100k_base` ranks read from `path`, e.g.
+    /// a copy of `cl100k_base.tiktoken` from your own artifact store. Every
+    /// single byte must have a rank, as in any tiktoken encoding.
+    pub fn from_ranks_file(path: impl AsRef<Path>) -> io::Result<Self> {
+        Self::from_ranks_reader(fs::File::open(path)?)
+    }
+
+    /// Like `from_ranks_file`, reading the ranks from `reader`, e.g. an
+    /// object store download.
+    pub fn from_ranks_reader(mut reader: impl Read) -> io::Result<Self> {
+        let mut source = Vec::new();
+        reader.read_to_end(&mut source)?;
+        Self::from_ranks(&source, || parse_ranks(&source))
+    }
+
+    /// Like `from_ranks_file`, but reading the ranks through a memory map:
+    /// they are hashed and parsed in place, without first copying the whole
+    /// file into memory, which keeps startup allocations down for large
+    /// encodings. The file must not be modified while it is being loaded.
+    #[cfg(feature = "mmap")]
+    pub fn from_ranks_file_mmap(path: impl AsRef<Path>) -> io::Result<Self> {
+        let file = fs::File::open(path)?;
+        // SAFETY: the map is only read, and the caller is warned that the file
+        // must not be modified while it is being loaded
+        let map = unsafe { memmap2::Mmap::map(&file)? };
+        Self::from_ranks(&map, || parse_ranks(&map))
+    }
+
+    /// GPT-4's tokenizer with the ranks in `source`, parsed by `parse_ranks`
+    /// unless the merges recovered from them are in the disk cache.
+    fn from_ranks<R: Borrow<FastIndexMap<Vec<u8>, Token>>>(
+        source: &[u8],
+        parse_ranks: impl FnOnce() -> io::Result<R>,
+    ) -> io::Result<Self> {
+        let key = merges_cache::content_key(source);
+        let RecoveredMerges {
+            byte_shuffle,
+            merges,
+        } = match merges_cache::load_merges(key) {
+            Some(cached) => cached,
+            None => {
+                let ranks = parse_ranks()?;
+                let recovered = RecoveredMerges {
+                    byte_shuffle: byte_shuffle(ranks.borrow())?,
+                    // the merges are those of gpt4, but we have to recover them
+                    merges: recover_merges(ranks.borrow())?,
+                };
+                merges_cache::store_merges(key, &recovered);
+                recovered
+            }
+        };
+        // reconstruct the vocab from the merges
+        let vocab = build_vocab(&merges);
+        Ok(Self::pretrained(&byte_shuffle, merges, vocab))
+    }
+
+    /// A tokenizer with the byte shuffle, merges and vocab of a pretrained
+    /// encoding, and GPT-4's special tokens. They are registered but not
+    /// allowed, so `encode` treats their literals as plain text, as it did
+    /// before they were registered.
+    fn pretrained(
+        byte_shuffle: &[u8; 256],
+        merges: FastHashMap<(Token, Token), Token>,
+        vocab: FastHashMap<Token, Vec<u8>>,
+    ) -> Self {
+        let mut tokenizer = Self::with_merges(byte_shuffle, merges, vocab);
+        // custom ranks may be as many as GPT-4's, so this can't check that
+        // the ids are free; decoding prefers the special tokens
+        tokenizer.set_special_tokens(
+            GPT4_SPECIAL_TOKENS
+                .iter()
+                .map(|&(special, idx)| (special.to_string(), idx))
+                .collect(),
+        );
+        tokenizer
+    }
+
+    /// A tokenizer with GPT-4's split pattern and byte shuffle but no merges
+    /// or special tokens, ready to be trained into a cl100k-compatible custom
+    /// vocabulary.
+    pub fn untrained() -> Self {
+        let merges = FastHashMap::default();
+        let vocab = build_vocab(&merges);
+        Self::with_merges(&cl100k_byte_shuffle(), merges, vocab)
+    }
+
+    /// A tokenizer permuting single bytes by `byte_shuffle`.
+    fn with_merges(
+        byte_shuffle: &[u8; 256],
+        merges: FastHashMap<(Token, Token), Token>,
+        vocab: FastHashMap<Token, Vec<u8>>,
+    ) -> Self {
+        let mut inverse_byte_shuffle = [0; 256];
+        for (b, &shuffled) in (0..=255).zip(byte_shuffle) {
+            inverse_byte_shuffle[shuffled as usize] = b;
+        }
 
         GPT4Tokenizer {
             merges,
+            prefix_index: PrefixIndex::new(&vocab),
             vocab,
-            byte_shuffle,
+            special_tokens: HashMap::new(),
+            inverse_special_tokens: HashMap::new(),
+            allowed_special: SpecialSet::None,
+            disallowed_special: SpecialSet::All,
+            normalizer: Normalizer::default(),
+            split_regex: GPT4_SPLIT_COMPILED_PATTERN.clone(),
+            byte_shuffle: *byte_shuffle,
             inverse_byte_shuffle,
+            unknown_id_policy: UnknownIdPolicy::default(),
         }
     }
 
-    fn get_stats(&self, ids: &[Token]) -> IndexMap<(Token, Token), Token> {
-        let mut counts = IndexMap::new();
-        for pair in ids.windows(2) {
-            // `windows(2)` creates pairs efficiently
-            let pair = (pair[0], pair[1]);
-            *counts.entry(pair).or_insert(0) += 1;
+    /// Normalize text before splitting it into chunks, when encoding and
+    /// training. Methods reporting byte offsets (`encode_with_offsets`,
+    /// `encode_with_limit`, `split_by_tokens`) still refer to the text as
+    /// given, and `truncate_to_tokens` keeps part of it.
+    pub fn set_normalizer(&mut self, normalizer: Normalizer) {
+        self.normalizer = normalizer;
+    }
+
+    /// Set what decoding does with ids that are neither in the vocab nor a
+    /// special token, by default `UnknownIdPolicy::Error`.
+    pub fn set_unknown_id_policy(&mut self, policy: UnknownIdPolicy) {
+        self.unknown_id_policy = policy;
+    }
+
+    /// Check that the merges, vocab and special tokens agree with each
+    /// other, e.g. after loading a model from an untrusted file, and report
+    /// every problem found.
+    pub fn validate(&self) -> ValidationReport {
+        validate(&self.merges, &self.vocab, &self.special_tokens)
+    }
+
+    /// The tokenizer if `validate` finds no problems, so a model can be
+    /// checked as it's loaded, e.g. `Self::load(path)?.validated()?`.
+    pub fn validated(self) -> Result<Self, ValidationReport> {
+        let report = self.validate();
+        if report.is_valid() {
+            Ok(self)
+        } else {
+            Err(report)
         }
-        counts
     }
 
-    fn merge(&self, ids: &[Token], pair: (Token, Token), new_token: Token) -> Vec<Token> {
-        // in the slice of ints (ids), replace all consecutive occurences of pair with the new token
-        let mut new_ids = Vec::with_capacity(ids.len());
-        let mut i = 0;
-        while i < ids.len() {
-            // if we are not at the very last position and the pair matches, replace it
-            if i < ids.len() - 1 && ids[i] == pair.0 && ids[i + 1] == pair.1 {
-                new_ids.push(new_token);
-                i += 2;
-            } else {
-                new_ids.push(ids[i]);
-                i += 1;
-            }
+    /// Bound the backtracking steps the split pattern may take to find a
+    /// chunk (1,000,000 by default), so adversarial input (e.g. very long
+    /// runs of unusual punctuation) can't hang encoding. Past the limit, the
+    /// rest of the line is split into runs of letters, digits, whitespace and
+    /// other characters instead.
+    pub fn set_backtrack_limit(&mut self, limit: usize) {
+        // GPT-4's pattern is known to compile
+        self.split_regex = compile(GPT4_SPLIT_PATTERN, limit).unwrap();
+    }
+
+    /// Save the vocabulary in tiktoken's format: one line per token with the
+    /// base64 of its (unshuffled) bytes and its rank, in rank order. The
+    /// result loads anywhere `cl100k_base.tiktoken` does. Special tokens are
+    /// not part of the format and are left out.
+    pub fn save_tiktoken(&self, path: impl AsRef<Path>) -> io::Result<()> {
+        save_atomically(path.as_ref(), |file| self.save_tiktoken_to_writer(file))
+    }
+
+    /// Like `save_tiktoken`, writing the ranks to `writer` instead.
+    pub fn save_tiktoken_to_writer(&self, writer: impl Write) -> io::Result<()> {
+        write_tiktoken(writer, &self.unshuffled_vocab())
+    }
+
+    /// Save the tokenizer as a HuggingFace `tokenizer.json`: a byte-level
+    /// BPE with the same vocabulary and merges (unshuffled), the tokenizer's
+    /// normalization, GPT-4's split pattern, and the special tokens as added
+    /// tokens, which `transformers` loads with
+    /// `PreTrainedTokenizerFast(tokenizer_file=...)`.
+    pub fn save_hf_tokenizer(&self, path: impl AsRef<Path>) -> io::Result<()> {
+        save_atomically(path.as_ref(), |file| self.save_hf_tokenizer_to_writer(file))
+    }
+
+    /// Like `save_hf_tokenizer`, writing the `tokenizer.json` to `writer` instead.
+    pub fn save_hf_tokenizer_to_writer(&self, writer: impl Write) -> io::Result<()> {
+        write_hf_tokenizer(
+            writer,
+            &self.normalizer,
+            Some(GPT4_SPLIT_PATTERN),
+            &self.unshuffled_vocab(),
+            &self.merges,
+            &self.special_tokens,
+        )
+    }
+
+    /// Save the tokenizer in GPT-2's format, as `vocab.json` and `merges.txt`
+    /// in the existing directory `dir`, for toolchains that predate
+    /// `tokenizer.json`.
+    pub fn save_gpt2(&self, dir: impl AsRef<Path>) -> io::Result<()> {
+        let dir = dir.as_ref();
+        let mut vocab = AtomicFile::create(dir.join("vocab.json"))?;
+        let mut merges = AtomicFile::create(dir.join("merges.txt"))?;
+        self.save_gpt2_to_writers(&mut vocab, &mut merges)?;
+        vocab.commit()?;
+        merges.commit()
+    }
+
+    /// Like `save_gpt2`, writing `vocab.json` to `vocab_writer` and
+    /// `merges.txt` to `merges_writer` instead.
+    pub fn save_gpt2_to_writers(
+        &self,
+        vocab_writer: impl Write,
+        merges_writer: impl Write,
+    ) -> io::Result<()> {
+        write_gpt2_files(
+            vocab_writer,
+            merges_writer,
+            &self.unshuffled_vocab(),
+            &self.merges,
+            &self.special_tokens,
+        )
+    }
+
+    /// The `tokenizer.ggml.*` metadata llama.cpp reads from a GGUF file, to
+    /// embed the tokenizer in a model with its GGUF writer.
+    pub fn gguf_metadata(&self) -> io::Result<Vec<(String, GgufValue)>> {
+        tokenizer_metadata(
+            &self.unshuffled_vocab(),
+            &self.merges,
+            &self.special_tokens,
+            pre_tokenizer_name(Some(GPT4_SPLIT_PATTERN)),
+        )
+    }
+
+    /// Save `gguf_metadata` as a vocab-only GGUF file.
+    pub fn save_gguf_vocab(&self, path: impl AsRef<Path>) -> io::Result<()> {
+        save_atomically(path.as_ref(), |file| self.save_gguf_vocab_to_writer(file))
+    }
+
+    /// Like `save_gguf_vocab`, writing the GGUF file to `writer` instead.
+    pub fn save_gguf_vocab_to_writer(&self, writer: impl Write) -> io::Result<()> {
+        write_gguf(writer, &self.gguf_metadata()?)
+    }
+
+    /// The tokenizer in the metadata of the GGUF file at `path`, e.g. a
+    /// LLaMA 3 model: a vocab split with GPT-4's pattern
+    /// (`tokenizer.ggml.pre` "llama-bpe") whose bytes have ids below 256, in
+    /// any order. The special tokens are the GGUF file's control and
+    /// user-defined tokens rather than GPT-4's.
+    pub fn from_gguf(path: impl AsRef<Path>) -> io::Result<Self> {
+        Self::from_gguf_reader(fs::File::open(path)?)
+    }
+
+    /// Like `from_gguf`, reading the GGUF file from `reader` instead.
+    pub fn from_gguf_reader(reader: impl Read) -> io::Result<Self> {
+        let invalid = |msg: String| io::Error::new(io::ErrorKind::InvalidData, msg);
+        let vocab = read_gguf_vocab(reader)?;
+        if pre_tokenizer_pattern(&vocab.pre) != Some(GPT4_SPLIT_PATTERN) {
+            return Err(invalid(format!(
+                "GGUF vocab splits text with {:?} rather than GPT-4's pattern",
+                vocab.pre
+            )));
         }
-        new_ids
+        let mut byte_shuffle = [0; 256];
+        for (slot, &idx) in byte_shuffle.iter_mut().zip(&vocab.byte_ids) {
+            *slot = u8::try_from(idx)
+                .map_err(|_| invalid(format!("GGUF vocab has byte id {} above 255", idx)))?;
+        }
+        let merges = vocab.merges.into_iter().collect();
+        // the tokens in the shuffled bytes, as in `from_ranks`
+        let vocab_bytes = build_vocab(&merges);
+        let mut tokenizer = Self::with_merges(&byte_shuffle, merges, vocab_bytes);
+        check_special_ids(&tokenizer.vocab, &vocab.special_tokens).map_err(invalid)?;
+        tokenizer.set_special_tokens(vocab.special_tokens);
+        Ok(tokenizer)
+    }
+
+    /// Save the minbpe `.vocab` listing of the tokenizer, for eyeballing the
+    /// vocabulary: every token's rendered (unshuffled) bytes, see
+    /// `render_token`, and the merge that produced it.
+    pub fn save_vocab(&self, path: impl AsRef<Path>) -> io::Result<()> {
+        save_atomically(path.as_ref(), |file| self.save_vocab_to_writer(file))
+    }
+
+    /// Like `save_vocab`, writing the listing to `writer` instead.
+    pub fn save_vocab_to_writer(&self, writer: impl Write) -> io::Result<()> {
+        write_vocab(
+            writer,
+            &self.unshuffled_vocab(),
+            &self.merges,
+            &self.inverse_special_tokens,
+        )
+    }
+
+    /// Export the merges as a Graphviz DOT graph, in which every merged
+    /// token points at the two tokens it was merged from, to visualize how
+    /// long tokens were built up (e.g. `dot -Tsvg merges.dot`).
+    pub fn export_merge_graph(&self, path: impl AsRef<Path>) -> io::Result<()> {
+        save_atomically(path.as_ref(), |file| {
+            self.export_merge_graph_to_writer(file)
+        })
+    }
+
+    /// Like `export_merge_graph`, writing the graph to `writer` instead.
+    pub fn export_merge_graph_to_writer(&self, writer: impl Write) -> io::Result<()> {
+        write_merge_graph(writer, &self.unshuffled_vocab(), &self.merges)
+    }
+
+    /// Export the vocabulary as a CSV or TSV table with a row per token: its
+    /// id, merge rank, length in bytes, escaped text and the ids it was
+    /// merged from, to analyze it with pandas, duckdb or a spreadsheet.
+    pub fn export_vocab_table(
+        &self,
+        path: impl AsRef<Path>,
+        format: TableFormat,
+    ) -> io::Result<()> {
+        save_atomically(path.as_ref(), |file| {
+            self.export_vocab_table_to_writer(file, format)
+        })
+    }
+
+    /// Like `export_vocab_table`, writing the table to `writer` instead.
+    pub fn export_vocab_table_to_writer(
+        &self,
+        writer: impl Write,
+        format: TableFormat,
+    ) -> io::Result<()> {
+        write_vocab_table(
+            writer,
+            format,
+            &self.unshuffled_vocab(),
+            &self.merges,
+            &self.special_tokens,
+        )
+    }
+
+    /// The vocab with the byte shuffle undone, as the tokens' actual bytes.
+    fn unshuffled_vocab(&self) -> FastHashMap<Token, Vec<u8>> {
+        self.vocab
+            .iter()
+            .map(|(&idx, bytes)| {
+                let bytes = bytes.iter().map(|&b| self.inverse_byte_shuffle[b as usize]);
+                (idx, bytes.collect())
+            })
+            .collect()
     }
 
     fn encode_chunk_inner(&self, text_bytes: &[u8]) -> Vec<Token> {
@@ -168,7 +633,7 @@ impl GPT4Tokenizer {
         let mut ids: Vec<Token> = text_bytes.iter().map(|&b| b as Token).collect();
         while ids.len() >= 2 {
             // Find the pair with the lowest merge index
-            let stats = self.get_stats(&ids);
+            let stats = get_stats(&ids);
 
             let pair_opt = stats
                 .keys()
@@ -180,7 +645,7 @@ impl GPT4Tokenizer {
                 Some(pair) => {
                     // Otherwise, merge the best pair (lowest merge index)
                     let idx = merges[&pair];
-                    ids = self.merge(&ids, pair, idx);
+                    ids = merge(&ids, pair, idx);
                 }
             };
         }
@@ -189,39 +654,680 @@ impl GPT4Tokenizer {
 
     fn encode_chunk(&self, text_bytes: &[u8]) -> Vec<Token> {
         // before we start processing bytes, we have to permute them
-        let text_bytes: Vec<u8> = text_bytes.iter().map(|&b| self.byte_shuffle[&b]).collect();
+        let text_bytes: Vec<u8> = text_bytes
+            .iter()
+            .map(|&b| self.byte_shuffle[b as usize])
+            .collect();
         self.encode_chunk_inner(&text_bytes)
     }
-}
 
-impl Tokenizer for GPT4Tokenizer {
-    fn encode(&mut self, text: &str) -> Vec<Token> {
-        let re = &GPT4_SPLIT_COMPILED_PATTERN;
-        let text_chunks: Vec<_> = re
-            .find_iter(text)
-            .map(|m| {
-                let matched = m.unwrap();
-                &text[matched.start()..matched.end()]
-            })
-            .collect();
+    /// Encode `text` with GPT-4's split pattern and merges only, treating any
+    /// special token literals as plain text.
+    pub fn encode_ordinary(&self, text: &str) -> Vec<Token> {
         let mut ids = Vec::new();
-        for chunk in text_chunks {
+        self.encode_ordinary_into(text, &mut ids);
+        ids
+    }
+
+    fn encode_ordinary_into(&self, text: &str, out: &mut Vec<Token>) {
+        let text = self.normalizer.normalize(text);
+        for chunk in split_chunks(&self.split_regex, &text) {
             let chunk_bytes = chunk.as_bytes();
             let chunk_ids = self.encode_chunk(chunk_bytes);
-            ids.extend(chunk_ids);
+            out.extend(chunk_ids);
+        }
+    }
+
+    /// Register special tokens, e.g. `{"<|endoftext|>": 100257}`, replacing
+    /// the current ones. They are decoded to their literal text and can be
+    /// produced by `encode_with_special`. A special token with the id of a
+    /// byte or merged token is an `InvalidInput` error.
+    pub fn register_special_tokens(
+        &mut self,
+        special_tokens: HashMap<String, Token>,
+    ) -> io::Result<()> {
+        check_special_ids(&self.vocab, &special_tokens)
+            .map_err(|msg| io::Error::new(io::ErrorKind::InvalidInput, msg))?;
+        self.set_special_tokens(special_tokens);
+        Ok(())
+    }
+
+    /// Choose the special tokens that `encode` and `try_encode` recognize,
+    /// none by default, so text from users can't inject control tokens. The
+    /// literals of the others are encoded as plain text by `encode`.
+    pub fn set_allowed_special(&mut self, allowed: SpecialSet) {
+        self.allowed_special = allowed;
+    }
+
+    /// Choose the special tokens whose literals `try_encode` rejects unless
+    /// they are also allowed, all registered ones by default.
+    pub fn set_disallowed_special(&mut self, disallowed: SpecialSet) {
+        self.disallowed_special = disallowed;
+    }
+
+    /// Encode `text` like `encode`, but fail on the literal of a special
+    /// token that is disallowed and not allowed, as tiktoken's `encode` does:
+    /// by default, on any registered special token.
+    pub fn try_encode(&self, text: &str) -> Result<Vec<Token>, EncodeError> {
+        let pieces = special_pieces(
+            text,
+            &self.special_tokens,
+            &self.allowed_special,
+            &self.disallowed_special,
+        )?;
+        let mut ids = Vec::new();
+        self.encode_pieces(pieces, &mut ids);
+        Ok(ids)
+    }
+
+    /// Encode `text`, turning the literals of the `allowed` special tokens into
+    /// their ids. The literals of `disallowed` special tokens are an error
+    /// (pass `SpecialSet::None` to encode them as plain text instead), as in
+    /// tiktoken, where `SpecialSet::All` is the default for `disallowed`.
+    pub fn encode_with_special(
+        &self,
+        text: &str,
+        allowed: &SpecialSet,
+        disallowed: &SpecialSet,
+    ) -> Result<Vec<Token>, EncodeError> {
+        let pieces = special_pieces(text, &self.special_tokens, allowed, disallowed)?;
+        let mut ids = Vec::new();
+        self.encode_pieces(pieces, &mut ids);
+        Ok(ids)
+    }
+
+    /// Encode `texts` like `encode`, spread over rayon's thread pool, with
+    /// the results in the order of `texts` and, with `padding`, all of the
+    /// same length.
+    #[cfg(feature = "parallel")]
+    pub fn encode_batch_parallel<S: AsRef<str> + Sync>(
+        &self,
+        texts: &[S],
+        padding: Option<&Padding>,
+    ) -> Vec<Vec<Token>> {
+        use rayon::prelude::*;
+
+        let progress = Progress::new(texts.len(), "encoding");
+        let mut batch: Vec<Vec<Token>> = texts
+            .par_iter()
+            .map(|text| {
+                let ids = self.encode_allowed(text.as_ref());
+                progress.inc(1);
+                ids
+            })
+            .collect();
+        progress.finish();
+        if let Some(padding) = padding {
+            padding.apply(&mut batch);
         }
+        batch
+    }
+
+    /// Decode `batch` like `decode`, spread over rayon's thread pool, with
+    /// the texts in the order of `batch`.
+    #[cfg(feature = "parallel")]
+    pub fn decode_batch_parallel<S: AsRef<[Token]> + Sync>(&self, batch: &[S]) -> Vec<String> {
+        use rayon::prelude::*;
+
+        let progress = Progress::new(batch.len(), "decoding");
+        let texts = batch
+            .par_iter()
+            .map(|ids| {
+                let text = self.decode(ids.as_ref());
+                progress.inc(1);
+                text
+            })
+            .collect();
+        progress.finish();
+        texts
+    }
+
+    /// Encode one large `text` like `encode`, cut at safe boundaries (after a
+    /// newline followed by non-whitespace, where the split pattern always
+    /// ends a chunk) into pieces encoded on rayon's thread pool. The tokens
+    /// are exactly those of sequential encoding.
+    #[cfg(feature = "parallel")]
+    pub fn encode_parallel(&self, text: &str) -> Vec<Token> {
+        use rayon::prelude::*;
+
+        // nothing is disallowed, so this can't fail
+        let pieces = special_pieces(
+            text,
+            &self.special_tokens,
+            &self.allowed_special,
+            &SpecialSet::None,
+        )
+        .unwrap();
+        let encoded: Vec<Vec<Token>> = pieces
+            .into_iter()
+            .flat_map(|piece| match piece {
+                Piece::Text(text) => split_safe(text, PARALLEL_PIECE_SIZE)
+                    .into_iter()
+                    .map(Piece::Text)
+                    .collect(),
+                special => vec![special],
+            })
+            .collect::<Vec<Piece>>()
+            .into_par_iter()
+            .map(|piece| match piece {
+                // the allowed specials are split off, so a piece has none
+                Piece::Text(text) => self.encode_ordinary(text),
+                Piece::Special(idx) => vec![idx],
+            })
+            .collect();
+        encoded.concat()
+    }
+
+    /// Encode everything `reader` yields like `encode`, streaming the tokens
+    /// out as the input is read, so arbitrarily large files are tokenized in
+    /// constant memory. The input is cut into blocks after a newline that is
+    /// followed by non-whitespace, where the split pattern always ends a
+    /// chunk, so the tokens match encoding the whole text at once (unless a
+    /// special token literal spans such a newline, or a single line exceeds
+    /// 16 MiB). Invalid UTF-8 and read errors are yielded as errors.
+    pub fn encode_reader<R: Read>(&self, reader: R) -> impl Iterator<Item = io::Result<Token>> {
+        encode_blocks(reader, move |text| self.encode_allowed(text))
+    }
+
+    /// Encode the file at `path` like `encode_reader`, through a memory map
+    /// instead of reads into a buffer, which saves copies and syscalls on
+    /// very large files. Windows are cut at safe boundaries, so the tokens
+    /// match encoding the whole text at once. The file must not be modified
+    /// while it is being encoded; invalid UTF-8 is an `InvalidData` error.
+    #[cfg(feature = "mmap")]
+    pub fn encode_file_mmap(&self, path: impl AsRef<Path>) -> io::Result<Vec<Token>> {
+        encode_mmap(path.as_ref(), |text, out| self.encode_into(text, out))
+    }
+
+    /// Count the tokens `encode` would produce for everything `reader` yields,
+    /// in constant memory and without collecting the tokens, e.g. to budget
+    /// a multi-gigabyte dataset. The input is read in the same blocks as
+    /// `encode_reader`.
+    pub fn count_tokens_reader<R: Read>(&self, reader: R) -> io::Result<u64> {
+        count_blocks(reader, |text| {
+            // nothing is disallowed, so this can't fail
+            let pieces = special_pieces(
+                text,
+                &self.special_tokens,
+                &self.allowed_special,
+                &SpecialSet::None,
+            )
+            .unwrap();
+            let mut count = 0;
+            for piece in pieces {
+                match piece {
+                    Piece::Text(text) => {
+                        let text = self.normalizer.normalize(text);
+                        for chunk in split_chunks(&self.split_regex, &text) {
+                            count += self.encode_chunk(chunk.as_bytes()).len();
+                        }
+                    }
+                    Piece::Special(_) => count += 1,
+                }
+            }
+            count
+        })
+    }
+
+    /// Decode `ids` to the raw bytes of their tokens, without the UTF-8
+    /// conversion of `decode`, so binary data round-tripped through tokens
+    /// comes back intact. Unknown ids are handled by the `UnknownIdPolicy`,
+    /// like in `decode`.
+    pub fn decode_to_bytes(&self, ids: &[Token]) -> Vec<u8> {
+        let mut text_bytes = Vec::new();
+        for &idx in ids {
+            self.decode_id(idx, &mut text_bytes);
+        }
+        text_bytes
+    }
+
+    /// Decode ids as an iterator yields them, e.g. from a channel or a
+    /// generation loop, like `decode` but without collecting them first.
+    pub fn decode_iter<I: IntoIterator<Item = Token>>(&self, ids: I) -> String {
+        let mut text_bytes = Vec::new();
+        for id in ids {
+            self.decode_id(id, &mut text_bytes);
+        }
+        // only copy the bytes if some need replacing
+        String::from_utf8(text_bytes)
+            .unwrap_or_else(|e| String::from_utf8_lossy(e.as_bytes()).into_owned())
+    }
+
+    /// Decode `ids` like `decode`, but fail on the first id that is neither
+    /// in the vocab nor a special token instead of replacing it, if the
+    /// `UnknownIdPolicy` is `Error`.
+    pub fn try_decode(&self, ids: &[Token]) -> Result<String, DecodeError> {
+        let mut text_bytes = Vec::new();
+        for (position, &id) in ids.iter().enumerate() {
+            if !self.try_decode_id(id, &mut text_bytes) {
+                return Err(DecodeError::UnknownId { position, id });
+            }
+        }
+        Ok(String::from_utf8_lossy(&text_bytes).into_owned())
+    }
+
+    /// Decode `ids` like `decode`, handling ids that are neither in the vocab
+    /// nor a special token by `policy`.
+    pub fn decode_lossy(&self, ids: &[Token], policy: &LossyPolicy) -> String {
+        decode_lossy(ids, policy, |id, out| self.decode_token(id, out))
+    }
+
+    /// Decode `ids` like `decode`, rendering special tokens by `rendering`,
+    /// e.g. to drop `<|endoftext|>` from model output.
+    pub fn decode_with_specials(&self, ids: &[Token], rendering: &SpecialRendering) -> String {
+        decode_with_specials(
+            ids,
+            rendering,
+            |id| self.inverse_special_tokens.contains_key(&id),
+            |id, out| {
+                self.decode_id(id, out);
+                true
+            },
+        )
+    }
+
+    /// Render token `id` for display with `render_token`, e.g. for vocab dumps.
+    /// Returns `None` for an unknown id.
+    pub fn render_token(&self, id: Token) -> Option<String> {
+        let mut bytes = Vec::new();
+        self.decode_token(id, &mut bytes)
+            .then(|| render_token(&bytes))
+    }
+
+    /// Decode `ids` like `decode`, also returning the byte range of the text
+    /// every id produced, e.g. to map attention or logprobs back onto the
+    /// generated text. Invalid UTF-8 is replaced like `String::from_utf8_lossy`
+    /// does; a range may start or end inside a character split over tokens.
+    pub fn decode_with_spans(&self, ids: &[Token]) -> (String, Vec<Range<usize>>) {
+        decode_with_spans(ids, |id, out| self.decode_id(id, out))
+    }
+
+    /// Render the tokens of `text` for a terminal, each on its own ANSI
+    /// background color, and with `show_ids` their ids below, e.g. to see
+    /// at a glance how a string is split.
+    pub fn visualize(&self, text: &str, show_ids: bool) -> String {
+        let ids = self.encode_allowed(text);
+        let (decoded, spans) = self.decode_with_spans(&ids);
+        visualize(&decoded, &ids, &spans, show_ids)
+    }
+
+    /// Decode only the text `ids` adds to the previously decoded `prev`, for
+    /// streaming output that is appended as tokens arrive. A character split
+    /// over tokens is held back until it is complete, so nothing returned
+    /// earlier ever changes. Panics if `ids` doesn't start with `prev`.
+    pub fn decode_delta(&self, prev: &[Token], ids: &[Token]) -> String {
+        decode_delta(prev, ids, |id, out| self.decode_id(id, out))
+    }
+
+    /// Decode `ids` like `decode`, appending the text to `out` instead of
+    /// building a new `String`.
+    pub fn decode_into(&self, ids: &[Token], out: &mut String) {
+        decode_into(ids, out, |id, out| self.decode_id(id, out))
+    }
+
+    /// Decode `ids` like `decode`, writing the text to `writer` as it is
+    /// decoded instead of building a `String`.
+    pub fn decode_to_writer<W: Write>(&self, ids: &[Token], writer: W) -> io::Result<()> {
+        decode_to_writer(ids, writer, |id, out| self.decode_id(id, out))
+    }
+
+    /// Decode tokens arriving one at a time, see `StreamDecoder`.
+    pub fn stream_decoder(&self) -> StreamDecoder<'_> {
+        StreamDecoder::new(|idx, out| self.decode_id(idx, out))
+    }
+
+    /// Append the bytes of token `idx` to `out`, returning whether it is known.
+    fn decode_token(&self, idx: Token, out: &mut Vec<u8>) -> bool {
+        if let Some(special) = self.inverse_special_tokens.get(&idx) {
+            // special tokens are stored as text, not as shuffled bytes
+            out.extend(special.as_bytes());
+        } else if let Some(bytes) = self.vocab.get(&idx) {
+            out.extend(bytes.iter().map(|&b| self.inverse_byte_shuffle[b as usize]));
+        } else {
+            return false;
+        }
+        true
+    }
+
+    /// Append the bytes of `id` to `out`, handling an unknown id by the
+    /// `UnknownIdPolicy`. Returns false if the policy makes it an error.
+    fn try_decode_id(&self, id: Token, out: &mut Vec<u8>) -> bool {
+        self.decode_token(id, out) || self.unknown_id_policy.apply(out)
+    }
+
+    /// Like `try_decode_id`, but an error becomes U+FFFD.
+    fn decode_id(&self, id: Token, out: &mut Vec<u8>) {
+        if !self.decode_token(id, out) {
+            self.unknown_id_policy.apply_or_replace(out);
+        }
+    }
+
+    /// Encode text that arrives in fragments, see `StreamEncoder`.
+    pub fn stream_encoder(&mut self) -> StreamEncoder<'_, Self> {
+        StreamEncoder::new(self)
+    }
+
+    /// Encode `text` with the special tokens allowed by `set_allowed_special`.
+    fn encode_allowed(&self, text: &str) -> Vec<Token> {
+        let mut ids = Vec::new();
+        self.encode_into(text, &mut ids);
         ids
     }
 
-    fn decode(&self, ids: &[Token]) -> String {
-        let text_bytes: Vec<u8> = ids
+    /// Encode `bytes` that may not be valid UTF-8, e.g. scraped web data.
+    /// Valid runs are encoded like `encode`, and every invalid byte becomes
+    /// its own byte-level token, so the data decodes back unchanged instead
+    /// of being lossy-converted up front.
+    pub fn encode_raw(&self, bytes: &[u8]) -> Vec<Token> {
+        let mut ids = Vec::new();
+        for chunk in bytes.utf8_chunks() {
+            self.encode_into(chunk.valid(), &mut ids);
+            ids.extend(
+                chunk
+                    .invalid()
+                    .iter()
+                    .map(|&b| self.byte_shuffle[b as usize] as Token),
+            );
+        }
+        ids
+    }
+
+    /// Encode `text` like `encode`, appending the tokens to `out`. Reusing
+    /// one buffer saves an allocation per call when encoding many short
+    /// strings.
+    pub fn encode_into(&self, text: &str, out: &mut Vec<Token>) {
+        // nothing is disallowed, so this can't fail
+        let pieces = special_pieces(
+            text,
+            &self.special_tokens,
+            &self.allowed_special,
+            &SpecialSet::None,
+        )
+        .unwrap();
+        self.encode_pieces(pieces, out);
+    }
+
+    fn encode_pieces(&self, pieces: Vec<Piece>, out: &mut Vec<Token>) {
+        for piece in pieces {
+            match piece {
+                Piece::Text(text) => self.encode_ordinary_into(text, out),
+                Piece::Special(idx) => out.push(idx),
+            }
+        }
+    }
+
+    /// The tokens whose bytes start with `prefix`, e.g. to find the tokens
+    /// allowed next during constrained decoding. Special tokens are not
+    /// included.
+    pub fn tokens_with_byte_prefix(&self, prefix: &[u8]) -> impl Iterator<Item = Token> + use<'_> {
+        // the index holds shuffled bytes, so shuffle the prefix to match
+        let prefix: Vec<u8> = prefix
             .iter()
-            .flat_map(|&idx| self.vocab[&idx].clone())
+            .map(|&b| self.byte_shuffle[b as usize])
             .collect();
-        let text_bytes: Vec<u8> = text_bytes
+        self.prefix_index.with_prefix(&prefix)
+    }
+
+    /// Prepare encoded prompt `ids` for token healing: remove up to
+    /// `backtrack` trailing tokens and return their bytes along with every
+    /// token extending them.
+    pub fn heal_tokens(&self, ids: &[Token], backtrack: usize) -> TokenHealing {
+        // the vocab holds shuffled bytes, which keep prefixes intact
+        let mut healing = heal_tokens(ids, backtrack, &self.vocab, &self.prefix_index);
+        for b in healing.prefix.iter_mut() {
+            *b = self.inverse_byte_shuffle[*b as usize];
+        }
+        healing
+    }
+
+    /// Encode `text` like `encode`, returning every chunk the split pattern
+    /// cut it into with its tokens and the merges that produced them, to
+    /// investigate why a string tokenizes unexpectedly.
+    pub fn encode_debug(&self, text: &str) -> Vec<ChunkTrace> {
+        // nothing is disallowed, so this can't fail
+        let pieces = special_pieces(
+            text,
+            &self.special_tokens,
+            &self.allowed_special,
+            &SpecialSet::None,
+        )
+        .unwrap();
+        let mut traces = Vec::new();
+        for piece in pieces {
+            match piece {
+                Piece::Text(text) => {
+                    let text = self.normalizer.normalize(text);
+                    for chunk in split_chunks(&self.split_regex, &text) {
+                        let ids = chunk
+                            .bytes()
+                            .map(|b| self.byte_shuffle[b as usize] as Token);
+                        traces.push(trace_chunk(chunk, ids.collect(), &self.merges));
+                    }
+                }
+                Piece::Special(idx) => {
+                    traces.push(trace_special(&self.inverse_special_tokens[&idx], idx))
+                }
+            }
+        }
+        traces
+    }
+
+    /// Encode `text` like `encode`, pairing every token with the byte range
+    /// of `text` it came from, e.g. to highlight tokens or align spans. A
+    /// range may start or end inside a character that is split over tokens,
+    /// and covers all the characters the normalizer replaced that it holds
+    /// part of (e.g. the `\r\n` of a `\n` token).
+    pub fn encode_with_offsets(&self, text: &str) -> Vec<(Token, Range<usize>)> {
+        let ids = self.encode_allowed(text);
+        // nothing is disallowed, so this can't fail
+        let pieces = special_pieces(
+            text,
+            &self.special_tokens,
+            &self.allowed_special,
+            &SpecialSet::None,
+        )
+        .unwrap();
+        let offsets = self.normalizer.offset_map(text, &pieces);
+        token_offsets(ids, &self.vocab, &self.inverse_special_tokens)
             .into_iter()
-            .map(|b| self.inverse_byte_shuffle[&b])
+            .map(|(id, range)| (id, offsets.start(range.start)..offsets.end(range.end)))
+            .collect()
+    }
+
+    /// Encode `text` like `encode`, but stop before the first chunk that
+    /// would take the count past `max_tokens`, e.g. to fill a context window
+    /// without tokenizing a whole document. Returns the tokens and the number
+    /// of bytes of `text` consumed; the tokens are exactly the encoding of
+    /// that prefix.
+    pub fn encode_with_limit(&self, text: &str, max_tokens: usize) -> (Vec<Token>, usize) {
+        // nothing is disallowed, so this can't fail
+        let pieces = special_pieces(
+            text,
+            &self.special_tokens,
+            &self.allowed_special,
+            &SpecialSet::None,
+        )
+        .unwrap();
+        encode_limited(
+            text,
+            pieces,
+            &self.normalizer,
+            &self.split_regex,
+            max_tokens,
+            |chunk, out| out.extend(self.encode_chunk(chunk.as_bytes())),
+        )
+    }
+
+    /// Cut `text` to at most `max_tokens` tokens at token boundaries, giving
+    /// up the part chosen by `truncation`. Returns the kept text and its exact
+    /// token count. The kept text is part of `text` as given, not normalized.
+    pub fn truncate_to_tokens(
+        &self,
+        text: &str,
+        max_tokens: usize,
+        truncation: &Truncation,
+    ) -> (String, usize) {
+        truncate(text, max_tokens, truncation, |text| {
+            self.encode_with_offsets(text)
+        })
+    }
+
+    /// Split `text` into consecutive pieces of at most `max_tokens` tokens,
+    /// each overlapping the previous one by `overlap_tokens` tokens, e.g. for
+    /// retrieval ingestion. Every piece comes with its span and exact count.
+    pub fn split_by_tokens<'a>(
+        &self,
+        text: &'a str,
+        max_tokens: usize,
+        overlap_tokens: usize,
+    ) -> Vec<TextChunk<'a>> {
+        self.split_by_tokens_at(text, max_tokens, overlap_tokens, Boundary::Token)
+    }
+
+    /// Like `split_by_tokens`, but prefer ending pieces at sentence or
+    /// paragraph boundaries, cutting a sentence only when it doesn't fit on
+    /// its own.
+    pub fn split_by_tokens_at<'a>(
+        &self,
+        text: &'a str,
+        max_tokens: usize,
+        overlap_tokens: usize,
+        boundary: Boundary,
+    ) -> Vec<TextChunk<'a>> {
+        split_by_tokens(text, max_tokens, overlap_tokens, boundary, |text| {
+            self.encode_with_offsets(text)
+        })
+    }
+}
+
+#[cfg(any(feature = "embed", feature = "codegen"))]
+impl Default for GPT4Tokenizer {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Trainable for GPT4Tokenizer {
+    /// Chunks come from GPT-4's split pattern (or the whole text without
+    /// `config.split`), with every byte permuted by the byte shuffle, exactly
+    /// as `encode` sees them.
+    fn training_chunks(&self, text: &str, config: &TrainConfig) -> Vec<Vec<Token>> {
+        let shuffled = |chunk: &str| {
+            chunk
+                .bytes()
+                .map(|b| self.byte_shuffle[b as usize] as Token)
+                .collect()
+        };
+        let text = &self.normalizer.normalize(text);
+        if config.split {
+            split_chunks(&self.split_regex, text)
+                .map(shuffled)
+                .collect()
+        } else {
+            vec![shuffled(text)]
+        }
+    }
+
+    fn merges(&self) -> &FastHashMap<(Token, Token), Token> {
+        &self.merges
+    }
+
+    fn special_tokens(&self) -> &HashMap<String, Token> {
+        &self.special_tokens
+    }
+
+    fn set_special_tokens(&mut self, special_tokens: HashMap<String, Token>) {
+        self.inverse_special_tokens = special_tokens
+            .iter()
+            .map(|(special, &idx)| (idx, special.clone()))
             .collect();
-        String::from_utf8_lossy(&text_bytes).to_string()
+        self.special_tokens = special_tokens;
+    }
+
+    fn set_merges(&mut self, merges: Vec<Merge>) {
+        self.merges = merges.into_iter().collect();
+        self.vocab = build_vocab(&self.merges);
+        self.prefix_index = PrefixIndex::new(&self.vocab);
+    }
+}
+
+impl Tokenizer for GPT4Tokenizer {
+    /// Encodes the special tokens allowed by `set_allowed_special` (none by
+    /// default) as their ids, and everything else as plain text;
+    /// `try_encode` rejects special token literals instead.
+    fn encode(&mut self, text: &str) -> Vec<Token> {
+        self.encode_allowed(text)
+    }
+
+    fn decode(&self, ids: &[Token]) -> String {
+        self.decode_iter(ids.iter().copied())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A ranks file with every byte, ranked in byte order, and then `tokens`.
+    fn ranks_file(tokens: &[&str]) -> String {
+        let mut out = String::new();
+        for (rank, token) in (0..256)
+            .map(|b| vec![b as u8])
+            .chain(tokens.iter().map(|token| token.as_bytes().to_vec()))
+            .enumerate()
+        {
+            out.push_str(&format!(
+                "{} {}\n",
+                general_purpose::STANDARD.encode(token),
+                rank
+            ));
+        }
+        out
+    }
+
+    #[test]
+    fn incoherent_ranks_are_an_error() {
+        // "abc" comes before the "ab" or "bc" it would be merged from
+        let file = ranks_file(&["abc", "ab"]);
+        let error = GPT4Tokenizer::from_ranks_reader(file.as_bytes())
+            .err()
+            .unwrap();
+        assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn truncated_ranks_are_an_error() {
+        let file = ranks_file(&["ab", "abc"]);
+        // cut off in the middle of the bytes
+        let truncated = &file[..file.len() / 3];
+        let error = GPT4Tokenizer::from_ranks_reader(truncated.as_bytes())
+            .err()
+            .unwrap();
+        assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[cfg(feature = "mmap")]
+    #[test]
+    fn incoherent_ranks_file_mmap_is_an_error() {
+        let path = std::env::temp_dir().join(format!("incoherent{}.tiktoken", std::process::id()));
+        fs::write(&path, ranks_file(&["abc", "ab"])).unwrap();
+        let result = GPT4Tokenizer::from_ranks_file_mmap(&path);
+        fs::remove_file(&path).unwrap();
+        assert_eq!(result.err().unwrap().kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[cfg(all(feature = "embed", not(feature = "codegen")))]
+    #[test]
+    fn cl100k_byte_shuffle_matches_ranks() {
+        assert_eq!(
+            cl100k_byte_shuffle(),
+            byte_shuffle(&GPT4_MERGEABLE_RANKS).unwrap()
+        );
+    }
+
+    #[cfg(feature = "codegen")]
+    #[test]
+    fn cl100k_byte_shuffle_matches_tables() {
+        assert_eq!(cl100k_byte_shuffle(), tables::CL100K_BYTE_SHUFFLE);
     }
 }
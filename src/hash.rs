@@ -0,0 +1,87 @@
+/*
+The hasher of the maps looked up in the inner loops of encoding and training:
+merges, vocabs, pair counts and chunk caches. std's SipHash resists HashDoS,
+but its setup dominates hashing keys as small as a pair of ids, so with the
+(default) `fxhash` feature these maps hash with FxHash, the hasher of rustc.
+Without it they use SipHash with random keys like std's maps, e.g. for
+services training on untrusted input.
+
+The map types are the same either way; the feature only changes the hashing.
+*/
+
+use std::collections::HashMap;
+#[cfg(not(feature = "fxhash"))]
+use std::collections::hash_map::{DefaultHasher, RandomState};
+use std::hash::{BuildHasher, Hasher};
+
+use indexmap::IndexMap;
+
+/// A `HashMap` hashing with `FastState`.
+pub type FastHashMap<K, V> = HashMap<K, V, FastState>;
+
+/// An `IndexMap` hashing with `FastState`.
+pub(crate) type FastIndexMap<K, V> = IndexMap<K, V, FastState>;
+
+/// Builds the `FastHasher` of a map.
+#[derive(Clone, Default)]
+pub struct FastState {
+    #[cfg(not(feature = "fxhash"))]
+    keys: RandomState,
+}
+
+impl BuildHasher for FastState {
+    type Hasher = FastHasher;
+
+    #[cfg(feature = "fxhash")]
+    fn build_hasher(&self) -> FastHasher {
+        FastHasher(rustc_hash::FxHasher::default())
+    }
+
+    #[cfg(not(feature = "fxhash"))]
+    fn build_hasher(&self) -> FastHasher {
+        FastHasher(self.keys.build_hasher())
+    }
+}
+
+/// FxHash with the `fxhash` feature, SipHash otherwise.
+pub struct FastHasher(
+    #[cfg(feature = "fxhash")] rustc_hash::FxHasher,
+    #[cfg(not(feature = "fxhash"))] DefaultHasher,
+);
+
+impl Hasher for FastHasher {
+    #[inline]
+    fn write(&mut self, bytes: &[u8]) {
+        self.0.write(bytes);
+    }
+
+    #[inline]
+    fn write_u8(&mut self, n: u8) {
+        self.0.write_u8(n);
+    }
+
+    #[inline]
+    fn write_u32(&mut self, n: u32) {
+        self.0.write_u32(n);
+    }
+
+    #[inline]
+    fn write_u64(&mut self, n: u64) {
+        self.0.write_u64(n);
+    }
+
+    #[inline]
+    fn write_usize(&mut self, n: usize) {
+        self.0.write_usize(n);
+    }
+
+    #[inline]
+    fn write_i32(&mut self, n: i32) {
+        self.0.write_i32(n);
+    }
+
+    #[inline]
+    fn finish(&self) -> u64 {
+        self.0.finish()
+    }
+}
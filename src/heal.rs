@@ -0,0 +1,55 @@
+/*
+Token healing for constrained generation. A prompt ending in the middle of a
+word (e.g. `"https:"`) is encoded with a token boundary the model rarely saw
+there, which skews the next prediction. Healing removes the last token(s) of
+the prompt and lets the model regenerate them, restricted to tokens that
+start with the removed bytes.
+*/
+
+use crate::base::Token;
+use crate::hash::FastHashMap;
+use crate::prefix::PrefixIndex;
+
+/// A prompt with its trailing tokens removed for healing.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TokenHealing {
+    /// The prompt ids that are kept.
+    pub ids: Vec<Token>,
+    /// The bytes of the removed tokens, which generation has to re-produce.
+    pub prefix: Vec<u8>,
+    /// The tokens whose bytes start with `prefix` (including the removed
+    /// token itself), in id order. The first generated token is one of these.
+    pub candidates: Vec<Token>,
+}
+
+/// Remove up to `backtrack` tokens from the end of `ids`, stopping early at a
+/// special token (or an id missing from `vocab`), which is never healed.
+pub(crate) fn heal_tokens(
+    ids: &[Token],
+    backtrack: usize,
+    vocab: &FastHashMap<Token, Vec<u8>>,
+    index: &PrefixIndex,
+) -> TokenHealing {
+    let removed = ids
+        .iter()
+        .rev()
+        .take(backtrack)
+        .take_while(|id| vocab.contains_key(id))
+        .count();
+    let (kept, tail) = ids.split_at(ids.len() - removed);
+    let prefix: Vec<u8> = tail
+        .iter()
+        .flat_map(|id| vocab[id].iter().copied())
+        .collect();
+    let mut candidates: Vec<Token> = if prefix.is_empty() {
+        Vec::new()
+    } else {
+        index.with_prefix(&prefix).collect()
+    };
+    candidates.sort_unstable();
+    TokenHealing {
+        ids: kept.to_vec(),
+        prefix,
+        candidates,
+    }
+}
@@ -0,0 +1,293 @@
+/*
+Exporting vocabularies to the HuggingFace `tokenizers` format, so tokenizers
+trained here can be loaded by `transformers`. The vocabulary is that of a
+byte-level BPE: every byte is a printable character (GPT-2's
+`bytes_to_unicode`), and a token is the string of its bytes' characters.
+The same vocabulary makes up the older two-file format of GPT-2: a
+`vocab.json` mapping token strings to ids, and a `merges.txt` with the pairs
+merged, one per line in order.
+*/
+
+use std::collections::HashMap;
+use std::fmt::Write as _;
+use std::io::{self, Write};
+
+use crate::base::Token;
+use crate::hash::FastHashMap;
+use crate::normalize::{CRLF_TO_LF, LOWERCASE, NFC, Normalizer};
+
+/// GPT-2's `bytes_to_unicode`: printable bytes stand for themselves, the
+/// others are mapped to the characters from U+0100 on, in byte order.
+fn byte_chars() -> [char; 256] {
+    let mut chars = ['\0'; 256];
+    let mut next = 256;
+    for b in 0..=255u8 {
+        let printable = matches!(b, b'!'..=b'~' | 0xA1..=0xAC | 0xAE..=0xFF);
+        chars[b as usize] = if printable {
+            b as char
+        } else {
+            next += 1;
+            char::from_u32(next - 1).unwrap()
+        };
+    }
+    chars
+}
+
+/// The inverse of `byte_chars`, to read byte-level strings.
+pub(crate) fn char_bytes() -> HashMap<char, u8> {
+    (0..=255).zip(byte_chars()).map(|(b, c)| (c, b)).collect()
+}
+
+/// `text` as a JSON string literal.
+pub(crate) fn json_string(text: &str) -> String {
+    let mut out = String::from("\"");
+    for c in text.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\r' => out.push_str("\\r"),
+            '\t' => out.push_str("\\t"),
+            c if c.is_control() => write!(out, "\\u{:04x}", c as u32).unwrap(),
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+    out
+}
+
+/// A vocabulary in byte-level strings.
+pub(crate) struct ByteLevel {
+    /// Every token's string and id, in the order of the ids.
+    pub(crate) tokens: Vec<(String, Token)>,
+    /// The strings of the pairs merged, in the order of the merges' ids.
+    pub(crate) merges: Vec<(String, String)>,
+}
+
+/// The byte-level strings of the tokens in `vocab`, which holds their actual
+/// bytes, and of its merges. Two ids with the same bytes are an error, as the
+/// vocabulary maps strings to ids.
+pub(crate) fn byte_level(
+    vocab: &FastHashMap<Token, Vec<u8>>,
+    merges: &FastHashMap<(Token, Token), Token>,
+) -> io::Result<ByteLevel> {
+    let chars = byte_chars();
+    let strings: HashMap<Token, String> = vocab
+        .iter()
+        .map(|(&idx, bytes)| (idx, bytes.iter().map(|&b| chars[b as usize]).collect()))
+        .collect();
+    let mut tokens: Vec<(String, Token)> =
+        strings.iter().map(|(&idx, s)| (s.clone(), idx)).collect();
+    tokens.sort_by_key(|&(_, idx)| idx);
+    let mut seen = HashMap::new();
+    for (string, idx) in &tokens {
+        if let Some(other) = seen.insert(string, idx) {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("tokens {} and {} have the same bytes", other, idx),
+            ));
+        }
+    }
+    let mut merges: Vec<_> = merges.iter().collect();
+    merges.sort_by_key(|&(_, &idx)| idx);
+    let string = |part: Token, idx: Token| {
+        strings.get(&part).cloned().ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!(
+                    "merge {} is made of token {}, which isn't in the vocab",
+                    idx, part
+                ),
+            )
+        })
+    };
+    let merges = merges
+        .into_iter()
+        .map(|(&(p0, p1), &idx)| Ok((string(p0, idx)?, string(p1, idx)?)))
+        .collect::<io::Result<_>>()?;
+    Ok(ByteLevel { tokens, merges })
+}
+
+/// Write `vocab.json` and `merges.txt` for a byte-level BPE with
+/// `vocab` (the actual bytes of the tokens) and `merges`. The special tokens
+/// are part of `vocab.json`, like `<|endoftext|>` in GPT-2's.
+pub(crate) fn write_gpt2_files(
+    mut vocab_writer: impl Write,
+    mut merges_writer: impl Write,
+    vocab: &FastHashMap<Token, Vec<u8>>,
+    merges: &FastHashMap<(Token, Token), Token>,
+    special_tokens: &HashMap<String, Token>,
+) -> io::Result<()> {
+    let ByteLevel { mut tokens, merges } = byte_level(vocab, merges)?;
+    tokens.extend(
+        special_tokens
+            .iter()
+            .map(|(special, &idx)| (special.clone(), idx)),
+    );
+    tokens.sort_by_key(|&(_, idx)| idx);
+    let entries: Vec<String> = tokens
+        .iter()
+        .map(|(string, idx)| format!("{}: {}", json_string(string), idx))
+        .collect();
+    vocab_writer.write_all(format!("{{{}}}", entries.join(", ")).as_bytes())?;
+    let mut out = String::from("#version: 0.2\n");
+    for (left, right) in merges {
+        writeln!(out, "{} {}", left, right).unwrap();
+    }
+    merges_writer.write_all(out.as_bytes())
+}
+
+/// The `normalizer` of a `tokenizer.json` applying `normalizer`'s steps.
+fn hf_normalizer(normalizer: &Normalizer) -> String {
+    let steps: Vec<&str> = normalizer
+        .steps()
+        .iter()
+        .map(|step| match step.as_str() {
+            CRLF_TO_LF => {
+                "{\"type\": \"Replace\", \"pattern\": {\"String\": \"\\r\\n\"}, \"content\": \"\\n\"}"
+            }
+            LOWERCASE => "{\"type\": \"Lowercase\"}",
+            NFC => "{\"type\": \"NFC\"}",
+            _ => unreachable!("unknown normalization step {:?}", step),
+        })
+        .collect();
+    match steps[..] {
+        [] => "null".to_string(),
+        [step] => step.to_string(),
+        _ => format!(
+            "{{\"type\": \"Sequence\", \"normalizers\": [{}]}}",
+            steps.join(", ")
+        ),
+    }
+}
+
+/// Save a `tokenizer.json` for a byte-level BPE with `vocab` (the actual
+/// bytes of the tokens) and `merges`, normalizing text with `normalizer` and
+/// splitting it with `pattern` first if given, and with `special_tokens` as
+/// added tokens.
+pub(crate) fn write_hf_tokenizer(
+    mut writer: impl Write,
+    normalizer: &Normalizer,
+    pattern: Option<&str>,
+    vocab: &FastHashMap<Token, Vec<u8>>,
+    merges: &FastHashMap<(Token, Token), Token>,
+    special_tokens: &HashMap<String, Token>,
+) -> io::Result<()> {
+    let ByteLevel { tokens, merges } = byte_level(vocab, merges)?;
+    let mut specials: Vec<_> = special_tokens.iter().collect();
+    specials.sort_by_key(|&(_, &idx)| idx);
+
+    let mut out =
+        String::from("{\n  \"version\": \"1.0\",\n  \"truncation\": null,\n  \"padding\": null,\n");
+    out.push_str("  \"added_tokens\": [");
+    for (i, (special, idx)) in specials.into_iter().enumerate() {
+        out.push_str(if i == 0 { "\n" } else { ",\n" });
+        write!(
+            out,
+            "    {{\"id\": {}, \"content\": {}, \"single_word\": false, \"lstrip\": false, \
+             \"rstrip\": false, \"normalized\": false, \"special\": true}}",
+            idx,
+            json_string(special)
+        )
+        .unwrap();
+    }
+    writeln!(
+        out,
+        "\n  ],\n  \"normalizer\": {},",
+        hf_normalizer(normalizer)
+    )
+    .unwrap();
+    // the split pattern cuts the text into chunks, which are then mapped to
+    // byte-level strings without any splitting of its own
+    let byte_level_step = "{\"type\": \"ByteLevel\", \"add_prefix_space\": false, \
+                      \"trim_offsets\": true, \"use_regex\": false}";
+    match pattern {
+        Some(pattern) => writeln!(
+            out,
+            "  \"pre_tokenizer\": {{\"type\": \"Sequence\", \"pretokenizers\": [\
+             {{\"type\": \"Split\", \"pattern\": {{\"Regex\": {}}}, \"behavior\": \"Isolated\", \
+             \"invert\": false}}, {}]}},",
+            json_string(pattern),
+            byte_level_step
+        )
+        .unwrap(),
+        None => writeln!(out, "  \"pre_tokenizer\": {},", byte_level_step).unwrap(),
+    }
+    writeln!(
+        out,
+        "  \"post_processor\": null,\n  \"decoder\": {},",
+        byte_level_step
+    )
+    .unwrap();
+    out.push_str(
+        "  \"model\": {\n    \"type\": \"BPE\",\n    \"dropout\": null,\n    \"unk_token\": null,\n    \
+         \"continuing_subword_prefix\": null,\n    \"end_of_word_suffix\": null,\n    \
+         \"fuse_unk\": false,\n    \"byte_fallback\": false,\n    \"ignore_merges\": false,\n    \
+         \"vocab\": {",
+    );
+    for (i, (string, idx)) in tokens.iter().enumerate() {
+        out.push_str(if i == 0 { "\n" } else { ",\n" });
+        write!(out, "      {}: {}", json_string(string), idx).unwrap();
+    }
+    out.push_str("\n    },\n    \"merges\": [");
+    for (i, (left, right)) in merges.iter().enumerate() {
+        out.push_str(if i == 0 { "\n" } else { ",\n" });
+        write!(out, "      {}", json_string(&format!("{} {}", left, right))).unwrap();
+    }
+    out.push_str("\n    ]\n  }\n}\n");
+    writer.write_all(out.as_bytes())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::base::build_vocab;
+
+    fn merges() -> FastHashMap<(Token, Token), Token> {
+        [((104, 101), 256), ((32, 256), 257)].into_iter().collect()
+    }
+
+    #[test]
+    fn bytes_map_to_gpt2_characters() {
+        let chars = byte_chars();
+        assert_eq!(chars[b'a' as usize], 'a');
+        assert_eq!(chars[b' ' as usize], 'Ġ');
+        assert_eq!(chars[b'\n' as usize], 'Ċ');
+        assert_eq!(char_bytes()[&'Ġ'], b' ');
+    }
+
+    #[test]
+    fn tokenizer_json() {
+        let merges = merges();
+        let specials = HashMap::from([("<|endoftext|>".to_string(), 258)]);
+        let mut out = Vec::new();
+        write_hf_tokenizer(
+            &mut out,
+            &Normalizer::new(),
+            Some(r"\s?\w+"),
+            &build_vocab(&merges),
+            &merges,
+            &specials,
+        )
+        .unwrap();
+        let out = String::from_utf8(out).unwrap();
+        assert!(out.contains("\"content\": \"<|endoftext|>\""));
+        assert!(out.contains("\"Regex\": \"\\\\s?\\\\w+\""));
+        assert!(out.contains("\"he\": 256,\n      \"Ġhe\": 257\n"));
+        assert!(out.contains("\"merges\": [\n      \"h e\",\n      \"Ġ he\"\n    ]"));
+        #[cfg(feature = "json")]
+        {
+            let json: serde_json::Value = serde_json::from_str(&out).unwrap();
+            assert_eq!(json["model"]["vocab"].as_object().unwrap().len(), 258);
+        }
+    }
+
+    #[test]
+    fn merges_of_missing_tokens_are_an_error() {
+        let mut merges = merges();
+        let vocab = build_vocab(&merges);
+        merges.insert((300, 104), 258);
+        let error = byte_level(&vocab, &merges).err().unwrap();
+        assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+    }
+}
@@ -0,0 +1,38 @@
+pub mod align;
+#[cfg(feature = "tokio")]
+pub mod async_io;
+pub mod atomic;
+pub mod base;
+pub mod basic;
+pub mod batch;
+pub mod bundle;
+pub mod cache;
+pub mod cache_dir;
+#[cfg(any(feature = "embed", feature = "codegen"))]
+pub mod chat;
+pub mod corpus;
+pub mod decode;
+pub mod gguf;
+pub mod gpt4;
+pub mod hash;
+pub mod heal;
+mod hf;
+mod merges_cache;
+mod model;
+pub mod normalize;
+mod prefix;
+mod pretokenize;
+mod progress;
+mod recover;
+pub mod regex;
+mod rng;
+mod spill;
+pub mod split;
+pub mod stream;
+pub mod table;
+pub mod trace;
+pub mod train;
+pub mod truncate;
+pub mod validate;
+pub mod verify;
+mod visualize;
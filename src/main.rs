@@ -1,13 +1,11 @@
-mod base;
-mod gpt4;
-
-use base::Tokenizer;
-use gpt4::GPT4Tokenizer;
+use tokenizer::base::Tokenizer;
+use tokenizer::gpt4::GPT4Tokenizer;
 
 fn main() {
     let sample_text = "Hello've world12345 how's are you!!!?";
 
-    let mut tokenizer = GPT4Tokenizer::new();
+    // reads the ranks from MINBPE_CL100K_PATH without the `embed` feature
+    let mut tokenizer = GPT4Tokenizer::try_new().expect("Can't load the cl100k_base ranks");
     let enc = tokenizer.encode(sample_text);
     let dec = tokenizer.decode(&enc);
 
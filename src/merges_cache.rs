@@ -0,0 +1,125 @@
+/*
+A disk cache of the merges GPT4Tokenizer recovers from tiktoken ranks.
+Recovering them replays a BPE training run over all ~100k tokens, which
+dominates `GPT4Tokenizer::new`, so the first run saves them (with the byte
+shuffle) to a file in the `cache_dir`, keyed by a hash of the ranks, and later
+runs load it.
+
+The cache is best-effort: a file that is missing, unreadable, of another
+format version or fails its checks (a checksum of its contents, the number of
+merges, a byte shuffle that is a permutation, merges that build ids 256, 257,
+... from lower ones) is recovered anew, and failing to write one is ignored.
+*/
+
+use std::fs;
+use std::io::{self, Write};
+use std::path::PathBuf;
+
+use crate::atomic::save_atomically;
+use crate::base::Token;
+use crate::cache_dir::cache_dir;
+use crate::hash::FastHashMap;
+use crate::model::content_hash;
+
+/// First line of a cache file, bumped whenever its format changes.
+const MERGES_CACHE_HEADER: &str = "minbpe-rs recovered merges v2";
+
+/// The key of ranks read from `source`, their file as it's stored.
+pub(crate) fn content_key(source: &[u8]) -> u64 {
+    content_hash(source)
+}
+
+fn cache_path(key: u64) -> PathBuf {
+    cache_dir().join(format!("merges-{:016x}.txt", key))
+}
+
+/// What GPT4Tokenizer derives from tiktoken ranks.
+pub(crate) struct RecoveredMerges {
+    pub(crate) byte_shuffle: [u8; 256],
+    pub(crate) merges: FastHashMap<(Token, Token), Token>,
+}
+
+/// The byte shuffle and merges cached for the ranks with `key`, if any.
+pub(crate) fn load_merges(key: u64) -> Option<RecoveredMerges> {
+    let text = fs::read_to_string(cache_path(key)).ok()?;
+    let (header, rest) = text.split_once('\n')?;
+    if header != MERGES_CACHE_HEADER {
+        return None;
+    }
+    let (summary, body) = rest.split_once('\n')?;
+    let (checksum, num_merges) = summary.split_once(' ')?;
+    let checksum = u64::from_str_radix(checksum, 16).ok()?;
+    let num_merges: usize = num_merges.parse().ok()?;
+    // a torn or tampered file recovers the merges anew
+    if content_hash(body.as_bytes()) != checksum {
+        return None;
+    }
+
+    let mut lines = body.lines();
+    let mut byte_shuffle = [0; 256];
+    let mut values = lines.next()?.split(' ');
+    let mut seen = [false; 256];
+    for slot in byte_shuffle.iter_mut() {
+        *slot = values.next()?.parse().ok()?;
+        if std::mem::replace(&mut seen[*slot as usize], true) {
+            return None;
+        }
+    }
+    if values.next().is_some() {
+        return None;
+    }
+    let mut merges = FastHashMap::default();
+    for (line, expected) in lines.zip(256..) {
+        let mut parts = line.split(' ').map(|part| part.parse::<Token>().ok());
+        let (p0, p1, idx) = (parts.next()??, parts.next()??, parts.next()??);
+        let parent = 0..expected;
+        if idx != expected || !parent.contains(&p0) || !parent.contains(&p1) {
+            return None;
+        }
+        if parts.next().is_some() || merges.insert((p0, p1), idx).is_some() {
+            return None;
+        }
+    }
+    if merges.len() != num_merges {
+        return None;
+    }
+    Some(RecoveredMerges {
+        byte_shuffle,
+        merges,
+    })
+}
+
+/// Cache the byte shuffle and merges recovered for the ranks with `key`.
+pub(crate) fn store_merges(key: u64, recovered: &RecoveredMerges) {
+    // a failed write only costs the next run the recovery
+    let _ = try_store_merges(key, recovered);
+}
+
+fn try_store_merges(key: u64, recovered: &RecoveredMerges) -> io::Result<()> {
+    let RecoveredMerges {
+        byte_shuffle,
+        merges,
+    } = recovered;
+    let path = cache_path(key);
+    fs::create_dir_all(path.parent().unwrap())?;
+    let mut sorted: Vec<_> = merges.iter().collect();
+    sorted.sort_by_key(|&(_, &idx)| idx);
+    let mut body = String::with_capacity(16 * sorted.len());
+    let values: Vec<String> = byte_shuffle.iter().map(|b| b.to_string()).collect();
+    body.push_str(&values.join(" "));
+    body.push('\n');
+    for (&(p0, p1), idx) in sorted {
+        body.push_str(&format!("{} {} {}\n", p0, p1, idx));
+    }
+    // concurrent runs never read a partial cache
+    save_atomically(&path, |file| {
+        writeln!(file, "{}", MERGES_CACHE_HEADER)?;
+        writeln!(
+            file,
+            "{:016x} {}",
+            content_hash(body.as_bytes()),
+            merges.len()
+        )?;
+        file.write_all(body.as_bytes())
+    })
+}
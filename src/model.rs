@@ -0,0 +1,903 @@
+/*
+The `.model` files of the Python minbpe, so models trained with the reference
+implementation can be used here and the other way around:
+
+    minbpe v1
+    <split pattern, empty for BasicTokenizer>
+    <number of special tokens>
+    <special> <id>       (one line per special token)
+    normalize <steps>    (only if the text is normalized)
+    <p0> <p1>            (one line per merge)
+
+The ids of the merges aren't stored: they are numbered from 256 in file order,
+so models whose merge ids have gaps (see `Trainable::train_more`) can't be
+saved in this format.
+The `normalize` line is our own, listing the normalization steps of
+RegexTokenizer (see `Normalizer`) separated by spaces. The Python minbpe has no
+normalization, so it refuses such a model rather than load a tokenizer that
+encodes differently.
+
+Alongside, minbpe writes a `.vocab` file for people to read, not to load: one
+line per token with its rendered bytes, and for merged tokens the two tokens
+they were merged from, e.g. `[ t][he] -> [ the] 262`.
+
+The merges can also be exported as a Graphviz DOT graph, with an edge from
+every merged token to each of the two tokens it was merged from (labeled `0`
+and `1` in their order), to visualize how long tokens were built up.
+
+For the tiktoken ecosystem, a vocabulary can be saved as a `.tiktoken` ranks
+file: one line per token with the base64 of its bytes and its rank.
+
+With the `binary` feature, models can also be saved in a compact format of
+our own: the magic bytes `MBPE`, a little-endian u16 version, the checksum of
+the model as a little-endian u64, and the state encoded with postcard.
+
+With the `json` feature, models can be saved as pretty JSON for people and
+diff tools: the pattern, the normalization steps, the special tokens, the
+merges in order as `[p0, p1, id]`, and the bytes of every token by id, with
+backslashes and bytes that aren't printable UTF-8 escaped as `\\` and `\xNN`,
+and the checksum of the model in hex.
+
+Binary and JSON models share a version, `MODEL_VERSION`, bumped whenever what
+they store changes:
+
+1. the pattern, special tokens and merges
+2. adds the checksum of the model
+3. adds the normalization steps
+
+Models of older versions are migrated on load, one version at a time, and
+models of newer versions are refused with an error saying so, rather than
+loaded without what this version of the crate doesn't know about.
+
+The checksum (see `model_checksum`) is verified on load, so a truncated or
+corrupted file is an error instead of a subtly different tokenizer. Models of
+version 1 predate it and load unchecked. The `.model` format has no room for
+one without breaking the Python minbpe.
+*/
+
+use base64::{Engine as _, engine::general_purpose};
+#[cfg(feature = "json")]
+use std::collections::BTreeMap;
+use std::collections::HashMap;
+use std::io::{self, Read, Write};
+
+#[cfg(feature = "json")]
+use crate::base::build_vocab;
+use crate::base::{Merge, Token, render_token};
+use crate::hash::FastHashMap;
+
+const MODEL_HEADER: &str = "minbpe v1";
+const NORMALIZE_PREFIX: &str = "normalize ";
+
+/// A 64-bit FNV-1a hash of `bytes`, stable across builds and platforms
+/// unlike `DefaultHasher`.
+pub(crate) fn content_hash(bytes: &[u8]) -> u64 {
+    bytes.iter().fold(0xcbf29ce484222325, |hash, &b| {
+        (hash ^ b as u64).wrapping_mul(0x100000001b3)
+    })
+}
+
+/// The checksum of a model: the `content_hash` of its pattern, its special
+/// tokens in id order and its merges in order, so it doesn't depend on the
+/// format the model is saved in.
+#[cfg(any(feature = "binary", feature = "json"))]
+fn model_checksum(model: &ModelFile) -> u64 {
+    let mut content = format!("{}\n", model.pattern);
+    let mut specials: Vec<_> = model.special_tokens.iter().collect();
+    specials.sort_by_key(|&(special, &idx)| (idx, special));
+    for (special, idx) in specials {
+        content.push_str(&format!("{} {}\n", special, idx));
+    }
+    // only hashed when there is any, so older checksums still match
+    if !model.normalization.is_empty() {
+        content.push_str(&format!(
+            "{}{}\n",
+            NORMALIZE_PREFIX,
+            model.normalization.join(" ")
+        ));
+    }
+    for ((p0, p1), idx) in &model.merges {
+        content.push_str(&format!("{} {} {}\n", p0, p1, idx));
+    }
+    content_hash(content.as_bytes())
+}
+
+/// Check that `model` was read back with the `checksum` it was saved with.
+#[cfg(any(feature = "binary", feature = "json"))]
+fn verify_checksum(model: &ModelFile, checksum: u64) -> io::Result<()> {
+    let actual = model_checksum(model);
+    if actual != checksum {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!(
+                "model checksum mismatch: the file says {:016x} but its contents hash to {:016x}, it is corrupted",
+                checksum, actual
+            ),
+        ));
+    }
+    Ok(())
+}
+
+/// The contents of a `.model` file, which is also the state (de)serialized
+/// with the `serde` feature. The vocab follows from the merges.
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub(crate) struct ModelFile {
+    /// Empty for `BasicTokenizer`.
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub(crate) pattern: String,
+    pub(crate) special_tokens: HashMap<String, Token>,
+    /// In the order of their ids.
+    pub(crate) merges: Vec<Merge>,
+    /// The steps of the `Normalizer`, empty for `BasicTokenizer`.
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub(crate) normalization: Vec<String>,
+}
+
+/// Check that every merge of a loaded model has an id above those before it,
+/// and is made of bytes or of tokens merged before it, so a corrupted or
+/// hand-edited file can't give a vocab with holes.
+pub(crate) fn check_merges(merges: &[Merge]) -> io::Result<()> {
+    let invalid = |msg: String| io::Error::new(io::ErrorKind::InvalidData, msg);
+    let mut ids = std::collections::HashSet::new();
+    let mut last = 255;
+    for &((p0, p1), idx) in merges {
+        if idx <= last {
+            return Err(invalid(format!(
+                "merge {} doesn't come after merge {}",
+                idx, last
+            )));
+        }
+        for part in [p0, p1] {
+            if part >= 256 && !ids.contains(&part) {
+                return Err(invalid(format!(
+                    "merge {} is made of token {}, which isn't merged before it",
+                    idx, part
+                )));
+            }
+        }
+        ids.insert(idx);
+        last = idx;
+    }
+    Ok(())
+}
+
+/// The version of binary and JSON models written, see the top of this file.
+#[cfg(any(feature = "binary", feature = "json"))]
+const MODEL_VERSION: u32 = 3;
+
+/// Check that a model of `version` can be migrated to `MODEL_VERSION`.
+#[cfg(any(feature = "binary", feature = "json"))]
+fn check_version(version: u32, format: &str) -> io::Result<()> {
+    let invalid = |msg: String| io::Error::new(io::ErrorKind::InvalidData, msg);
+    if version == 0 {
+        return Err(invalid(format!("invalid {} model version 0", format)));
+    }
+    if version > MODEL_VERSION {
+        return Err(invalid(format!(
+            "{} model of version {} is newer than this version of the crate \
+             reads (up to {}), upgrade it to load the model",
+            format, version, MODEL_VERSION
+        )));
+    }
+    Ok(())
+}
+
+/// The state of binary models of versions 1 and 2.
+#[cfg(feature = "binary")]
+#[derive(serde::Deserialize)]
+struct ModelFileV2 {
+    pattern: String,
+    special_tokens: HashMap<String, Token>,
+    merges: Vec<Merge>,
+}
+
+/// Version 3 added the normalization steps, none before.
+#[cfg(feature = "binary")]
+impl From<ModelFileV2> for ModelFile {
+    fn from(model: ModelFileV2) -> Self {
+        ModelFile {
+            pattern: model.pattern,
+            special_tokens: model.special_tokens,
+            merges: model.merges,
+            normalization: Vec::new(),
+        }
+    }
+}
+
+/// Save a model whose merges have the ids 256, 257, ... in order, as after
+/// training, since loading numbers them that way. Other ids are an error.
+pub(crate) fn write_model(mut writer: impl Write, model: &ModelFile) -> io::Result<()> {
+    // the pattern takes exactly one line
+    if model.pattern.contains(['\n', '\r']) {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!(
+                "split pattern {:?} contains a line break, which a .model file can't hold",
+                model.pattern
+            ),
+        ));
+    }
+    let mut out = format!(
+        "{}\n{}\n{}\n",
+        MODEL_HEADER,
+        model.pattern,
+        model.special_tokens.len()
+    );
+    let mut specials: Vec<_> = model.special_tokens.iter().collect();
+    specials.sort_by_key(|&(_, &idx)| idx);
+    for (special, idx) in specials {
+        // every special token takes exactly one line
+        if special.contains(['\n', '\r']) {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!(
+                    "special token {:?} contains a line break, which a .model file can't hold",
+                    special
+                ),
+            ));
+        }
+        out.push_str(&format!("{} {}\n", special, idx));
+    }
+    if !model.normalization.is_empty() {
+        out.push_str(&format!(
+            "{}{}\n",
+            NORMALIZE_PREFIX,
+            model.normalization.join(" ")
+        ));
+    }
+    for (((p0, p1), idx), expected) in model.merges.iter().zip(256..) {
+        // loading numbers the merges from 256, any other ids would change
+        if *idx != expected {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!(
+                    "merge {} should have id {} to be saved as a .model file, which doesn't store the ids; save a binary or JSON model instead",
+                    idx, expected
+                ),
+            ));
+        }
+        out.push_str(&format!("{} {}\n", p0, p1));
+    }
+    writer.write_all(out.as_bytes())
+}
+
+/// Save the minbpe `.vocab` listing of `vocab` (and the special tokens), in
+/// the order of the ids.
+pub(crate) fn write_vocab(
+    mut writer: impl Write,
+    vocab: &FastHashMap<Token, Vec<u8>>,
+    merges: &FastHashMap<(Token, Token), Token>,
+    inverse_special_tokens: &HashMap<Token, String>,
+) -> io::Result<()> {
+    let inverse_merges: HashMap<Token, (Token, Token)> =
+        merges.iter().map(|(&pair, &idx)| (idx, pair)).collect();
+    let mut ids: Vec<Token> = vocab
+        .keys()
+        .chain(inverse_special_tokens.keys())
+        .copied()
+        .collect();
+    ids.sort_unstable();
+    ids.dedup();
+    let render = |idx: Token| match inverse_special_tokens.get(&idx) {
+        Some(special) => special.clone(),
+        None => render_token(&vocab[&idx]),
+    };
+    let mut out = String::new();
+    for idx in ids {
+        match inverse_merges.get(&idx) {
+            Some(&(idx0, idx1)) => out.push_str(&format!(
+                "[{}][{}] -> [{}] {}\n",
+                render(idx0),
+                render(idx1),
+                render(idx),
+                idx
+            )),
+            None => out.push_str(&format!("[{}] {}\n", render(idx), idx)),
+        }
+    }
+    writer.write_all(out.as_bytes())
+}
+
+/// Escape `text` for a DOT string literal.
+fn dot_escape(text: &str) -> String {
+    text.replace('\\', "\\\\").replace('"', "\\\"")
+}
+
+/// Save the DOT graph of `merges`, with the tokens labeled by their rendered
+/// `vocab` bytes and ids. Single bytes are drawn as boxes.
+pub(crate) fn write_merge_graph(
+    mut writer: impl Write,
+    vocab: &FastHashMap<Token, Vec<u8>>,
+    merges: &FastHashMap<(Token, Token), Token>,
+) -> io::Result<()> {
+    let mut sorted: Vec<_> = merges.iter().collect();
+    sorted.sort_by_key(|&(_, &idx)| idx);
+    let mut bytes: Vec<Token> = sorted
+        .iter()
+        .flat_map(|&(&(p0, p1), _)| [p0, p1])
+        .filter(|&idx| idx < 256)
+        .collect();
+    bytes.sort_unstable();
+    bytes.dedup();
+    // the token over its id
+    let label = |idx: Token| format!("\"{}\\n{}\"", dot_escape(&render_token(&vocab[&idx])), idx);
+    let mut out = String::from("digraph merges {\n  node [shape=ellipse];\n");
+    for idx in bytes {
+        out.push_str(&format!("  {} [label={}, shape=box];\n", idx, label(idx)));
+    }
+    for (&(p0, p1), &idx) in sorted {
+        out.push_str(&format!("  {} [label={}];\n", idx, label(idx)));
+        out.push_str(&format!("  {} -> {} [label=\"0\"];\n", idx, p0));
+        out.push_str(&format!("  {} -> {} [label=\"1\"];\n", idx, p1));
+    }
+    out.push_str("}\n");
+    writer.write_all(out.as_bytes())
+}
+
+/// Save `vocab` as a `.tiktoken` ranks file, in rank order. tiktoken looks
+/// tokens up by their bytes, so two ids with the same bytes (which minbpe's
+/// training can produce by merging `a bc` and `ab c`) are an error.
+pub(crate) fn write_tiktoken(
+    mut writer: impl Write,
+    vocab: &FastHashMap<Token, Vec<u8>>,
+) -> io::Result<()> {
+    let mut ranks: Vec<_> = vocab.iter().collect();
+    ranks.sort_by_key(|&(&idx, _)| idx);
+    let mut seen = HashMap::new();
+    let mut out = String::new();
+    for (&idx, bytes) in ranks {
+        if let Some(other) = seen.insert(bytes, idx) {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("tokens {} and {} have the same bytes", other, idx),
+            ));
+        }
+        out.push_str(&general_purpose::STANDARD.encode(bytes));
+        out.push_str(&format!(" {}\n", idx));
+    }
+    writer.write_all(out.as_bytes())
+}
+
+#[cfg(feature = "binary")]
+const BINARY_MAGIC: &[u8; 4] = b"MBPE";
+
+/// Save a model in the binary format.
+#[cfg(feature = "binary")]
+pub(crate) fn write_binary(mut writer: impl Write, model: &ModelFile) -> io::Result<()> {
+    let mut out = BINARY_MAGIC.to_vec();
+    out.extend((MODEL_VERSION as u16).to_le_bytes());
+    out.extend(model_checksum(model).to_le_bytes());
+    let out = postcard::to_extend(model, out).map_err(io::Error::other)?;
+    writer.write_all(&out)
+}
+
+/// Read a model saved by `write_binary`.
+#[cfg(feature = "binary")]
+pub(crate) fn read_binary(mut reader: impl Read) -> io::Result<ModelFile> {
+    let invalid = |msg: String| io::Error::new(io::ErrorKind::InvalidData, msg);
+    let mut bytes = Vec::new();
+    reader.read_to_end(&mut bytes)?;
+    let Some((header, state)) = bytes.split_first_chunk::<6>() else {
+        return Err(invalid("not a binary minbpe-rs model".to_string()));
+    };
+    if &header[..4] != BINARY_MAGIC {
+        return Err(invalid("not a binary minbpe-rs model".to_string()));
+    }
+    let version = u16::from_le_bytes([header[4], header[5]]) as u32;
+    check_version(version, "binary")?;
+    // the checksum came with version 2
+    let (checksum, state) = if version >= 2 {
+        let Some((checksum, state)) = state.split_first_chunk::<8>() else {
+            return Err(invalid("truncated binary model".to_string()));
+        };
+        (Some(u64::from_le_bytes(*checksum)), state)
+    } else {
+        (None, state)
+    };
+    let model = match version {
+        1 | 2 => postcard::from_bytes::<ModelFileV2>(state).map(ModelFile::from),
+        _ => postcard::from_bytes(state),
+    }
+    .map_err(|e| invalid(e.to_string()))?;
+    check_merges(&model.merges)?;
+    if let Some(checksum) = checksum {
+        verify_checksum(&model, checksum)?;
+    }
+    Ok(model)
+}
+
+#[cfg(feature = "json")]
+const JSON_FORMAT: &str = "minbpe-rs json";
+
+/// The layout of a JSON model of `MODEL_VERSION`.
+#[cfg(feature = "json")]
+#[derive(serde::Deserialize)]
+struct JsonModel {
+    /// Missing in version 1.
+    checksum: Option<String>,
+    pattern: String,
+    normalization: Vec<String>,
+    special_tokens: HashMap<String, Token>,
+    merges: Vec<(Token, Token, Token)>,
+    vocab: BTreeMap<Token, String>,
+}
+
+/// Escape `bytes` for a JSON model or vocab table: printable UTF-8 stays as
+/// it is.
+pub(crate) fn escape_bytes(bytes: &[u8]) -> String {
+    let mut out = String::new();
+    for chunk in bytes.utf8_chunks() {
+        for c in chunk.valid().chars() {
+            match c {
+                '\\' => out.push_str("\\\\"),
+                c if c.is_control() => {
+                    let mut buf = [0; 4];
+                    for b in c.encode_utf8(&mut buf).bytes() {
+                        out.push_str(&format!("\\x{:02x}", b));
+                    }
+                }
+                c => out.push(c),
+            }
+        }
+        for b in chunk.invalid() {
+            out.push_str(&format!("\\x{:02x}", b));
+        }
+    }
+    out
+}
+
+/// Undo `escape_bytes`, or `None` for a malformed escape.
+#[cfg(feature = "json")]
+fn unescape_bytes(text: &str) -> Option<Vec<u8>> {
+    let mut out = Vec::new();
+    let mut rest = text;
+    while let Some(i) = rest.find('\\') {
+        out.extend(&rest.as_bytes()[..i]);
+        rest = &rest[i + 1..];
+        if let Some(after) = rest.strip_prefix('\\') {
+            out.push(b'\\');
+            rest = after;
+        } else {
+            let hex = rest.strip_prefix('x')?.get(..2)?;
+            out.push(u8::from_str_radix(hex, 16).ok()?);
+            rest = &rest[3..];
+        }
+    }
+    out.extend(rest.as_bytes());
+    Some(out)
+}
+
+/// Save a model and its `vocab` as pretty JSON.
+#[cfg(feature = "json")]
+pub(crate) fn write_json(
+    mut writer: impl Write,
+    model: &ModelFile,
+    vocab: &FastHashMap<Token, Vec<u8>>,
+) -> io::Result<()> {
+    use crate::hf::json_string;
+    use std::fmt::Write as _;
+
+    let mut out = String::from("{\n");
+    writeln!(out, "  \"format\": {},", json_string(JSON_FORMAT)).unwrap();
+    writeln!(out, "  \"version\": {},", MODEL_VERSION).unwrap();
+    writeln!(out, "  \"checksum\": \"{:016x}\",", model_checksum(model)).unwrap();
+    writeln!(out, "  \"pattern\": {},", json_string(&model.pattern)).unwrap();
+    let steps: Vec<String> = model
+        .normalization
+        .iter()
+        .map(|step| json_string(step))
+        .collect();
+    writeln!(out, "  \"normalization\": [{}],", steps.join(", ")).unwrap();
+    let mut specials: Vec<_> = model.special_tokens.iter().collect();
+    specials.sort_by_key(|&(_, &idx)| idx);
+    let specials: Vec<String> = specials
+        .into_iter()
+        .map(|(special, idx)| format!("    {}: {}", json_string(special), idx))
+        .collect();
+    writeln!(
+        out,
+        "  \"special_tokens\": {{\n{}\n  }},",
+        specials.join(",\n")
+    )
+    .unwrap();
+    let merges: Vec<String> = model
+        .merges
+        .iter()
+        .map(|&((p0, p1), idx)| format!("    [{}, {}, {}]", p0, p1, idx))
+        .collect();
+    writeln!(out, "  \"merges\": [\n{}\n  ],", merges.join(",\n")).unwrap();
+    let mut ids: Vec<_> = vocab.keys().copied().collect();
+    ids.sort_unstable();
+    let vocab: Vec<String> = ids
+        .into_iter()
+        .map(|idx| {
+            format!(
+                "    \"{}\": {}",
+                idx,
+                json_string(&escape_bytes(&vocab[&idx]))
+            )
+        })
+        .collect();
+    writeln!(out, "  \"vocab\": {{\n{}\n  }}\n}}", vocab.join(",\n")).unwrap();
+    writer.write_all(out.as_bytes())
+}
+
+/// Migrate the fields of a JSON model of `version` to `MODEL_VERSION`, one
+/// version at a time.
+#[cfg(feature = "json")]
+fn migrate_json(object: &mut serde_json::Map<String, serde_json::Value>, version: u32) {
+    use serde_json::Value;
+
+    for from in version..MODEL_VERSION {
+        match from {
+            // version 2 added the checksum, without which older models load
+            1 => {
+                object.insert("checksum".to_string(), Value::Null);
+            }
+            // version 3 added the normalization steps, none before
+            2 => {
+                object.insert("normalization".to_string(), Value::Array(Vec::new()));
+            }
+            _ => unreachable!("no migration from JSON model version {}", from),
+        }
+    }
+}
+
+/// Read a model saved by `write_json`. The vocab is checked against the one
+/// the merges build, so a hand-edited file can't disagree with itself.
+#[cfg(feature = "json")]
+pub(crate) fn read_json(mut reader: impl Read) -> io::Result<ModelFile> {
+    let invalid = |msg: String| io::Error::new(io::ErrorKind::InvalidData, msg);
+    let mut bytes = Vec::new();
+    reader.read_to_end(&mut bytes)?;
+    let mut object: serde_json::Map<String, serde_json::Value> = serde_json::from_slice(&bytes)?;
+    if object.get("format").and_then(|format| format.as_str()) != Some(JSON_FORMAT) {
+        return Err(invalid("not a minbpe-rs JSON model".to_string()));
+    }
+    let version = object
+        .get("version")
+        .and_then(|version| version.as_u64())
+        .and_then(|version| u32::try_from(version).ok())
+        .ok_or_else(|| invalid("missing JSON model version".to_string()))?;
+    check_version(version, "JSON")?;
+    migrate_json(&mut object, version);
+    let json: JsonModel = serde_json::from_value(object.into())?;
+    let checksum = match (version, &json.checksum) {
+        (1, _) => None,
+        (_, Some(hex)) => Some(
+            u64::from_str_radix(hex, 16)
+                .map_err(|_| invalid(format!("malformed checksum {:?}", hex)))?,
+        ),
+        (_, None) => return Err(invalid("missing checksum".to_string())),
+    };
+    let merges: Vec<Merge> = json
+        .merges
+        .into_iter()
+        .map(|(p0, p1, idx)| ((p0, p1), idx))
+        .collect();
+    check_merges(&merges)?;
+    let expected = build_vocab(&merges.iter().copied().collect());
+    for (idx, text) in &json.vocab {
+        let bytes = unescape_bytes(text)
+            .ok_or_else(|| invalid(format!("malformed bytes of token {}", idx)))?;
+        if expected.get(idx) != Some(&bytes) {
+            return Err(invalid(format!("token {} doesn't match the merges", idx)));
+        }
+    }
+    if json.vocab.len() != expected.len() {
+        return Err(invalid("vocab doesn't match the merges".to_string()));
+    }
+    let model = ModelFile {
+        pattern: json.pattern,
+        special_tokens: json.special_tokens,
+        merges,
+        normalization: json.normalization,
+    };
+    if let Some(checksum) = checksum {
+        verify_checksum(&model, checksum)?;
+    }
+    Ok(model)
+}
+
+/// Read a model saved by `write_model` or by the Python minbpe.
+pub(crate) fn read_model(mut reader: impl Read) -> io::Result<ModelFile> {
+    let invalid = |msg: &str| io::Error::new(io::ErrorKind::InvalidData, msg.to_string());
+    let mut text = String::new();
+    reader.read_to_string(&mut text)?;
+    let mut lines = text.lines();
+    match lines.next() {
+        Some(MODEL_HEADER) => {}
+        Some(header) if header.starts_with("minbpe v") => {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!(
+                    "{} models are newer than the minbpe v1 this crate reads",
+                    header
+                ),
+            ));
+        }
+        _ => return Err(invalid("not a minbpe v1 model")),
+    }
+    let pattern = lines
+        .next()
+        .ok_or_else(|| invalid("missing split pattern"))?;
+    let num_special: usize = lines
+        .next()
+        .and_then(|line| line.trim().parse().ok())
+        .ok_or_else(|| invalid("malformed number of special tokens"))?;
+    let mut special_tokens = HashMap::new();
+    for _ in 0..num_special {
+        let line = lines
+            .next()
+            .ok_or_else(|| invalid("missing special token"))?;
+        // the id comes last, the literal itself may contain spaces
+        let (special, idx) = line
+            .rsplit_once(' ')
+            .ok_or_else(|| invalid("malformed special token"))?;
+        let idx = idx
+            .trim()
+            .parse()
+            .map_err(|_| invalid("malformed special token"))?;
+        special_tokens.insert(special.to_string(), idx);
+    }
+    let mut normalization = Vec::new();
+    if let Some(steps) = lines
+        .clone()
+        .next()
+        .and_then(|line| line.strip_prefix(NORMALIZE_PREFIX))
+    {
+        normalization = steps.split_whitespace().map(str::to_string).collect();
+        lines.next();
+    }
+    let merges = lines
+        .zip(256..)
+        .map(|(line, idx)| {
+            let ids: Vec<Token> = line
+                .split_whitespace()
+                .map(|id| id.parse())
+                .collect::<Result<_, _>>()
+                .map_err(|_| invalid("malformed merge line in model"))?;
+            match ids[..] {
+                [p0, p1] => Ok(((p0, p1), idx)),
+                _ => Err(invalid("malformed merge line in model")),
+            }
+        })
+        .collect::<io::Result<Vec<_>>>()?;
+    check_merges(&merges)?;
+    Ok(ModelFile {
+        pattern: pattern.to_string(),
+        special_tokens,
+        merges,
+        normalization,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::base::{SpecialSet, Tokenizer};
+    use crate::basic::BasicTokenizer;
+    #[cfg(any(feature = "binary", feature = "json"))]
+    use crate::normalize::Normalizer;
+    use crate::regex::RegexTokenizer;
+    use crate::train::{TrainConfig, Trainable};
+
+    const TEXT: &str = "hello world, hello there <|endoftext|> the world is wide";
+
+    fn trained() -> RegexTokenizer {
+        let mut tokenizer = RegexTokenizer::new();
+        tokenizer.train(include_str!("../taylorswift.txt"), TrainConfig::new(300));
+        tokenizer
+            .register_special_tokens(HashMap::from([
+                ("<|endoftext|>".to_string(), 300),
+                ("<|fim prefix|>".to_string(), 301),
+            ]))
+            .unwrap();
+        tokenizer.set_allowed_special(SpecialSet::All);
+        tokenizer
+    }
+
+    #[test]
+    fn model_round_trip() {
+        let mut tokenizer = trained();
+        let mut file = Vec::new();
+        tokenizer.save_to_writer(&mut file).unwrap();
+        let mut loaded = RegexTokenizer::load_from_reader(&file[..]).unwrap();
+        loaded.set_allowed_special(SpecialSet::All);
+        assert_eq!(loaded.merges(), tokenizer.merges());
+        assert_eq!(loaded.special_tokens(), tokenizer.special_tokens());
+        assert_eq!(
+            loaded.encode_ordinary(TEXT),
+            tokenizer.encode_ordinary(TEXT)
+        );
+        let ids = tokenizer.try_encode(TEXT).unwrap();
+        assert!(ids.contains(&300));
+        assert_eq!(loaded.try_encode(TEXT).unwrap(), ids);
+        assert!(loaded.validate().is_valid());
+    }
+
+    #[test]
+    fn merge_ids_with_gaps_are_refused() {
+        let mut tokenizer = RegexTokenizer::new();
+        tokenizer.train(include_str!("../taylorswift.txt"), TrainConfig::new(298));
+        tokenizer
+            .register_special_tokens(HashMap::from([
+                ("<|a|>".to_string(), 298),
+                ("<|b|>".to_string(), 299),
+            ]))
+            .unwrap();
+        tokenizer.train_more("hello hello world world", 5);
+        let error = tokenizer.save_to_writer(Vec::new()).unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::InvalidInput);
+    }
+
+    #[test]
+    fn merges_of_unknown_tokens_are_invalid() {
+        // merge 257 is made of 300, which doesn't exist yet
+        let file = format!("{}\n\n0\n104 105\n300 104\n", MODEL_HEADER);
+        let error = BasicTokenizer::load_from_reader(file.as_bytes())
+            .err()
+            .unwrap();
+        assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+        let valid = format!("{}\n\n0\n104 105\n256 104\n", MODEL_HEADER);
+        assert!(BasicTokenizer::load_from_reader(valid.as_bytes()).is_ok());
+        let error = check_merges(&[((104, 105), 256), ((256, 256), 256)]).unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn patterns_a_model_file_cant_hold_are_refused() {
+        for pattern in ["", "\\w+|\n"] {
+            let tokenizer = RegexTokenizer::new_with_pattern(pattern).unwrap();
+            let error = tokenizer.save_to_writer(Vec::new()).unwrap_err();
+            assert_eq!(error.kind(), io::ErrorKind::InvalidInput);
+        }
+    }
+
+    #[test]
+    fn special_ids_of_regular_tokens_are_refused() {
+        let mut tokenizer = trained();
+        let specials = HashMap::from([("<|endoftext|>".to_string(), 299)]);
+        let error = tokenizer.register_special_tokens(specials).unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::InvalidInput);
+        let file = format!("{}\n\n1\n<|endoftext|> 256\n104 105\n", MODEL_HEADER);
+        let error = BasicTokenizer::load_from_reader(file.as_bytes())
+            .err()
+            .unwrap();
+        assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn basic_model_round_trip() {
+        // as written by the Python minbpe's BasicTokenizer
+        let file = format!(
+            "{}\n\n1\n<|endoftext|> 259\n104 101\n256 108\n257 108\n",
+            MODEL_HEADER
+        );
+        let mut tokenizer = BasicTokenizer::load_from_reader(file.as_bytes()).unwrap();
+        assert_eq!(tokenizer.encode("hello"), [258, 111]);
+        assert_eq!(tokenizer.decode(&[258, 259]), "hell<|endoftext|>");
+        let mut saved = Vec::new();
+        tokenizer.save_to_writer(&mut saved).unwrap();
+        assert_eq!(String::from_utf8(saved).unwrap(), file);
+    }
+
+    #[cfg(feature = "binary")]
+    #[test]
+    fn binary_round_trip() {
+        let mut tokenizer = trained();
+        tokenizer.set_normalizer(Normalizer::new().lowercase(true));
+        let mut file = Vec::new();
+        tokenizer.save_binary_to_writer(&mut file).unwrap();
+        assert_eq!(&file[..4], BINARY_MAGIC);
+        let mut loaded = RegexTokenizer::load_binary_from_reader(&file[..]).unwrap();
+        loaded.set_allowed_special(SpecialSet::All);
+        assert_eq!(loaded.merges(), tokenizer.merges());
+        assert_eq!(loaded.special_tokens(), tokenizer.special_tokens());
+        let text = TEXT
+            .to_uppercase()
+            .replace("<|ENDOFTEXT|>", "<|endoftext|>");
+        assert_eq!(
+            loaded.try_encode(&text).unwrap(),
+            tokenizer.try_encode(TEXT).unwrap()
+        );
+    }
+
+    #[cfg(feature = "json")]
+    #[test]
+    fn json_round_trip() {
+        let mut tokenizer = trained();
+        tokenizer.set_normalizer(Normalizer::new().crlf_to_lf(true));
+        let mut file = Vec::new();
+        tokenizer.save_json_to_writer(&mut file).unwrap();
+        let json: serde_json::Value = serde_json::from_slice(&file).unwrap();
+        assert_eq!(json["format"], JSON_FORMAT);
+        assert_eq!(json["normalization"][0], "crlf_to_lf");
+        assert_eq!(json["vocab"]["10"], "\\x0a");
+        let mut loaded = RegexTokenizer::load_json_from_reader(&file[..]).unwrap();
+        loaded.set_allowed_special(SpecialSet::All);
+        assert_eq!(loaded.merges(), tokenizer.merges());
+        assert_eq!(loaded.special_tokens(), tokenizer.special_tokens());
+        let text = TEXT.replace(' ', "\r\n");
+        assert_eq!(
+            loaded.try_encode(&text).unwrap(),
+            tokenizer.try_encode(&text).unwrap()
+        );
+    }
+
+    #[cfg(feature = "json")]
+    #[test]
+    fn escaped_bytes_round_trip() {
+        let bytes = b"a\\b\n\xe2\x82 \xff\xc3\xa9";
+        let escaped = escape_bytes(bytes);
+        assert_eq!(escaped, "a\\\\b\\x0a\\xe2\\x82 \\xffé");
+        assert_eq!(unescape_bytes(&escaped).unwrap(), bytes);
+        assert_eq!(unescape_bytes("\\x4"), None);
+    }
+
+    #[cfg(feature = "binary")]
+    #[test]
+    fn corrupted_binary_models_are_refused() {
+        let mut file = Vec::new();
+        trained().save_binary_to_writer(&mut file).unwrap();
+        for corrupted in [&file[..file.len() - 3], &file[..10]] {
+            let error = read_binary(corrupted).err().unwrap();
+            assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+        }
+        // a flipped bit in the checksum
+        file[6] ^= 1;
+        let error = read_binary(&file[..]).err().unwrap();
+        assert!(error.to_string().contains("checksum mismatch"), "{}", error);
+    }
+
+    #[cfg(feature = "json")]
+    #[test]
+    fn edited_json_models_are_refused() {
+        let mut file = Vec::new();
+        let tokenizer = trained();
+        tokenizer.save_json_to_writer(&mut file).unwrap();
+        let mut json: serde_json::Value = serde_json::from_slice(&file).unwrap();
+        json["special_tokens"]["<|endoftext|>"] = 302.into();
+        let error = read_json(json.to_string().as_bytes()).err().unwrap();
+        assert!(error.to_string().contains("checksum mismatch"), "{}", error);
+    }
+
+    #[cfg(feature = "binary")]
+    #[test]
+    fn binary_models_of_other_versions() {
+        let mut current = Vec::new();
+        trained().save_binary_to_writer(&mut current).unwrap();
+        let model = read_binary(&current[..]).unwrap();
+        // version 1: no checksum, and no normalization in the state
+        let mut file = BINARY_MAGIC.to_vec();
+        file.extend(1u16.to_le_bytes());
+        let state = (&model.pattern, &model.special_tokens, &model.merges);
+        file.extend(postcard::to_allocvec(&state).unwrap());
+        let loaded = read_binary(&file[..]).unwrap();
+        assert_eq!(loaded.merges, model.merges);
+        assert!(loaded.normalization.is_empty());
+        // a version from the future
+        file[4..6].copy_from_slice(&(MODEL_VERSION as u16 + 1).to_le_bytes());
+        let error = read_binary(&file[..]).err().unwrap();
+        assert!(error.to_string().contains("newer"), "{}", error);
+    }
+
+    #[cfg(feature = "json")]
+    #[test]
+    fn json_models_of_other_versions() {
+        let mut file = Vec::new();
+        trained().save_json_to_writer(&mut file).unwrap();
+        let mut json: serde_json::Value = serde_json::from_slice(&file).unwrap();
+        // version 1 had neither the checksum nor the normalization
+        let object = json.as_object_mut().unwrap();
+        object.remove("checksum");
+        object.remove("normalization");
+        object.insert("version".to_string(), 1.into());
+        let loaded = read_json(json.to_string().as_bytes()).unwrap();
+        assert_eq!(loaded.special_tokens.len(), 2);
+        assert!(loaded.normalization.is_empty());
+        json["version"] = (MODEL_VERSION + 1).into();
+        let error = read_json(json.to_string().as_bytes()).err().unwrap();
+        assert!(error.to_string().contains("newer"), "{}", error);
+    }
+}
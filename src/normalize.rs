@@ -0,0 +1,306 @@
+/*
+Normalizing text before it is split into chunks, so that strings which look
+the same but are written differently (e.g. `é` as one code point or as `e`
+plus a combining accent) encode to the same tokens, or that differ only in
+case when training a case-insensitive vocabulary, or only in their line
+endings (Windows `\r\n` against `\n`).
+*/
+
+use std::borrow::Cow;
+use std::ops::Range;
+
+use crate::base::Piece;
+
+/// The normalization steps applied to text before encoding and training.
+/// Does nothing by default.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Normalizer {
+    crlf: bool,
+    lowercase: bool,
+    #[cfg(feature = "nfc")]
+    nfc: bool,
+}
+
+impl Normalizer {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Replace `\r\n` line endings with `\n`, so documents with mixed line
+    /// endings encode the same. Decoding then yields `\n` line endings.
+    pub fn crlf_to_lf(mut self, crlf: bool) -> Self {
+        self.crlf = crlf;
+        self
+    }
+
+    /// Lowercase text (with `str::to_lowercase`), e.g. for a case-insensitive
+    /// vocabulary. Decoding then yields lowercase text.
+    pub fn lowercase(mut self, lowercase: bool) -> Self {
+        self.lowercase = lowercase;
+        self
+    }
+
+    /// Compose text into Unicode Normalization Form C.
+    #[cfg(feature = "nfc")]
+    pub fn nfc(mut self, nfc: bool) -> Self {
+        self.nfc = nfc;
+        self
+    }
+
+    /// Apply the normalization steps to `text`, borrowing it when it is
+    /// normalized already.
+    pub fn normalize<'a>(&self, text: &'a str) -> Cow<'a, str> {
+        let text = Cow::Borrowed(text);
+        let text = if self.crlf { crlf_to_lf(text) } else { text };
+        let text = if self.lowercase {
+            lowercase(text)
+        } else {
+            text
+        };
+        #[cfg(feature = "nfc")]
+        let text = if self.nfc { nfc(text) } else { text };
+        text
+    }
+
+    /// Like `normalize`, also recording in `offsets` where the normalized
+    /// text came from, for `text` starting at byte `at` of the original text
+    /// and after the text recorded so far.
+    pub(crate) fn normalize_mapped<'a>(
+        &self,
+        text: &'a str,
+        at: usize,
+        offsets: &mut OffsetMap,
+    ) -> Cow<'a, str> {
+        let normalized = self.normalize(text);
+        if let Cow::Borrowed(_) = normalized {
+            return normalized;
+        }
+        let shift = offsets.shift();
+        let mut changed = Vec::new();
+        let mut rebuilt = String::with_capacity(normalized.len());
+        for segment in segments(text) {
+            let start = segment.as_ptr() as usize - text.as_ptr() as usize;
+            let piece = self.normalize(segment);
+            if piece != segment {
+                changed.push((
+                    rebuilt.len()..rebuilt.len() + piece.len(),
+                    start..start + segment.len(),
+                ));
+            }
+            rebuilt.push_str(&piece);
+        }
+        // should normalizing the segments one by one differ from normalizing
+        // the whole text, only its ends can be mapped
+        if rebuilt != normalized {
+            changed = vec![(0..normalized.len(), 0..text.len())];
+        }
+        let norm_at = at.wrapping_add_signed(shift);
+        offsets
+            .changed
+            .extend(changed.into_iter().map(|(norm, raw)| {
+                (
+                    norm.start + norm_at..norm.end + norm_at,
+                    raw.start + at..raw.end + at,
+                )
+            }));
+        normalized
+    }
+
+    /// Where the text encoded from `pieces` of `text` (its normalized text
+    /// pieces and the special tokens between them) comes from in `text`.
+    pub(crate) fn offset_map(&self, text: &str, pieces: &[Piece]) -> OffsetMap {
+        let mut offsets = OffsetMap::default();
+        for piece in pieces {
+            if let Piece::Text(piece) = piece {
+                let at = piece.as_ptr() as usize - text.as_ptr() as usize;
+                self.normalize_mapped(piece, at, &mut offsets);
+            }
+        }
+        offsets
+    }
+
+    /// The names of the enabled steps in the order they are applied, as
+    /// saved with a model.
+    pub(crate) fn steps(&self) -> Vec<String> {
+        let mut steps = Vec::new();
+        if self.crlf {
+            steps.push(CRLF_TO_LF.to_string());
+        }
+        if self.lowercase {
+            steps.push(LOWERCASE.to_string());
+        }
+        #[cfg(feature = "nfc")]
+        if self.nfc {
+            steps.push(NFC.to_string());
+        }
+        steps
+    }
+
+    /// The normalizer with the `steps` a model was saved with.
+    pub(crate) fn from_steps(steps: &[String]) -> Result<Self, String> {
+        let mut normalizer = Self::new();
+        for step in steps {
+            match step.as_str() {
+                CRLF_TO_LF => normalizer.crlf = true,
+                LOWERCASE => normalizer.lowercase = true,
+                #[cfg(feature = "nfc")]
+                NFC => normalizer.nfc = true,
+                #[cfg(not(feature = "nfc"))]
+                NFC => {
+                    return Err(
+                        "model normalizes text to NFC, which needs the `nfc` feature".to_string(),
+                    );
+                }
+                _ => return Err(format!("unknown normalization step {:?}", step)),
+            }
+        }
+        Ok(normalizer)
+    }
+}
+
+/// Maps byte offsets of normalized text back to the original text. Offsets
+/// inside something the normalizer changed (e.g. the `\n` of a `\r\n`)
+/// round to either end of the original characters.
+#[derive(Debug, Clone, Default)]
+pub(crate) struct OffsetMap {
+    /// The spans the normalizer changed, as ranges of the normalized and of
+    /// the original text, in order.
+    changed: Vec<(Range<usize>, Range<usize>)>,
+}
+
+impl OffsetMap {
+    /// How much longer the normalized text is than the original, so far.
+    fn shift(&self) -> isize {
+        self.changed
+            .last()
+            .map_or(0, |(norm, raw)| norm.end as isize - raw.end as isize)
+    }
+
+    /// The changed span containing or ending last before `at`, if any.
+    fn span(&self, at: usize) -> Option<&(Range<usize>, Range<usize>)> {
+        let i = self.changed.partition_point(|(norm, _)| norm.start < at);
+        i.checked_sub(1).map(|i| &self.changed[i])
+    }
+
+    /// The original offset of `at`, if it isn't inside a changed span.
+    pub(crate) fn exact(&self, at: usize) -> Option<usize> {
+        match self.span(at) {
+            Some((norm, _)) if at < norm.end => None,
+            Some((norm, raw)) => Some(raw.end + (at - norm.end)),
+            None => Some(at),
+        }
+    }
+
+    /// The original offset of `at` as the start of a range, rounding down.
+    pub(crate) fn start(&self, at: usize) -> usize {
+        match self.span(at) {
+            Some((norm, raw)) if at < norm.end => raw.start,
+            _ => self.exact(at).unwrap(),
+        }
+    }
+
+    /// The original offset of `at` as the end of a range, rounding up.
+    pub(crate) fn end(&self, at: usize) -> usize {
+        self.exact(at)
+            .unwrap_or_else(|| self.span(at).unwrap().1.end)
+    }
+}
+
+/// Cut `text` into the spans that normalize independently: every character
+/// with the ones NFC composes with it, and `\r\n`. Words with a `Σ` stay
+/// whole, as lowercasing it depends on whether it ends the word.
+fn segments(text: &str) -> Vec<&str> {
+    let sigma_words: Vec<Range<usize>> = text
+        .split(|c: char| !c.is_alphabetic() && !composes_backwards(c))
+        .filter(|word| word.contains('Σ'))
+        .map(|word| {
+            let start = word.as_ptr() as usize - text.as_ptr() as usize;
+            start..start + word.len()
+        })
+        .collect();
+    let mut word = 0;
+    let mut segments = Vec::new();
+    let mut start = 0;
+    let mut prev = None;
+    for (i, c) in text.char_indices() {
+        while word < sigma_words.len() && sigma_words[word].end <= i {
+            word += 1;
+        }
+        let joined = prev == Some('\r') && c == '\n'
+            || composes_backwards(c)
+            || sigma_words.get(word).is_some_and(|word| word.start < i);
+        if i > 0 && !joined {
+            segments.push(&text[start..i]);
+            start = i;
+        }
+        prev = Some(c);
+    }
+    if start < text.len() {
+        segments.push(&text[start..]);
+    }
+    segments
+}
+
+/// Whether NFC may compose `c` with the character before it: combining marks
+/// and the vowels and final consonants of Hangul syllables.
+#[cfg(feature = "nfc")]
+fn composes_backwards(c: char) -> bool {
+    unicode_normalization::char::canonical_combining_class(c) != 0
+        || ('\u{1161}'..='\u{11c2}').contains(&c)
+}
+
+#[cfg(not(feature = "nfc"))]
+fn composes_backwards(_c: char) -> bool {
+    false
+}
+
+// names of the steps in saved models
+pub(crate) const CRLF_TO_LF: &str = "crlf_to_lf";
+pub(crate) const LOWERCASE: &str = "lowercase";
+pub(crate) const NFC: &str = "nfc";
+
+fn crlf_to_lf(text: Cow<str>) -> Cow<str> {
+    if !text.contains("\r\n") {
+        return text;
+    }
+    Cow::Owned(text.replace("\r\n", "\n"))
+}
+
+fn lowercase(text: Cow<str>) -> Cow<str> {
+    if text.is_ascii() && !text.bytes().any(|b| b.is_ascii_uppercase()) {
+        return text;
+    }
+    Cow::Owned(text.to_lowercase())
+}
+
+#[cfg(feature = "nfc")]
+fn nfc(text: Cow<str>) -> Cow<str> {
+    use unicode_normalization::{IsNormalized, UnicodeNormalization, is_nfc_quick};
+
+    match is_nfc_quick(text.chars()) {
+        IsNormalized::Yes => text,
+        _ => Cow::Owned(text.nfc().collect()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn offsets_map_back_to_the_original_text() {
+        let text = "A\r\nBΣ ΟΔΟΣ x";
+        let normalizer = Normalizer::new().crlf_to_lf(true).lowercase(true);
+        let mut offsets = OffsetMap::default();
+        let normalized = normalizer.normalize_mapped(text, 0, &mut offsets);
+        assert_eq!(normalized, "a\nbς οδος x");
+        // "a" and "\n"
+        assert_eq!(offsets.start(0)..offsets.end(1), 0..1);
+        assert_eq!(offsets.start(1)..offsets.end(2), 1..3);
+        // the whole of "ΟΔΟΣ" for a part of "οδος"
+        let word = normalized.find('ο').unwrap();
+        assert_eq!(offsets.exact(word + 2), None);
+        assert_eq!(offsets.start(word + 2)..offsets.end(word + 4), 7..15);
+        assert_eq!(offsets.exact(normalized.len()), Some(text.len()));
+    }
+}
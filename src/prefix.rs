@@ -0,0 +1,35 @@
+/*
+An index over the byte strings of a vocabulary, for asking which tokens start
+with given bytes, e.g. to mask the logits of a model during grammar-constrained
+decoding.
+*/
+
+use crate::base::Token;
+use crate::hash::FastHashMap;
+
+/// The tokens of a vocabulary sorted by their bytes, so the tokens sharing a
+/// prefix are a contiguous run found with a binary search.
+#[derive(Debug, Clone, Default)]
+pub(crate) struct PrefixIndex {
+    entries: Vec<(Vec<u8>, Token)>,
+}
+
+impl PrefixIndex {
+    pub(crate) fn new(vocab: &FastHashMap<Token, Vec<u8>>) -> Self {
+        let mut entries: Vec<(Vec<u8>, Token)> = vocab
+            .iter()
+            .map(|(&idx, bytes)| (bytes.clone(), idx))
+            .collect();
+        entries.sort_unstable();
+        PrefixIndex { entries }
+    }
+
+    /// The tokens whose bytes start with `prefix`, in byte order.
+    pub(crate) fn with_prefix(&self, prefix: &[u8]) -> impl Iterator<Item = Token> + use<'_> {
+        let start = self
+            .entries
+            .partition_point(|(bytes, _)| bytes.as_slice() < prefix);
+        let len = self.entries[start..].partition_point(|(bytes, _)| bytes.starts_with(prefix));
+        self.entries[start..start + len].iter().map(|&(_, idx)| idx)
+    }
+}
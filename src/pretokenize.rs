@@ -0,0 +1,114 @@
+/*
+Splitting text into chunks with a split pattern, guarded against runaway
+backtracking. `fancy_regex` gives up on a match once it exceeds its backtrack
+limit; instead of failing the whole encode, the rest of the offending line is
+split with a simple character-class rule and matching resumes after it.
+*/
+
+use std::io;
+
+use fancy_regex::{Regex, RegexBuilder};
+
+/// The default backtrack limit, the same as `fancy_regex`'s.
+pub(crate) const DEFAULT_BACKTRACK_LIMIT: usize = 1_000_000;
+
+/// Compile a split pattern with a backtrack limit.
+pub(crate) fn compile(pattern: &str, backtrack_limit: usize) -> io::Result<Regex> {
+    RegexBuilder::new(pattern)
+        .backtrack_limit(backtrack_limit)
+        .build()
+        .map_err(|e| {
+            io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("invalid split pattern {:?}: {}", pattern, e),
+            )
+        })
+}
+
+/// The chunks `re` finds in `text`, like `find_iter`.
+pub(crate) fn split_chunks<'a>(re: &'a Regex, text: &'a str) -> Chunks<'a> {
+    Chunks {
+        re,
+        text,
+        pos: 0,
+        fallback_end: 0,
+    }
+}
+
+pub(crate) struct Chunks<'a> {
+    re: &'a Regex,
+    text: &'a str,
+    pos: usize,
+    /// End of the region being split by `simple_chunk` after a failed match.
+    fallback_end: usize,
+}
+
+impl<'a> Iterator for Chunks<'a> {
+    type Item = &'a str;
+
+    fn next(&mut self) -> Option<&'a str> {
+        loop {
+            if self.pos < self.fallback_end {
+                let start = self.pos;
+                self.pos += simple_chunk(&self.text[start..self.fallback_end]);
+                return Some(&self.text[start..self.pos]);
+            }
+            if self.pos >= self.text.len() {
+                return None;
+            }
+            match self.re.find_from_pos(self.text, self.pos) {
+                Ok(Some(m)) if m.start() < m.end() => {
+                    self.pos = m.end();
+                    return Some(m.as_str());
+                }
+                Ok(Some(m)) => {
+                    // step over an empty match to make progress
+                    let next = self.text[m.end()..].chars().next()?;
+                    self.pos = m.end() + next.len_utf8();
+                }
+                Ok(None) => return None,
+                Err(_) => {
+                    // the backtrack limit was hit: fall back for the rest of the line
+                    self.fallback_end = match self.text[self.pos..].find('\n') {
+                        Some(at) => self.pos + at + 1,
+                        None => self.text.len(),
+                    };
+                }
+            }
+        }
+    }
+}
+
+#[derive(PartialEq, Eq)]
+enum CharClass {
+    Letter,
+    Number,
+    Space,
+    Other,
+}
+
+impl CharClass {
+    fn of(c: char) -> Self {
+        if c.is_alphabetic() {
+            CharClass::Letter
+        } else if c.is_numeric() {
+            CharClass::Number
+        } else if c.is_whitespace() {
+            CharClass::Space
+        } else {
+            CharClass::Other
+        }
+    }
+}
+
+/// Length of the run of characters of the same class `text` starts with.
+fn simple_chunk(text: &str) -> usize {
+    let mut chars = text.char_indices();
+    let Some((_, first)) = chars.next() else {
+        return 0;
+    };
+    let class = CharClass::of(first);
+    chars
+        .find(|&(_, c)| CharClass::of(c) != class)
+        .map_or(text.len(), |(at, _)| at)
+}
@@ -0,0 +1,61 @@
+/*
+Progress bars for long-running operations, drawn with `indicatif` when the
+`progress` feature is enabled. Without the feature every method is a no-op,
+so callers never need their own `cfg`s. Bars are only drawn when stderr is a
+terminal.
+*/
+
+#[cfg(feature = "progress")]
+use indicatif::{ProgressBar, ProgressStyle};
+
+pub(crate) struct Progress {
+    #[cfg(feature = "progress")]
+    bar: ProgressBar,
+}
+
+impl Progress {
+    /// A bar counting `len` steps, e.g. merges.
+    #[cfg_attr(not(feature = "progress"), allow(unused_variables))]
+    pub(crate) fn new(len: usize, message: &'static str) -> Self {
+        Progress {
+            #[cfg(feature = "progress")]
+            bar: styled(
+                len,
+                message,
+                "{msg} [{elapsed_precise}] {wide_bar} {human_pos}/{human_len} (eta {eta})",
+            ),
+        }
+    }
+
+    /// A bar counting `len` bytes of input.
+    #[cfg_attr(not(feature = "progress"), allow(unused_variables))]
+    pub(crate) fn bytes(len: usize, message: &'static str) -> Self {
+        Progress {
+            #[cfg(feature = "progress")]
+            bar: styled(
+                len,
+                message,
+                "{msg} [{elapsed_precise}] {wide_bar} {bytes}/{total_bytes} ({bytes_per_sec}, eta {eta})",
+            ),
+        }
+    }
+
+    #[cfg_attr(not(feature = "progress"), allow(unused_variables))]
+    pub(crate) fn inc(&self, steps: usize) {
+        #[cfg(feature = "progress")]
+        self.bar.inc(steps as u64);
+    }
+
+    /// Leave the bar at its final state, e.g. when training stops early.
+    pub(crate) fn finish(&self) {
+        #[cfg(feature = "progress")]
+        self.bar.abandon();
+    }
+}
+
+#[cfg(feature = "progress")]
+fn styled(len: usize, message: &'static str, template: &str) -> ProgressBar {
+    let bar = ProgressBar::new(len as u64).with_message(message);
+    bar.set_style(ProgressStyle::with_template(template).unwrap());
+    bar
+}
@@ -0,0 +1,74 @@
+/*
+Recovering the merges of a tiktoken encoding from its ranks. tiktoken only
+stores the bytes of every token by rank, but `GPT4Tokenizer` (like minbpe)
+merges pairs of tokens, so the pairings have to be found again: a token of
+rank r is what BPE makes of its bytes with only the merges of lower ranks,
+which leaves exactly the two tokens it was merged from.
+
+The build script includes this file as well (see `codegen`), so it only uses
+std and is generic over the rank type.
+*/
+
+/// The parts BPE splits `token` into when it only applies merges of a rank
+/// below `max_rank`, with `rank_of` the rank of some bytes, if they are a
+/// token.
+fn bpe<T: Copy + Ord>(
+    rank_of: &impl Fn(&[u8]) -> Option<T>,
+    token: &[u8],
+    max_rank: T,
+) -> Vec<Vec<u8>> {
+    let mut parts: Vec<Vec<u8>> = token.iter().map(|&b| vec![b]).collect();
+    loop {
+        let mut min: Option<(usize, T)> = None;
+        for (i, pair) in parts.windows(2).enumerate() {
+            let rank = rank_of(&[&pair[0][..], &pair[1][..]].concat());
+            if let Some(rank) = rank
+                && min.is_none_or(|(_, min_rank)| rank < min_rank)
+            {
+                min = Some((i, rank));
+            }
+        }
+        match min {
+            Some((i, rank)) if rank < max_rank => {
+                let right = parts.remove(i + 1);
+                parts[i].extend(right);
+            }
+            _ => return parts,
+        }
+    }
+}
+
+/// A merge `((left, right), rank)`.
+pub(crate) type RankedMerge<T> = ((T, T), T);
+
+/// The merges `((left, right), rank)` of the tokens of `ranks` that are
+/// longer than a byte, in the order of `ranks`. `tick` is called once per
+/// token, e.g. to report progress. Fails on a token that isn't made of two
+/// tokens of lower ranks, as in a truncated or hand-edited ranks file.
+pub(crate) fn recover_merges<'a, T: Copy + Ord>(
+    ranks: impl IntoIterator<Item = (&'a [u8], T)>,
+    rank_of: impl Fn(&[u8]) -> Option<T>,
+    mut tick: impl FnMut(),
+) -> Result<Vec<RankedMerge<T>>, String> {
+    let mut merges = Vec::new();
+    for (token, rank) in ranks {
+        tick();
+        if token.len() == 1 {
+            continue;
+        }
+        let pair = bpe(&rank_of, token, rank);
+        // recover the integer ranks of the pair
+        let (left, right) = match &pair[..] {
+            [left, right] => (rank_of(left), rank_of(right)),
+            _ => (None, None),
+        };
+        let (Some(left), Some(right)) = (left, right) else {
+            return Err(format!(
+                "token {:?} isn't made of two tokens of lower ranks",
+                String::from_utf8_lossy(token)
+            ));
+        };
+        merges.push(((left, right), rank));
+    }
+    Ok(merges)
+}
@@ -1,159 +1,1175 @@
-use crate::base::{Token, Tokenizer};
+use crate::atomic::{AtomicFile, save_atomically};
+use crate::base::{
+    DecodeError, EncodeError, Merge, Piece, SpecialSet, Token, Tokenizer, build_vocab,
+    check_special_ids, decode_token, encode_bytes, render_token, special_pieces, token_offsets,
+};
+#[cfg(feature = "parallel")]
+use crate::batch::Padding;
+use crate::cache::{CacheCapacity, ChunkCache, SharedCache};
+use crate::decode::{
+    LossyPolicy, SpecialRendering, UnknownIdPolicy, decode_delta, decode_into, decode_lossy,
+    decode_to_writer, decode_with_spans, decode_with_specials,
+};
+use crate::gguf::{
+    GgufValue, pre_tokenizer_name, pre_tokenizer_pattern, read_gguf_vocab, tokenizer_metadata,
+    write_gguf,
+};
+use crate::hash::FastHashMap;
+use crate::heal::{TokenHealing, heal_tokens};
+use crate::hf::{write_gpt2_files, write_hf_tokenizer};
+#[cfg(feature = "serde")]
+use crate::model::check_merges;
+use crate::model::{
+    ModelFile, read_model, write_merge_graph, write_model, write_tiktoken, write_vocab,
+};
+#[cfg(feature = "binary")]
+use crate::model::{read_binary, write_binary};
+#[cfg(feature = "json")]
+use crate::model::{read_json, write_json};
+use crate::normalize::Normalizer;
+use crate::prefix::PrefixIndex;
+use crate::pretokenize::{DEFAULT_BACKTRACK_LIMIT, compile, split_chunks};
+#[cfg(feature = "parallel")]
+use crate::progress::Progress;
+use crate::split::{Boundary, TextChunk, split_by_tokens};
+#[cfg(feature = "mmap")]
+use crate::stream::encode_mmap;
+#[cfg(feature = "parallel")]
+use crate::stream::{PARALLEL_PIECE_SIZE, split_safe};
+use crate::stream::{StreamDecoder, StreamEncoder, count_blocks, encode_blocks};
+use crate::table::{TableFormat, write_vocab_table};
+use crate::trace::{ChunkTrace, trace_chunk, trace_special};
+use crate::train::{TrainConfig, Trainable};
+use crate::truncate::{Truncation, encode_limited, truncate};
+use crate::validate::{ValidationReport, validate};
+use crate::visualize::visualize;
 use fancy_regex::Regex;
 use std::collections::HashMap;
+use std::fs;
+use std::io::{self, Read, Write};
+use std::ops::Range;
+use std::path::Path;
+use std::sync::Arc;
 
-const GPT4_SPLIT_PATTERN: &str = r"'(?i:[sdmt]|ll|ve|re)|[^\r\n\p{L}\p{N}]?+\p{L}+|\p{N}{1,3}| ?[^\s\p{L}\p{N}]++[\r\n]*|\s*[\r\n]|\s+(?!\S)|\s+";
+pub const GPT2_SPLIT_PATTERN: &str =
+    r"'(?:[sdmt]|ll|ve|re)| ?\p{L}+| ?\p{N}+| ?[^\s\p{L}\p{N}]+|\s+(?!\S)|\s+";
+pub const GPT4_SPLIT_PATTERN: &str = r"'(?i:[sdmt]|ll|ve|re)|[^\r\n\p{L}\p{N}]?+\p{L}+|\p{N}{1,3}| ?[^\s\p{L}\p{N}]++[\r\n]*|\s*[\r\n]|\s+(?!\S)|\s+";
+/// Split pattern tuned for source code: identifiers keep their underscores
+/// (`__init__`, `snake_case`), runs of spaces/tabs stay a single chunk so
+/// indentation is learned as a whole, contractions are not special-cased
+/// (quotes are string delimiters in code) and digit runs are capped at 3.
+pub const CODE_SPLIT_PATTERN: &str = r"[^\t\r\n\p{L}\p{N}_]?+[\p{L}_][\p{L}\p{N}_]*|\p{N}{1,3}| ?[^\s\p{L}\p{N}_]++[\r\n]*|\s*[\r\n]+|[ \t]+|\s+";
+
+/// How runs of digits are grouped into chunks by the split pattern.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DigitGrouping {
+    /// Split digit runs into chunks of at most `n` digits (GPT-4 uses 3).
+    UpTo(usize),
+    /// Keep a whole run of digits in a single chunk (GPT-2 style).
+    Unbounded,
+}
+
+/// Knobs for building a GPT-4 style split pattern. The pretokenization policy
+/// decides which byte sequences can ever be merged, so changing it changes the
+/// vocabulary that training learns.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SplitPattern {
+    pub digits: DigitGrouping,
+    /// Merge runs of whitespace into a single chunk (as GPT-4 does). When
+    /// disabled, every whitespace character becomes its own chunk.
+    pub merge_whitespace: bool,
+}
+
+impl SplitPattern {
+    /// The policy behind `GPT4_SPLIT_PATTERN`.
+    pub fn gpt4() -> Self {
+        SplitPattern {
+            digits: DigitGrouping::UpTo(3),
+            merge_whitespace: true,
+        }
+    }
+
+    pub fn digits(mut self, digits: DigitGrouping) -> Self {
+        self.digits = digits;
+        self
+    }
+
+    pub fn merge_whitespace(mut self, merge_whitespace: bool) -> Self {
+        self.merge_whitespace = merge_whitespace;
+        self
+    }
+
+    /// Render the regex, suitable for `RegexTokenizer::new_with_pattern`.
+    pub fn build(&self) -> String {
+        let digits = match self.digits {
+            DigitGrouping::UpTo(n) => {
+                assert!(n >= 1, "Digit groups must hold at least one digit");
+                format!(r"\p{{N}}{{1,{}}}", n)
+            }
+            DigitGrouping::Unbounded => r"\p{N}+".to_string(),
+        };
+        let whitespace = if self.merge_whitespace {
+            r"\s*[\r\n]|\s+(?!\S)|\s+"
+        } else {
+            r"\s"
+        };
+        format!(
+            r"'(?i:[sdmt]|ll|ve|re)|[^\r\n\p{{L}}\p{{N}}]?+\p{{L}}+|{}| ?[^\s\p{{L}}\p{{N}}]++[\r\n]*|{}",
+            digits, whitespace
+        )
+    }
+}
+
+impl Default for SplitPattern {
+    fn default() -> Self {
+        Self::gpt4()
+    }
+}
 
 pub struct RegexTokenizer {
-    merges: HashMap<(Token, Token), Token>,
-    vocab: HashMap<Token, Vec<u8>>,
+    merges: FastHashMap<(Token, Token), Token>,
+    vocab: FastHashMap<Token, Vec<u8>>,
+    /// The vocab sorted by bytes, for prefix queries.
+    prefix_index: PrefixIndex,
+    special_tokens: HashMap<String, Token>,
+    inverse_special_tokens: HashMap<Token, String>,
+    /// Special tokens that `encode` turns into their ids.
+    allowed_special: SpecialSet,
+    /// Special tokens whose literals `try_encode` rejects.
+    disallowed_special: SpecialSet,
     pattern: String,
-    cache: HashMap<String, Vec<Token>>,
+    /// Applied to text before splitting, see `set_normalizer`.
+    normalizer: Normalizer,
+    /// `pattern` compiled, see `set_backtrack_limit`.
+    split_regex: Arc<Regex>,
+    /// Tokens of recently encoded chunks.
+    cache: ChunkCache,
+    /// A cache shared with other threads, used when encoding through `&self`.
+    shared_cache: Option<Arc<SharedCache>>,
+    /// What decoding does with unknown ids, see `set_unknown_id_policy`.
+    unknown_id_policy: UnknownIdPolicy,
 }
 
 impl RegexTokenizer {
     pub fn new() -> Self {
-        let mut tokenizer = RegexTokenizer {
-            merges: HashMap::new(),
-            vocab: HashMap::new(),
-            pattern: GPT4_SPLIT_PATTERN.to_string(),
-            cache: HashMap::new(),
-        };
-        tokenizer.build_vocab();
-        tokenizer
-    }
-
-    fn get_stats(&self, ids: &[Token]) -> HashMap<(Token, Token), Token> {
-        let mut counts = HashMap::new();
-        for pair in ids.windows(2) {
-            // `windows(2)` creates pairs efficiently
-            let pair = (pair[0], pair[1]);
-            *counts.entry(pair).or_insert(0) += 1;
-        }
-        counts
-    }
-
-    fn sorted_stats(&self, stats: HashMap<(Token, Token), i32>) -> Vec<((Token, Token), i32)> {
-        let mut sorted_pairs: Vec<_> = stats.into_iter().collect();
-        sorted_pairs.sort_by(|a, b| a.1.cmp(&b.1)); // Sort in ascending order
-        sorted_pairs
-    }
-
-    fn merge(&self, ids: &[Token], pair: (Token, Token), new_token: Token) -> Vec<Token> {
-        // in the slice of ints (ids), replace all consecutive occurences of pair with the new token
-        let mut new_ids = Vec::with_capacity(ids.len());
-        let mut i = 0;
-        while i < ids.len() {
-            // if we are not at the very last position and the pair matches, replace it
-            if i < ids.len() - 1 && ids[i] == pair.0 && ids[i + 1] == pair.1 {
-                new_ids.push(new_token);
-                i += 2;
-            } else {
-                new_ids.push(ids[i]);
-                i += 1;
-            }
+        // GPT-4's pattern is known to compile
+        Self::new_with_pattern(GPT4_SPLIT_PATTERN).unwrap()
+    }
+
+    /// Create a tokenizer that splits text with `pattern`, e.g. one of
+    /// `GPT2_SPLIT_PATTERN`, `GPT4_SPLIT_PATTERN`, `CODE_SPLIT_PATTERN` or a
+    /// pattern rendered by `SplitPattern::build`. Fails if `pattern` isn't a
+    /// valid regex.
+    pub fn new_with_pattern(pattern: &str) -> io::Result<Self> {
+        let split_regex = Arc::new(compile(pattern, DEFAULT_BACKTRACK_LIMIT)?);
+        let merges = FastHashMap::default();
+        let vocab = build_vocab(&merges);
+        Ok(RegexTokenizer {
+            merges,
+            prefix_index: PrefixIndex::new(&vocab),
+            vocab,
+            special_tokens: HashMap::new(),
+            inverse_special_tokens: HashMap::new(),
+            allowed_special: SpecialSet::None,
+            disallowed_special: SpecialSet::All,
+            pattern: pattern.to_string(),
+            normalizer: Normalizer::default(),
+            split_regex,
+            cache: ChunkCache::new(CacheCapacity::default()),
+            shared_cache: None,
+            unknown_id_policy: UnknownIdPolicy::default(),
+        })
+    }
+
+    /// Save the tokenizer as a minbpe `.model` file: its split pattern,
+    /// special tokens, normalization steps and merges, loadable by the
+    /// Python minbpe as well unless the text is normalized.
+    ///
+    /// Like every `save*` method, this replaces the file atomically; wrap
+    /// the writer of `save_to_writer` in an `AtomicFile` to also `fsync` it.
+    pub fn save(&self, path: impl AsRef<Path>) -> io::Result<()> {
+        save_atomically(path.as_ref(), |file| self.save_to_writer(file))
+    }
+
+    /// Like `save`, writing the `.model` file to `writer` instead. An empty
+    /// split pattern can't be saved, as it marks a `BasicTokenizer` model.
+    pub fn save_to_writer(&self, writer: impl Write) -> io::Result<()> {
+        let model = self.model_file();
+        if model.pattern.is_empty() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "an empty split pattern would save a BasicTokenizer model",
+            ));
         }
-        new_ids
+        write_model(writer, &model)
     }
 
-    /// Train a vocabulary of size `vocab_size` in distinct Tokens from `text`.
-    pub fn train(&mut self, text: &str, vocab_size: i32) {
-        self.cache.clear();
+    /// Save the vocabulary as a `.tiktoken` ranks file, the base64 of every
+    /// token's bytes and its id, so tiktoken and tiktoken-rs can use it with
+    /// the tokenizer's split pattern. Special tokens are not part of the
+    /// format and are left out; pass them to tiktoken separately.
+    pub fn save_tiktoken(&self, path: impl AsRef<Path>) -> io::Result<()> {
+        save_atomically(path.as_ref(), |file| self.save_tiktoken_to_writer(file))
+    }
 
-        assert!(vocab_size >= 256, "Vocab size must be at least 256");
-        let num_merges = vocab_size - 256;
-
-        let text_bytes = text.as_bytes();
-        let mut ids: Vec<Token> = text_bytes.iter().map(|t| *t as Token).collect();
-
-        for i in 0..num_merges {
-            let stats = self.get_stats(&ids);
-            let mut sorted = self.sorted_stats(stats);
-            let idx = 256 + i;
-            if let Some((top_pair, _count)) = sorted.pop() {
-                ids = self.merge(&ids, top_pair, idx);
-                self.merges.insert(top_pair, idx);
-                self.vocab.insert(
-                    idx,
-                    [
-                        self.vocab[&top_pair.0].clone(),
-                        self.vocab[&top_pair.1].clone(),
-                    ]
-                    .concat(),
-                );
-            }
+    /// Like `save_tiktoken`, writing the ranks to `writer` instead.
+    pub fn save_tiktoken_to_writer(&self, writer: impl Write) -> io::Result<()> {
+        write_tiktoken(writer, &self.vocab)
+    }
+
+    /// Save the tokenizer as a HuggingFace `tokenizer.json`: a byte-level
+    /// BPE with the same vocabulary and merges, normalizing and splitting
+    /// text the same way, and the special tokens as added tokens, which
+    /// `transformers` loads with `PreTrainedTokenizerFast(tokenizer_file=...)`.
+    pub fn save_hf_tokenizer(&self, path: impl AsRef<Path>) -> io::Result<()> {
+        save_atomically(path.as_ref(), |file| self.save_hf_tokenizer_to_writer(file))
+    }
+
+    /// Like `save_hf_tokenizer`, writing the `tokenizer.json` to `writer` instead.
+    pub fn save_hf_tokenizer_to_writer(&self, writer: impl Write) -> io::Result<()> {
+        write_hf_tokenizer(
+            writer,
+            &self.normalizer,
+            Some(&self.pattern),
+            &self.vocab,
+            &self.merges,
+            &self.special_tokens,
+        )
+    }
+
+    /// Save the tokenizer in GPT-2's format, as `vocab.json` and `merges.txt`
+    /// in the existing directory `dir`, for toolchains that predate
+    /// `tokenizer.json`. The split pattern is not part of the format.
+    pub fn save_gpt2(&self, dir: impl AsRef<Path>) -> io::Result<()> {
+        let dir = dir.as_ref();
+        let mut vocab = AtomicFile::create(dir.join("vocab.json"))?;
+        let mut merges = AtomicFile::create(dir.join("merges.txt"))?;
+        self.save_gpt2_to_writers(&mut vocab, &mut merges)?;
+        vocab.commit()?;
+        merges.commit()
+    }
+
+    /// Like `save_gpt2`, writing `vocab.json` to `vocab_writer` and
+    /// `merges.txt` to `merges_writer` instead.
+    pub fn save_gpt2_to_writers(
+        &self,
+        vocab_writer: impl Write,
+        merges_writer: impl Write,
+    ) -> io::Result<()> {
+        write_gpt2_files(
+            vocab_writer,
+            merges_writer,
+            &self.vocab,
+            &self.merges,
+            &self.special_tokens,
+        )
+    }
+
+    /// The `tokenizer.ggml.*` metadata llama.cpp reads from a GGUF file, to
+    /// embed the tokenizer in a model with its GGUF writer. llama.cpp doesn't read
+    /// the split pattern but knows GPT-2's and GPT-4's by name, and uses its
+    /// default for others.
+    pub fn gguf_metadata(&self) -> io::Result<Vec<(String, GgufValue)>> {
+        tokenizer_metadata(
+            &self.vocab,
+            &self.merges,
+            &self.special_tokens,
+            pre_tokenizer_name(Some(&self.pattern)),
+        )
+    }
+
+    /// Save `gguf_metadata` as a vocab-only GGUF file.
+    pub fn save_gguf_vocab(&self, path: impl AsRef<Path>) -> io::Result<()> {
+        save_atomically(path.as_ref(), |file| self.save_gguf_vocab_to_writer(file))
+    }
+
+    /// Like `save_gguf_vocab`, writing the GGUF file to `writer` instead.
+    pub fn save_gguf_vocab_to_writer(&self, writer: impl Write) -> io::Result<()> {
+        write_gguf(writer, &self.gguf_metadata()?)
+    }
+
+    /// The tokenizer in the metadata of the GGUF file at `path`, e.g. a
+    /// llama.cpp model or one `save_gguf_vocab` wrote. Its split pattern is
+    /// the one `tokenizer.ggml.pre` names, which must be GPT-4's
+    /// ("llama-bpe") or GPT-2's ("gpt-2"), and every byte must have its own
+    /// id; a vocab with GPT-4's pattern and shuffled bytes, like LLaMA 3's,
+    /// loads with `GPT4Tokenizer::from_gguf`.
+    pub fn from_gguf(path: impl AsRef<Path>) -> io::Result<Self> {
+        Self::from_gguf_reader(fs::File::open(path)?)
+    }
+
+    /// Like `from_gguf`, reading the GGUF file from `reader` instead.
+    pub fn from_gguf_reader(reader: impl Read) -> io::Result<Self> {
+        let vocab = read_gguf_vocab(reader)?;
+        vocab.check_unshuffled()?;
+        let pattern = pre_tokenizer_pattern(&vocab.pre).ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("unknown GGUF pre-tokenizer {:?}", vocab.pre),
+            )
+        })?;
+        let mut tokenizer = Self::new_with_pattern(pattern)?;
+        tokenizer.set_merges(vocab.merges);
+        check_special_ids(&tokenizer.vocab, &vocab.special_tokens)
+            .map_err(|msg| io::Error::new(io::ErrorKind::InvalidData, msg))?;
+        tokenizer.set_special_tokens(vocab.special_tokens);
+        Ok(tokenizer)
+    }
+
+    /// Save the minbpe `.vocab` listing of the tokenizer, for eyeballing what
+    /// it learned: every token's rendered bytes (see `render_token`) and the
+    /// merge that produced it. It can't be loaded back, use `save` for that.
+    pub fn save_vocab(&self, path: impl AsRef<Path>) -> io::Result<()> {
+        save_atomically(path.as_ref(), |file| self.save_vocab_to_writer(file))
+    }
+
+    /// Like `save_vocab`, writing the listing to `writer` instead.
+    pub fn save_vocab_to_writer(&self, writer: impl Write) -> io::Result<()> {
+        write_vocab(
+            writer,
+            &self.vocab,
+            &self.merges,
+            &self.inverse_special_tokens,
+        )
+    }
+
+    /// Export the merges as a Graphviz DOT graph, in which every merged
+    /// token points at the two tokens it was merged from, to visualize how
+    /// long tokens were built up (e.g. `dot -Tsvg merges.dot`).
+    pub fn export_merge_graph(&self, path: impl AsRef<Path>) -> io::Result<()> {
+        save_atomically(path.as_ref(), |file| {
+            self.export_merge_graph_to_writer(file)
+        })
+    }
+
+    /// Like `export_merge_graph`, writing the graph to `writer` instead.
+    pub fn export_merge_graph_to_writer(&self, writer: impl Write) -> io::Result<()> {
+        write_merge_graph(writer, &self.vocab, &self.merges)
+    }
+
+    /// Export the vocabulary as a CSV or TSV table with a row per token: its
+    /// id, merge rank, length in bytes, escaped text and the ids it was
+    /// merged from, to analyze it with pandas, duckdb or a spreadsheet.
+    pub fn export_vocab_table(
+        &self,
+        path: impl AsRef<Path>,
+        format: TableFormat,
+    ) -> io::Result<()> {
+        save_atomically(path.as_ref(), |file| {
+            self.export_vocab_table_to_writer(file, format)
+        })
+    }
+
+    /// Like `export_vocab_table`, writing the table to `writer` instead.
+    pub fn export_vocab_table_to_writer(
+        &self,
+        writer: impl Write,
+        format: TableFormat,
+    ) -> io::Result<()> {
+        write_vocab_table(
+            writer,
+            format,
+            &self.vocab,
+            &self.merges,
+            &self.special_tokens,
+        )
+    }
+
+    /// Load a tokenizer from a minbpe `.model` file, e.g. one trained with the
+    /// Python minbpe.
+    pub fn load(path: impl AsRef<Path>) -> io::Result<Self> {
+        Self::load_from_reader(fs::File::open(path)?)
+    }
+
+    /// Like `load`, reading the `.model` file from `reader`, e.g. embedded
+    /// bytes or a network stream.
+    pub fn load_from_reader(reader: impl Read) -> io::Result<Self> {
+        Self::from_model_file(read_model(reader)?)
+            .map_err(|msg| io::Error::new(io::ErrorKind::InvalidData, msg))
+    }
+
+    /// Save the tokenizer in a compact binary format with a version header,
+    /// quicker to load and smaller than a `.model` file.
+    #[cfg(feature = "binary")]
+    pub fn save_binary(&self, path: impl AsRef<Path>) -> io::Result<()> {
+        save_atomically(path.as_ref(), |file| self.save_binary_to_writer(file))
+    }
+
+    /// Like `save_binary`, writing the model to `writer` instead.
+    #[cfg(feature = "binary")]
+    pub fn save_binary_to_writer(&self, writer: impl Write) -> io::Result<()> {
+        write_binary(writer, &self.model_file())
+    }
+
+    /// Load a tokenizer saved by `save_binary`. Its checksum is verified, so
+    /// a corrupted file is an `InvalidData` error.
+    #[cfg(feature = "binary")]
+    pub fn load_binary(path: impl AsRef<Path>) -> io::Result<Self> {
+        Self::load_binary_from_reader(fs::File::open(path)?)
+    }
+
+    /// Like `load_binary`, reading the model from `reader`.
+    #[cfg(feature = "binary")]
+    pub fn load_binary_from_reader(reader: impl Read) -> io::Result<Self> {
+        Self::from_model_file(read_binary(reader)?)
+            .map_err(|msg| io::Error::new(io::ErrorKind::InvalidData, msg))
+    }
+
+    /// Save the tokenizer as pretty JSON, to diff and inspect it with
+    /// standard tools: its pattern, special tokens, merges in order and the
+    /// bytes of every token by id.
+    #[cfg(feature = "json")]
+    pub fn save_json(&self, path: impl AsRef<Path>) -> io::Result<()> {
+        save_atomically(path.as_ref(), |file| self.save_json_to_writer(file))
+    }
+
+    /// Like `save_json`, writing the JSON to `writer` instead.
+    #[cfg(feature = "json")]
+    pub fn save_json_to_writer(&self, writer: impl Write) -> io::Result<()> {
+        write_json(writer, &self.model_file(), &self.vocab)
+    }
+
+    /// Load a tokenizer saved by `save_json`. Its checksum is verified, so
+    /// a corrupted or edited file is an `InvalidData` error.
+    #[cfg(feature = "json")]
+    pub fn load_json(path: impl AsRef<Path>) -> io::Result<Self> {
+        Self::load_json_from_reader(fs::File::open(path)?)
+    }
+
+    /// Like `load_json`, reading the JSON from `reader`.
+    #[cfg(feature = "json")]
+    pub fn load_json_from_reader(reader: impl Read) -> io::Result<Self> {
+        Self::from_model_file(read_json(reader)?)
+            .map_err(|msg| io::Error::new(io::ErrorKind::InvalidData, msg))
+    }
+
+    fn model_file(&self) -> ModelFile {
+        let mut merges: Vec<Merge> = self
+            .merges
+            .iter()
+            .map(|(&pair, &idx)| (pair, idx))
+            .collect();
+        merges.sort_by_key(|&(_, idx)| idx);
+        ModelFile {
+            pattern: self.pattern.clone(),
+            special_tokens: self.special_tokens.clone(),
+            merges,
+            normalization: self.normalizer.steps(),
         }
     }
 
+    fn from_model_file(model: ModelFile) -> Result<Self, String> {
+        let mut tokenizer = Self::new_with_pattern(&model.pattern).map_err(|e| e.to_string())?;
+        tokenizer.set_normalizer(Normalizer::from_steps(&model.normalization)?);
+        tokenizer.set_merges(model.merges);
+        check_special_ids(&tokenizer.vocab, &model.special_tokens)?;
+        tokenizer.set_special_tokens(model.special_tokens);
+        Ok(tokenizer)
+    }
+
+    /// Bound the cache of encoded chunks, evicting the least recently used
+    /// ones past `capacity`, or turn it off with `CacheCapacity::Disabled`.
+    /// It holds up to 65536 chunks by default.
+    pub fn set_cache_capacity(&mut self, capacity: CacheCapacity) {
+        self.cache.set_capacity(capacity);
+    }
+
+    /// Let the methods encoding through `&self` (`encode_shared`,
+    /// `encode_batch_parallel`, `count_tokens_reader`) look up and fill
+    /// `cache`, so threads encoding through them benefit from each other's
+    /// hits. The cache may also be shared between tokenizers, as long as
+    /// they have the same merges.
+    pub fn set_shared_cache(&mut self, cache: Arc<SharedCache>) {
+        self.shared_cache = Some(cache);
+    }
+
+    /// Normalize text before splitting it into chunks, when encoding and
+    /// training. Methods reporting byte offsets (`encode_with_offsets`,
+    /// `encode_with_limit`, `split_by_tokens`) still refer to the text as
+    /// given, and `truncate_to_tokens` keeps part of it.
+    pub fn set_normalizer(&mut self, normalizer: Normalizer) {
+        self.normalizer = normalizer;
+    }
+
+    /// Set what decoding does with ids that are neither in the vocab nor a
+    /// special token, by default `UnknownIdPolicy::Error`.
+    pub fn set_unknown_id_policy(&mut self, policy: UnknownIdPolicy) {
+        self.unknown_id_policy = policy;
+    }
+
+    /// Check that the merges, vocab and special tokens agree with each
+    /// other, e.g. after loading a model from an untrusted file, and report
+    /// every problem found.
+    pub fn validate(&self) -> ValidationReport {
+        validate(&self.merges, &self.vocab, &self.special_tokens)
+    }
+
+    /// The tokenizer if `validate` finds no problems, so a model can be
+    /// checked as it's loaded, e.g. `Self::load(path)?.validated()?`.
+    pub fn validated(self) -> Result<Self, ValidationReport> {
+        let report = self.validate();
+        if report.is_valid() {
+            Ok(self)
+        } else {
+            Err(report)
+        }
+    }
+
+    /// Bound the backtracking steps the split pattern may take to find a
+    /// chunk (1,000,000 by default), so adversarial input (e.g. very long
+    /// runs of unusual punctuation) can't hang encoding. Past the limit, the
+    /// rest of the line is split into runs of letters, digits, whitespace and
+    /// other characters instead.
+    pub fn set_backtrack_limit(&mut self, limit: usize) {
+        // the pattern already compiled once, the limit can't break it
+        self.split_regex = Arc::new(compile(&self.pattern, limit).unwrap());
+    }
+
+    /// The compiled split pattern, which methods taking `&mut self` can hold
+    /// on to while encoding.
+    fn split_regex(&self) -> Arc<Regex> {
+        Arc::clone(&self.split_regex)
+    }
+
     // Given a string, return a list of integers (tokens)
-    fn bpe(&mut self, text: &str) -> Vec<Token> {
+    fn bpe(&mut self, text: &str, out: &mut Vec<Token>) {
         if let Some(cached) = self.cache.get(text) {
-            return cached.clone();
-        }
-        // Convert all bytes to integers in range 0..255
-        let text_bytes = text.as_bytes();
-        let mut ids: Vec<i32> = text_bytes.into_iter().map(|&t| t as Token).collect();
-
-        while ids.len() >= 2 {
-            let stats = self.get_stats(&ids);
-            // Find the pair with the lowest merge index
-            let pair = stats
-                .keys()
-                .min_by_key(|&&p| self.merges.get(&p).unwrap_or(&i32::MAX));
-            // If no valid merge is found, stop
-            if let Some(&pair) = pair {
-                if !self.merges.contains_key(&pair) {
-                    break;
-                }
-                // Merge the best pair
-                let idx = self.merges[&pair];
-                ids = self.merge(&ids, pair, idx);
-            } else {
-                break;
-            }
+            out.extend_from_slice(cached);
+            return;
         }
-        self.cache.insert(text.to_string(), ids.clone());
+        let ids = encode_bytes(text.as_bytes(), &self.merges);
+        out.extend_from_slice(&ids);
+        self.cache.insert(text, ids);
+    }
+
+    /// Encode `text` with the split pattern and merges only, treating any
+    /// special token literals as plain text.
+    pub fn encode_ordinary(&mut self, text: &str) -> Vec<Token> {
+        let mut ids = Vec::new();
+        self.encode_ordinary_into(text, &mut ids);
         ids
     }
 
-    fn build_vocab(&mut self) {
-        self.vocab = (0..256).map(|idx| (idx, vec![idx as u8])).collect();
-        // Reconstruct the vocab
-        for ((p0, p1), idx) in &self.merges {
-            if let (Some(v0), Some(v1)) = (self.vocab.get(&p0), self.vocab.get(&p1)) {
-                let mut merged = v0.clone();
-                merged.extend(v1);
-                self.vocab.insert(*idx, merged);
-            }
+    /// Like `bpe`, but through `&self`: the own cache is only read, and the
+    /// shared one (if any) is read and filled.
+    fn bpe_shared(&self, text: &str, out: &mut Vec<Token>) {
+        if let Some(cached) = self.cache.peek(text) {
+            out.extend_from_slice(cached);
+            return;
+        }
+        let Some(shared) = &self.shared_cache else {
+            out.extend(encode_bytes(text.as_bytes(), &self.merges));
+            return;
+        };
+        if !shared.get_into(text, out) {
+            let ids = encode_bytes(text.as_bytes(), &self.merges);
+            out.extend_from_slice(&ids);
+            shared.insert(text, ids);
         }
     }
-}
 
-impl Tokenizer for RegexTokenizer {
-    /// A Tokenizer can encode a string into a list of integers.
-    fn encode(&mut self, text: &str) -> Vec<Token> {
+    fn encode_ordinary_into(&mut self, text: &str, out: &mut Vec<Token>) {
+        let text = self.normalizer.normalize(text);
         // split text into chunks of text by categories defined in regex pattern
-        let re = Regex::new(&self.pattern).unwrap();
-        let text_chunks: Vec<_> = re.find_iter(text).map(|m| m.unwrap().as_str()).collect();
+        let re = self.split_regex();
+        let text_chunks: Vec<_> = split_chunks(&re, &text).collect();
         // all chunks of text are encoded separately, then results are joined
-        let mut ids: Vec<Token> = Vec::new();
         for chunk in text_chunks {
-            let chunk_ids = self.bpe(chunk);
-            ids.extend(chunk_ids);
+            self.bpe(chunk, out);
+        }
+    }
+
+    /// Encode `bytes` that may not be valid UTF-8, e.g. scraped web data.
+    /// Valid runs are encoded like `encode`, and every invalid byte becomes
+    /// its own byte-level token, so the data decodes back unchanged instead
+    /// of being lossy-converted up front.
+    pub fn encode_raw(&mut self, bytes: &[u8]) -> Vec<Token> {
+        let mut ids = Vec::new();
+        for chunk in bytes.utf8_chunks() {
+            self.encode_into(chunk.valid(), &mut ids);
+            ids.extend(chunk.invalid().iter().map(|&b| b as Token));
         }
         ids
     }
 
-    /// A Tokenizer can decode a list of integers into a string.
-    fn decode(&self, ids: &[Token]) -> String {
-        // Decode the ids into bytes
+    /// Encode `text` like `encode`, but through a shared reference, so one
+    /// tokenizer can serve several threads at once. Chunks are looked up in
+    /// the shared cache set with `set_shared_cache`, if any.
+    pub fn encode_shared(&self, text: &str) -> Vec<Token> {
+        let re = self.split_regex();
+        let mut ids = Vec::new();
+        self.encode_shared_into(&re, text, &mut ids);
+        ids
+    }
+
+    fn encode_shared_into(&self, re: &Regex, text: &str, out: &mut Vec<Token>) {
+        // nothing is disallowed, so this can't fail
+        let pieces = special_pieces(
+            text,
+            &self.special_tokens,
+            &self.allowed_special,
+            &SpecialSet::None,
+        )
+        .unwrap();
+        for piece in pieces {
+            match piece {
+                Piece::Text(text) => {
+                    let text = self.normalizer.normalize(text);
+                    for chunk in split_chunks(re, &text) {
+                        self.bpe_shared(chunk, out);
+                    }
+                }
+                Piece::Special(idx) => out.push(idx),
+            }
+        }
+    }
+
+    /// Encode `text` like `encode`, appending the tokens to `out`. Reusing
+    /// one buffer saves an allocation per call when encoding many short
+    /// strings.
+    pub fn encode_into(&mut self, text: &str, out: &mut Vec<Token>) {
+        // nothing is disallowed, so this can't fail
+        let pieces = special_pieces(
+            text,
+            &self.special_tokens,
+            &self.allowed_special,
+            &SpecialSet::None,
+        )
+        .unwrap();
+        self.encode_pieces(pieces, out);
+    }
+
+    /// Register special tokens, e.g. `{"<|endoftext|>": 100257}`, replacing
+    /// the current ones. They are decoded to their literal text and can be
+    /// produced by `encode_with_special`. A special token with the id of a
+    /// byte or merged token is an `InvalidInput` error.
+    pub fn register_special_tokens(
+        &mut self,
+        special_tokens: HashMap<String, Token>,
+    ) -> io::Result<()> {
+        check_special_ids(&self.vocab, &special_tokens)
+            .map_err(|msg| io::Error::new(io::ErrorKind::InvalidInput, msg))?;
+        self.set_special_tokens(special_tokens);
+        Ok(())
+    }
+
+    /// Choose the special tokens that `encode` and `try_encode` recognize,
+    /// none by default, so text from users can't inject control tokens. The
+    /// literals of the others are encoded as plain text by `encode`.
+    pub fn set_allowed_special(&mut self, allowed: SpecialSet) {
+        self.allowed_special = allowed;
+    }
+
+    /// Choose the special tokens whose literals `try_encode` rejects unless
+    /// they are also allowed, all registered ones by default.
+    pub fn set_disallowed_special(&mut self, disallowed: SpecialSet) {
+        self.disallowed_special = disallowed;
+    }
+
+    /// Encode `text` like `encode`, but fail on the literal of a special
+    /// token that is disallowed and not allowed, as tiktoken's `encode` does:
+    /// by default, on any registered special token.
+    pub fn try_encode(&mut self, text: &str) -> Result<Vec<Token>, EncodeError> {
+        let pieces = special_pieces(
+            text,
+            &self.special_tokens,
+            &self.allowed_special,
+            &self.disallowed_special,
+        )?;
+        let mut ids = Vec::new();
+        self.encode_pieces(pieces, &mut ids);
+        Ok(ids)
+    }
+
+    /// Encode `text`, turning the literals of the `allowed` special tokens into
+    /// their ids. The literals of `disallowed` special tokens are an error
+    /// (pass `SpecialSet::None` to encode them as plain text instead), as in
+    /// tiktoken, where `SpecialSet::All` is the default for `disallowed`.
+    pub fn encode_with_special(
+        &mut self,
+        text: &str,
+        allowed: &SpecialSet,
+        disallowed: &SpecialSet,
+    ) -> Result<Vec<Token>, EncodeError> {
+        let pieces = special_pieces(text, &self.special_tokens, allowed, disallowed)?;
+        let mut ids = Vec::new();
+        self.encode_pieces(pieces, &mut ids);
+        Ok(ids)
+    }
+
+    /// Encode `texts` like `encode`, spread over rayon's thread pool, with
+    /// the results in the order of `texts` and, with `padding`, all of the
+    /// same length. The tokenizer's own cache is read but not updated, the
+    /// shared one (see `set_shared_cache`) is both.
+    #[cfg(feature = "parallel")]
+    pub fn encode_batch_parallel<S: AsRef<str> + Sync>(
+        &self,
+        texts: &[S],
+        padding: Option<&Padding>,
+    ) -> Vec<Vec<Token>> {
+        use rayon::prelude::*;
+
+        let re = self.split_regex();
+        let progress = Progress::new(texts.len(), "encoding");
+        let mut batch: Vec<Vec<Token>> = texts
+            .par_iter()
+            .map(|text| {
+                let mut ids = Vec::new();
+                self.encode_shared_into(&re, text.as_ref(), &mut ids);
+                progress.inc(1);
+                ids
+            })
+            .collect();
+        progress.finish();
+        if let Some(padding) = padding {
+            padding.apply(&mut batch);
+        }
+        batch
+    }
+
+    /// Decode `batch` like `decode`, spread over rayon's thread pool, with
+    /// the texts in the order of `batch`.
+    #[cfg(feature = "parallel")]
+    pub fn decode_batch_parallel<S: AsRef<[Token]> + Sync>(&self, batch: &[S]) -> Vec<String> {
+        use rayon::prelude::*;
+
+        let progress = Progress::new(batch.len(), "decoding");
+        let texts = batch
+            .par_iter()
+            .map(|ids| {
+                let text = self.decode(ids.as_ref());
+                progress.inc(1);
+                text
+            })
+            .collect();
+        progress.finish();
+        texts
+    }
+
+    /// Encode one large `text` like `encode`, cut at safe boundaries (after a
+    /// newline followed by non-whitespace, where the split pattern always
+    /// ends a chunk) into pieces encoded on rayon's thread pool. The tokens
+    /// are exactly those of sequential encoding.
+    /// Chunks are looked up in the caches like `encode_shared` does.
+    #[cfg(feature = "parallel")]
+    pub fn encode_parallel(&self, text: &str) -> Vec<Token> {
+        use rayon::prelude::*;
+
+        let re = self.split_regex();
+        // nothing is disallowed, so this can't fail
+        let pieces = special_pieces(
+            text,
+            &self.special_tokens,
+            &self.allowed_special,
+            &SpecialSet::None,
+        )
+        .unwrap();
+        let encoded: Vec<Vec<Token>> = pieces
+            .into_iter()
+            .flat_map(|piece| match piece {
+                Piece::Text(text) => split_safe(text, PARALLEL_PIECE_SIZE)
+                    .into_iter()
+                    .map(Piece::Text)
+                    .collect(),
+                special => vec![special],
+            })
+            .collect::<Vec<Piece>>()
+            .into_par_iter()
+            .map(|piece| match piece {
+                // the allowed specials are split off, so a piece has none
+                Piece::Text(text) => {
+                    let mut ids = Vec::new();
+                    self.encode_shared_into(&re, text, &mut ids);
+                    ids
+                }
+                Piece::Special(idx) => vec![idx],
+            })
+            .collect();
+        encoded.concat()
+    }
+
+    /// Encode everything `reader` yields like `encode`, streaming the tokens
+    /// out as the input is read, so arbitrarily large files are tokenized in
+    /// constant memory. The input is cut into blocks after a newline that is
+    /// followed by non-whitespace, where the split pattern always ends a
+    /// chunk, so the tokens match encoding the whole text at once (unless a
+    /// special token literal spans such a newline, or a single line exceeds
+    /// 16 MiB). Invalid UTF-8 and read errors are yielded as errors.
+    pub fn encode_reader<R: Read>(&mut self, reader: R) -> impl Iterator<Item = io::Result<Token>> {
+        encode_blocks(reader, move |text| self.encode(text))
+    }
+
+    /// Encode the file at `path` like `encode_reader`, through a memory map
+    /// instead of reads into a buffer, which saves copies and syscalls on
+    /// very large files. Windows are cut at safe boundaries, so the tokens
+    /// match encoding the whole text at once. The file must not be modified
+    /// while it is being encoded; invalid UTF-8 is an `InvalidData` error.
+    #[cfg(feature = "mmap")]
+    pub fn encode_file_mmap(&mut self, path: impl AsRef<Path>) -> io::Result<Vec<Token>> {
+        encode_mmap(path.as_ref(), |text, out| self.encode_into(text, out))
+    }
+
+    /// Count the tokens `encode` would produce for everything `reader` yields,
+    /// in constant memory and without collecting the tokens, e.g. to budget
+    /// a multi-gigabyte dataset. The input is read in the same blocks as
+    /// `encode_reader`.
+    pub fn count_tokens_reader<R: Read>(&self, reader: R) -> io::Result<u64> {
+        let re = self.split_regex();
+        let mut ids = Vec::new();
+        count_blocks(reader, |text| {
+            ids.clear();
+            self.encode_shared_into(&re, text, &mut ids);
+            ids.len()
+        })
+    }
+
+    /// Decode `ids` to the raw bytes of their tokens, without the UTF-8
+    /// conversion of `decode`, so binary data round-tripped through tokens
+    /// comes back intact. Unknown ids are handled by the `UnknownIdPolicy`,
+    /// like in `decode`.
+    pub fn decode_to_bytes(&self, ids: &[Token]) -> Vec<u8> {
         let mut text_bytes = Vec::new();
         for &id in ids {
-            if let Some(bytes) = self.vocab.get(&id) {
-                text_bytes.extend(bytes);
+            self.decode_id(id, &mut text_bytes);
+        }
+        text_bytes
+    }
+
+    /// Decode ids as an iterator yields them, e.g. from a channel or a
+    /// generation loop, like `decode` but without collecting them first.
+    pub fn decode_iter<I: IntoIterator<Item = Token>>(&self, ids: I) -> String {
+        let mut text_bytes = Vec::new();
+        for id in ids {
+            self.decode_id(id, &mut text_bytes);
+        }
+        // only copy the bytes if some need replacing
+        String::from_utf8(text_bytes)
+            .unwrap_or_else(|e| String::from_utf8_lossy(e.as_bytes()).into_owned())
+    }
+
+    /// Decode `ids` like `decode`, but fail on the first id that is neither
+    /// in the vocab nor a special token instead of replacing it, if the
+    /// `UnknownIdPolicy` is `Error`.
+    pub fn try_decode(&self, ids: &[Token]) -> Result<String, DecodeError> {
+        let mut text_bytes = Vec::new();
+        for (position, &id) in ids.iter().enumerate() {
+            if !self.try_decode_id(id, &mut text_bytes) {
+                return Err(DecodeError::UnknownId { position, id });
+            }
+        }
+        Ok(String::from_utf8_lossy(&text_bytes).into_owned())
+    }
+
+    /// Decode `ids` like `decode`, handling ids that are neither in the vocab
+    /// nor a special token by `policy`.
+    pub fn decode_lossy(&self, ids: &[Token], policy: &LossyPolicy) -> String {
+        decode_lossy(ids, policy, |id, out| {
+            decode_token(id, &self.vocab, &self.inverse_special_tokens, out)
+        })
+    }
+
+    /// Decode `ids` like `decode`, rendering special tokens by `rendering`,
+    /// e.g. to drop `<|endoftext|>` from model output.
+    pub fn decode_with_specials(&self, ids: &[Token], rendering: &SpecialRendering) -> String {
+        decode_with_specials(
+            ids,
+            rendering,
+            |id| self.inverse_special_tokens.contains_key(&id),
+            |id, out| {
+                self.decode_id(id, out);
+                true
+            },
+        )
+    }
+
+    /// Render token `id` for display with `render_token`, e.g. for vocab dumps.
+    /// Returns `None` for an unknown id.
+    pub fn render_token(&self, id: Token) -> Option<String> {
+        let mut bytes = Vec::new();
+        decode_token(id, &self.vocab, &self.inverse_special_tokens, &mut bytes)
+            .then(|| render_token(&bytes))
+    }
+
+    /// Decode `ids` like `decode`, also returning the byte range of the text
+    /// every id produced, e.g. to map attention or logprobs back onto the
+    /// generated text. Invalid UTF-8 is replaced like `String::from_utf8_lossy`
+    /// does; a range may start or end inside a character split over tokens.
+    pub fn decode_with_spans(&self, ids: &[Token]) -> (String, Vec<Range<usize>>) {
+        decode_with_spans(ids, |id, out| self.decode_id(id, out))
+    }
+
+    /// Render the tokens of `text` for a terminal, each on its own ANSI
+    /// background color, and with `show_ids` their ids below, e.g. to see
+    /// at a glance how a string is split.
+    pub fn visualize(&mut self, text: &str, show_ids: bool) -> String {
+        let ids = self.encode(text);
+        let (decoded, spans) = self.decode_with_spans(&ids);
+        visualize(&decoded, &ids, &spans, show_ids)
+    }
+
+    /// Decode only the text `ids` adds to the previously decoded `prev`, for
+    /// streaming output that is appended as tokens arrive. A character split
+    /// over tokens is held back until it is complete, so nothing returned
+    /// earlier ever changes. Panics if `ids` doesn't start with `prev`.
+    pub fn decode_delta(&self, prev: &[Token], ids: &[Token]) -> String {
+        decode_delta(prev, ids, |id, out| self.decode_id(id, out))
+    }
+
+    /// Decode `ids` like `decode`, appending the text to `out` instead of
+    /// building a new `String`.
+    pub fn decode_into(&self, ids: &[Token], out: &mut String) {
+        decode_into(ids, out, |id, out| self.decode_id(id, out))
+    }
+
+    /// Decode `ids` like `decode`, writing the text to `writer` as it is
+    /// decoded instead of building a `String`.
+    pub fn decode_to_writer<W: Write>(&self, ids: &[Token], writer: W) -> io::Result<()> {
+        decode_to_writer(ids, writer, |id, out| self.decode_id(id, out))
+    }
+
+    /// Decode tokens arriving one at a time, see `StreamDecoder`.
+    pub fn stream_decoder(&self) -> StreamDecoder<'_> {
+        StreamDecoder::new(|idx, out| self.decode_id(idx, out))
+    }
+
+    /// Append the bytes of `id` to `out`, handling an unknown id by the
+    /// `UnknownIdPolicy`. Returns false if the policy makes it an error.
+    fn try_decode_id(&self, id: Token, out: &mut Vec<u8>) -> bool {
+        decode_token(id, &self.vocab, &self.inverse_special_tokens, out)
+            || self.unknown_id_policy.apply(out)
+    }
+
+    /// Like `try_decode_id`, but an error becomes U+FFFD.
+    fn decode_id(&self, id: Token, out: &mut Vec<u8>) {
+        if !decode_token(id, &self.vocab, &self.inverse_special_tokens, out) {
+            self.unknown_id_policy.apply_or_replace(out);
+        }
+    }
+
+    /// Encode text that arrives in fragments, see `StreamEncoder`.
+    pub fn stream_encoder(&mut self) -> StreamEncoder<'_, Self> {
+        StreamEncoder::new(self)
+    }
+
+    fn encode_pieces(&mut self, pieces: Vec<Piece>, out: &mut Vec<Token>) {
+        for piece in pieces {
+            match piece {
+                Piece::Text(text) => self.encode_ordinary_into(text, out),
+                Piece::Special(idx) => out.push(idx),
             }
         }
-        // Convert bytes to String
-        String::from_utf8(text_bytes).unwrap_or_else(|_| "�".to_string())
+    }
+
+    /// The tokens whose bytes start with `prefix`, e.g. to find the tokens
+    /// allowed next during constrained decoding. Special tokens are not
+    /// included.
+    pub fn tokens_with_byte_prefix(&self, prefix: &[u8]) -> impl Iterator<Item = Token> + use<'_> {
+        self.prefix_index.with_prefix(prefix)
+    }
+
+    /// Prepare encoded prompt `ids` for token healing: remove up to
+    /// `backtrack` trailing tokens and return their bytes along with every
+    /// token extending them.
+    pub fn heal_tokens(&self, ids: &[Token], backtrack: usize) -> TokenHealing {
+        heal_tokens(ids, backtrack, &self.vocab, &self.prefix_index)
+    }
+
+    /// Encode `text` like `encode`, returning every chunk the split pattern
+    /// cut it into with its tokens and the merges that produced them, to
+    /// investigate why a string tokenizes unexpectedly.
+    pub fn encode_debug(&self, text: &str) -> Vec<ChunkTrace> {
+        let re = self.split_regex();
+        // nothing is disallowed, so this can't fail
+        let pieces = special_pieces(
+            text,
+            &self.special_tokens,
+            &self.allowed_special,
+            &SpecialSet::None,
+        )
+        .unwrap();
+        let mut traces = Vec::new();
+        for piece in pieces {
+            match piece {
+                Piece::Text(text) => {
+                    let text = self.normalizer.normalize(text);
+                    for chunk in split_chunks(&re, &text) {
+                        let ids = chunk.bytes().map(|b| b as Token);
+                        traces.push(trace_chunk(chunk, ids.collect(), &self.merges));
+                    }
+                }
+                Piece::Special(idx) => {
+                    traces.push(trace_special(&self.inverse_special_tokens[&idx], idx))
+                }
+            }
+        }
+        traces
+    }
+
+    /// Encode `text` like `encode`, pairing every token with the byte range
+    /// of `text` it came from, e.g. to highlight tokens or align spans. A
+    /// range may start or end inside a character that is split over tokens,
+    /// and covers all the characters the normalizer replaced that it holds
+    /// part of (e.g. the `\r\n` of a `\n` token).
+    pub fn encode_with_offsets(&mut self, text: &str) -> Vec<(Token, Range<usize>)> {
+        let ids = self.encode(text);
+        // nothing is disallowed, so this can't fail
+        let pieces = special_pieces(
+            text,
+            &self.special_tokens,
+            &self.allowed_special,
+            &SpecialSet::None,
+        )
+        .unwrap();
+        let offsets = self.normalizer.offset_map(text, &pieces);
+        token_offsets(ids, &self.vocab, &self.inverse_special_tokens)
+            .into_iter()
+            .map(|(id, range)| (id, offsets.start(range.start)..offsets.end(range.end)))
+            .collect()
+    }
+
+    /// Encode `text` like `encode`, but stop before the first chunk that
+    /// would take the count past `max_tokens`, e.g. to fill a context window
+    /// without tokenizing a whole document. Returns the tokens and the number
+    /// of bytes of `text` consumed; the tokens are exactly the encoding of
+    /// that prefix.
+    pub fn encode_with_limit(&mut self, text: &str, max_tokens: usize) -> (Vec<Token>, usize) {
+        let re = self.split_regex();
+        // nothing is disallowed, so this can't fail
+        let pieces = special_pieces(
+            text,
+            &self.special_tokens,
+            &self.allowed_special,
+            &SpecialSet::None,
+        )
+        .unwrap();
+        let normalizer = self.normalizer;
+        encode_limited(text, pieces, &normalizer, &re, max_tokens, |chunk, out| {
+            self.bpe(chunk, out)
+        })
+    }
+
+    /// Cut `text` to at most `max_tokens` tokens at token boundaries, giving
+    /// up the part chosen by `truncation`. Returns the kept text and its exact
+    /// token count. The kept text is part of `text` as given, not normalized.
+    pub fn truncate_to_tokens(
+        &mut self,
+        text: &str,
+        max_tokens: usize,
+        truncation: &Truncation,
+    ) -> (String, usize) {
+        truncate(text, max_tokens, truncation, |text| {
+            self.encode_with_offsets(text)
+        })
+    }
+
+    /// Split `text` into consecutive pieces of at most `max_tokens` tokens,
+    /// each overlapping the previous one by `overlap_tokens` tokens, e.g. for
+    /// retrieval ingestion. Every piece comes with its span and exact count.
+    pub fn split_by_tokens<'a>(
+        &mut self,
+        text: &'a str,
+        max_tokens: usize,
+        overlap_tokens: usize,
+    ) -> Vec<TextChunk<'a>> {
+        self.split_by_tokens_at(text, max_tokens, overlap_tokens, Boundary::Token)
+    }
+
+    /// Like `split_by_tokens`, but prefer ending pieces at sentence or
+    /// paragraph boundaries, cutting a sentence only when it doesn't fit on
+    /// its own.
+    pub fn split_by_tokens_at<'a>(
+        &mut self,
+        text: &'a str,
+        max_tokens: usize,
+        overlap_tokens: usize,
+        boundary: Boundary,
+    ) -> Vec<TextChunk<'a>> {
+        split_by_tokens(text, max_tokens, overlap_tokens, boundary, |text| {
+            self.encode_with_offsets(text)
+        })
+    }
+}
+
+impl Default for RegexTokenizer {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Serialized as its split pattern, normalization steps, merges and special tokens.
+#[cfg(feature = "serde")]
+impl serde::Serialize for RegexTokenizer {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serde::Serialize::serialize(&self.model_file(), serializer)
+    }
+}
+
+#[cfg(feature = "serde")]
+impl<'de> serde::Deserialize<'de> for RegexTokenizer {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let model = ModelFile::deserialize(deserializer)?;
+        check_merges(&model.merges).map_err(serde::de::Error::custom)?;
+        Self::from_model_file(model).map_err(serde::de::Error::custom)
+    }
+}
+
+impl Trainable for RegexTokenizer {
+    /// With `config.split` (the default) the text is split into chunks with
+    /// the tokenizer's pattern and pairs are only counted within a chunk,
+    /// matching how `encode` works. Without it, training runs on the raw byte
+    /// stream and merges may cross chunk boundaries.
+    fn training_chunks(&self, text: &str, config: &TrainConfig) -> Vec<Vec<Token>> {
+        let text = &self.normalizer.normalize(text);
+        if config.split {
+            let re = self.split_regex();
+            split_chunks(&re, text)
+                .map(|chunk| chunk.bytes().map(|b| b as Token).collect())
+                .collect()
+        } else {
+            vec![text.bytes().map(|b| b as Token).collect()]
+        }
+    }
+
+    fn merges(&self) -> &FastHashMap<(Token, Token), Token> {
+        &self.merges
+    }
+
+    fn special_tokens(&self) -> &HashMap<String, Token> {
+        &self.special_tokens
+    }
+
+    fn set_special_tokens(&mut self, special_tokens: HashMap<String, Token>) {
+        self.inverse_special_tokens = special_tokens
+            .iter()
+            .map(|(special, &idx)| (idx, special.clone()))
+            .collect();
+        self.special_tokens = special_tokens;
+    }
+
+    fn set_merges(&mut self, merges: Vec<Merge>) {
+        self.cache.clear();
+        if let Some(shared) = &self.shared_cache {
+            shared.clear();
+        }
+        self.merges = merges.into_iter().collect();
+        self.vocab = build_vocab(&self.merges);
+        self.prefix_index = PrefixIndex::new(&self.vocab);
+    }
+}
+
+impl Tokenizer for RegexTokenizer {
+    /// A Tokenizer can encode a string into a list of integers. The special
+    /// tokens allowed by `set_allowed_special` (none by default) are encoded
+    /// as their ids, and everything else as plain text; `try_encode` rejects
+    /// special token literals instead.
+    fn encode(&mut self, text: &str) -> Vec<Token> {
+        let mut ids = Vec::new();
+        self.encode_into(text, &mut ids);
+        ids
+    }
+
+    /// A Tokenizer can decode a list of integers into a string.
+    fn decode(&self, ids: &[Token]) -> String {
+        self.decode_iter(ids.iter().copied())
     }
 }
@@ -0,0 +1,212 @@
+/*
+Chunk counting under a memory budget. Once the counts outgrow the budget they
+are spilled to partition files on disk, split by chunk hash so that every
+partition can be aggregated on its own. At the end the rarest chunks are
+dropped until what's left fits the budget again.
+*/
+
+use std::collections::BTreeMap;
+use std::collections::hash_map::DefaultHasher;
+use std::fs::{self, File};
+use std::hash::{Hash, Hasher};
+use std::io::{self, BufReader, BufWriter, Read, Write};
+use std::path::{Path, PathBuf};
+use std::process;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use crate::base::Token;
+use crate::hash::FastHashMap;
+use crate::train::{ChunkCounts, MemoryLimit};
+
+/// Number of partition files the counts are spread over.
+const PARTITIONS: usize = 64;
+
+/// Rough size of one entry of a `ChunkCounts` map: the ids plus the `Vec`
+/// header, the count and the map's own overhead.
+fn entry_size(chunk: &[Token]) -> usize {
+    size_of_val(chunk) + 48
+}
+
+/// Counts chunks like `count_chunks`, spilling to disk past `limit`.
+pub(crate) struct SpillCounts {
+    counts: ChunkCounts,
+    used: usize,
+    limit: Option<MemoryLimit>,
+    dir: Option<PathBuf>,
+}
+
+impl SpillCounts {
+    pub(crate) fn new(limit: Option<MemoryLimit>) -> Self {
+        SpillCounts {
+            counts: ChunkCounts::default(),
+            used: 0,
+            limit,
+            dir: None,
+        }
+    }
+
+    pub(crate) fn add(&mut self, chunks: Vec<Vec<Token>>) -> io::Result<()> {
+        for chunk in chunks {
+            let size = entry_size(&chunk);
+            let count = self.counts.entry(chunk).or_insert(0);
+            if *count == 0 {
+                self.used += size;
+            }
+            *count += 1;
+        }
+        if let Some(limit) = &self.limit
+            && self.used > limit.bytes
+        {
+            self.spill()?;
+        }
+        Ok(())
+    }
+
+    /// Append the in-memory counts to the partition files and start over.
+    fn spill(&mut self) -> io::Result<()> {
+        let dir = match &self.dir {
+            Some(dir) => dir.clone(),
+            None => {
+                let dir = self
+                    .limit
+                    .as_ref()
+                    .unwrap()
+                    .spill_dir
+                    .join(spill_dir_name());
+                fs::create_dir_all(&dir)?;
+                self.dir = Some(dir.clone());
+                dir
+            }
+        };
+        let mut partitions: Vec<Vec<(Vec<Token>, usize)>> = vec![Vec::new(); PARTITIONS];
+        for (chunk, count) in self.counts.drain() {
+            partitions[partition(&chunk)].push((chunk, count));
+        }
+        for (i, entries) in partitions.into_iter().enumerate() {
+            let file = File::options()
+                .create(true)
+                .append(true)
+                .open(dir.join(i.to_string()))?;
+            let mut out = BufWriter::new(file);
+            for (chunk, count) in entries {
+                write_entry(&mut out, &chunk, count)?;
+            }
+            out.flush()?;
+        }
+        self.used = 0;
+        Ok(())
+    }
+
+    /// The final counts, and how many distinct chunks had to be dropped to fit
+    /// them in the memory budget. Nothing is dropped if nothing was spilled.
+    pub(crate) fn finish(mut self) -> io::Result<(ChunkCounts, usize)> {
+        match self.dir.clone() {
+            Some(dir) => self.merge_partitions(&dir),
+            None => Ok((std::mem::take(&mut self.counts), 0)),
+        }
+    }
+
+    fn merge_partitions(&mut self, dir: &Path) -> io::Result<(ChunkCounts, usize)> {
+        self.spill()?;
+        let budget = self.limit.as_ref().unwrap().bytes;
+        // first pass: aggregate every partition, write it back and tally how
+        // much memory the chunks of each count take
+        let mut sizes: BTreeMap<usize, usize> = BTreeMap::new();
+        let mut total = 0;
+        for i in 0..PARTITIONS {
+            let path = dir.join(i.to_string());
+            let counts = read_partition(&path)?;
+            let mut out = BufWriter::new(File::create(&path)?);
+            for (chunk, count) in counts {
+                *sizes.entry(count).or_insert(0) += entry_size(&chunk);
+                write_entry(&mut out, &chunk, count)?;
+                total += 1;
+            }
+            out.flush()?;
+        }
+        // keep the most frequent chunks that fit, never splitting a count
+        let mut threshold = usize::MAX;
+        let mut used = 0;
+        for (&count, &size) in sizes.iter().rev() {
+            if used + size > budget {
+                break;
+            }
+            used += size;
+            threshold = count;
+        }
+        // second pass: load what's kept
+        let mut counts = ChunkCounts::default();
+        for i in 0..PARTITIONS {
+            counts.extend(
+                read_partition(&dir.join(i.to_string()))?
+                    .into_iter()
+                    .filter(|&(_, count)| count >= threshold),
+            );
+        }
+        let dropped = total - counts.len();
+        Ok((counts, dropped))
+    }
+}
+
+impl Drop for SpillCounts {
+    fn drop(&mut self) {
+        if let Some(dir) = &self.dir {
+            let _ = fs::remove_dir_all(dir);
+        }
+    }
+}
+
+/// A directory name no other training run in this or another process uses.
+fn spill_dir_name() -> String {
+    static RUNS: AtomicUsize = AtomicUsize::new(0);
+    format!(
+        "minbpe-spill-{}-{}",
+        process::id(),
+        RUNS.fetch_add(1, Ordering::Relaxed)
+    )
+}
+
+fn partition(chunk: &[Token]) -> usize {
+    let mut hasher = DefaultHasher::new();
+    chunk.hash(&mut hasher);
+    hasher.finish() as usize % PARTITIONS
+}
+
+/// Entries are stored as the number of ids, the ids and the count, all
+/// little-endian.
+fn write_entry(out: &mut impl Write, chunk: &[Token], count: usize) -> io::Result<()> {
+    out.write_all(&(chunk.len() as u32).to_le_bytes())?;
+    for id in chunk {
+        out.write_all(&id.to_le_bytes())?;
+    }
+    out.write_all(&(count as u64).to_le_bytes())
+}
+
+/// Read and aggregate a partition file; a missing file is an empty partition.
+fn read_partition(path: &Path) -> io::Result<FastHashMap<Vec<Token>, usize>> {
+    let mut counts = FastHashMap::default();
+    let file = match File::open(path) {
+        Ok(file) => file,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(counts),
+        Err(e) => return Err(e),
+    };
+    let mut input = BufReader::new(file);
+    let mut len = [0; 4];
+    loop {
+        match input.read_exact(&mut len) {
+            Ok(()) => {}
+            Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => break,
+            Err(e) => return Err(e),
+        }
+        let mut ids = vec![0; u32::from_le_bytes(len) as usize * 4];
+        input.read_exact(&mut ids)?;
+        let chunk = ids
+            .chunks_exact(4)
+            .map(|id| Token::from_le_bytes(id.try_into().unwrap()))
+            .collect();
+        let mut count = [0; 8];
+        input.read_exact(&mut count)?;
+        *counts.entry(chunk).or_insert(0) += u64::from_le_bytes(count) as usize;
+    }
+    Ok(counts)
+}
@@ -0,0 +1,252 @@
+/*
+Splitting a document into consecutive pieces that each fit a token budget,
+optionally overlapping, e.g. to ingest it into a retrieval index.
+*/
+
+use std::ops::Range;
+
+use crate::base::Token;
+
+/// A piece of a document produced by `split_by_tokens`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TextChunk<'a> {
+    pub text: &'a str,
+    /// Byte range of `text` within the document.
+    pub span: Range<usize>,
+    /// Number of tokens `text` encodes to on its own.
+    pub num_tokens: usize,
+}
+
+/// Where `split_by_tokens_at` prefers to end a piece. Stronger boundaries
+/// compare greater.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Boundary {
+    /// Anywhere between two tokens.
+    #[default]
+    Token,
+    /// After the punctuation ending a sentence (`.`, `!` or `?`, possibly
+    /// followed by closing quotes or brackets) and before whitespace.
+    Sentence,
+    /// At a blank line between paragraphs.
+    Paragraph,
+}
+
+impl Boundary {
+    /// The strongest boundary at byte `at` of `text`.
+    fn at(text: &str, at: usize) -> Self {
+        let (before, after) = text.split_at(at);
+        let head = before.trim_end();
+        let gap = [
+            &before[head.len()..],
+            &after[..after.len() - after.trim_start().len()],
+        ];
+        if gap.iter().map(|ws| ws.matches('\n').count()).sum::<usize>() >= 2 {
+            Boundary::Paragraph
+        } else if gap.iter().any(|ws| !ws.is_empty())
+            && head
+                .trim_end_matches(['"', '\'', ')', ']', '”', '’'])
+                .ends_with(['.', '!', '?'])
+        {
+            Boundary::Sentence
+        } else {
+            Boundary::Token
+        }
+    }
+}
+
+/// Split `text` into pieces of at most `max_tokens` tokens as encoded by
+/// `encode`, each starting `overlap_tokens` tokens before the previous one
+/// ended. `encode` reports the offsets of its tokens in `text` itself, not in
+/// what it normalizes `text` to, so pieces are slices of `text` like the
+/// text `truncate` keeps. Pieces are cut at token boundaries (moved back to
+/// the start of a character a token only holds part of) and re-encoded, so
+/// `num_tokens` is exact. Pieces end at the last boundary as strong as
+/// `boundary` that fits, falling back to weaker ones (a sentence inside a
+/// long paragraph, then any token inside a long sentence) only when there is
+/// none. A character, or text the normalizer changed as a whole, that alone
+/// needs more than `max_tokens` tokens still gets a piece of its own.
+pub(crate) fn split_by_tokens<'a, F>(
+    text: &'a str,
+    max_tokens: usize,
+    overlap_tokens: usize,
+    boundary: Boundary,
+    mut encode: F,
+) -> Vec<TextChunk<'a>>
+where
+    F: FnMut(&str) -> Vec<(Token, Range<usize>)>,
+{
+    assert!(max_tokens > 0, "Chunks must hold at least one token");
+    assert!(
+        overlap_tokens < max_tokens,
+        "Overlap must be smaller than the chunk size"
+    );
+    let offsets = encode(text);
+    let n = offsets.len();
+    // byte offset where token `i` starts, on a character boundary (and within
+    // `text`, should `encode` report offsets past its end)
+    let cut = |i: usize| {
+        let mut at = if i == n {
+            text.len()
+        } else {
+            offsets[i].1.start.min(text.len())
+        };
+        while !text.is_char_boundary(at) {
+            at -= 1;
+        }
+        at
+    };
+
+    let mut chunks = Vec::new();
+    let mut start = 0;
+    while start < n {
+        // a character split over tokens can't be cut in half
+        let mut shortest = start + 1;
+        while shortest < n && cut(shortest) == cut(start) {
+            shortest += 1;
+        }
+        let longest = (start + max_tokens).min(n).max(shortest);
+        // try the strongest boundaries first, down to any token, and the
+        // longest piece at each level
+        let strengths: Vec<Boundary> = (shortest..=longest)
+            .map(|end| match end {
+                end if end == n => Boundary::Paragraph,
+                end => Boundary::at(text, cut(end)),
+            })
+            .collect();
+        let strengths = &strengths;
+        let levels = [Boundary::Paragraph, Boundary::Sentence, Boundary::Token]
+            .into_iter()
+            .filter(|&level| level <= boundary);
+        let candidates = levels.flat_map(|level| {
+            (shortest..=longest)
+                .rev()
+                .filter(move |&end| strengths[end - shortest] >= level)
+        });
+        let mut fitted = None;
+        for end in candidates {
+            let span = cut(start)..cut(end);
+            let num_tokens = encode(&text[span.clone()]).len();
+            // tokens may merge differently once the text is cut
+            if num_tokens <= max_tokens {
+                fitted = Some((end, span, num_tokens));
+                break;
+            }
+        }
+        let (end, span, num_tokens) = fitted.unwrap_or_else(|| {
+            let span = cut(start)..cut(shortest);
+            let num_tokens = encode(&text[span.clone()]).len();
+            (shortest, span, num_tokens)
+        });
+        chunks.push(TextChunk {
+            text: &text[span.clone()],
+            span,
+            num_tokens,
+        });
+        if end == n {
+            break;
+        }
+        start = end.saturating_sub(overlap_tokens).max(start + 1);
+    }
+    chunks
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::base::Tokenizer;
+    use crate::normalize::Normalizer;
+    use crate::regex::RegexTokenizer;
+    use crate::train::{TrainConfig, Trainable};
+
+    const CORPUS: &str = include_str!("../taylorswift.txt");
+
+    fn trained() -> RegexTokenizer {
+        let mut tokenizer = RegexTokenizer::new();
+        tokenizer.train(CORPUS, TrainConfig::new(400));
+        tokenizer
+    }
+
+    /// The start of the corpus, cut on a character boundary.
+    fn text() -> &'static str {
+        let mut end = 3000;
+        while !CORPUS.is_char_boundary(end) {
+            end -= 1;
+        }
+        &CORPUS[..end]
+    }
+
+    fn concat(chunks: &[TextChunk]) -> String {
+        chunks.iter().map(|chunk| chunk.text).collect()
+    }
+
+    #[test]
+    fn pieces_cover_the_text() {
+        let mut tokenizer = trained();
+        let text = text();
+        let chunks = tokenizer.split_by_tokens(text, 50, 0);
+        assert!(chunks.len() > 1);
+        assert_eq!(concat(&chunks), text);
+        for chunk in &chunks {
+            assert_eq!(&text[chunk.span.clone()], chunk.text);
+            assert!(chunk.num_tokens <= 50);
+            assert_eq!(tokenizer.encode(chunk.text).len(), chunk.num_tokens);
+        }
+    }
+
+    #[test]
+    fn pieces_overlap() {
+        let mut tokenizer = trained();
+        let text = text();
+        let chunks = tokenizer.split_by_tokens(text, 50, 10);
+        assert_eq!(chunks.first().unwrap().span.start, 0);
+        assert_eq!(chunks.last().unwrap().span.end, text.len());
+        for pair in chunks.windows(2) {
+            assert!(pair[1].span.start > pair[0].span.start);
+            assert!(pair[1].span.start < pair[0].span.end);
+        }
+    }
+
+    #[test]
+    fn normalized_pieces_are_slices_of_the_text() {
+        let mut tokenizer = trained();
+        tokenizer.set_normalizer(Normalizer::new().crlf_to_lf(true).lowercase(true));
+        let text = text().replace('\n', "\r\n").to_uppercase();
+        let chunks = tokenizer.split_by_tokens(&text, 20, 0);
+        assert_eq!(concat(&chunks), text);
+        for chunk in &chunks {
+            assert!(chunk.num_tokens <= 20);
+            assert_eq!(tokenizer.encode(chunk.text).len(), chunk.num_tokens);
+        }
+    }
+
+    #[test]
+    fn boundaries() {
+        let text = "One. \"Two!\" Three\n\nFour? e.g.5";
+        let at = |needle: &str| Boundary::at(text, text.find(needle).unwrap());
+        assert_eq!(at(" \"Two"), Boundary::Sentence);
+        assert_eq!(at(" Three"), Boundary::Sentence);
+        assert_eq!(at("\n\nFour"), Boundary::Paragraph);
+        assert_eq!(at("Four"), Boundary::Paragraph);
+        assert_eq!(at("ree"), Boundary::Token);
+        assert_eq!(at("5"), Boundary::Token);
+    }
+
+    #[test]
+    fn pieces_end_at_sentences() {
+        let mut tokenizer = trained();
+        let text = "The first sentence is here. Then a second one follows it. \
+                    And a third closes the paragraph.\n\nA new paragraph starts.";
+        for boundary in [Boundary::Sentence, Boundary::Paragraph] {
+            let chunks = tokenizer.split_by_tokens_at(text, 30, 0, boundary);
+            assert_eq!(concat(&chunks), text);
+            for chunk in &chunks[..chunks.len() - 1] {
+                assert!(chunk.num_tokens <= 30);
+                assert!(chunk.text.trim_end().ends_with('.'), "{:?}", chunk.text);
+            }
+        }
+        // a sentence longer than the budget is cut anywhere
+        let chunks = tokenizer.split_by_tokens_at(text, 4, 0, Boundary::Sentence);
+        assert!(chunks.iter().all(|chunk| chunk.num_tokens <= 4));
+        assert_eq!(concat(&chunks), text);
+    }
+}
@@ -0,0 +1,280 @@
+/*
+Helpers for processing text incrementally, from a reader or fragment by
+fragment, so that large inputs never have to be held in memory as one
+`String`.
+*/
+
+use std::io::{self, Read};
+
+use crate::base::{Token, Tokenizer};
+use crate::decode::drain_complete_utf8;
+
+/// Number of bytes requested from the reader at a time.
+const BLOCK_SIZE: usize = 1 << 20;
+/// If no safe boundary shows up within this many bytes, cut at the last
+/// character boundary instead of buffering forever.
+const MAX_BUFFER: usize = 16 * BLOCK_SIZE;
+
+/// Find the last point in `text` where it can be cut without changing how the
+/// split patterns chunk it: just after a newline that is followed by a
+/// non-whitespace character. The newline always ends its chunk there, and the
+/// following chunk can't reach back across it.
+pub(crate) fn last_safe_boundary(text: &str) -> Option<usize> {
+    let bytes = text.as_bytes();
+    (1..bytes.len())
+        .rev()
+        .find(|&i| bytes[i - 1] == b'\n' && !text[i..].starts_with(char::is_whitespace))
+}
+
+/// Size above which `split_safe` cuts a document for parallel encoding.
+#[cfg(feature = "parallel")]
+pub(crate) const PARALLEL_PIECE_SIZE: usize = 1 << 16;
+
+/// Cut `text` at safe boundaries into pieces of at least `min_len` bytes
+/// (except the last), which encode independently to the same tokens as `text`
+/// as a whole. Unlike `SafeChunks`, a piece grows for as long as it takes to
+/// reach a safe boundary, so the guarantee always holds.
+#[cfg(any(feature = "parallel", feature = "mmap"))]
+pub(crate) fn split_safe(text: &str, min_len: usize) -> Vec<&str> {
+    let bytes = text.as_bytes();
+    let mut pieces = Vec::new();
+    let mut start = 0;
+    while start < text.len() {
+        let end = (start + min_len.max(1)..text.len())
+            .find(|&i| bytes[i - 1] == b'\n' && !text[i..].starts_with(char::is_whitespace))
+            .unwrap_or(text.len());
+        pieces.push(&text[start..end]);
+        start = end;
+    }
+    pieces
+}
+
+/// Encode the file at `path` through a memory map, one window of about
+/// `BLOCK_SIZE` bytes cut at a safe boundary at a time, so only the pages of
+/// the current window need to be resident.
+#[cfg(feature = "mmap")]
+pub(crate) fn encode_mmap<F>(path: &std::path::Path, mut encode: F) -> io::Result<Vec<Token>>
+where
+    F: FnMut(&str, &mut Vec<Token>),
+{
+    let file = std::fs::File::open(path)?;
+    // SAFETY: the map is only read, and the caller is warned that the file
+    // must not be modified while it is being encoded
+    let map = unsafe { memmap2::Mmap::map(&file)? };
+    let text =
+        std::str::from_utf8(&map).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+    let mut ids = Vec::new();
+    for window in split_safe(text, BLOCK_SIZE) {
+        encode(window, &mut ids);
+    }
+    Ok(ids)
+}
+
+/// Yields the text of a reader as consecutive pieces cut at safe boundaries.
+pub(crate) struct SafeChunks<R> {
+    reader: R,
+    buf: Vec<u8>,
+    done: bool,
+}
+
+impl<R: Read> SafeChunks<R> {
+    pub(crate) fn new(reader: R) -> Self {
+        SafeChunks {
+            reader,
+            buf: Vec::new(),
+            done: false,
+        }
+    }
+
+    fn take(&mut self, len: usize) -> String {
+        let rest = self.buf.split_off(len);
+        let piece = std::mem::replace(&mut self.buf, rest);
+        // callers only cut at validated character boundaries
+        String::from_utf8(piece).unwrap()
+    }
+}
+
+/// Encode the text of `reader` one safe block at a time with `encode`,
+/// yielding the tokens of a block as soon as it is encoded. Only one block is
+/// held in memory at a time.
+pub(crate) fn encode_blocks<R, F>(
+    reader: R,
+    mut encode: F,
+) -> impl Iterator<Item = io::Result<Token>>
+where
+    R: Read,
+    F: FnMut(&str) -> Vec<Token>,
+{
+    SafeChunks::new(reader).flat_map(move |block| {
+        let tokens: Vec<io::Result<Token>> = match block {
+            Ok(text) => encode(&text).into_iter().map(Ok).collect(),
+            Err(e) => vec![Err(e)],
+        };
+        tokens
+    })
+}
+
+/// Add up `count` over the text of `reader`, one safe block at a time.
+pub(crate) fn count_blocks<R, F>(reader: R, mut count: F) -> io::Result<u64>
+where
+    R: Read,
+    F: FnMut(&str) -> usize,
+{
+    let mut total = 0;
+    for block in SafeChunks::new(reader) {
+        total += count(&block?) as u64;
+    }
+    Ok(total)
+}
+
+/// Encodes text that arrives in arbitrary fragments, e.g. from a socket.
+/// Created by `RegexTokenizer::stream_encoder` or
+/// `GPT4Tokenizer::stream_encoder`.
+///
+/// The trailing part of the text that could still change how it is chunked
+/// is held back, so every token returned by `push` is final: all of them
+/// together with those of `finish` are exactly what encoding the whole text
+/// at once gives. Text is released at safe boundaries (a newline followed by
+/// non-whitespace), so a long run without one stays buffered.
+pub struct StreamEncoder<'a, T> {
+    tokenizer: &'a mut T,
+    pending: String,
+}
+
+impl<'a, T: Tokenizer> StreamEncoder<'a, T> {
+    pub(crate) fn new(tokenizer: &'a mut T) -> Self {
+        StreamEncoder {
+            tokenizer,
+            pending: String::new(),
+        }
+    }
+
+    /// Add the next fragment, returning the tokens that became final.
+    pub fn push(&mut self, text: &str) -> Vec<Token> {
+        // a new boundary can only show up in the new text, or right after a
+        // newline that ended the previous fragment
+        let mut from = self.pending.len();
+        if self.pending.ends_with('\n') {
+            from -= 1;
+        }
+        self.pending.push_str(text);
+        let Some(cut) = last_safe_boundary(&self.pending[from..]) else {
+            return Vec::new();
+        };
+        let rest = self.pending.split_off(from + cut);
+        let ready = std::mem::replace(&mut self.pending, rest);
+        self.tokenizer.encode(&ready)
+    }
+
+    /// Encode whatever is still held back.
+    pub fn finish(self) -> Vec<Token> {
+        self.tokenizer.encode(&self.pending)
+    }
+}
+
+/// Appends the bytes of a token to a buffer.
+type TokenBytes<'a> = dyn Fn(Token, &mut Vec<u8>) + 'a;
+
+/// Decodes tokens as they arrive one at a time, e.g. from a model generating
+/// text. Created by the `stream_decoder` method of the tokenizers.
+///
+/// A token may end in the middle of a character; its leading bytes are held
+/// back until the token completing it arrives, so no replacement characters
+/// show up for text that is valid as a whole. Everything `push` returns
+/// together with `finish` is exactly what `decode` gives for all the tokens.
+pub struct StreamDecoder<'a> {
+    token_bytes: Box<TokenBytes<'a>>,
+    pending: Vec<u8>,
+    text: String,
+}
+
+impl<'a> StreamDecoder<'a> {
+    pub(crate) fn new(token_bytes: impl Fn(Token, &mut Vec<u8>) + 'a) -> Self {
+        StreamDecoder {
+            token_bytes: Box::new(token_bytes),
+            pending: Vec::new(),
+            text: String::new(),
+        }
+    }
+
+    /// Add the next token, returning the text that became complete.
+    pub fn push(&mut self, id: Token) -> &str {
+        (self.token_bytes)(id, &mut self.pending);
+        self.text.clear();
+        drain_complete_utf8(&mut self.pending, &mut self.text);
+        &self.text
+    }
+
+    /// Decode whatever is still held back, i.e. a character the tokens ended
+    /// in the middle of.
+    pub fn finish(self) -> String {
+        String::from_utf8_lossy(&self.pending).into_owned()
+    }
+}
+
+fn invalid_utf8() -> io::Error {
+    io::Error::new(
+        io::ErrorKind::InvalidData,
+        "stream did not contain valid UTF-8",
+    )
+}
+
+impl<R: Read> Iterator for SafeChunks<R> {
+    type Item = io::Result<String>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            if self.done {
+                if self.buf.is_empty() {
+                    return None;
+                }
+                if std::str::from_utf8(&self.buf).is_err() {
+                    self.buf.clear();
+                    return Some(Err(invalid_utf8()));
+                }
+                let len = self.buf.len();
+                return Some(Ok(self.take(len)));
+            }
+
+            let start = self.buf.len();
+            self.buf.resize(start + BLOCK_SIZE, 0);
+            let read = match self.reader.read(&mut self.buf[start..]) {
+                Ok(read) => read,
+                Err(e) => {
+                    self.buf.truncate(start);
+                    if e.kind() == io::ErrorKind::Interrupted {
+                        continue;
+                    }
+                    return Some(Err(e));
+                }
+            };
+            self.buf.truncate(start + read);
+            if read == 0 {
+                self.done = true;
+                continue;
+            }
+            if self.buf.len() < BLOCK_SIZE {
+                continue;
+            }
+
+            // only the valid UTF-8 prefix is a candidate, the tail may be a
+            // character cut in half by the read
+            let valid = match std::str::from_utf8(&self.buf) {
+                Ok(text) => text.len(),
+                Err(e) if e.error_len().is_none() => e.valid_up_to(),
+                Err(_) => {
+                    self.buf.clear();
+                    self.done = true;
+                    return Some(Err(invalid_utf8()));
+                }
+            };
+            let text = std::str::from_utf8(&self.buf[..valid]).unwrap();
+            if let Some(cut) = last_safe_boundary(text) {
+                return Some(Ok(self.take(cut)));
+            }
+            if self.buf.len() >= MAX_BUFFER {
+                return Some(Ok(self.take(valid)));
+            }
+        }
+    }
+}
@@ -0,0 +1,112 @@
+/*
+The vocabulary as a CSV or TSV table, one row per token, for analyzing it
+with pandas, duckdb or a spreadsheet instead of parsing a model format:
+
+    id,rank,length,text,left,right,special
+
+`rank` is the order the token was merged in (from 0), `length` its number of
+bytes and `left` and `right` the ids it was merged from; they are empty for
+single bytes and special tokens. `text` is the token's bytes with backslashes
+and bytes that aren't printable UTF-8 escaped as `\\` and `\xNN`, so a cell
+never holds a tab or line break. In CSV, cells with commas or quotes are
+quoted.
+*/
+
+use std::collections::HashMap;
+use std::io::{self, Write};
+
+use crate::base::Token;
+use crate::hash::FastHashMap;
+use crate::model::escape_bytes;
+
+/// The layout of a vocab table, see `export_vocab_table`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TableFormat {
+    /// Comma-separated values.
+    Csv,
+    /// Tab-separated values.
+    Tsv,
+}
+
+impl TableFormat {
+    fn separator(self) -> char {
+        match self {
+            TableFormat::Csv => ',',
+            TableFormat::Tsv => '\t',
+        }
+    }
+
+    /// `text` as a cell.
+    fn cell(self, text: &str) -> String {
+        match self {
+            TableFormat::Csv if text.contains([',', '"']) => {
+                format!("\"{}\"", text.replace('"', "\"\""))
+            }
+            _ => text.to_string(),
+        }
+    }
+}
+
+const COLUMNS: [&str; 7] = ["id", "rank", "length", "text", "left", "right", "special"];
+
+/// Save the table of `vocab` (the actual bytes of the tokens), `merges` and
+/// `special_tokens`, in the order of the ids.
+pub(crate) fn write_vocab_table(
+    mut writer: impl Write,
+    format: TableFormat,
+    vocab: &FastHashMap<Token, Vec<u8>>,
+    merges: &FastHashMap<(Token, Token), Token>,
+    special_tokens: &HashMap<String, Token>,
+) -> io::Result<()> {
+    let mut ordered: Vec<_> = merges.iter().map(|(&pair, &idx)| (idx, pair)).collect();
+    ordered.sort_unstable();
+    let parents: HashMap<Token, (usize, (Token, Token))> = ordered
+        .into_iter()
+        .enumerate()
+        .map(|(rank, (idx, pair))| (idx, (rank, pair)))
+        .collect();
+    let mut rows: Vec<(Token, Vec<String>)> = vocab
+        .iter()
+        .map(|(&idx, bytes)| {
+            let (rank, left, right) = match parents.get(&idx) {
+                Some(&(rank, (left, right))) => {
+                    (rank.to_string(), left.to_string(), right.to_string())
+                }
+                None => Default::default(),
+            };
+            let text = format.cell(&escape_bytes(bytes));
+            let row = vec![
+                idx.to_string(),
+                rank,
+                bytes.len().to_string(),
+                text,
+                left,
+                right,
+                "false".to_string(),
+            ];
+            (idx, row)
+        })
+        .collect();
+    rows.extend(special_tokens.iter().map(|(special, &idx)| {
+        let row = vec![
+            idx.to_string(),
+            String::new(),
+            special.len().to_string(),
+            format.cell(&escape_bytes(special.as_bytes())),
+            String::new(),
+            String::new(),
+            "true".to_string(),
+        ];
+        (idx, row)
+    }));
+    rows.sort_by_key(|(idx, _)| *idx);
+
+    let separator = format.separator().to_string();
+    let mut out = COLUMNS.join(&separator);
+    out.push('\n');
+    for (_, row) in rows {
+        out.push_str(&row.join(&separator));
+        out.push('\n');
+    }
+    writer.write_all(out.as_bytes())
+}
@@ -0,0 +1,46 @@
+/*
+A step-by-step account of how a text is encoded, for investigating why a
+string tokenizes unexpectedly: the chunks the split pattern cut it into, and
+the merges that turned every chunk's bytes into its tokens.
+*/
+
+use crate::base::{Merge, Token, apply_merges_with};
+use crate::hash::FastHashMap;
+
+/// How one chunk of a text was encoded, see `encode_debug`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ChunkTrace {
+    /// The chunk as matched by the split pattern (after normalization), or
+    /// the literal of a special token.
+    pub text: String,
+    /// The tokens of the chunk.
+    pub ids: Vec<Token>,
+    /// The merges applied to the chunk's bytes, in order. Empty for a special
+    /// token.
+    pub merges: Vec<Merge>,
+}
+
+/// Encode the chunk `text`, starting from its byte-level `ids`, recording the
+/// merges.
+pub(crate) fn trace_chunk(
+    text: &str,
+    ids: Vec<Token>,
+    merges: &FastHashMap<(Token, Token), Token>,
+) -> ChunkTrace {
+    let mut applied = Vec::new();
+    let ids = apply_merges_with(ids, merges, |merge| applied.push(merge));
+    ChunkTrace {
+        text: text.to_string(),
+        ids,
+        merges: applied,
+    }
+}
+
+/// The trace of a special token.
+pub(crate) fn trace_special(literal: &str, idx: Token) -> ChunkTrace {
+    ChunkTrace {
+        text: literal.to_string(),
+        ids: vec![idx],
+        merges: Vec::new(),
+    }
+}
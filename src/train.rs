@@ -0,0 +1,1462 @@
+/*
+Training interface shared by the trainable tokenizers, plus the BPE training
+loop they all run once the corpus has been turned into chunks of byte ids.
+*/
+
+use std::cmp::Ordering;
+use std::collections::{BinaryHeap, HashMap, HashSet};
+use std::fmt;
+use std::fs::{self, File};
+use std::io::{self, Read, Write};
+use std::ops::ControlFlow;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use crate::atomic::save_atomically;
+use crate::base::{Merge, Token, apply_merges, split_specials};
+use crate::corpus::{Corpus, FileStats};
+use crate::hash::FastHashMap;
+use crate::progress::Progress;
+use crate::rng::Rng;
+use crate::spill::SpillCounts;
+use crate::stream::SafeChunks;
+
+/// A merge that was just learned, as reported to the `on_merge` callback.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MergeEvent {
+    pub pair: (Token, Token),
+    /// Id assigned to the merged token.
+    pub idx: Token,
+    /// Number of occurrences of `pair` in the corpus when it was merged.
+    pub count: usize,
+    /// Time spent in the merge loop so far.
+    pub elapsed: Duration,
+}
+
+/// Which pair wins when several share the highest count. Either rule makes
+/// training reproducible from run to run.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum TieBreak {
+    /// Prefer the pair with the lowest ids, comparing the first id and then
+    /// the second. Low ids are the bytes and the earliest merges.
+    #[default]
+    LowestIds,
+    /// Prefer the pair with the highest ids, i.e. build on recent merges.
+    HighestIds,
+}
+
+impl TieBreak {
+    /// Order two pairs with equal counts so that the winner compares greater.
+    fn cmp(self, a: (Token, Token), b: (Token, Token)) -> Ordering {
+        match self {
+            TieBreak::LowestIds => b.cmp(&a),
+            TieBreak::HighestIds => a.cmp(&b),
+        }
+    }
+}
+
+/// A pair waiting in the training queue, ordered so that the pair to merge
+/// next is the greatest.
+#[derive(PartialEq, Eq)]
+struct QueuedPair {
+    pair: (Token, Token),
+    count: usize,
+    tie_break: TieBreak,
+}
+
+impl QueuedPair {
+    fn new(pair: (Token, Token), count: usize, tie_break: TieBreak) -> Self {
+        QueuedPair {
+            pair,
+            count,
+            tie_break,
+        }
+    }
+}
+
+impl Ord for QueuedPair {
+    fn cmp(&self, other: &Self) -> Ordering {
+        self.count
+            .cmp(&other.count)
+            .then_with(|| self.tie_break.cmp(self.pair, other.pair))
+    }
+}
+
+impl PartialOrd for QueuedPair {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+/// Called after every merge. Returning `ControlFlow::Break` stops training,
+/// keeping the merges learned so far.
+pub type MergeCallback = Arc<dyn Fn(&MergeEvent) -> ControlFlow<()> + Send + Sync>;
+
+/// Options controlling a training run.
+#[derive(Clone)]
+pub struct TrainConfig {
+    /// Size of the final vocabulary, including the 256 byte tokens.
+    pub vocab_size: usize,
+    /// Split the corpus with the tokenizer's pattern and only count pairs
+    /// within a chunk. Tokenizers without a pattern ignore this.
+    pub split: bool,
+    /// Stop learning merges once the most frequent remaining pair occurs
+    /// fewer than this many times, instead of memorizing one-off sequences.
+    pub min_frequency: usize,
+    /// Stop as soon as no pair occurs more than once. Merging pairs that only
+    /// occur once just memorizes the corpus. On by default.
+    pub early_stop: bool,
+    /// Never create tokens longer than this many bytes. Pairs that would
+    /// exceed it are skipped, which keeps repetitive corpora from producing
+    /// pathological mega-tokens.
+    pub max_token_length: Option<usize>,
+    pub tie_break: TieBreak,
+    /// Special tokens such as `<|endoftext|>`. Their literal text is cut out
+    /// of the corpus so it never feeds into merges, and they get the ids
+    /// right after the learned merges, counting towards `vocab_size`.
+    pub special_tokens: Vec<String>,
+    /// Only learn merges between these bytes, e.g. ASCII for a corpus of hex
+    /// dumps. Text made of other bytes is left out of training and encodes
+    /// as raw bytes. `None` allows every byte.
+    pub alphabet: Option<Vec<u8>>,
+    /// Strings that become tokens before anything is learned, with the first
+    /// ids after the bytes, e.g. the codons of a DNA vocabulary. Every
+    /// prefix of a seed becomes a token as well.
+    pub seed_tokens: Vec<String>,
+    pub checkpoint: Option<Checkpoint>,
+    /// Train on a reproducible random sample of the corpus instead of all
+    /// of it, to iterate quickly on vocabulary design.
+    pub sample: Option<Sample>,
+    /// BPE-dropout while training: every occurrence of the pair being merged
+    /// is left unmerged with probability `rate`, and those occurrences are
+    /// never merged later on. Decisions are made once per distinct chunk, so
+    /// all occurrences of the same chunk share them. Runs resumed from a
+    /// checkpoint don't reproduce them.
+    pub dropout: Option<Dropout>,
+    /// Bound the memory used for counting chunks when training from files.
+    pub memory_limit: Option<MemoryLimit>,
+    pub on_merge: Option<MergeCallback>,
+}
+
+/// How much of the corpus to train on. The corpus is sampled line by line,
+/// and the same seed always picks the same lines.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Sample {
+    pub size: SampleSize,
+    pub seed: u64,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum SampleSize {
+    /// Keep each line with this probability.
+    Fraction(f64),
+    /// Keep roughly this many bytes, spread over the whole corpus. When the
+    /// corpus size isn't known up front (`train_from_iter`) lines are kept
+    /// in order until the budget is used up.
+    Bytes(usize),
+}
+
+/// Seeded merge skipping, see `TrainConfig::dropout`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Dropout {
+    pub rate: f64,
+    pub seed: u64,
+}
+
+/// A memory budget for the chunk counts, see `TrainConfig::memory_limit`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MemoryLimit {
+    /// Approximate number of bytes the chunk counts may take.
+    pub bytes: usize,
+    /// Where the counts are spilled to; a scratch directory is created in it
+    /// and removed once training is done.
+    pub spill_dir: PathBuf,
+}
+
+/// Where and how often a training run saves the merges learned so far, see
+/// `Trainable::resume_training`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Checkpoint {
+    pub path: PathBuf,
+    /// Save after every `every` merges.
+    pub every: usize,
+}
+
+impl TrainConfig {
+    pub fn new(vocab_size: usize) -> Self {
+        TrainConfig {
+            vocab_size,
+            split: true,
+            min_frequency: 0,
+            early_stop: true,
+            max_token_length: None,
+            tie_break: TieBreak::default(),
+            special_tokens: Vec::new(),
+            alphabet: None,
+            seed_tokens: Vec::new(),
+            checkpoint: None,
+            sample: None,
+            dropout: None,
+            memory_limit: None,
+            on_merge: None,
+        }
+    }
+
+    pub fn split(mut self, split: bool) -> Self {
+        self.split = split;
+        self
+    }
+
+    pub fn min_frequency(mut self, min_frequency: usize) -> Self {
+        self.min_frequency = min_frequency;
+        self
+    }
+
+    pub fn early_stop(mut self, early_stop: bool) -> Self {
+        self.early_stop = early_stop;
+        self
+    }
+
+    pub fn max_token_length(mut self, max_token_length: usize) -> Self {
+        self.max_token_length = Some(max_token_length);
+        self
+    }
+
+    pub fn tie_break(mut self, tie_break: TieBreak) -> Self {
+        self.tie_break = tie_break;
+        self
+    }
+
+    pub fn special_tokens<I>(mut self, special_tokens: I) -> Self
+    where
+        I: IntoIterator,
+        I::Item: Into<String>,
+    {
+        self.special_tokens = special_tokens.into_iter().map(Into::into).collect();
+        self
+    }
+
+    pub fn alphabet(mut self, alphabet: impl IntoIterator<Item = u8>) -> Self {
+        self.alphabet = Some(alphabet.into_iter().collect());
+        self
+    }
+
+    pub fn seed_tokens<I>(mut self, seed_tokens: I) -> Self
+    where
+        I: IntoIterator,
+        I::Item: Into<String>,
+    {
+        self.seed_tokens = seed_tokens.into_iter().map(Into::into).collect();
+        self
+    }
+
+    /// Save the merges learned so far to `path` after every `every` merges.
+    pub fn checkpoint(mut self, path: impl Into<PathBuf>, every: usize) -> Self {
+        assert!(every > 0, "Checkpoint interval must be at least 1");
+        self.checkpoint = Some(Checkpoint {
+            path: path.into(),
+            every,
+        });
+        self
+    }
+
+    pub fn sample(mut self, size: SampleSize, seed: u64) -> Self {
+        self.sample = Some(Sample { size, seed });
+        self
+    }
+
+    pub fn dropout(mut self, rate: f64, seed: u64) -> Self {
+        assert!((0.0..1.0).contains(&rate), "Dropout rate must be in [0, 1)");
+        self.dropout = Some(Dropout { rate, seed });
+        self
+    }
+
+    /// Keep the chunk counts of `train_from_file` and `train_from_corpus`
+    /// within roughly `bytes`, for corpora far larger than RAM. Past the
+    /// budget the counts are spilled to disk under `spill_dir` and aggregated
+    /// there; if they still don't fit, the rarest chunks are dropped (see
+    /// `TrainReport::dropped_chunks`). The merge loop needs a few times the
+    /// size of the counts it is given on top of that.
+    pub fn memory_limit(mut self, bytes: usize, spill_dir: impl Into<PathBuf>) -> Self {
+        self.memory_limit = Some(MemoryLimit {
+            bytes,
+            spill_dir: spill_dir.into(),
+        });
+        self
+    }
+
+    /// Report every merge to `callback`, e.g. to drive a progress display or
+    /// to implement a custom stopping criterion.
+    pub fn on_merge<F>(mut self, callback: F) -> Self
+    where
+        F: Fn(&MergeEvent) -> ControlFlow<()> + Send + Sync + 'static,
+    {
+        self.on_merge = Some(Arc::new(callback));
+        self
+    }
+}
+
+impl fmt::Debug for TrainConfig {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("TrainConfig")
+            .field("vocab_size", &self.vocab_size)
+            .field("split", &self.split)
+            .field("min_frequency", &self.min_frequency)
+            .field("early_stop", &self.early_stop)
+            .field("max_token_length", &self.max_token_length)
+            .field("tie_break", &self.tie_break)
+            .field("special_tokens", &self.special_tokens)
+            .field("alphabet", &self.alphabet)
+            .field("seed_tokens", &self.seed_tokens)
+            .field("checkpoint", &self.checkpoint)
+            .field("sample", &self.sample)
+            .field("dropout", &self.dropout)
+            .field("memory_limit", &self.memory_limit)
+            .field("on_merge", &self.on_merge.as_ref().map(|_| ".."))
+            .finish()
+    }
+}
+
+/// What a training run produced.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct TrainReport {
+    /// Number of merges learned. This can be lower than requested when the
+    /// corpus runs out of pairs to merge or training stops early.
+    pub num_merges: usize,
+    /// Training stopped because the best remaining pair was too rare, either
+    /// below `min_frequency` or occurring only once with `early_stop`.
+    pub stopped_early: bool,
+    /// Number of occurrences of each merged pair at the time it was merged,
+    /// in merge order. This is the pair-count distribution of the vocabulary.
+    pub merge_counts: Vec<usize>,
+    /// Distinct chunks the corpus was reduced to before counting pairs.
+    pub unique_chunks: usize,
+    /// Distinct chunks left out to stay within `TrainConfig::memory_limit`.
+    pub dropped_chunks: usize,
+    /// Length of the training corpus in tokens before and after training.
+    /// When training from scratch `tokens_before` is the number of bytes.
+    pub tokens_before: usize,
+    pub tokens_after: usize,
+    /// Time spent splitting and counting the corpus.
+    pub pretokenize_time: Duration,
+    /// Time spent in the merge loop.
+    pub merge_time: Duration,
+    /// Per-file contributions when training from a `Corpus`.
+    pub files: Vec<FileStats>,
+    /// Number of checkpoints written.
+    pub checkpoints: usize,
+    /// The last error hit while writing a checkpoint. Training carries on
+    /// regardless, so a full disk doesn't throw away hours of work.
+    pub checkpoint_error: Option<String>,
+}
+
+impl TrainReport {
+    /// How many times shorter the training corpus became, e.g. `3.2` when
+    /// every token covers 3.2 of the original ids on average.
+    pub fn compression_ratio(&self) -> f64 {
+        if self.tokens_after == 0 {
+            return 1.0;
+        }
+        self.tokens_before as f64 / self.tokens_after as f64
+    }
+}
+
+/// Why `train_from_ids` could not learn from its sequences.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum TrainIdsError {
+    /// The id at index `position` of sequence `sequence` is neither in the
+    /// vocab nor a special token.
+    UnknownId {
+        sequence: usize,
+        position: usize,
+        id: Token,
+    },
+}
+
+impl fmt::Display for TrainIdsError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TrainIdsError::UnknownId {
+                sequence,
+                position,
+                id,
+            } => write!(
+                f,
+                "unknown token id {} at position {} of sequence {}",
+                id, position, sequence
+            ),
+        }
+    }
+}
+
+impl std::error::Error for TrainIdsError {}
+
+/// Unique training chunks and the number of times each occurred in the
+/// corpus. Natural text repeats the same words over and over, so counting
+/// pairs over the weighted unique chunks is much faster than over every
+/// occurrence.
+pub type ChunkCounts = FastHashMap<Vec<Token>, usize>;
+
+/// A tokenizer that can learn its merges from a corpus.
+pub trait Trainable {
+    /// Turn `text` into the id sequences that pairs are counted within.
+    fn training_chunks(&self, text: &str, config: &TrainConfig) -> Vec<Vec<Token>>;
+
+    /// The merges the tokenizer currently applies.
+    fn merges(&self) -> &FastHashMap<(Token, Token), Token>;
+
+    /// Replace the tokenizer's merges with the ones learned by a training run.
+    fn set_merges(&mut self, merges: Vec<Merge>);
+
+    fn special_tokens(&self) -> &HashMap<String, Token>;
+
+    fn set_special_tokens(&mut self, special_tokens: HashMap<String, Token>);
+
+    fn train(&mut self, corpus: &str, config: TrainConfig) -> TrainReport {
+        let start = Instant::now();
+        let mut counts = ChunkCounts::default();
+        let mut sampler = Sampler::new(&config, Some(corpus.len()));
+        count_chunks(
+            &mut counts,
+            corpus_chunks(self, corpus, &config, &mut sampler),
+        );
+        let pretokenize_time = start.elapsed();
+
+        let mut report = self.train_counts(counts, config);
+        report.pretokenize_time = pretokenize_time;
+        report
+    }
+
+    /// Train on chunks that were already produced by `training_chunks` and
+    /// aggregated with their number of occurrences.
+    fn train_counts(&mut self, counts: ChunkCounts, config: TrainConfig) -> TrainReport {
+        let (merges, report) = if config.seed_tokens.is_empty() {
+            learn_merges(counts, &FastHashMap::default(), 256, &config)
+        } else {
+            let seeds = seed_merges(self, &config);
+            let first_id = next_id(seeds.iter().map(|&(_, idx)| idx));
+            let seeds: FastHashMap<_, _> = seeds.into_iter().collect();
+            let tokens_before = counts.iter().map(|(ids, count)| ids.len() * count).sum();
+            let mut seeded = ChunkCounts::default();
+            for (ids, count) in counts {
+                *seeded.entry(apply_merges(ids, &seeds)).or_insert(0) += count;
+            }
+            let (new_merges, mut report) = learn_merges(seeded, &seeds, first_id, &config);
+            report.tokens_before = tokens_before;
+            let mut merges: Vec<Merge> = seeds.into_iter().collect();
+            merges.sort_by_key(|&(_, idx)| idx);
+            merges.extend(new_merges);
+            (merges, report)
+        };
+        let first_special = next_id(merges.iter().map(|&(_, idx)| idx));
+        self.set_merges(merges);
+        self.set_special_tokens(assign_special_ids(&config.special_tokens, first_special));
+        report
+    }
+
+    /// Learn up to `additional_merges` new merges from `text` on top of the
+    /// current ones, e.g. to adapt a trained or loaded vocabulary to a new
+    /// domain without retraining from scratch. New ids continue after the
+    /// current highest id, special tokens included, and the literal text of
+    /// the special tokens is kept out of the new merges.
+    ///
+    /// The special tokens keep their ids, so text already encoded with them
+    /// stays valid, but if they come right after the merges the new merges
+    /// leave a gap in the merge ids. Such a tokenizer can't be saved as a
+    /// `.model` file, which numbers the merges from 256 without gaps; save
+    /// it in the binary or JSON format instead.
+    fn train_more(&mut self, text: &str, additional_merges: usize) -> TrainReport {
+        let (first_id, config) = extension_config(self, additional_merges);
+        let (merges, report) = continue_training(self, text, first_id, &config);
+        self.set_merges(merges);
+        report
+    }
+
+    /// Like `train_more`, but learn from sequences this tokenizer already
+    /// encoded, e.g. a corpus that was only kept around as token ids. Special
+    /// token ids split a sequence, so no merge spans one, and any merges the
+    /// ids are still missing are applied before counting. Pairs are counted
+    /// across a whole sequence, so for a tokenizer with a split pattern pass
+    /// each chunk's ids separately to keep merges within chunks.
+    ///
+    /// Fails on the first id the tokenizer doesn't know, leaving the
+    /// tokenizer unchanged.
+    fn train_from_ids<I>(
+        &mut self,
+        sequences: I,
+        additional_merges: usize,
+    ) -> Result<TrainReport, TrainIdsError>
+    where
+        I: IntoIterator,
+        I::Item: AsRef<[Token]>,
+        Self: Sized,
+    {
+        let (first_id, config) = extension_config(self, additional_merges);
+        let start = Instant::now();
+        let specials: HashSet<Token> = self.special_tokens().values().copied().collect();
+        let known: HashSet<Token> = self.merges().values().copied().collect();
+        let mut counts = ChunkCounts::default();
+        for (sequence, ids) in sequences.into_iter().enumerate() {
+            let ids = ids.as_ref();
+            if let Some((position, &id)) = ids.iter().enumerate().find(|&(_, &id)| {
+                !(0..256).contains(&id) && !known.contains(&id) && !specials.contains(&id)
+            }) {
+                return Err(TrainIdsError::UnknownId {
+                    sequence,
+                    position,
+                    id,
+                });
+            }
+            for chunk in ids.split(|id| specials.contains(id)) {
+                if !chunk.is_empty() {
+                    *counts
+                        .entry(apply_merges(chunk.to_vec(), self.merges()))
+                        .or_insert(0) += 1;
+                }
+            }
+        }
+        let pretokenize_time = start.elapsed();
+
+        let (merges, report) = extend_merges(self, counts, first_id, &config, pretokenize_time);
+        self.set_merges(merges);
+        Ok(report)
+    }
+
+    /// Drop the merges that are used fewer than `min_usage` times when
+    /// encoding `corpus`, along with every merge built on top of them, and
+    /// renumber what's left densely: merges keep their order from id 256 on
+    /// and the special tokens follow. Returns the new id of every id that
+    /// was kept, bytes included, to remap already-encoded data with.
+    fn prune(&mut self, min_usage: usize, corpus: &str) -> HashMap<Token, Token> {
+        let specials: Vec<String> = self.special_tokens().keys().cloned().collect();
+        let config = TrainConfig::new(0).special_tokens(specials);
+        let mut counts = ChunkCounts::default();
+        count_chunks(&mut counts, corpus_chunks(self, corpus, &config, &mut None));
+        let usage = merge_usage(&counts, self.merges());
+
+        let mut merges: Vec<Merge> = self
+            .merges()
+            .iter()
+            .map(|(&pair, &idx)| (pair, idx))
+            .collect();
+        merges.sort_by_key(|&(_, idx)| idx);
+        let mut mapping: HashMap<Token, Token> = (0..256).map(|id| (id, id)).collect();
+        let mut kept = Vec::new();
+        for ((p0, p1), idx) in merges {
+            let used = usage.get(&idx).copied().unwrap_or(0) >= min_usage;
+            if let (true, Some(&n0), Some(&n1)) = (used, mapping.get(&p0), mapping.get(&p1)) {
+                let new_idx = 256 + kept.len() as Token;
+                mapping.insert(idx, new_idx);
+                kept.push(((n0, n1), new_idx));
+            }
+        }
+
+        let mut special_tokens: Vec<(String, Token)> = self
+            .special_tokens()
+            .iter()
+            .map(|(special, &idx)| (special.clone(), idx))
+            .collect();
+        special_tokens.sort_by_key(|&(_, idx)| idx);
+        let first_special = 256 + kept.len() as Token;
+        let special_tokens: HashMap<String, Token> = special_tokens
+            .into_iter()
+            .enumerate()
+            .map(|(i, (special, idx))| {
+                let new_idx = first_special + i as Token;
+                mapping.insert(idx, new_idx);
+                (special, new_idx)
+            })
+            .collect();
+
+        self.set_merges(kept);
+        self.set_special_tokens(special_tokens);
+        mapping
+    }
+
+    /// Continue a training run that was interrupted, starting from the
+    /// merges saved to `checkpoint` (see `TrainConfig::checkpoint`). With the
+    /// same corpus and config the result is identical to an uninterrupted run,
+    /// unless the config has `dropout`: a checkpoint only holds the merges,
+    /// not the occurrences dropout left unmerged, so a resumed run continues
+    /// from fully merged chunks and learns different merges from there on.
+    fn resume_training(
+        &mut self,
+        corpus: &str,
+        checkpoint: impl AsRef<Path>,
+        config: TrainConfig,
+    ) -> io::Result<TrainReport>
+    where
+        Self: Sized,
+    {
+        let merges = read_checkpoint(checkpoint)?;
+        let first_id = next_id(merges.iter().map(|&(_, idx)| idx));
+        self.set_merges(merges);
+        let (merges, report) = continue_training(self, corpus, first_id, &config);
+        let first_special = next_id(merges.iter().map(|&(_, idx)| idx));
+        self.set_merges(merges);
+        self.set_special_tokens(assign_special_ids(&config.special_tokens, first_special));
+        Ok(report)
+    }
+
+    /// Train on a sequence of documents, e.g. rows from a database or lines of
+    /// a JSONL file, without concatenating them first. Each document is
+    /// chunked on its own, so no pair is counted across two documents.
+    fn train_from_iter<I>(&mut self, docs: I, config: TrainConfig) -> TrainReport
+    where
+        I: IntoIterator,
+        I::Item: AsRef<str>,
+        Self: Sized,
+    {
+        let start = Instant::now();
+        let mut counts = ChunkCounts::default();
+        let mut sampler = Sampler::new(&config, None);
+        for doc in docs {
+            let chunks = corpus_chunks(self, doc.as_ref(), &config, &mut sampler);
+            count_chunks(&mut counts, chunks);
+        }
+        let pretokenize_time = start.elapsed();
+
+        let mut report = self.train_counts(counts, config);
+        report.pretokenize_time = pretokenize_time;
+        report
+    }
+
+    /// Train on the contents of the file at `path`. The file is read in
+    /// blocks cut after newlines, so it never has to fit in a single `String`.
+    /// Pairs are never counted across those cuts.
+    fn train_from_file(
+        &mut self,
+        path: impl AsRef<Path>,
+        config: TrainConfig,
+    ) -> io::Result<TrainReport>
+    where
+        Self: Sized,
+    {
+        let start = Instant::now();
+        let mut counts = SpillCounts::new(config.memory_limit.clone());
+        let size = fs::metadata(path.as_ref())?.len() as usize;
+        let mut sampler = Sampler::new(&config, Some(size));
+        let progress = Progress::bytes(size, "reading");
+        read_training_chunks(
+            self,
+            path.as_ref(),
+            &config,
+            &mut sampler,
+            &mut counts,
+            &progress,
+        )?;
+        progress.finish();
+        let (counts, dropped_chunks) = counts.finish()?;
+        let pretokenize_time = start.elapsed();
+
+        let mut report = self.train_counts(counts, config);
+        report.pretokenize_time = pretokenize_time;
+        report.dropped_chunks = dropped_chunks;
+        Ok(report)
+    }
+
+    /// Train on every file of `corpus`, reporting how much each one contributed.
+    fn train_from_corpus(&mut self, corpus: &Corpus, config: TrainConfig) -> io::Result<TrainReport>
+    where
+        Self: Sized,
+    {
+        let start = Instant::now();
+        let mut counts = SpillCounts::new(config.memory_limit.clone());
+        let mut size = 0;
+        for path in corpus.files() {
+            size += fs::metadata(path)?.len() as usize;
+        }
+        let mut sampler = Sampler::new(&config, Some(size));
+        let progress = Progress::bytes(size, "reading");
+        let mut files = Vec::with_capacity(corpus.files().len());
+        for path in corpus.files() {
+            files.push(read_training_chunks(
+                self,
+                path,
+                &config,
+                &mut sampler,
+                &mut counts,
+                &progress,
+            )?);
+        }
+        progress.finish();
+        let (counts, dropped_chunks) = counts.finish()?;
+        let pretokenize_time = start.elapsed();
+
+        let mut report = self.train_counts(counts, config);
+        report.pretokenize_time = pretokenize_time;
+        report.dropped_chunks = dropped_chunks;
+        report.files = files;
+        Ok(report)
+    }
+}
+
+/// Add every chunk of `chunks` to `counts`.
+pub fn count_chunks(counts: &mut ChunkCounts, chunks: Vec<Vec<Token>>) {
+    for chunk in chunks {
+        *counts.entry(chunk).or_insert(0) += 1;
+    }
+}
+
+/// How many times each merge is applied when encoding the chunks of
+/// `counts`. Every application leaves a token that either ends up in the
+/// output or is consumed by a later merge, so walking the merges from the
+/// last to the first and pushing each token's usage down to its pair counts
+/// them all without re-running the encoder step by step.
+fn merge_usage(
+    counts: &ChunkCounts,
+    merges: &FastHashMap<(Token, Token), Token>,
+) -> HashMap<Token, usize> {
+    let mut usage: HashMap<Token, usize> = HashMap::new();
+    for (chunk, &count) in counts {
+        for id in apply_merges(chunk.clone(), merges) {
+            *usage.entry(id).or_insert(0) += count;
+        }
+    }
+    let mut by_idx: Vec<_> = merges.iter().map(|(&pair, &idx)| (idx, pair)).collect();
+    by_idx.sort_unstable_by(|a, b| b.cmp(a));
+    for (idx, (p0, p1)) in by_idx {
+        let used = usage.get(&idx).copied().unwrap_or(0);
+        *usage.entry(p0).or_insert(0) += used;
+        *usage.entry(p1).or_insert(0) += used;
+    }
+    usage
+}
+
+/// Learn merges from `text` on top of the tokenizer's current ones, with new
+/// ids starting at `first_id`. Returns the old and new merges together.
+fn continue_training<T: Trainable + ?Sized>(
+    tokenizer: &T,
+    text: &str,
+    first_id: Token,
+    config: &TrainConfig,
+) -> (Vec<Merge>, TrainReport) {
+    // replay the existing merges so that the new ones build on top of them
+    let start = Instant::now();
+    let mut counts = ChunkCounts::default();
+    let mut sampler = Sampler::new(config, Some(text.len()));
+    for chunk in corpus_chunks(tokenizer, text, config, &mut sampler) {
+        *counts
+            .entry(apply_merges(chunk, tokenizer.merges()))
+            .or_insert(0) += 1;
+    }
+    let pretokenize_time = start.elapsed();
+    extend_merges(tokenizer, counts, first_id, config, pretokenize_time)
+}
+
+/// Learn merges from `counts`, which already have the tokenizer's merges
+/// applied, and return the old and new merges together.
+fn extend_merges<T: Trainable + ?Sized>(
+    tokenizer: &T,
+    counts: ChunkCounts,
+    first_id: Token,
+    config: &TrainConfig,
+    pretokenize_time: Duration,
+) -> (Vec<Merge>, TrainReport) {
+    let (new_merges, mut report) = learn_merges(counts, tokenizer.merges(), first_id, config);
+    report.pretokenize_time = pretokenize_time;
+    let mut merges: Vec<_> = tokenizer
+        .merges()
+        .iter()
+        .map(|(&pair, &idx)| (pair, idx))
+        .collect();
+    merges.sort_by_key(|&(_, idx)| idx);
+    merges.extend(new_merges);
+    (merges, report)
+}
+
+/// The config `train_more` and `train_from_ids` learn `additional_merges`
+/// with: new ids continue after the current highest id, special tokens
+/// included. Returns the first new id along with it.
+fn extension_config<T: Trainable + ?Sized>(
+    tokenizer: &T,
+    additional_merges: usize,
+) -> (Token, TrainConfig) {
+    let first_id = next_id(
+        tokenizer
+            .merges()
+            .values()
+            .chain(tokenizer.special_tokens().values())
+            .copied(),
+    );
+    // the existing special tokens are cut out of the corpus but keep their
+    // ids, so the room `learn_merges` reserves for them is added on top
+    let specials: Vec<String> = tokenizer.special_tokens().keys().cloned().collect();
+    let config = TrainConfig::new(first_id as usize + additional_merges + specials.len())
+        .special_tokens(specials);
+    (first_id, config)
+}
+
+const CHECKPOINT_HEADER: &str = "minbpe-rs checkpoint v1";
+
+/// Save `merges` as a checkpoint: a header line, then one `p0 p1 idx` line
+/// per merge in the order they were learned. The file is replaced atomically,
+/// so an interruption never leaves a half-written checkpoint behind.
+fn write_checkpoint(path: &Path, merges: &[Merge]) -> io::Result<()> {
+    let mut out = String::from(CHECKPOINT_HEADER);
+    out.push('\n');
+    for &((p0, p1), idx) in merges {
+        out.push_str(&format!("{} {} {}\n", p0, p1, idx));
+    }
+    save_atomically(path, |file| file.write_all(out.as_bytes()))
+}
+
+/// Read the merges saved by a training checkpoint, in the order they were learned.
+pub fn read_checkpoint(path: impl AsRef<Path>) -> io::Result<Vec<Merge>> {
+    read_checkpoint_from_reader(fs::File::open(path)?)
+}
+
+/// Like `read_checkpoint`, reading the checkpoint from `reader`.
+pub fn read_checkpoint_from_reader(mut reader: impl Read) -> io::Result<Vec<Merge>> {
+    let invalid = |msg: &str| io::Error::new(io::ErrorKind::InvalidData, msg.to_string());
+    let mut text = String::new();
+    reader.read_to_string(&mut text)?;
+    let mut lines = text.lines();
+    if lines.next() != Some(CHECKPOINT_HEADER) {
+        return Err(invalid("not a training checkpoint"));
+    }
+    lines
+        .map(|line| {
+            let ids: Vec<Token> = line
+                .split(' ')
+                .map(|id| id.parse())
+                .collect::<Result<_, _>>()
+                .map_err(|_| invalid("malformed merge line in checkpoint"))?;
+            match ids[..] {
+                [p0, p1, idx] => Ok(((p0, p1), idx)),
+                _ => Err(invalid("malformed merge line in checkpoint")),
+            }
+        })
+        .collect()
+}
+
+/// `training_chunks` of the parts of `text` between the literals of
+/// `config.special_tokens`, so that special text never feeds into merges.
+/// With a `sampler`, only the lines it keeps are used, and with
+/// `config.alphabet` characters made of other bytes are cut out.
+fn corpus_chunks<T: Trainable + ?Sized>(
+    tokenizer: &T,
+    text: &str,
+    config: &TrainConfig,
+    sampler: &mut Option<Sampler>,
+) -> Vec<Vec<Token>> {
+    let specials: Vec<&str> = config.special_tokens.iter().map(String::as_str).collect();
+    let pieces: Vec<&str> = match sampler {
+        Some(sampler) => text
+            .split_inclusive('\n')
+            .filter(|line| sampler.keep(line))
+            .collect(),
+        None => vec![text],
+    };
+    let mut allowed = [config.alphabet.is_none(); 256];
+    for &b in config.alphabet.iter().flatten() {
+        allowed[b as usize] = true;
+    }
+    let outside = |c: char| {
+        c.encode_utf8(&mut [0; 4])
+            .bytes()
+            .any(|b| !allowed[b as usize])
+    };
+    pieces
+        .into_iter()
+        .flat_map(|piece| split_specials(piece, &specials))
+        .flat_map(|piece| piece.split(outside).filter(|part| !part.is_empty()))
+        .flat_map(|piece| tokenizer.training_chunks(piece, config))
+        .collect()
+}
+
+/// Decides which lines of the corpus make it into a sampled training run.
+struct Sampler {
+    rng: Rng,
+    fraction: f64,
+    remaining_bytes: Option<usize>,
+}
+
+impl Sampler {
+    /// The sampler for `config.sample`, if any, over a corpus of
+    /// `corpus_size` bytes when that's known.
+    fn new(config: &TrainConfig, corpus_size: Option<usize>) -> Option<Self> {
+        let sample = config.sample?;
+        let (fraction, remaining_bytes) = match sample.size {
+            SampleSize::Fraction(fraction) => (fraction, None),
+            SampleSize::Bytes(budget) => {
+                let fraction = match corpus_size {
+                    Some(size) if size > 0 => budget as f64 / size as f64,
+                    _ => 1.0,
+                };
+                (fraction, Some(budget))
+            }
+        };
+        Some(Sampler {
+            rng: Rng::new(sample.seed),
+            fraction,
+            remaining_bytes,
+        })
+    }
+
+    fn keep(&mut self, line: &str) -> bool {
+        // always draw, so that the choice for a line doesn't depend on
+        // whether the budget ran out before it
+        let picked = self.rng.next_f64() < self.fraction;
+        match &mut self.remaining_bytes {
+            _ if !picked => false,
+            Some(remaining) if line.len() > *remaining => {
+                *remaining = 0;
+                false
+            }
+            Some(remaining) => {
+                *remaining -= line.len();
+                true
+            }
+            None => true,
+        }
+    }
+}
+
+/// The merges that build `config.seed_tokens` from their bytes, left to right,
+/// with ids from 256 on. Prefixes shared between seeds are only merged once.
+fn seed_merges<T: Trainable + ?Sized>(tokenizer: &T, config: &TrainConfig) -> Vec<Merge> {
+    let raw = TrainConfig::new(config.vocab_size).split(false);
+    let mut merges: FastHashMap<(Token, Token), Token> = FastHashMap::default();
+    for seed in &config.seed_tokens {
+        for ids in tokenizer.training_chunks(seed, &raw) {
+            let mut ids = ids.into_iter();
+            let Some(mut token) = ids.next() else {
+                continue;
+            };
+            for id in ids {
+                let next = 256 + merges.len() as Token;
+                token = *merges.entry((token, id)).or_insert(next);
+            }
+        }
+    }
+    let mut merges: Vec<Merge> = merges.into_iter().collect();
+    merges.sort_by_key(|&(_, idx)| idx);
+    merges
+}
+
+/// Give `special_tokens` consecutive ids starting at `first_id`, ignoring
+/// repeated literals.
+fn assign_special_ids(special_tokens: &[String], first_id: Token) -> HashMap<String, Token> {
+    let mut ids = HashMap::new();
+    for special in special_tokens {
+        let next = first_id + ids.len() as Token;
+        ids.entry(special.clone()).or_insert(next);
+    }
+    ids
+}
+
+/// Stream the file at `path` through `tokenizer.training_chunks`, adding the
+/// chunks to `counts`.
+fn read_training_chunks<T: Trainable + ?Sized>(
+    tokenizer: &T,
+    path: &Path,
+    config: &TrainConfig,
+    sampler: &mut Option<Sampler>,
+    counts: &mut SpillCounts,
+    progress: &Progress,
+) -> io::Result<FileStats> {
+    let mut stats = FileStats {
+        path: path.to_path_buf(),
+        bytes: 0,
+        chunks: 0,
+    };
+    for text in SafeChunks::new(File::open(path)?) {
+        let text = text?;
+        let text_chunks = corpus_chunks(tokenizer, &text, config, sampler);
+        stats.bytes += text.len();
+        stats.chunks += text_chunks.len();
+        counts.add(text_chunks)?;
+        progress.inc(text.len());
+    }
+    Ok(stats)
+}
+
+/// Pair counts over the whole corpus, plus the chunks each pair occurs in.
+#[derive(Default)]
+struct PairIndex {
+    counts: FastHashMap<(Token, Token), usize>,
+    chunks: FastHashMap<(Token, Token), HashSet<usize>>,
+}
+
+impl PairIndex {
+    fn add_chunk(&mut self, i: usize, ids: &[Token], count: usize) {
+        for pair in ids.windows(2) {
+            let pair = (pair[0], pair[1]);
+            *self.counts.entry(pair).or_insert(0) += count;
+            self.chunks.entry(pair).or_default().insert(i);
+        }
+    }
+
+    #[cfg(not(feature = "parallel"))]
+    fn build(chunks: &[(Vec<Token>, usize)]) -> Self {
+        let mut index = PairIndex::default();
+        for (i, (ids, count)) in chunks.iter().enumerate() {
+            index.add_chunk(i, ids, *count);
+        }
+        index
+    }
+
+    /// Count the pairs of each shard of `chunks` on its own thread, then
+    /// combine the per-shard maps.
+    #[cfg(feature = "parallel")]
+    fn build(chunks: &[(Vec<Token>, usize)]) -> Self {
+        use rayon::prelude::*;
+
+        chunks
+            .par_iter()
+            .enumerate()
+            .fold(PairIndex::default, |mut index, (i, (ids, count))| {
+                index.add_chunk(i, ids, *count);
+                index
+            })
+            .reduce(PairIndex::default, |mut a, mut b| {
+                if a.counts.len() < b.counts.len() {
+                    std::mem::swap(&mut a, &mut b);
+                }
+                for (pair, count) in b.counts {
+                    *a.counts.entry(pair).or_insert(0) += count;
+                }
+                for (pair, chunks) in b.chunks {
+                    a.chunks.entry(pair).or_default().extend(chunks);
+                }
+                a
+            })
+    }
+}
+
+/// The result of `merge_with_changes`.
+struct MergedChunk {
+    ids: Vec<Token>,
+    /// Pairs that disappeared, once per occurrence.
+    removed: Vec<(Token, Token)>,
+    /// Pairs that appeared in their place, once per occurrence.
+    added: Vec<(Token, Token)>,
+}
+
+/// Like `merge`, but also reports how the pairs of `ids` changed. Only the
+/// neighbors of a merged occurrence change, so long chunks don't have to be
+/// recounted. Occurrences for which `keep` returns false are left unmerged.
+fn merge_with_changes(
+    ids: &[Token],
+    pair: (Token, Token),
+    new_token: Token,
+    mut keep: impl FnMut() -> bool,
+) -> MergedChunk {
+    let mut new_ids = Vec::with_capacity(ids.len());
+    // start positions of the pairs touching a merged occurrence, before and
+    // after the merge; both lists are sorted, so duplicates are adjacent
+    let mut old_starts = Vec::new();
+    let mut new_starts = Vec::new();
+    let mut i = 0;
+    while i < ids.len() {
+        if i + 1 < ids.len() && ids[i] == pair.0 && ids[i + 1] == pair.1 && keep() {
+            old_starts.extend((i.saturating_sub(1)..=i + 1).filter(|&k| k + 1 < ids.len()));
+            new_starts.extend(new_ids.len().saturating_sub(1)..=new_ids.len());
+            new_ids.push(new_token);
+            i += 2;
+        } else {
+            new_ids.push(ids[i]);
+            i += 1;
+        }
+    }
+    old_starts.dedup();
+    new_starts.dedup();
+    let removed = old_starts.iter().map(|&k| (ids[k], ids[k + 1])).collect();
+    let added = new_starts
+        .iter()
+        .filter(|&&k| k + 1 < new_ids.len())
+        .map(|&k| (new_ids[k], new_ids[k + 1]))
+        .collect();
+    MergedChunk {
+        ids: new_ids,
+        removed,
+        added,
+    }
+}
+
+/// The id right after the highest of `ids`, or 256 past the raw bytes.
+fn next_id(ids: impl IntoIterator<Item = Token>) -> Token {
+    ids.into_iter().map(|idx| idx + 1).max().unwrap_or(256)
+}
+
+/// Run BPE over the weighted unique chunks in `counts`, returning the learned
+/// merges in the order they were learned along with the merge statistics of
+/// the report. The chunks must already have the `existing` merges applied.
+/// New ids are assigned consecutively from `first_id`, stopping in time to
+/// leave room for `config.special_tokens` within `config.vocab_size`.
+pub(crate) fn learn_merges(
+    counts: ChunkCounts,
+    existing: &FastHashMap<(Token, Token), Token>,
+    first_id: Token,
+    config: &TrainConfig,
+) -> (Vec<Merge>, TrainReport) {
+    assert!(config.vocab_size >= 256, "Vocab size must be at least 256");
+    let num_merges = config
+        .vocab_size
+        .saturating_sub(first_id as usize + config.special_tokens.len());
+
+    // byte length of every token, to enforce `max_token_length`
+    let mut lengths: HashMap<Token, usize> = (0..256).map(|idx| (idx, 1)).collect();
+    let mut ordered: Vec<_> = existing.iter().collect();
+    ordered.sort_by_key(|&(_, &idx)| idx);
+    for (&(p0, p1), &idx) in ordered {
+        lengths.insert(idx, lengths[&p0] + lengths[&p1]);
+    }
+    let max_len = config.max_token_length.unwrap_or(usize::MAX);
+
+    let mut report = TrainReport {
+        unique_chunks: counts.len(),
+        ..Default::default()
+    };
+    // single ids can never take part in a merge, they only add to the totals
+    let mut chunks: Vec<(Vec<Token>, usize)> = Vec::with_capacity(counts.len());
+    let mut single_ids = 0;
+    for (ids, count) in counts {
+        report.tokens_before += ids.len() * count;
+        if ids.len() >= 2 {
+            chunks.push((ids, count));
+        } else {
+            single_ids += ids.len() * count;
+        }
+    }
+    if config.dropout.is_some() {
+        // the counts come in hash order; dropout needs a reproducible one
+        chunks.sort_unstable();
+    }
+
+    let start = Instant::now();
+    // pair counts over the whole corpus, plus the chunks each pair occurs in,
+    // so that a merge only has to revisit the chunks it affects
+    let PairIndex {
+        counts: mut pair_counts,
+        chunks: mut pair_chunks,
+    } = PairIndex::build(&chunks);
+    // the queue may hold outdated counts; they are fixed up lazily when popped
+    let mut queue: BinaryHeap<QueuedPair> = pair_counts
+        .iter()
+        .map(|(&pair, &count)| QueuedPair::new(pair, count, config.tie_break))
+        .collect();
+
+    let mut dropout = config
+        .dropout
+        .map(|dropout| (Rng::new(dropout.seed), dropout.rate));
+    // pairs merged with dropout can still occur; they are never merged again
+    let mut retired = HashSet::new();
+
+    let progress = Progress::new(num_merges, "merging");
+    let mut merges = Vec::with_capacity(num_merges);
+    while merges.len() < num_merges {
+        let Some(top) = queue.pop() else {
+            // nothing left to merge
+            break;
+        };
+        let top_pair = top.pair;
+        let count = pair_counts.get(&top_pair).copied().unwrap_or(0);
+        if count != top.count {
+            if count > 0 {
+                queue.push(QueuedPair::new(top_pair, count, config.tie_break));
+            }
+            continue;
+        }
+        if lengths[&top_pair.0] + lengths[&top_pair.1] > max_len {
+            continue;
+        }
+        if count < config.min_frequency || (config.early_stop && count < 2) {
+            report.stopped_early = true;
+            break;
+        }
+
+        let idx = first_id + merges.len() as Token;
+        let mut touched = HashSet::new();
+        let mut affected: Vec<usize> = pair_chunks
+            .remove(&top_pair)
+            .unwrap_or_default()
+            .into_iter()
+            .collect();
+        if dropout.is_some() {
+            affected.sort_unstable();
+        }
+        for i in affected {
+            let (ids, weight) = &mut chunks[i];
+            let keep = || match &mut dropout {
+                Some((rng, rate)) => rng.next_f64() >= *rate,
+                None => true,
+            };
+            let merged = merge_with_changes(ids, top_pair, idx, keep);
+            for pair in merged.removed {
+                if retired.contains(&pair) {
+                    continue;
+                }
+                let pair_count = pair_counts.get_mut(&pair).unwrap();
+                *pair_count -= *weight;
+                if *pair_count == 0 {
+                    pair_counts.remove(&pair);
+                }
+            }
+            for pair in merged.added {
+                if retired.contains(&pair) {
+                    continue;
+                }
+                *pair_counts.entry(pair).or_insert(0) += *weight;
+                pair_chunks.entry(pair).or_default().insert(i);
+                touched.insert(pair);
+            }
+            *ids = merged.ids;
+        }
+        for pair in touched {
+            if let Some(&count) = pair_counts.get(&pair) {
+                queue.push(QueuedPair::new(pair, count, config.tie_break));
+            }
+        }
+
+        if dropout.is_some() {
+            pair_counts.remove(&top_pair);
+            pair_chunks.remove(&top_pair);
+            retired.insert(top_pair);
+        }
+
+        merges.push((top_pair, idx));
+        lengths.insert(idx, lengths[&top_pair.0] + lengths[&top_pair.1]);
+        report.merge_counts.push(count);
+        progress.inc(1);
+
+        if let Some(checkpoint) = &config.checkpoint
+            && merges.len() % checkpoint.every == 0
+        {
+            let mut all: Vec<Merge> = existing.iter().map(|(&pair, &idx)| (pair, idx)).collect();
+            all.sort_by_key(|&(_, idx)| idx);
+            all.extend_from_slice(&merges);
+            match write_checkpoint(&checkpoint.path, &all) {
+                Ok(()) => report.checkpoints += 1,
+                Err(e) => report.checkpoint_error = Some(e.to_string()),
+            }
+        }
+
+        if let Some(on_merge) = &config.on_merge {
+            let event = MergeEvent {
+                pair: top_pair,
+                idx,
+                count,
+                elapsed: start.elapsed(),
+            };
+            if on_merge(&event).is_break() {
+                break;
+            }
+        }
+    }
+
+    progress.finish();
+    report.num_merges = merges.len();
+    report.tokens_after = single_ids
+        + chunks
+            .iter()
+            .map(|(ids, count)| ids.len() * count)
+            .sum::<usize>();
+    report.merge_time = start.elapsed();
+    (merges, report)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::base::{get_stats, merge};
+
+    /// Textbook BPE: recount every pair of every chunk before each merge.
+    fn naive_merges(counts: &ChunkCounts, config: &TrainConfig) -> Vec<Merge> {
+        let mut chunks: Vec<(Vec<Token>, usize)> = counts
+            .iter()
+            .map(|(ids, &count)| (ids.clone(), count))
+            .collect();
+        let mut lengths: HashMap<Token, usize> = (0..256).map(|idx| (idx, 1)).collect();
+        let max_len = config.max_token_length.unwrap_or(usize::MAX);
+        let mut merges = Vec::new();
+        for idx in 256..config.vocab_size as Token {
+            let mut stats: HashMap<(Token, Token), usize> = HashMap::new();
+            for (ids, count) in &chunks {
+                for (pair, n) in get_stats(ids) {
+                    *stats.entry(pair).or_insert(0) += n * count;
+                }
+            }
+            let best = stats
+                .into_iter()
+                .filter(|&(pair, _)| lengths[&pair.0] + lengths[&pair.1] <= max_len)
+                .max_by(|a, b| a.1.cmp(&b.1).then_with(|| config.tie_break.cmp(a.0, b.0)));
+            let Some((pair, count)) = best else {
+                break;
+            };
+            if count < config.min_frequency || (config.early_stop && count < 2) {
+                break;
+            }
+            for (ids, _) in &mut chunks {
+                *ids = merge(ids, pair, idx);
+            }
+            lengths.insert(idx, lengths[&pair.0] + lengths[&pair.1]);
+            merges.push((pair, idx));
+        }
+        merges
+    }
+
+    fn chunk_counts<'a>(chunks: impl IntoIterator<Item = &'a str>) -> ChunkCounts {
+        let mut counts = ChunkCounts::default();
+        for chunk in chunks {
+            *counts
+                .entry(chunk.bytes().map(Token::from).collect())
+                .or_insert(0) += 1;
+        }
+        counts
+    }
+
+    fn assert_same_merges(counts: &ChunkCounts, config: &TrainConfig) {
+        let (merges, _) = learn_merges(counts.clone(), &FastHashMap::default(), 256, config);
+        assert_eq!(merges, naive_merges(counts, config));
+    }
+
+    #[test]
+    fn overlapping_runs() {
+        let counts = chunk_counts(["aaa", "aaaa", "aaaaa", "aaaaaaa", "abababa", "aabaab"]);
+        for vocab_size in [257, 258, 260, 264, 280] {
+            assert_same_merges(&counts, &TrainConfig::new(vocab_size));
+        }
+    }
+
+    #[test]
+    fn ties() {
+        let counts = chunk_counts(["ab", "cd", "ef", "ba", "dc", "abcdef", "fedcba"]);
+        for tie_break in [TieBreak::LowestIds, TieBreak::HighestIds] {
+            let config = TrainConfig::new(270).tie_break(tie_break);
+            assert_same_merges(&counts, &config);
+        }
+    }
+
+    #[test]
+    fn early_stop() {
+        let counts = chunk_counts(["hello", "world", "help", "hold"]);
+        for early_stop in [true, false] {
+            let config = TrainConfig::new(300).early_stop(early_stop);
+            assert_same_merges(&counts, &config);
+        }
+        let (_, report) = learn_merges(
+            counts.clone(),
+            &FastHashMap::default(),
+            256,
+            &TrainConfig::new(300),
+        );
+        assert!(report.stopped_early);
+        let config = TrainConfig::new(300).early_stop(false);
+        let (_, report) = learn_merges(counts, &FastHashMap::default(), 256, &config);
+        // merged down to a single token per word
+        assert!(!report.stopped_early);
+        assert_eq!(report.tokens_after, 4);
+    }
+
+    #[test]
+    fn min_frequency_and_max_token_length() {
+        let counts = chunk_counts(["aaaa", "aaaa", "abab", "abab", "abab", "cdcdcd"]);
+        assert_same_merges(&counts, &TrainConfig::new(280).min_frequency(3));
+        assert_same_merges(&counts, &TrainConfig::new(280).max_token_length(2));
+    }
+
+    #[test]
+    fn random_corpus() {
+        // a small alphabet makes for many overlaps and ties
+        let mut rng = Rng::new(42);
+        let text: String = (0..20_000)
+            .map(|_| b"aab  c"[(rng.next_u64() % 6) as usize] as char)
+            .collect();
+        let counts = chunk_counts(text.split(' '));
+        for tie_break in [TieBreak::LowestIds, TieBreak::HighestIds] {
+            assert_same_merges(&counts, &TrainConfig::new(400).tie_break(tie_break));
+        }
+    }
+
+    #[test]
+    fn taylorswift() {
+        let text = include_str!("../taylorswift.txt");
+        let counts = chunk_counts(text.split_inclusive(char::is_whitespace));
+        assert_same_merges(&counts, &TrainConfig::new(356));
+    }
+
+    #[test]
+    fn resume_matches_uninterrupted_run() {
+        use crate::regex::RegexTokenizer;
+
+        let text = include_str!("../taylorswift.txt");
+        let path = std::env::temp_dir().join(format!("resume-{}.ckpt", std::process::id()));
+        let mut full = RegexTokenizer::new();
+        full.train(text, TrainConfig::new(400));
+
+        let config = TrainConfig::new(400)
+            .checkpoint(&path, 25)
+            .on_merge(|event| {
+                if event.idx == 256 + 60 {
+                    ControlFlow::Break(())
+                } else {
+                    ControlFlow::Continue(())
+                }
+            });
+        let mut interrupted = RegexTokenizer::new();
+        interrupted.train(text, config);
+        assert_eq!(read_checkpoint(&path).unwrap().len(), 50);
+
+        let mut resumed = RegexTokenizer::new();
+        resumed
+            .resume_training(text, &path, TrainConfig::new(400))
+            .unwrap();
+        fs::remove_file(&path).unwrap();
+        assert_eq!(resumed.merges(), full.merges());
+    }
+
+    #[test]
+    fn train_more_after_specials() {
+        use crate::basic::BasicTokenizer;
+
+        let mut tokenizer = BasicTokenizer::new();
+        tokenizer.train("hello world, hello there", TrainConfig::new(260));
+        let specials = HashMap::from([("<|a|>".to_string(), 260), ("<|b|>".to_string(), 261)]);
+        tokenizer.set_special_tokens(specials.clone());
+        let report = tokenizer.train_more("the other world, the other there", 3);
+        assert_eq!(report.num_merges, 3);
+        // the specials keep their ids and the new merges go after them
+        assert_eq!(tokenizer.special_tokens(), &specials);
+        let mut ids: Vec<Token> = tokenizer.merges().values().copied().collect();
+        ids.sort_unstable();
+        assert_eq!(ids, [256, 257, 258, 259, 262, 263, 264]);
+        let error = tokenizer.save_to_writer(Vec::new()).unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::InvalidInput);
+    }
+
+    #[test]
+    fn prune_keeps_the_used_merges() {
+        use crate::base::{SpecialSet, Tokenizer};
+        use crate::regex::RegexTokenizer;
+
+        let corpus = "hello world, the world is wide <|endoftext|> and hello again";
+        let mut tokenizer = RegexTokenizer::new();
+        tokenizer.train(include_str!("../taylorswift.txt"), TrainConfig::new(400));
+        tokenizer
+            .register_special_tokens(HashMap::from([("<|endoftext|>".to_string(), 400)]))
+            .unwrap();
+        tokenizer.set_allowed_special(SpecialSet::All);
+        let before = tokenizer.encode(corpus);
+        let mapping = tokenizer.prune(1, corpus);
+        let num_merges = tokenizer.merges().len() as Token;
+        assert!(num_merges < 144);
+        // merges the corpus doesn't use never applied to it
+        let remapped: Vec<Token> = before.iter().map(|id| mapping[id]).collect();
+        assert_eq!(tokenizer.encode(corpus), remapped);
+        let mut ids: Vec<Token> = tokenizer.merges().values().copied().collect();
+        ids.sort_unstable();
+        assert!(ids.into_iter().eq(256..256 + num_merges));
+        assert_eq!(
+            tokenizer.special_tokens()["<|endoftext|>"],
+            256 + num_merges
+        );
+
+        let mapping = tokenizer.prune(usize::MAX, corpus);
+        assert!(tokenizer.merges().is_empty());
+        assert_eq!(mapping.len(), 257);
+        assert_eq!(tokenizer.special_tokens()["<|endoftext|>"], 256);
+    }
+}
@@ -0,0 +1,233 @@
+/*
+Cutting text down to a token budget, e.g. to fit a document into a context
+window. The text is cut at token boundaries, and the result is re-encoded so
+the reported count is always exact.
+*/
+
+use fancy_regex::Regex;
+use std::ops::Range;
+
+use crate::base::{Piece, Token};
+use crate::normalize::{Normalizer, OffsetMap};
+use crate::pretokenize::split_chunks;
+
+/// Which part of the text `truncate_to_tokens` gives up.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Truncation {
+    /// Keep the start of the text.
+    End,
+    /// Keep the end of the text.
+    Start,
+    /// Keep both ends and put `ellipsis` (e.g. `"…"`) in place of the middle.
+    /// Its tokens count towards the budget.
+    Middle { ellipsis: String },
+}
+
+/// Cut `text` to at most `max_tokens` tokens as encoded by `encode` (which
+/// returns tokens with their byte ranges), returning the kept text and its
+/// token count.
+pub(crate) fn truncate<F>(
+    text: &str,
+    max_tokens: usize,
+    truncation: &Truncation,
+    mut encode: F,
+) -> (String, usize)
+where
+    F: FnMut(&str) -> Vec<(Token, Range<usize>)>,
+{
+    let offsets = encode(text);
+    if offsets.len() <= max_tokens {
+        return (text.to_string(), offsets.len());
+    }
+    // byte offset where the first `n` tokens end, and where the last `n` start,
+    // moved onto the characters those tokens hold completely (and within
+    // `text`, should `encode` report offsets past its end)
+    let head = |n: usize| {
+        let mut cut = if n == 0 { 0 } else { offsets[n - 1].1.end };
+        cut = cut.min(text.len());
+        while !text.is_char_boundary(cut) {
+            cut -= 1;
+        }
+        cut
+    };
+    let tail = |n: usize| {
+        let mut cut = if n == 0 {
+            text.len()
+        } else {
+            offsets[offsets.len() - n].1.start
+        };
+        cut = cut.min(text.len());
+        while !text.is_char_boundary(cut) {
+            cut += 1;
+        }
+        cut
+    };
+
+    // tokens may merge differently once the text is cut, so re-encode and
+    // give up one more token until the budget holds
+    match truncation {
+        Truncation::End => {
+            for n in (0..=max_tokens).rev() {
+                let kept = &text[..head(n)];
+                let count = encode(kept).len();
+                if count <= max_tokens {
+                    return (kept.to_string(), count);
+                }
+            }
+        }
+        Truncation::Start => {
+            for n in (0..=max_tokens).rev() {
+                let kept = &text[tail(n)..];
+                let count = encode(kept).len();
+                if count <= max_tokens {
+                    return (kept.to_string(), count);
+                }
+            }
+        }
+        Truncation::Middle { ellipsis } => {
+            let ellipsis_tokens = encode(ellipsis).len();
+            if ellipsis_tokens > max_tokens {
+                return truncate(text, max_tokens, &Truncation::End, encode);
+            }
+            let room = max_tokens - ellipsis_tokens;
+            let (mut front, mut back) = (room - room / 2, room / 2);
+            loop {
+                let kept = format!(
+                    "{}{}{}",
+                    &text[..head(front)],
+                    ellipsis,
+                    &text[tail(back)..]
+                );
+                let count = encode(&kept).len();
+                if count <= max_tokens || front + back == 0 {
+                    return (kept, count);
+                }
+                if front > back {
+                    front -= 1;
+                } else {
+                    back -= 1;
+                }
+            }
+        }
+    }
+    (String::new(), 0)
+}
+
+/// Encode the `pieces` of `text` chunk by chunk (as normalized by
+/// `normalizer`, split by `re` and encoded by `encode_chunk`), stopping before
+/// the first chunk or special token that would take the count past
+/// `max_tokens`. Returns the tokens and the number of bytes of `text` they
+/// cover, which encode to exactly those tokens.
+pub(crate) fn encode_limited<F>(
+    text: &str,
+    pieces: Vec<Piece>,
+    normalizer: &Normalizer,
+    re: &Regex,
+    max_tokens: usize,
+    mut encode_chunk: F,
+) -> (Vec<Token>, usize)
+where
+    F: FnMut(&str, &mut Vec<Token>),
+{
+    let mut ids = Vec::new();
+    let mut consumed = 0;
+    let mut chunk_ids = Vec::new();
+    for piece in pieces {
+        match piece {
+            Piece::Text(piece) => {
+                // pieces are slices of `text`, and every special token is
+                // followed by one, so this also accounts for the specials
+                let offset = piece.as_ptr() as usize - text.as_ptr() as usize;
+                consumed = offset;
+                // the number of tokens the `consumed` bytes encode to
+                let mut kept = ids.len();
+                let mut offsets = OffsetMap::default();
+                let normalized = normalizer.normalize_mapped(piece, 0, &mut offsets);
+                for chunk in split_chunks(re, &normalized) {
+                    chunk_ids.clear();
+                    encode_chunk(chunk, &mut chunk_ids);
+                    if ids.len() + chunk_ids.len() > max_tokens {
+                        ids.truncate(kept);
+                        return (ids, consumed);
+                    }
+                    ids.extend_from_slice(&chunk_ids);
+                    let end = chunk.as_ptr() as usize - normalized.as_ptr() as usize + chunk.len();
+                    // a chunk ending inside characters the normalizer changed
+                    // doesn't end anywhere in `text`, so it can't stop there
+                    if let Some(end) = offsets.exact(end) {
+                        (consumed, kept) = (offset + end, ids.len());
+                    }
+                }
+                consumed = offset + piece.len();
+            }
+            Piece::Special(idx) => {
+                if ids.len() == max_tokens {
+                    return (ids, consumed);
+                }
+                ids.push(idx);
+            }
+        }
+    }
+    (ids, consumed)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::base::Tokenizer;
+    use crate::normalize::Normalizer;
+    use crate::regex::RegexTokenizer;
+    use crate::train::{TrainConfig, Trainable};
+
+    const TEXT: &str =
+        "Taylor Alison Swift (born December 13, 1989) is an American\r\nSinger-Songwriter.";
+
+    fn trained() -> RegexTokenizer {
+        let mut tokenizer = RegexTokenizer::new();
+        tokenizer.train(include_str!("../taylorswift.txt"), TrainConfig::new(400));
+        tokenizer
+    }
+
+    #[test]
+    fn budget_holds_for_every_strategy() {
+        let mut tokenizer = trained();
+        let total = tokenizer.encode(TEXT).len();
+        let ellipsis = Truncation::Middle {
+            ellipsis: "…".to_string(),
+        };
+        for max_tokens in 0..=total + 1 {
+            for truncation in [Truncation::End, Truncation::Start, ellipsis.clone()] {
+                let (kept, count) = tokenizer.truncate_to_tokens(TEXT, max_tokens, &truncation);
+                assert!(count <= max_tokens, "{:?} {}", truncation, max_tokens);
+                assert_eq!(tokenizer.encode(&kept).len(), count);
+                match truncation {
+                    _ if max_tokens >= total => assert_eq!(kept, TEXT),
+                    Truncation::End => assert!(TEXT.starts_with(&kept)),
+                    Truncation::Start => assert!(TEXT.ends_with(&kept)),
+                    Truncation::Middle { .. } => match kept.split_once('…') {
+                        Some((head, tail)) => {
+                            assert!(TEXT.starts_with(head) && TEXT.ends_with(tail))
+                        }
+                        // no room for the ellipsis
+                        None => assert!(TEXT.starts_with(&kept)),
+                    },
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn normalized_text_is_kept_as_given() {
+        let mut tokenizer = trained();
+        tokenizer.set_normalizer(Normalizer::new().crlf_to_lf(true).lowercase(true));
+        let total = tokenizer.encode(TEXT).len();
+        for max_tokens in 0..total {
+            let (kept, count) = tokenizer.truncate_to_tokens(TEXT, max_tokens, &Truncation::Start);
+            assert!(TEXT.ends_with(&kept));
+            assert!(count <= max_tokens);
+            assert_eq!(tokenizer.encode(&kept).len(), count);
+        }
+        let (kept, _) = tokenizer.truncate_to_tokens(TEXT, total - 1, &Truncation::End);
+        assert!(kept.starts_with("Taylor Alison"));
+    }
+}
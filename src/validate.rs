@@ -0,0 +1,180 @@
+/*
+Checking that a tokenizer's merges, vocab and special tokens agree with each
+other, e.g. right after loading a model from a file of unknown provenance.
+A hand-edited or partly corrupted model loads without complaint, but a merge
+whose parents don't exist never gets a vocab entry, so encoding can produce
+ids that decoding then can't map back to text. `validate` reports every such problem
+upfront, and `validated` turns them into an error.
+*/
+
+use std::collections::{HashMap, HashSet};
+use std::error::Error;
+use std::fmt;
+use std::io;
+use std::ops::Range;
+
+use crate::base::Token;
+use crate::hash::FastHashMap;
+
+/// A problem found by `validate`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Issue {
+    /// A merge produces the id of a single byte.
+    ByteOverwritten { id: Token },
+    /// Two merges produce the same id.
+    DuplicateId { id: Token },
+    /// A parent of the merge producing `id` is neither a byte nor produced
+    /// by an earlier merge, so the token can't be built.
+    UndefinedParent { id: Token, parent: Token },
+    /// The vocab has no bytes for the token a merge produces.
+    MissingToken { id: Token },
+    /// The bytes of the token aren't those of its parents concatenated.
+    WrongBytes { id: Token },
+    /// The vocab has a token that is neither a byte nor produced by a merge.
+    StrayToken { id: Token },
+    /// No merge produces these ids, though later ones do.
+    MissingIds { ids: Range<Token> },
+    /// A special token has the id of a byte or merged token.
+    SpecialCollision { special: String, id: Token },
+}
+
+impl fmt::Display for Issue {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Issue::ByteOverwritten { id } => write!(f, "merge produces byte id {}", id),
+            Issue::DuplicateId { id } => write!(f, "several merges produce id {}", id),
+            Issue::UndefinedParent { id, parent } => write!(
+                f,
+                "merge producing {} has parent {} which isn't defined before it",
+                id, parent
+            ),
+            Issue::MissingToken { id } => write!(f, "token {} has no bytes in the vocab", id),
+            Issue::WrongBytes { id } => write!(
+                f,
+                "bytes of token {} aren't those of its parents concatenated",
+                id
+            ),
+            Issue::StrayToken { id } => write!(f, "token {} isn't produced by any merge", id),
+            Issue::MissingIds { ids } if ids.len() == 1 => {
+                write!(f, "no merge produces id {}", ids.start)
+            }
+            Issue::MissingIds { ids } => {
+                write!(f, "no merge produces ids {} to {}", ids.start, ids.end - 1)
+            }
+            Issue::SpecialCollision { special, id } => write!(
+                f,
+                "special token {:?} has the id {} of a regular token",
+                special, id
+            ),
+        }
+    }
+}
+
+/// The outcome of `validate`.
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub struct ValidationReport {
+    /// The number of merges checked.
+    pub merges: usize,
+    /// The problems found.
+    pub issues: Vec<Issue>,
+}
+
+impl ValidationReport {
+    /// Whether no problems were found.
+    pub fn is_valid(&self) -> bool {
+        self.issues.is_empty()
+    }
+}
+
+impl fmt::Display for ValidationReport {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if self.is_valid() {
+            return write!(f, "{} merges, no problems", self.merges);
+        }
+        write!(f, "{} merges, {} problems:", self.merges, self.issues.len())?;
+        for issue in &self.issues {
+            write!(f, "\n- {}", issue)?;
+        }
+        Ok(())
+    }
+}
+
+impl Error for ValidationReport {}
+
+/// So that `load(path)?.validated()?` works in functions returning
+/// `io::Result`.
+impl From<ValidationReport> for io::Error {
+    fn from(report: ValidationReport) -> Self {
+        io::Error::new(io::ErrorKind::InvalidData, report)
+    }
+}
+
+/// Check `merges`, the `vocab` built from them and `special_tokens` against
+/// each other.
+pub(crate) fn validate(
+    merges: &FastHashMap<(Token, Token), Token>,
+    vocab: &FastHashMap<Token, Vec<u8>>,
+    special_tokens: &HashMap<String, Token>,
+) -> ValidationReport {
+    let mut issues = Vec::new();
+    let mut ordered: Vec<_> = merges.iter().map(|(&pair, &idx)| (idx, pair)).collect();
+    ordered.sort_unstable();
+
+    let mut defined: HashSet<Token> = (0..256).collect();
+    for (i, &(idx, (p0, p1))) in ordered.iter().enumerate() {
+        if idx < 256 {
+            issues.push(Issue::ByteOverwritten { id: idx });
+        } else if i > 0 && ordered[i - 1].0 == idx {
+            issues.push(Issue::DuplicateId { id: idx });
+        }
+        for parent in [p0, p1] {
+            if !defined.contains(&parent) {
+                issues.push(Issue::UndefinedParent { id: idx, parent });
+            }
+        }
+        defined.insert(idx);
+        match (vocab.get(&idx), vocab.get(&p0), vocab.get(&p1)) {
+            (None, _, _) => issues.push(Issue::MissingToken { id: idx }),
+            (Some(bytes), Some(left), Some(right))
+                if *bytes != [&left[..], &right[..]].concat() =>
+            {
+                issues.push(Issue::WrongBytes { id: idx });
+            }
+            // the bytes match, or a missing parent is reported already
+            _ => {}
+        }
+    }
+
+    let mut stray: Vec<Token> = vocab
+        .keys()
+        .copied()
+        .filter(|idx| !defined.contains(idx))
+        .collect();
+    stray.sort_unstable();
+    issues.extend(stray.into_iter().map(|id| Issue::StrayToken { id }));
+
+    // merges are numbered from 256 without gaps, as after training
+    let mut next = 256;
+    for &(idx, _) in &ordered {
+        if idx > next {
+            issues.push(Issue::MissingIds { ids: next..idx });
+        }
+        next = next.max(idx + 1);
+    }
+
+    let mut specials: Vec<_> = special_tokens.iter().collect();
+    specials.sort_by_key(|&(special, &idx)| (idx, special));
+    for (special, &idx) in specials {
+        if defined.contains(&idx) || vocab.contains_key(&idx) {
+            issues.push(Issue::SpecialCollision {
+                special: special.clone(),
+                id: idx,
+            });
+        }
+    }
+
+    ValidationReport {
+        merges: merges.len(),
+        issues,
+    }
+}
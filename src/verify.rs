@@ -0,0 +1,71 @@
+/*
+Checking that a tokenizer reproduces text exactly, e.g. after loading or
+converting a model: encode a sample of the corpus, decode it again, and see
+which parts of the text came back different.
+*/
+
+use std::ops::Range;
+
+/// The outcome of `verify_roundtrip`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RoundTripReport {
+    /// The number of tokens the text encoded to.
+    pub tokens: usize,
+    /// The parts of the text that decoded differently, in order.
+    pub mismatches: Vec<Mismatch>,
+}
+
+impl RoundTripReport {
+    /// Whether the text came back exactly.
+    pub fn is_lossless(&self) -> bool {
+        self.mismatches.is_empty()
+    }
+}
+
+/// A part of the text that didn't survive the round trip.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Mismatch {
+    /// The byte range of the original text. It may start or end inside a
+    /// multi-byte character.
+    pub range: Range<usize>,
+    /// What was decoded in its place.
+    pub decoded: String,
+}
+
+/// Find the parts of `text` that differ in `decoded`. After a difference the
+/// comparison resumes at the next line of both, so changes that keep the
+/// line structure (e.g. case or Unicode normalization) are reported one by
+/// one, each narrowed to the bytes that actually differ.
+pub(crate) fn compare(text: &[u8], decoded: &[u8]) -> Vec<Mismatch> {
+    let line_end = |bytes: &[u8], from: usize| {
+        bytes[from..]
+            .iter()
+            .position(|&b| b == b'\n')
+            .map_or(bytes.len(), |i| from + i + 1)
+    };
+    let mut mismatches = Vec::new();
+    let (mut i, mut j) = (0, 0);
+    loop {
+        while i < text.len() && j < decoded.len() && text[i] == decoded[j] {
+            i += 1;
+            j += 1;
+        }
+        if i == text.len() && j == decoded.len() {
+            return mismatches;
+        }
+        let (text_end, decoded_end) = (line_end(text, i), line_end(decoded, j));
+        // the rest of the line may well match again
+        let mut common = 0;
+        while common < text_end - i
+            && common < decoded_end - j
+            && text[text_end - 1 - common] == decoded[decoded_end - 1 - common]
+        {
+            common += 1;
+        }
+        mismatches.push(Mismatch {
+            range: i..text_end - common,
+            decoded: String::from_utf8_lossy(&decoded[j..decoded_end - common]).into_owned(),
+        });
+        (i, j) = (text_end, decoded_end);
+    }
+}
@@ -0,0 +1,60 @@
+/*
+Rendering a tokenization for a terminal, like the tiktokenizer web UI: every
+token's text on a background color alternating with its neighbours'.
+*/
+
+use std::ops::Range;
+
+use crate::base::Token;
+
+/// ANSI 256-color backgrounds cycled through, light enough for dark text.
+const BACKGROUNDS: [u8; 5] = [153, 186, 218, 151, 223];
+const RESET: &str = "\x1b[0m";
+
+/// Render `text`, the decoding of `ids` with `spans` as returned by
+/// `decode_with_spans`, with each token on its own background color, and
+/// with `show_ids` the ids below it in the same colors. A character split
+/// over tokens goes to the token holding its first byte.
+pub(crate) fn visualize(
+    text: &str,
+    ids: &[Token],
+    spans: &[Range<usize>],
+    show_ids: bool,
+) -> String {
+    let ceil = |mut i: usize| {
+        while !text.is_char_boundary(i) {
+            i += 1;
+        }
+        i
+    };
+    let mut out = String::new();
+    for (i, span) in spans.iter().enumerate() {
+        let piece = &text[ceil(span.start)..ceil(span.end)];
+        if piece.is_empty() {
+            continue;
+        }
+        let color = format!("\x1b[30;48;5;{}m", BACKGROUNDS[i % BACKGROUNDS.len()]);
+        // a background carried over a line break fills the rest of the line
+        for (j, line) in piece.split('\n').enumerate() {
+            if j > 0 {
+                out.push('\n');
+            }
+            if !line.is_empty() {
+                out.push_str(&color);
+                out.push_str(line);
+                out.push_str(RESET);
+            }
+        }
+    }
+    if show_ids {
+        out.push('\n');
+        for (i, id) in ids.iter().enumerate() {
+            if i > 0 {
+                out.push(' ');
+            }
+            let color = BACKGROUNDS[i % BACKGROUNDS.len()];
+            out.push_str(&format!("\x1b[30;48;5;{}m{}{}", color, id, RESET));
+        }
+    }
+    out
+}